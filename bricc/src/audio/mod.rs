@@ -0,0 +1,86 @@
+pub mod ringtone;
+pub mod traits;
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+
+pub const MAX_VOLUME: u8 = 100;
+pub const DEFAULT_VOLUME: u8 = 70;
+
+/// Volume and mute, shared between an audio module and the streams it opened so
+/// changes apply to streams already running on other threads.
+#[derive(Clone)]
+pub struct AudioControls {
+    volume: Arc<AtomicU8>,
+    muted: Arc<AtomicBool>,
+}
+
+impl AudioControls {
+    pub fn new() -> AudioControls {
+        AudioControls {
+            volume: Arc::new(AtomicU8::new(DEFAULT_VOLUME)),
+            muted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume.load(Ordering::Relaxed)
+    }
+
+    pub fn set_volume(&self, volume: u8) {
+        self.volume.store(volume.min(MAX_VOLUME), Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// Scales samples on their way to the speaker.
+    pub fn apply_volume(&self, pcm: &mut [i16]) {
+        let volume = self.volume() as i32;
+        if volume == MAX_VOLUME as i32 {
+            return;
+        }
+        for sample in pcm.iter_mut() {
+            *sample = (*sample as i32 * volume / MAX_VOLUME as i32) as i16;
+        }
+    }
+
+    /// Silences samples on their way from the mic, if muted.
+    pub fn apply_mute(&self, pcm: &mut [i16]) {
+        if self.is_muted() {
+            for sample in pcm.iter_mut() {
+                *sample = 0;
+            }
+        }
+    }
+}
+
+impl Default for AudioControls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Linear interpolation between sample rates. Good enough for voice at phone rates.
+pub fn resample(pcm: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    if from_rate == to_rate || pcm.is_empty() {
+        return pcm.to_vec();
+    }
+    let out_len = (pcm.len() as u64 * to_rate as u64 / from_rate as u64) as usize;
+    let mut out = Vec::with_capacity(out_len);
+    for i in 0..out_len {
+        // Position in the input, in 1/65536ths of a sample.
+        let position = i as u64 * from_rate as u64 * 65536 / to_rate as u64;
+        let index = (position >> 16) as usize;
+        let fraction = (position & 0xffff) as i32;
+        let a = pcm[index.min(pcm.len() - 1)] as i32;
+        let b = pcm[(index + 1).min(pcm.len() - 1)] as i32;
+        out.push((a + (((b - a) * fraction) >> 16)) as i16);
+    }
+    out
+}
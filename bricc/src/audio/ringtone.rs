@@ -0,0 +1,68 @@
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+
+use super::traits::PlaybackStream;
+
+// The North American ring: 440 + 480 Hz, two seconds on, four off.
+const RING_FREQUENCIES_HZ: [f32; 2] = [440.0, 480.0];
+const RING_ON: Duration = Duration::from_secs(2);
+const RING_PERIOD: Duration = Duration::from_secs(6);
+const RING_AMPLITUDE: f32 = 8000.0;
+// Queued ahead of time, so a slow loop iteration doesn't leave a gap.
+const RING_LEAD: Duration = Duration::from_millis(60);
+const RING_CHUNK_SAMPLES: usize = 160;
+
+/// Plays the ring on a playback stream until dropped. Needs `pump`ing regularly.
+pub struct Ringtone {
+    playback: Box<dyn PlaybackStream>,
+    started_at: Instant,
+    written: u64,
+}
+
+impl Ringtone {
+    pub fn new(playback: Box<dyn PlaybackStream>) -> Ringtone {
+        Ringtone {
+            playback,
+            started_at: Instant::now(),
+            written: 0,
+        }
+    }
+
+    /// Writes whatever has come due since the last call.
+    pub fn pump(&mut self) {
+        let format = self.playback.format();
+        let channels = format.channels.max(1) as usize;
+        let due = (self.started_at.elapsed() + RING_LEAD).as_micros() as u64
+            * format.sample_rate as u64
+            / 1_000_000;
+        let mut chunk = [0i16; RING_CHUNK_SAMPLES];
+        while self.written < due {
+            let frames = ((due - self.written) as usize).min(RING_CHUNK_SAMPLES / channels);
+            for (frame, samples) in chunk.chunks_exact_mut(channels).take(frames).enumerate() {
+                let sample = self.sample(self.written + frame as u64, format.sample_rate);
+                samples.iter_mut().for_each(|out| *out = sample);
+            }
+            let taken = self.playback.write(&chunk[..frames * channels]) / channels;
+            if taken == 0 {
+                // Full; the rest will still be due next time.
+                break;
+            }
+            self.written += taken as u64;
+        }
+    }
+
+    fn sample(&self, frame: u64, sample_rate: u32) -> i16 {
+        let rate = sample_rate as u64;
+        // Whole periods of both tones fit in a cadence, so restarting the phase is seamless.
+        let position = frame % (RING_PERIOD.as_secs() * rate);
+        if position >= RING_ON.as_secs() * rate {
+            return 0;
+        }
+        let t = position as f32 / sample_rate as f32;
+        let mixed: f32 = RING_FREQUENCIES_HZ
+            .iter()
+            .map(|frequency| (2.0 * PI * frequency * t).sin())
+            .sum();
+        (mixed * RING_AMPLITUDE / RING_FREQUENCIES_HZ.len() as f32) as i16
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+
+use super::AudioControls;
+
+/// Audio is always signed 16 bit PCM, interleaved if there's more than one channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl AudioFormat {
+    pub fn mono(sample_rate: u32) -> AudioFormat {
+        AudioFormat {
+            sample_rate,
+            channels: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AudioError {
+    Unsupported(AudioFormat),
+    Device(String),
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioError::Unsupported(format) => write!(
+                f,
+                "Unsupported format {} Hz x{}",
+                format.sample_rate, format.channels
+            ),
+            AudioError::Device(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Microphone samples. Streams get handed to whichever thread moves the audio.
+pub trait CaptureStream: Send {
+    fn format(&self) -> AudioFormat;
+    /// Copies out as many captured samples as are ready, up to `pcm.len()`. Never blocks.
+    fn read(&mut self, pcm: &mut [i16]) -> usize;
+}
+
+pub trait PlaybackStream: Send {
+    fn format(&self) -> AudioFormat;
+    /// Queues samples for the speaker and returns how many were taken. Never blocks.
+    fn write(&mut self, pcm: &[i16]) -> usize;
+}
+
+pub trait AudioModule {
+    type Capture: CaptureStream + 'static;
+    type Playback: PlaybackStream + 'static;
+
+    /// The closest format the hardware does to `wanted`. Open streams with this and
+    /// resample if it isn't what the codec wants.
+    fn negotiate_format(&self, wanted: AudioFormat) -> AudioFormat;
+    fn open_capture(&mut self, format: AudioFormat) -> Result<Self::Capture, AudioError>;
+    fn open_playback(&mut self, format: AudioFormat) -> Result<Self::Playback, AudioError>;
+
+    /// Volume and mute, shared with every stream this module opens.
+    fn controls(&self) -> AudioControls;
+
+    /// Speaker volume, 0 to `MAX_VOLUME`.
+    fn volume(&self) -> u8 {
+        self.controls().volume()
+    }
+
+    fn set_volume(&mut self, volume: u8) {
+        self.controls().set_volume(volume)
+    }
+
+    /// Mutes the microphone.
+    fn is_muted(&self) -> bool {
+        self.controls().is_muted()
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.controls().set_muted(muted)
+    }
+}
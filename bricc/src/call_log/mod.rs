@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::prefs::kv_store::KvStore;
+use crate::voip::call::{CallDirection, CallInfo};
+
+pub const CALL_LOG_PREFS_KEY: &str = "call_log";
+// The oldest drop off the end once it's full.
+const MAX_CALL_LOG_ENTRIES: usize = 30;
+// Anything earlier means the clock was never set, 2021-01-01.
+const EARLIEST_PLAUSIBLE_TIME: u64 = 1_609_459_200;
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CallKind {
+    Dialed,
+    Received,
+    /// Incoming and never answered, whether the caller gave up or we turned it down.
+    Missed,
+}
+
+impl fmt::Display for CallKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            CallKind::Dialed => "Dialed",
+            CallKind::Received => "Received",
+            CallKind::Missed => "Missed",
+        };
+        f.write_str(label)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CallLogEntry {
+    pub kind: CallKind,
+    pub remote_uri: String,
+    /// The user part of the remote URI, which is the number for most providers.
+    pub number: String,
+    /// Who it was in the contacts at the time, if anyone.
+    pub contact_name: Option<String>,
+    /// When it started, in seconds since the Unix epoch. Zero if the clock wasn't set.
+    pub timestamp: u64,
+    /// How long it was connected for.
+    pub duration_secs: u32,
+}
+
+impl CallLogEntry {
+    pub fn from_call(info: &CallInfo, contact_name: Option<String>) -> CallLogEntry {
+        let kind = match (info.direction, info.connected_at) {
+            (CallDirection::Outgoing, _) => CallKind::Dialed,
+            (CallDirection::Incoming, Some(_)) => CallKind::Received,
+            (CallDirection::Incoming, None) => CallKind::Missed,
+        };
+        let duration_secs = match info.connected_at {
+            Some(connected_at) => {
+                let ended_at = info.ended_at.unwrap_or_else(Instant::now);
+                ended_at.saturating_duration_since(connected_at).as_secs() as u32
+            }
+            None => 0,
+        };
+        let timestamp = match unix_time_now() {
+            Some(now) => now.saturating_sub(info.started_at.elapsed().as_secs()),
+            None => 0,
+        };
+        CallLogEntry {
+            kind,
+            remote_uri: info.remote_uri.clone(),
+            number: info.remote_user(),
+            contact_name,
+            timestamp,
+            duration_secs,
+        }
+    }
+
+    /// The contact's name if there was one, or else the number.
+    pub fn display_name(&self) -> &str {
+        self.contact_name.as_deref().unwrap_or(&self.number)
+    }
+
+    /// "dd/mm hh:mm" in UTC, or None if the clock wasn't set when the call was made.
+    pub fn formatted_time(&self) -> Option<String> {
+        if self.timestamp < EARLIEST_PLAUSIBLE_TIME {
+            return None;
+        }
+        let (_, month, day) = civil_from_days(self.timestamp / SECONDS_PER_DAY);
+        let seconds_of_day = self.timestamp % SECONDS_PER_DAY;
+        Some(format!(
+            "{:02}/{:02} {:02}:{:02}",
+            day,
+            month,
+            seconds_of_day / 3600,
+            seconds_of_day % 3600 / 60
+        ))
+    }
+
+    pub fn formatted_duration(&self) -> String {
+        let minutes = self.duration_secs / 60;
+        if minutes >= 60 {
+            format!(
+                "{}:{:02}:{:02}",
+                minutes / 60,
+                minutes % 60,
+                self.duration_secs % 60
+            )
+        } else {
+            format!("{}:{:02}", minutes, self.duration_secs % 60)
+        }
+    }
+}
+
+fn unix_time_now() -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    if now < EARLIEST_PLAUSIBLE_TIME {
+        None
+    } else {
+        Some(now)
+    }
+}
+
+// Year, month and day of a day count since 1970-01-01, from Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Every call made, taken or missed, newest first, kept in the `KvStore` as one ring
+/// of at most `MAX_CALL_LOG_ENTRIES`. Shared between the GUI and `Bricc` like the
+/// `MessageStore`; changes are written back by `save`.
+pub struct CallLog {
+    entries: VecDeque<CallLogEntry>,
+    dirty: bool,
+}
+
+impl CallLog {
+    pub fn load<KvStoreImpl: KvStore>(kv_store: &mut KvStoreImpl) -> CallLog {
+        let entries = match kv_store.get::<VecDeque<CallLogEntry>>(CALL_LOG_PREFS_KEY.into()) {
+            Ok(Some(entries)) => entries,
+            Ok(None) => VecDeque::new(),
+            Err(err) => {
+                println!("Lost the call log: {}", err);
+                VecDeque::new()
+            }
+        };
+        CallLog {
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub fn save<KvStoreImpl: KvStore>(&mut self, kv_store: &mut KvStoreImpl) -> Result<(), String> {
+        if self.dirty {
+            self.dirty = false;
+            kv_store.put(CALL_LOG_PREFS_KEY.into(), &self.entries)?;
+        }
+        Ok(())
+    }
+
+    pub fn needs_saving(&self) -> bool {
+        self.dirty
+    }
+
+    /// Newest first, only those of `kind` if given.
+    pub fn entries(&self, kind: Option<CallKind>) -> Vec<CallLogEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| match kind {
+                Some(kind) => entry.kind == kind,
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn record(&mut self, entry: CallLogEntry) {
+        self.entries.push_front(entry);
+        self.entries.truncate(MAX_CALL_LOG_ENTRIES);
+        self.dirty = true;
+    }
+}
@@ -0,0 +1,450 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::prefs::kv_store::KvStore;
+
+pub mod vcard;
+
+// Which contacts there are, and the next ID to hand out. Each contact lives under a
+// key of its own, like the message threads, so the book isn't limited to one NVS entry.
+pub const CONTACTS_PREFS_KEY: &str = "contacts";
+// NVS keys are at most 15 characters, which rules out "contact_".
+const CONTACT_PREFS_KEY_PREFIX: &str = "ct_";
+// What the contacts under the index are stored as. Bump it when `Contact` changes
+// shape, and teach `load` to upgrade the old one.
+const CONTACTS_FORMAT_VERSION: u32 = 2;
+// Which contact is on which digit, as (digit, ID) pairs.
+pub const SPEED_DIAL_PREFS_KEY: &str = "speed_dial";
+/// Holding 1 always calls voicemail, so speed dials start at 2.
+pub const VOICEMAIL_DIGIT: u8 = 1;
+pub const SPEED_DIAL_DIGITS: std::ops::RangeInclusive<u8> = 2..=9;
+// Below this many digits, a number only matches if it's exactly the same.
+const MIN_SUFFIX_MATCH_DIGITS: usize = 7;
+
+/// Stays with a contact through edits and renames, and isn't reused after a delete.
+pub type ContactId = u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NumberKind {
+    Mobile,
+    Home,
+    Work,
+    /// A SIP URI rather than a phone number.
+    Sip,
+    Other,
+}
+
+impl NumberKind {
+    pub const ALL: [NumberKind; 5] = [
+        NumberKind::Mobile,
+        NumberKind::Home,
+        NumberKind::Work,
+        NumberKind::Sip,
+        NumberKind::Other,
+    ];
+}
+
+impl fmt::Display for NumberKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            NumberKind::Mobile => "Mobile",
+            NumberKind::Home => "Home",
+            NumberKind::Work => "Work",
+            NumberKind::Sip => "SIP",
+            NumberKind::Other => "Other",
+        };
+        f.write_str(label)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ContactNumber {
+    pub kind: NumberKind,
+    /// Digits for a phone number, or the URI, with or without `sip:`, for `Sip`.
+    pub number: String,
+}
+
+impl ContactNumber {
+    /// Whether a dialed or calling number, or the user part of a URI, is this one.
+    pub fn matches(&self, number: &str) -> bool {
+        match self.kind {
+            NumberKind::Sip => {
+                let user = uri_user(&self.number);
+                user == number || numbers_match(user, number)
+            }
+            _ => numbers_match(&self.number, number),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Contact {
+    pub name: String,
+    pub numbers: Vec<ContactNumber>,
+    pub email: String,
+    pub note: String,
+    /// Which of `numbers` pressing `Call` on the contact dials.
+    pub default_number: usize,
+}
+
+impl Contact {
+    /// Someone with a single mobile number, or none if `number` is empty.
+    pub fn new(name: String, number: String) -> Contact {
+        let numbers = if number.trim().is_empty() {
+            vec![]
+        } else {
+            vec![ContactNumber {
+                kind: NumberKind::Mobile,
+                number,
+            }]
+        };
+        Contact {
+            name,
+            numbers,
+            ..Contact::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.trim().is_empty()
+            && self.numbers.is_empty()
+            && self.email.trim().is_empty()
+            && self.note.trim().is_empty()
+    }
+
+    /// What `Call` dials: the chosen default, or the first there is.
+    pub fn default_number(&self) -> Option<&ContactNumber> {
+        self.default_index().map(|index| &self.numbers[index])
+    }
+
+    pub fn default_index(&self) -> Option<usize> {
+        if self.default_number < self.numbers.len() {
+            Some(self.default_number)
+        } else if self.numbers.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+
+    /// Whether digits typed on the keypad pick this contact out: spelled T9-style at the
+    /// start of any word of the name ("526" finds "Jane Doe"), or the start of a number.
+    pub fn matches_keys(&self, keys: &str) -> bool {
+        if keys.is_empty() {
+            return true;
+        }
+        let name_matches = self
+            .name
+            .split_whitespace()
+            .any(|word| t9_keys(word).starts_with(keys));
+        name_matches
+            || self.numbers.iter().any(|number| {
+                let number = match number.kind {
+                    NumberKind::Sip => uri_user(&number.number),
+                    _ => &number.number,
+                };
+                digits_of(number).starts_with(keys)
+            })
+    }
+
+    pub fn has_number(&self, number: &str) -> bool {
+        self.numbers.iter().any(|entry| entry.matches(number))
+    }
+
+    /// Drops one of the numbers, keeping the default on the same one if it's not the
+    /// one going.
+    pub fn remove_number(&mut self, index: usize) {
+        if index >= self.numbers.len() {
+            return;
+        }
+        self.numbers.remove(index);
+        if self.default_number > index {
+            self.default_number -= 1;
+        } else if self.default_number == index {
+            self.default_number = 0;
+        }
+    }
+}
+
+// Format 1: one number and nothing else.
+#[derive(Deserialize)]
+struct ContactV1 {
+    name: String,
+    phone_number: String,
+}
+
+impl From<ContactV1> for Contact {
+    fn from(contact: ContactV1) -> Contact {
+        Contact::new(contact.name, contact.phone_number)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContactEntry {
+    pub id: ContactId,
+    pub contact: Contact,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ContactIndex {
+    // Missing before there were versions.
+    #[serde(default = "first_format_version")]
+    version: u32,
+    next_id: ContactId,
+    ids: Vec<ContactId>,
+}
+
+fn first_format_version() -> u32 {
+    1
+}
+
+fn contact_key(id: ContactId) -> String {
+    format!("{}{}", CONTACT_PREFS_KEY_PREFIX, id)
+}
+
+/// Everyone we know, sorted by name and kept in the `KvStore`. Shared between panes
+/// through the `GuiContext`; changes are written back by `save`.
+pub struct ContactBook {
+    entries: Vec<ContactEntry>,
+    next_id: ContactId,
+    // Contacts changed or deleted since the last save, and whether the index did.
+    dirty_ids: Vec<ContactId>,
+    index_dirty: bool,
+    speed_dial: Vec<(u8, ContactId)>,
+    speed_dial_dirty: bool,
+}
+
+impl ContactBook {
+    pub fn load<KvStoreImpl: KvStore>(kv_store: &mut KvStoreImpl) -> ContactBook {
+        let index = match kv_store.get::<ContactIndex>(CONTACTS_PREFS_KEY.into()) {
+            Ok(Some(index)) => index,
+            Ok(None) => ContactIndex {
+                version: CONTACTS_FORMAT_VERSION,
+                next_id: 0,
+                ids: vec![],
+            },
+            Err(err) => {
+                println!("Lost the contacts: {}", err);
+                ContactIndex {
+                    version: CONTACTS_FORMAT_VERSION,
+                    next_id: 0,
+                    ids: vec![],
+                }
+            }
+        };
+        if index.version > CONTACTS_FORMAT_VERSION {
+            println!(
+                "Contacts are in format {}, newer than this build",
+                index.version
+            );
+        }
+        let mut entries = vec![];
+        for id in index.ids.iter().copied() {
+            let contact = match index.version {
+                1 => kv_store
+                    .get::<ContactV1>(contact_key(id))
+                    .map(|contact| contact.map(Contact::from)),
+                _ => kv_store.get::<Contact>(contact_key(id)),
+            };
+            match contact {
+                Ok(Some(contact)) => entries.push(ContactEntry { id, contact }),
+                _ => println!("Lost contact {}", id),
+            }
+        }
+        let next_id = entries
+            .iter()
+            .map(|entry| entry.id + 1)
+            .fold(index.next_id, ContactId::max);
+        let mut speed_dial: Vec<(u8, ContactId)> = match kv_store.get(SPEED_DIAL_PREFS_KEY.into()) {
+            Ok(Some(speed_dial)) => speed_dial,
+            _ => vec![],
+        };
+        speed_dial.retain(|(_, id)| entries.iter().any(|entry| entry.id == *id));
+        let upgraded = index.version < CONTACTS_FORMAT_VERSION;
+        let mut book = ContactBook {
+            // Old ones get rewritten in the current format.
+            dirty_ids: if upgraded { index.ids } else { vec![] },
+            index_dirty: upgraded,
+            entries,
+            next_id,
+            speed_dial,
+            speed_dial_dirty: false,
+        };
+        book.sort();
+        book
+    }
+
+    /// Writes back whatever changed since last time.
+    pub fn save<KvStoreImpl: KvStore>(&mut self, kv_store: &mut KvStoreImpl) -> Result<(), String> {
+        let mut ids = std::mem::take(&mut self.dirty_ids);
+        ids.sort_unstable();
+        ids.dedup();
+        for id in ids {
+            match self.get(id) {
+                Some(contact) => kv_store.put(contact_key(id), contact)?,
+                // Deleted; don't leave them lying around.
+                None => kv_store.put(contact_key(id), &Option::<Contact>::None)?,
+            }
+        }
+        if self.index_dirty {
+            self.index_dirty = false;
+            let index = ContactIndex {
+                version: CONTACTS_FORMAT_VERSION,
+                next_id: self.next_id,
+                ids: self.entries.iter().map(|entry| entry.id).collect(),
+            };
+            kv_store.put(CONTACTS_PREFS_KEY.into(), &index)?;
+        }
+        if self.speed_dial_dirty {
+            self.speed_dial_dirty = false;
+            kv_store.put(SPEED_DIAL_PREFS_KEY.into(), &self.speed_dial)?;
+        }
+        Ok(())
+    }
+
+    pub fn needs_saving(&self) -> bool {
+        self.index_dirty || !self.dirty_ids.is_empty() || self.speed_dial_dirty
+    }
+
+    /// Alphabetically by name.
+    pub fn contacts(&self) -> &[ContactEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, id: ContactId) -> Option<&Contact> {
+        self.entries
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| &entry.contact)
+    }
+
+    pub fn add(&mut self, contact: Contact) -> ContactId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(ContactEntry { id, contact });
+        self.sort();
+        self.dirty_ids.push(id);
+        self.index_dirty = true;
+        id
+    }
+
+    /// Replaces what's stored for `id`. False if there's no such contact any more.
+    pub fn update(&mut self, id: ContactId, contact: Contact) -> bool {
+        let entry = match self.entries.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if entry.contact == contact {
+            return true;
+        }
+        entry.contact = contact;
+        self.sort();
+        self.dirty_ids.push(id);
+        true
+    }
+
+    pub fn remove(&mut self, id: ContactId) {
+        let count = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        if self.entries.len() != count {
+            self.dirty_ids.push(id);
+            self.index_dirty = true;
+        }
+        let assigned = self.speed_dial.len();
+        self.speed_dial
+            .retain(|(_, assigned_id)| *assigned_id != id);
+        if self.speed_dial.len() != assigned {
+            self.speed_dial_dirty = true;
+        }
+    }
+
+    /// Who holding `digit` on the idle screen calls, if anyone.
+    pub fn speed_dial(&self, digit: u8) -> Option<&ContactEntry> {
+        let (_, id) = self.speed_dial.iter().find(|(key, _)| *key == digit)?;
+        self.entries.iter().find(|entry| entry.id == *id)
+    }
+
+    /// Puts a contact on one of `SPEED_DIAL_DIGITS`, or takes whoever's there off.
+    pub fn set_speed_dial(&mut self, digit: u8, id: Option<ContactId>) {
+        if !SPEED_DIAL_DIGITS.contains(&digit) {
+            return;
+        }
+        self.speed_dial.retain(|(key, _)| *key != digit);
+        if let Some(id) = id {
+            self.speed_dial.push((digit, id));
+            self.speed_dial.sort_unstable();
+        }
+        self.speed_dial_dirty = true;
+    }
+
+    /// The contact a dialed or calling number belongs to, if any.
+    pub fn find_by_number(&self, number: &str) -> Option<&Contact> {
+        self.entries
+            .iter()
+            .map(|entry| &entry.contact)
+            .find(|contact| contact.has_number(number))
+    }
+
+    // Case doesn't matter, and the nameless go last. The index follows the order so a
+    // reload doesn't need to sort, but only gets written when it changes anyway.
+    fn sort(&mut self) {
+        let before: Vec<ContactId> = self.entries.iter().map(|entry| entry.id).collect();
+        self.entries.sort_by_cached_key(|entry| {
+            let name = entry.contact.name.trim().to_lowercase();
+            (name.is_empty(), name, entry.id)
+        });
+        if self.entries.iter().map(|entry| entry.id).ne(before) {
+            self.index_dirty = true;
+        }
+    }
+}
+
+/// The user part of a SIP URI, which for most providers is the number.
+pub fn uri_user(uri: &str) -> &str {
+    let without_scheme = match uri.find(':') {
+        Some(idx) => &uri[idx + 1..],
+        None => uri,
+    };
+    match without_scheme.find('@') {
+        Some(idx) => &without_scheme[..idx],
+        None => without_scheme,
+    }
+}
+
+// The keys you'd press to type `text`; anything without a key of its own goes on 1
+// with the punctuation.
+fn t9_keys(text: &str) -> String {
+    text.chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            'a'..='c' => '2',
+            'd'..='f' => '3',
+            'g'..='i' => '4',
+            'j'..='l' => '5',
+            'm'..='o' => '6',
+            'p'..='s' => '7',
+            't'..='v' => '8',
+            'w'..='z' => '9',
+            digit @ '0'..='9' => digit,
+            _ => '1',
+        })
+        .collect()
+}
+
+fn digits_of(number: &str) -> String {
+    number.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+/// Whether two numbers are the same line. Punctuation is ignored, and "+1 555 123 4567"
+/// matches "5551234567" since the national part is the same.
+pub fn numbers_match(a: &str, b: &str) -> bool {
+    let (a, b) = (digits_of(a), digits_of(b));
+    if a.is_empty() || b.is_empty() {
+        return false;
+    }
+    if a == b {
+        return true;
+    }
+    let (shorter, longer) = if a.len() < b.len() { (a, b) } else { (b, a) };
+    shorter.len() >= MIN_SUFFIX_MATCH_DIGITS && longer.ends_with(&shorter)
+}
@@ -0,0 +1,444 @@
+use std::fmt;
+
+use super::{Contact, ContactNumber, NumberKind};
+
+// RFC 6350 section 3.2; 3.0 says the same.
+const MAX_LINE_OCTETS: usize = 75;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VCardVersion {
+    V3,
+    V4,
+}
+
+impl fmt::Display for VCardVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VCardVersion::V3 => f.write_str("3.0"),
+            VCardVersion::V4 => f.write_str("4.0"),
+        }
+    }
+}
+
+// One content line, with the group and case taken out of the name.
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn parse(line: &str) -> Option<Property> {
+        let colon = unquoted_find(line, ':')?;
+        let mut parts = split_unquoted(&line[..colon], ';').into_iter();
+        let name = parts.next()?;
+        // "item1.TEL" is just a TEL as far as we care.
+        let name = name.rsplit('.').next().unwrap_or(name).to_ascii_uppercase();
+        let params = parts
+            .map(|param| match param.find('=') {
+                Some(eq) => (
+                    param[..eq].trim().to_ascii_uppercase(),
+                    param[eq + 1..].trim_matches('"').to_string(),
+                ),
+                // vCard 2.1 leaves out the TYPE=, and older 3.0 exporters copy it.
+                None => ("TYPE".into(), param.to_string()),
+            })
+            .collect();
+        Some(Property {
+            name,
+            params,
+            value: line[colon + 1..].to_string(),
+        })
+    }
+
+    // Every TYPE, however they were split up, lower case.
+    fn types(&self) -> Vec<String> {
+        self.params
+            .iter()
+            .filter(|(name, _)| name == "TYPE")
+            .flat_map(|(_, value)| value.split(','))
+            .map(|value| value.trim().to_ascii_lowercase())
+            .collect()
+    }
+
+    fn is_preferred(&self) -> bool {
+        self.types().iter().any(|kind| kind == "pref")
+            || self.params.iter().any(|(name, _)| name == "PREF")
+    }
+}
+
+fn unquoted_find(text: &str, wanted: char) -> Option<usize> {
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == wanted && !quoted => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut rest = text;
+    while let Some(index) = unquoted_find(rest, separator) {
+        parts.push(&rest[..index]);
+        rest = &rest[index + separator.len_utf8()..];
+    }
+    parts.push(rest);
+    parts
+}
+
+// Splits a structured value like N on the separators that aren't escaped.
+fn split_escaped(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    parts.last_mut().unwrap().push('\\');
+                    parts.last_mut().unwrap().push(escaped);
+                }
+            }
+            c if c == separator => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Long lines carry on after a CRLF and a space.
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        match (
+            line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(continued), Some(last)) => last.push_str(continued),
+            _ => lines.push(line.into()),
+        }
+    }
+    lines
+}
+
+fn fold(line: &str, out: &mut String) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            out.push_str("\r\n ");
+            // The space counts.
+            octets = 1;
+        }
+        out.push(c);
+        octets += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn number_kind(types: &[String]) -> NumberKind {
+    for kind in types {
+        match kind.as_str() {
+            "cell" | "mobile" | "iphone" => return NumberKind::Mobile,
+            "home" => return NumberKind::Home,
+            "work" => return NumberKind::Work,
+            _ => {}
+        }
+    }
+    NumberKind::Other
+}
+
+fn type_names(kind: NumberKind) -> &'static str {
+    match kind {
+        NumberKind::Mobile => "cell",
+        NumberKind::Home => "home",
+        NumberKind::Work => "work",
+        NumberKind::Sip | NumberKind::Other => "voice",
+    }
+}
+
+// "Given Middle Family" and so on, from N's Family;Given;Additional;Prefix;Suffix.
+fn name_from_n(value: &str) -> String {
+    let parts: Vec<String> = split_escaped(value, ';')
+        .iter()
+        .map(|part| unescape(&part.replace(',', " ")))
+        .collect();
+    let part = |index: usize| parts.get(index).map(|p| p.trim()).unwrap_or("");
+    [part(3), part(1), part(2), part(0), part(4)]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Every contact in a vCard 2.1, 3.0 or 4.0 file. Properties we don't keep are skipped.
+pub fn parse(text: &str) -> Result<Vec<Contact>, String> {
+    let mut contacts = vec![];
+    // The contact so far, its name from N in case there's no FN, and whether a TEL
+    // was marked preferred yet.
+    let mut current: Option<(Contact, Option<String>, bool)> = None;
+    for (index, line) in unfold(text).iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let property = Property::parse(line)
+            .ok_or_else(|| format!("Line {} isn't a vCard property", index + 1))?;
+        let is_vcard = property.value.trim().eq_ignore_ascii_case("VCARD");
+        match property.name.as_str() {
+            "BEGIN" if is_vcard => {
+                if current.is_some() {
+                    return Err(format!("Nested BEGIN on line {}", index + 1));
+                }
+                current = Some((Contact::default(), None, false));
+                continue;
+            }
+            "END" if is_vcard => {
+                let (mut contact, n, _) = current
+                    .take()
+                    .ok_or_else(|| format!("END without BEGIN on line {}", index + 1))?;
+                if contact.name.trim().is_empty() {
+                    contact.name = n.unwrap_or_default();
+                }
+                if !contact.is_empty() {
+                    contacts.push(contact);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let (contact, n, has_preferred) = match &mut current {
+            Some(current) => current,
+            None => return Err(format!("Line {} is outside a vCard", index + 1)),
+        };
+        match property.name.as_str() {
+            "FN" => contact.name = unescape(&property.value),
+            "N" => *n = Some(name_from_n(&property.value)),
+            "TEL" => {
+                let value = unescape(&property.value);
+                let (kind, number) = if value.starts_with("sip:") || value.starts_with("sips:") {
+                    (NumberKind::Sip, value)
+                } else {
+                    let number = value.strip_prefix("tel:").unwrap_or(&value);
+                    (number_kind(&property.types()), number.to_string())
+                };
+                if number.trim().is_empty() {
+                    continue;
+                }
+                if property.is_preferred() && !*has_preferred {
+                    *has_preferred = true;
+                    contact.default_number = contact.numbers.len();
+                }
+                contact.numbers.push(ContactNumber { kind, number });
+            }
+            "IMPP" | "X-SIP" => {
+                let value = unescape(&property.value);
+                if property.name == "X-SIP" || value.starts_with("sip:") {
+                    contact.numbers.push(ContactNumber {
+                        kind: NumberKind::Sip,
+                        number: value,
+                    });
+                }
+            }
+            "EMAIL" if contact.email.is_empty() || property.is_preferred() => {
+                contact.email = unescape(&property.value);
+            }
+            "NOTE" => {
+                if !contact.note.is_empty() {
+                    contact.note.push('\n');
+                }
+                contact.note.push_str(&unescape(&property.value));
+            }
+            _ => {}
+        }
+    }
+    if current.is_some() {
+        return Err("vCard without an END".into());
+    }
+    Ok(contacts)
+}
+
+/// The contacts as one vCard file.
+pub fn serialize(contacts: &[Contact], version: VCardVersion) -> String {
+    let mut out = String::new();
+    for contact in contacts {
+        fold("BEGIN:VCARD", &mut out);
+        fold(&format!("VERSION:{}", version), &mut out);
+        fold(&format!("FN:{}", escape(&contact.name)), &mut out);
+        // Required in 3.0. Which part is the family name is guesswork, so it's all Given.
+        fold(&format!("N:;{};;;", escape(&contact.name)), &mut out);
+        let default_index = contact.default_index();
+        for (index, number) in contact.numbers.iter().enumerate() {
+            let is_default = contact.numbers.len() > 1 && Some(index) == default_index;
+            let line = match (number.kind, version) {
+                (NumberKind::Sip, _) => format!("IMPP:{}", sip_uri(&number.number)),
+                (kind, VCardVersion::V3) => format!(
+                    "TEL;TYPE={}{}:{}",
+                    type_names(kind).to_ascii_uppercase(),
+                    if is_default { ",PREF" } else { "" },
+                    escape(&number.number)
+                ),
+                (kind, VCardVersion::V4) => format!(
+                    "TEL;TYPE={}{}:{}",
+                    type_names(kind),
+                    if is_default { ";PREF=1" } else { "" },
+                    escape(&number.number)
+                ),
+            };
+            fold(&line, &mut out);
+        }
+        if !contact.email.is_empty() {
+            fold(&format!("EMAIL:{}", escape(&contact.email)), &mut out);
+        }
+        if !contact.note.is_empty() {
+            fold(&format!("NOTE:{}", escape(&contact.note)), &mut out);
+        }
+        fold("END:VCARD", &mut out);
+    }
+    out
+}
+
+fn sip_uri(number: &str) -> String {
+    if number.starts_with("sip:") || number.starts_with("sips:") {
+        number.into()
+    } else {
+        format!("sip:{}", number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(kind: NumberKind, number: &str) -> ContactNumber {
+        ContactNumber {
+            kind,
+            number: number.into(),
+        }
+    }
+
+    #[test]
+    fn parses_a_phone_export() {
+        let text = "BEGIN:VCARD\r\n\
+                    VERSION:3.0\r\n\
+                    N:Doe;Jane;;Dr.;\r\n\
+                    item1.TEL;TYPE=HOME:+44 20 7946 0000\r\n\
+                    TEL;TYPE=CELL,PREF:07700 900\r\n 123\r\n\
+                    IMPP:sip:jane@example.com\r\n\
+                    EMAIL;TYPE=INTERNET:jane@example.com\r\n\
+                    NOTE:Met at the\\, um\\; conference\\nTwice\r\n\
+                    PHOTO;ENCODING=b:AAAA\r\n\
+                    END:VCARD\r\n";
+        let contacts = parse(text).unwrap();
+        assert_eq!(contacts.len(), 1);
+        let jane = &contacts[0];
+        // No FN, so the name comes from N.
+        assert_eq!(jane.name, "Dr. Jane Doe");
+        assert_eq!(
+            jane.numbers,
+            vec![
+                number(NumberKind::Home, "+44 20 7946 0000"),
+                number(NumberKind::Mobile, "07700 900123"),
+                number(NumberKind::Sip, "sip:jane@example.com"),
+            ]
+        );
+        assert_eq!(jane.default_number, 1);
+        assert_eq!(jane.email, "jane@example.com");
+        assert_eq!(jane.note, "Met at the, um; conference\nTwice");
+    }
+
+    #[test]
+    fn reads_vcard_2_1_types() {
+        let text = "BEGIN:VCARD\nVERSION:2.1\nFN:Bob\nTEL;WORK;VOICE:555 0100\nEND:VCARD\n";
+        let contacts = parse(text).unwrap();
+        assert_eq!(contacts[0].name, "Bob");
+        assert_eq!(
+            contacts[0].numbers,
+            vec![number(NumberKind::Work, "555 0100")]
+        );
+    }
+
+    #[test]
+    fn skips_empty_cards() {
+        let text = "BEGIN:VCARD\nVERSION:4.0\nEND:VCARD\n\nBEGIN:VCARD\nFN:Ann\nEND:VCARD\n";
+        let contacts = parse(text).unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].name, "Ann");
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        assert!(parse("BEGIN:VCARD\nBEGIN:VCARD\n").is_err());
+        assert!(parse("FN:Nobody\n").is_err());
+        assert!(parse("BEGIN:VCARD\nFN:Ann\n").is_err());
+        assert!(parse("BEGIN:VCARD\nno colon here\nEND:VCARD\n").is_err());
+    }
+
+    #[test]
+    fn round_trips_in_both_versions() {
+        let contact = Contact {
+            name: "O'Brien, Pat; Jr.".into(),
+            numbers: vec![
+                number(NumberKind::Mobile, "07700 900456"),
+                number(NumberKind::Work, "555 0199"),
+                number(NumberKind::Sip, "pat@example.com"),
+            ],
+            email: "pat@example.com".into(),
+            note: "Line one\nLine two".into(),
+            default_number: 1,
+        };
+        for version in [VCardVersion::V3, VCardVersion::V4].iter() {
+            let text = serialize(std::slice::from_ref(&contact), *version);
+            assert!(text.contains(&format!("VERSION:{}\r\n", version)));
+            let mut expected = contact.clone();
+            expected.numbers[2].number = "sip:pat@example.com".into();
+            assert_eq!(parse(&text).unwrap(), vec![expected]);
+        }
+    }
+
+    #[test]
+    fn folds_long_lines() {
+        let contact = Contact {
+            name: "é".repeat(60),
+            ..Default::default()
+        };
+        let text = serialize(std::slice::from_ref(&contact), VCardVersion::V4);
+        assert!(text.split("\r\n").all(|line| line.len() <= MAX_LINE_OCTETS));
+        assert_eq!(parse(&text).unwrap()[0].name, contact.name);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::contacts::vcard::{self, VCardVersion};
+use crate::contacts::ContactBook;
 use crate::input::traits::{InputModule, UserInput};
 use crate::network::wifi::{PSKKey, WifiModuleInterface, SSID};
 use crate::realtime::rt_ctl::RtSystemControl;
@@ -11,9 +13,10 @@ use embedded_graphics::{
 };
 use std::io::{Read, Write};
 use std::iter::FromIterator;
-use std::net::{Shutdown, TcpListener};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{thread, thread::JoinHandle};
 
@@ -22,6 +25,107 @@ struct TelnetFramebuffer {
 }
 
 const TELNET_THREAD_STACK_SIZE_BYTES: usize = 8192usize;
+// Ends a pasted vCard import, like the end of an SMTP message.
+const END_OF_PASTE: &str = ".";
+const COMMAND_HELP: &str = "Commands:\r\n\
+    vcard export [3|4]  dump the contacts as vCard 3.0 (default) or 4.0\r\n\
+    vcard import        paste vCards, then a line with just a dot\r\n";
+
+// A line typed after `:`, without the line ending. None once the connection's gone.
+fn read_line(stream: &mut TcpStream) -> Option<String> {
+    let mut line = vec![];
+    loop {
+        let mut byte = [0u8];
+        match stream.read(&mut byte) {
+            Ok(1) => {}
+            _ => return None,
+        }
+        match byte[0] {
+            b'\n' => return Some(String::from_utf8_lossy(&line).into()),
+            b'\r' => {}
+            // Telnet option negotiation, which we don't do.
+            0xff => {
+                let mut command = [0u8; 2];
+                if stream.read_exact(&mut command).is_err() {
+                    return None;
+                }
+            }
+            byte => line.push(byte),
+        }
+    }
+}
+
+fn export_vcards(
+    stream: &mut TcpStream,
+    contacts: &Arc<Mutex<ContactBook>>,
+    version: VCardVersion,
+) -> std::io::Result<()> {
+    let text = match contacts.lock() {
+        Ok(contacts) => {
+            let contacts: Vec<_> = contacts
+                .contacts()
+                .iter()
+                .map(|entry| entry.contact.clone())
+                .collect();
+            vcard::serialize(&contacts, version)
+        }
+        Err(_) => return stream.write_all(b"Contacts unavailable\r\n"),
+    };
+    stream.write_all(text.as_bytes())
+}
+
+fn import_vcards(
+    stream: &mut TcpStream,
+    contacts: &Arc<Mutex<ContactBook>>,
+) -> std::io::Result<()> {
+    stream.write_all(b"Paste vCards, then a line with just a dot\r\n")?;
+    let mut text = String::new();
+    loop {
+        match read_line(stream) {
+            Some(line) if line.trim() == END_OF_PASTE => break,
+            Some(line) => {
+                text.push_str(&line);
+                text.push('\n');
+            }
+            None => return Ok(()),
+        }
+    }
+    let imported = match vcard::parse(&text) {
+        Ok(imported) => imported,
+        Err(err) => return stream.write_all(format!("Not imported: {}\r\n", err).as_bytes()),
+    };
+    let count = imported.len();
+    match contacts.lock() {
+        Ok(mut contacts) => {
+            for contact in imported {
+                contacts.add(contact);
+            }
+        }
+        Err(_) => return stream.write_all(b"Contacts unavailable\r\n"),
+    }
+    stream.write_all(format!("Imported {} contacts\r\n", count).as_bytes())
+}
+
+fn run_command(
+    stream: &mut TcpStream,
+    command: &str,
+    contacts: Option<&Arc<Mutex<ContactBook>>>,
+) -> std::io::Result<()> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let contacts = match (words.first(), contacts) {
+        (Some(&"vcard"), Some(contacts)) => contacts,
+        (Some(&"vcard"), None) => return stream.write_all(b"No contacts yet\r\n"),
+        _ => return stream.write_all(COMMAND_HELP.as_bytes()),
+    };
+    match words.get(1..) {
+        Some(["export"]) | Some(["export", "3"]) => {
+            export_vcards(stream, contacts, VCardVersion::V3)
+        }
+        Some(["export", "4"]) => export_vcards(stream, contacts, VCardVersion::V4),
+        Some(["import"]) => import_vcards(stream, contacts),
+        _ => stream.write_all(COMMAND_HELP.as_bytes()),
+    }
+}
 
 impl TelnetFramebuffer {
     pub fn setup_thread<RtSystem: RtSystemControl>(
@@ -69,6 +173,7 @@ pub struct TelnetModule {
     #[allow(unused)]
     telnet_framebuffer: TelnetFramebuffer,
     draw_call_sender: Sender<Vec<Pixel<BinaryColor>>>,
+    contacts_sender: Sender<Arc<Mutex<ContactBook>>>,
 }
 
 impl DrawTarget for TelnetModule {
@@ -153,6 +258,7 @@ impl TelnetModule {
         let (frame_sender, frame_listener) = sync_channel(1);
         let (input_sender, input_listener) = sync_channel(20);
         let (draw_call_sender, draw_call_receiver) = channel();
+        let (contacts_sender, contacts_receiver) = channel::<Arc<Mutex<ContactBook>>>();
         let mut framebuffer = TelnetFramebuffer {
             thread_handle: None,
         };
@@ -175,6 +281,8 @@ impl TelnetModule {
 
             println!("Starting tcp loop");
 
+            let mut contacts = None;
+
             loop {
                 println!("Listening for tcp connection.");
                 let result = listener.accept();
@@ -240,6 +348,16 @@ impl TelnetModule {
                                     b'7' => Some(UserInput::Number(7)),
                                     b'8' => Some(UserInput::Number(8)),
                                     b'9' => Some(UserInput::Number(9)),
+                                    // No key-up over telnet, so shift stands in for
+                                    // holding. Shift-3 and shift-8 are taken.
+                                    b')' => Some(UserInput::NumberHeld(0)),
+                                    b'!' => Some(UserInput::NumberHeld(1)),
+                                    b'@' => Some(UserInput::NumberHeld(2)),
+                                    b'$' => Some(UserInput::NumberHeld(4)),
+                                    b'%' => Some(UserInput::NumberHeld(5)),
+                                    b'^' => Some(UserInput::NumberHeld(6)),
+                                    b'&' => Some(UserInput::NumberHeld(7)),
+                                    b'(' => Some(UserInput::NumberHeld(9)),
                                     b' ' => Some(UserInput::SoftKey),
                                     b'#' => Some(UserInput::Hash),
                                     b'*' => Some(UserInput::Star),
@@ -253,6 +371,24 @@ impl TelnetModule {
                                         kill_sesh = true;
                                         break;
                                     }
+                                    b':' => {
+                                        if let Ok(book) = contacts_receiver.try_recv() {
+                                            contacts = Some(book);
+                                        }
+                                        let ran = match read_line(&mut stream) {
+                                            Some(command) => run_command(
+                                                &mut stream,
+                                                &command,
+                                                contacts.as_ref(),
+                                            )
+                                            .is_ok(),
+                                            None => false,
+                                        };
+                                        if !ran {
+                                            kill_sesh = true;
+                                        }
+                                        break;
+                                    }
                                     _ => None,
                                 };
                                 match user_input {
@@ -297,10 +433,18 @@ impl TelnetModule {
             TelnetModule {
                 telnet_framebuffer: framebuffer,
                 draw_call_sender,
+                contacts_sender,
             },
             TelnetModuleInputInterface {
                 input_receiver: input_listener,
             },
         );
     }
+
+    /// Lets the `:vcard` commands at the contacts, once `Bricc` has loaded them.
+    pub fn attach_contacts(&self, contacts: Arc<Mutex<ContactBook>>) {
+        if self.contacts_sender.send(contacts).is_err() {
+            println!("Telnet session is gone, no vCard commands");
+        }
+    }
 }
@@ -0,0 +1,12 @@
+use std::sync::mpsc::Sender;
+
+use crate::voip::user_agent::UserAgentEvent;
+
+/// Something a background subsystem needs the UI to react to, whatever it is showing.
+/// They all arrive on the one channel `Bricc::bricc_loop` drains.
+#[derive(Clone, Debug)]
+pub enum SystemEvent {
+    UserAgent(UserAgentEvent),
+}
+
+pub type SystemEventSender = Sender<SystemEvent>;
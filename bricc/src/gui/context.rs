@@ -0,0 +1,52 @@
+use std::sync::{Arc, Mutex};
+
+use crate::audio::AudioControls;
+use crate::call_log::CallLog;
+use crate::contacts::ContactBook;
+use crate::messages::MessageStore;
+use crate::voip::user_agent::UserAgentInterface;
+
+/// What panes need from the rest of the phone. Cloned down the pane tree.
+#[derive(Clone)]
+pub struct GuiContext {
+    pub user_agent: UserAgentInterface,
+    pub contacts: Arc<Mutex<ContactBook>>,
+    pub messages: Arc<Mutex<MessageStore>>,
+    pub call_log: Arc<Mutex<CallLog>>,
+    pub audio: AudioControls,
+}
+
+impl GuiContext {
+    pub fn new(
+        user_agent: UserAgentInterface,
+        contacts: Arc<Mutex<ContactBook>>,
+        messages: Arc<Mutex<MessageStore>>,
+        call_log: Arc<Mutex<CallLog>>,
+        audio: AudioControls,
+    ) -> GuiContext {
+        GuiContext {
+            user_agent,
+            contacts,
+            messages,
+            call_log,
+            audio,
+        }
+    }
+
+    pub fn unread_message_count(&self) -> u32 {
+        match self.messages.lock() {
+            Ok(messages) => messages.unread_count(),
+            Err(_) => 0,
+        }
+    }
+
+    /// The name to show for a number: the contact's if we know them.
+    pub fn contact_name_for(&self, number: &str) -> Option<String> {
+        match self.contacts.lock() {
+            Ok(contacts) => contacts
+                .find_by_number(number)
+                .map(|contact| contact.name.clone()),
+            Err(_) => None,
+        }
+    }
+}
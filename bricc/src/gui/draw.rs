@@ -0,0 +1,44 @@
+use embedded_graphics::draw_target::DrawTarget;
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyle};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::{OriginDimensions, Point};
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use embedded_graphics::Drawable;
+use profont::PROFONT_7_POINT;
+
+pub fn draw_text<Display: DrawTarget<Color = BinaryColor>>(
+    framebuffer: &mut Display,
+    text: &str,
+    font: &MonoFont,
+    position: Point,
+    alignment: Alignment,
+    baseline: Baseline,
+) {
+    let character_style = MonoTextStyle::new(font, BinaryColor::On);
+    let text_style = TextStyleBuilder::new()
+        .alignment(alignment)
+        .baseline(baseline)
+        .build();
+    if Text::with_text_style(text, position, character_style, text_style)
+        .draw(framebuffer)
+        .is_err()
+    {
+        println!("Failed to draw text");
+    }
+}
+
+/// What the soft key does right now, along the bottom edge.
+pub fn draw_soft_key_label<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
+    framebuffer: &mut Display,
+    label: &str,
+) {
+    let size = framebuffer.size();
+    draw_text(
+        framebuffer,
+        label,
+        &PROFONT_7_POINT,
+        Point::new(size.width as i32 / 2, size.height as i32 - 1),
+        Alignment::Center,
+        Baseline::Bottom,
+    );
+}
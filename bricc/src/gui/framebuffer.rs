@@ -0,0 +1,134 @@
+use std::convert::Infallible;
+
+use embedded_graphics::draw_target::DrawTarget;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::{OriginDimensions, Point, Size};
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::Pixel;
+
+/// What panes draw on. One frame at a time is drawn here, then copied to the real
+/// display with `draw_to`, so panes don't need to know what driver that is.
+pub struct Framebuffer {
+    size: Size,
+    pixels: Vec<BinaryColor>,
+    // Where drawing goes for now: points are relative to it and clipped to it.
+    viewport: Rectangle,
+    // The part of the screen the viewport and every one around it overlap, in screen
+    // coordinates.
+    clip: Rectangle,
+}
+
+impl Framebuffer {
+    pub fn new(size: Size) -> Framebuffer {
+        Framebuffer {
+            size,
+            pixels: vec![BinaryColor::Off; (size.width * size.height) as usize],
+            viewport: Rectangle::new(Point::zero(), size),
+            clip: Rectangle::new(Point::zero(), size),
+        }
+    }
+
+    /// Runs `draw` as if `area` were the whole screen, for parts of a pane drawn by
+    /// something that fills whatever it's given, like a `Menu`. Nothing is drawn
+    /// outside the area it's in, however far `area` reaches.
+    pub fn within<F: FnOnce(&mut Framebuffer)>(&mut self, area: &Rectangle, draw: F) {
+        let (outer, outer_clip) = (self.viewport, self.clip);
+        self.viewport = Rectangle::new(outer.top_left + area.top_left, area.size);
+        self.clip = outer_clip.intersection(&self.viewport);
+        draw(self);
+        self.viewport = outer;
+        self.clip = outer_clip;
+    }
+
+    /// Copies the frame to the display, whatever driver it has.
+    pub fn draw_to<Display: DrawTarget<Color = BinaryColor>>(
+        &self,
+        display: &mut Display,
+    ) -> Result<(), Display::Error> {
+        display.fill_contiguous(
+            &Rectangle::new(Point::zero(), self.size),
+            self.pixels.iter().copied(),
+        )
+    }
+
+    fn index_of(&self, point: Point) -> Option<usize> {
+        if point.x < 0
+            || point.y < 0
+            || point.x >= self.size.width as i32
+            || point.y >= self.size.height as i32
+        {
+            return None;
+        }
+        Some(point.y as usize * self.size.width as usize + point.x as usize)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let point = point + self.viewport.top_left;
+            if !self.clip.contains(point) {
+                continue;
+            }
+            if let Some(index) = self.index_of(point) {
+                self.pixels[index] = color;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        self.viewport.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(framebuffer: &Framebuffer) -> Vec<Point> {
+        let mut lit = vec![];
+        for y in 0..framebuffer.size.height as i32 {
+            for x in 0..framebuffer.size.width as i32 {
+                let point = Point::new(x, y);
+                if framebuffer.pixels[framebuffer.index_of(point).unwrap()] == BinaryColor::On {
+                    lit.push(point);
+                }
+            }
+        }
+        lit
+    }
+
+    #[test]
+    fn draws_relative_to_the_area() {
+        let mut framebuffer = Framebuffer::new(Size::new(8, 8));
+        let area = Rectangle::new(Point::new(2, 3), Size::new(2, 1));
+        framebuffer.within(&area, |framebuffer| {
+            assert_eq!(framebuffer.size(), area.size);
+            let _ = framebuffer.clear(BinaryColor::On);
+        });
+        assert_eq!(lit(&framebuffer), vec![Point::new(2, 3), Point::new(3, 3)]);
+        assert_eq!(framebuffer.size(), Size::new(8, 8));
+    }
+
+    #[test]
+    fn clips_nested_areas_to_the_outer_one() {
+        let mut framebuffer = Framebuffer::new(Size::new(8, 8));
+        let area = Rectangle::new(Point::new(2, 2), Size::new(3, 3));
+        framebuffer.within(&area, |framebuffer| {
+            // Reaches past the outer area on the right and bottom.
+            framebuffer.within(&area, |framebuffer| {
+                let _ = framebuffer.clear(BinaryColor::On);
+            });
+        });
+        assert_eq!(lit(&framebuffer), vec![Point::new(4, 4)]);
+    }
+}
@@ -1,9 +1,9 @@
 use crate::gui::traits::GuiElement;
 use crate::input::traits::UserInput;
 
+use super::framebuffer::Framebuffer;
 use super::traits::GuiAction;
 
-use embedded_graphics::draw_target::DrawTarget;
 use embedded_graphics::geometry::OriginDimensions;
 use embedded_graphics::mono_font::MonoTextStyle;
 use embedded_graphics::pixelcolor::BinaryColor;
@@ -41,12 +41,8 @@ pub struct Menu<MenuOption: ToString + MenuElement + Clone> {
     y_offset_pixels: i32,
 }
 
-impl<
-        MenuOption: ToString + MenuElement + Clone,
-        Display: OriginDimensions + DrawTarget<Color = BinaryColor>,
-    > GuiElement<Display> for Menu<MenuOption>
-{
-    fn render(&mut self, framebuffer: &mut Display) {
+impl<MenuOption: ToString + MenuElement + Clone> GuiElement for Menu<MenuOption> {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
         let style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::On);
         let selected_style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::Off);
         let selected_box_primitive_style = PrimitiveStyleBuilder::new()
@@ -70,13 +66,11 @@ impl<
         }
 
         for option in &self.options {
+            // Only the first nine have a number key to pick them with.
             let str = if ord < 9 {
-                let mut tmp = (ord + 1).to_string();
-                tmp.push_str(": ");
-                tmp.push_str(&option.to_string());
-                tmp
+                format!("{}: {}", ord + 1, option.to_string())
             } else {
-                String::from("")
+                format!("   {}", option.to_string())
             };
             let pt = Point::new(x, y - self.y_offset_pixels);
             if ord == self.cursor {
@@ -106,7 +100,7 @@ impl<
 }
 
 impl<MenuOption: ToString + MenuElement + Clone> Menu<MenuOption> {
-    pub fn new<Display: DrawTarget>(options: Vec<MenuOption>) -> Menu<MenuOption> {
+    pub fn new(options: Vec<MenuOption>) -> Menu<MenuOption> {
         Menu {
             options,
             cursor: 0,
@@ -114,6 +108,19 @@ impl<MenuOption: ToString + MenuElement + Clone> Menu<MenuOption> {
         }
     }
 
+    /// Swaps the options for new ones, keeping the cursor where it was if it can.
+    pub fn set_options(&mut self, options: Vec<MenuOption>) {
+        self.options = options;
+        if self.cursor >= self.options.len() {
+            self.cursor = self.options.len().saturating_sub(1);
+        }
+    }
+
+    /// The option under the cursor.
+    pub fn selected(&self) -> Option<&MenuOption> {
+        self.options.get(self.cursor)
+    }
+
     pub fn process_input(&mut self, input: UserInput) -> MenuInputEventResult<MenuOption> {
         match input {
             UserInput::Up => {
@@ -153,6 +160,7 @@ impl<MenuOption: ToString + MenuElement + Clone> Menu<MenuOption> {
             UserInput::Star => MenuInputEventResult::WrappedGuiAction(GuiAction::Nothing),
             UserInput::Hash => MenuInputEventResult::WrappedGuiAction(GuiAction::Nothing),
             UserInput::Power => MenuInputEventResult::WrappedGuiAction(GuiAction::Nothing),
+            UserInput::NumberHeld(_) => MenuInputEventResult::WrappedGuiAction(GuiAction::Nothing),
         }
     }
 }
@@ -1,4 +1,8 @@
+pub mod context;
+pub mod draw;
+pub mod framebuffer;
 pub mod menu;
+pub mod navigator;
 pub mod panes;
 pub mod text_input;
 pub mod traits;
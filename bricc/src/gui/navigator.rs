@@ -0,0 +1,249 @@
+use crate::input::traits::UserInput;
+
+use super::framebuffer::Framebuffer;
+use super::traits::{GuiAction, GuiElement, Pane};
+
+/// The stack of open panes. The top one is shown and gets input; what it returns to
+/// open or close panes is carried out here, so panes never hold their children. The
+/// bottom pane is never closed.
+pub struct Navigator {
+    stack: Vec<Box<dyn Pane>>,
+}
+
+impl GuiElement for Navigator {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        self.top().render(framebuffer);
+    }
+}
+
+impl Pane for Navigator {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        let action = self.top().process_input(input);
+        self.navigate(action)
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        match self.stack.last() {
+            Some(pane) => pane.is_preventing_lock(),
+            None => false,
+        }
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        let action = self.top().tick();
+        self.navigate(action)
+    }
+}
+
+impl Navigator {
+    pub fn new(root: Box<dyn Pane>) -> Navigator {
+        Navigator { stack: vec![root] }
+    }
+
+    /// How many panes are open, the bottom one included.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn top(&mut self) -> &mut Box<dyn Pane> {
+        self.stack
+            .last_mut()
+            .expect("Navigator stack is never empty")
+    }
+
+    // Anything that isn't about opening or closing panes goes back to the caller.
+    fn navigate(&mut self, action: GuiAction) -> GuiAction {
+        match action {
+            GuiAction::Push(pane) => {
+                self.stack.push(pane);
+                GuiAction::ScreenUpdated
+            }
+            GuiAction::Replace(pane) => {
+                self.stack.pop();
+                self.stack.push(pane);
+                GuiAction::ScreenUpdated
+            }
+            GuiAction::PopPane => {
+                if self.pop() {
+                    GuiAction::ScreenUpdated
+                } else {
+                    GuiAction::Nothing
+                }
+            }
+            GuiAction::PopTo(depth) => {
+                self.stack.truncate(depth.max(1));
+                GuiAction::ScreenUpdated
+            }
+            GuiAction::PopWith(result) => {
+                if !self.pop() {
+                    return GuiAction::Nothing;
+                }
+                // The result may well open something else.
+                let action = self.top().on_result(result);
+                match self.navigate(action) {
+                    GuiAction::Nothing => GuiAction::ScreenUpdated,
+                    action => action,
+                }
+            }
+            action => action,
+        }
+    }
+
+    fn pop(&mut self) -> bool {
+        if self.stack.len() <= 1 {
+            return false;
+        }
+        self.stack.pop();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::gui::traits::PaneResult;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Notes what reaches it in a log shared with the test.
+    struct StubPane {
+        name: &'static str,
+        log: Log,
+        /// Opens a pane of this name when handed a result.
+        opens_on_result: Option<&'static str>,
+    }
+
+    impl GuiElement for StubPane {
+        fn render(&mut self, _framebuffer: &mut Framebuffer) {}
+    }
+
+    impl Pane for StubPane {
+        fn process_input(&mut self, _input: UserInput) -> GuiAction {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} got input", self.name));
+            GuiAction::Nothing
+        }
+
+        fn is_preventing_lock(&self) -> bool {
+            false
+        }
+
+        fn tick(&mut self) -> GuiAction {
+            GuiAction::Nothing
+        }
+
+        fn on_result(&mut self, result: PaneResult) -> GuiAction {
+            let PaneResult::Text(text) = result;
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} got {}", self.name, text));
+            match self.opens_on_result {
+                Some(name) => GuiAction::Push(stub(name, &self.log)),
+                None => GuiAction::ScreenUpdated,
+            }
+        }
+    }
+
+    fn stub(name: &'static str, log: &Log) -> Box<dyn Pane> {
+        Box::new(StubPane {
+            name,
+            log: log.clone(),
+            opens_on_result: None,
+        })
+    }
+
+    fn navigator() -> (Navigator, Log) {
+        let log = Log::default();
+        (Navigator::new(stub("root", &log)), log)
+    }
+
+    /// Which pane input goes to.
+    fn top_name(navigator: &mut Navigator, log: &Log) -> String {
+        navigator.process_input(UserInput::Up);
+        let entry = log.lock().unwrap().pop().unwrap();
+        entry.trim_end_matches(" got input").to_string()
+    }
+
+    #[test]
+    fn pushes_and_pops_but_keeps_the_root() {
+        let (mut navigator, log) = navigator();
+        let action = navigator.navigate(GuiAction::Push(stub("a", &log)));
+        assert!(matches!(action, GuiAction::ScreenUpdated));
+        assert_eq!(navigator.depth(), 2);
+        assert_eq!(top_name(&mut navigator, &log), "a");
+
+        assert!(matches!(
+            navigator.navigate(GuiAction::PopPane),
+            GuiAction::ScreenUpdated
+        ));
+        assert_eq!(top_name(&mut navigator, &log), "root");
+        assert!(matches!(
+            navigator.navigate(GuiAction::PopPane),
+            GuiAction::Nothing
+        ));
+        assert_eq!(navigator.depth(), 1);
+    }
+
+    #[test]
+    fn pops_to_a_depth_but_no_further_than_the_root() {
+        let (mut navigator, log) = navigator();
+        navigator.navigate(GuiAction::Push(stub("a", &log)));
+        navigator.navigate(GuiAction::Push(stub("b", &log)));
+        navigator.navigate(GuiAction::PopTo(2));
+        assert_eq!(top_name(&mut navigator, &log), "a");
+
+        navigator.navigate(GuiAction::Push(stub("b", &log)));
+        navigator.navigate(GuiAction::PopTo(0));
+        assert_eq!(navigator.depth(), 1);
+        assert_eq!(top_name(&mut navigator, &log), "root");
+    }
+
+    #[test]
+    fn replaces_the_top_pane() {
+        let (mut navigator, log) = navigator();
+        navigator.navigate(GuiAction::Push(stub("a", &log)));
+        navigator.navigate(GuiAction::Replace(stub("b", &log)));
+        assert_eq!(navigator.depth(), 2);
+        assert_eq!(top_name(&mut navigator, &log), "b");
+        navigator.navigate(GuiAction::PopPane);
+        assert_eq!(top_name(&mut navigator, &log), "root");
+    }
+
+    #[test]
+    fn hands_a_result_to_the_pane_underneath() {
+        let (mut navigator, log) = navigator();
+        navigator.navigate(GuiAction::Push(stub("a", &log)));
+        navigator.navigate(GuiAction::Push(stub("b", &log)));
+        let action = navigator.navigate(GuiAction::PopWith(PaneResult::Text("1234".into())));
+        assert!(matches!(action, GuiAction::ScreenUpdated));
+        assert_eq!(*log.lock().unwrap(), vec!["a got 1234".to_string()]);
+        assert_eq!(top_name(&mut navigator, &log), "a");
+    }
+
+    #[test]
+    fn opens_what_the_result_asks_for() {
+        let log = Log::default();
+        let mut navigator = Navigator::new(Box::new(StubPane {
+            name: "root",
+            log: log.clone(),
+            opens_on_result: Some("c"),
+        }));
+        navigator.navigate(GuiAction::Push(stub("a", &log)));
+        navigator.navigate(GuiAction::PopWith(PaneResult::Text("1234".into())));
+        assert_eq!(navigator.depth(), 2);
+        assert_eq!(top_name(&mut navigator, &log), "c");
+    }
+
+    #[test]
+    fn keeps_a_result_from_the_root() {
+        let (mut navigator, log) = navigator();
+        let action = navigator.navigate(GuiAction::PopWith(PaneResult::Text("1234".into())));
+        assert!(matches!(action, GuiAction::Nothing));
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use embedded_graphics::prelude::{OriginDimensions, Point};
+use embedded_graphics::text::{Alignment, Baseline};
+use profont::{PROFONT_7_POINT, PROFONT_9_POINT};
+
+use crate::audio::MAX_VOLUME;
+use crate::gui::context::GuiContext;
+use crate::gui::draw::{draw_soft_key_label, draw_text};
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::menu::{Menu, MenuElement, MenuElementType, MenuInputEventResult};
+use crate::gui::text_input::{KeyboardType, TextInputHelper, TextInputResult};
+use crate::gui::traits::{GuiAction, GuiElement, Pane};
+use crate::input::traits::UserInput;
+use crate::voip::call::{CallDirection, CallHandle, CallInfo, CallState};
+use crate::voip::dtmf::DtmfDigit;
+
+const VOLUME_STEP: u8 = 10;
+const MULTI_PRESS_TIMEOUT: Duration = Duration::from_millis(1000);
+// Keys the far end pressed, the latest ones; older ones scroll off the left. They
+// share the timer's row, which is 16 columns wide.
+const MAX_SHOWN_DTMF_DIGITS: usize = 10;
+
+#[derive(Clone, Copy)]
+enum CallOption {
+    Mute(bool),
+    Hold(bool),
+    /// Put this call on hold and take the other one off.
+    Swap(CallHandle),
+    /// Join the other call to this one and drop out.
+    Transfer(CallHandle),
+    BlindTransfer,
+    NewCall,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl fmt::Display for CallOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            CallOption::Mute(false) => "Mute",
+            CallOption::Mute(true) => "Unmute",
+            CallOption::Hold(false) => "Hold",
+            CallOption::Hold(true) => "Resume",
+            CallOption::Swap(_) => "Swap calls",
+            CallOption::Transfer(_) => "Transfer",
+            CallOption::BlindTransfer => "Blind transfer",
+            CallOption::NewCall => "New call",
+            CallOption::VolumeUp => "Volume +",
+            CallOption::VolumeDown => "Volume -",
+        };
+        f.write_str(label)
+    }
+}
+
+impl MenuElement for CallOption {
+    fn menu_item_type(&self) -> MenuElementType {
+        MenuElementType::Button
+    }
+}
+
+// What a number typed in the call pane is for.
+enum NumberEntry {
+    NewCall(TextInputHelper),
+    Transfer(TextInputHelper),
+}
+
+/// Shown on top of everything for as long as a call lasts. Up/Down change the volume,
+/// the soft key has the rest, `Call` or `Power` hang up. With a second call up, it shows
+/// whichever one isn't on hold.
+pub struct CallPane {
+    context: GuiContext,
+    handle: CallHandle,
+    info: Option<CallInfo>,
+    options: Option<Menu<CallOption>>,
+    number_entry: Option<NumberEntry>,
+    shown_seconds: Option<u64>,
+    received_dtmf: String,
+}
+
+impl GuiElement for CallPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        match &mut self.number_entry {
+            Some(NumberEntry::NewCall(helper)) | Some(NumberEntry::Transfer(helper)) => {
+                helper.render(framebuffer);
+                return;
+            }
+            None => {}
+        }
+        if let Some(options) = &mut self.options {
+            options.render(framebuffer);
+            return;
+        }
+        let info = match &self.info {
+            Some(info) => info,
+            None => return,
+        };
+        let size = framebuffer.size();
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let line_height = PROFONT_7_POINT.character_size.height as i32;
+
+        let mut status = match (&info.state, info.on_hold, &info.transfer) {
+            (CallState::Connected, _, Some(transfer)) => transfer.to_string(),
+            (CallState::Connected, true, None) => "On hold".into(),
+            (state, _, _) => state.to_string(),
+        };
+        if self.context.audio.is_muted() && info.is_active() {
+            status.push_str(", muted");
+        }
+        draw_text(
+            framebuffer,
+            &status,
+            &PROFONT_7_POINT,
+            Point::new(margin, 0),
+            Alignment::Left,
+            Baseline::Top,
+        );
+        draw_text(
+            framebuffer,
+            &self.remote_party(info),
+            &PROFONT_9_POINT,
+            Point::new(size.width as i32 / 2, line_height + margin),
+            Alignment::Center,
+            Baseline::Top,
+        );
+        let timer = self
+            .shown_seconds
+            .map(|seconds| format!("{:02}:{:02}", seconds / 60, seconds % 60));
+        let timer_row = match (timer, self.received_dtmf.is_empty()) {
+            (Some(timer), true) => timer,
+            (Some(timer), false) => format!("{} {}", timer, self.received_dtmf),
+            (None, _) => self.received_dtmf.clone(),
+        };
+        if !timer_row.is_empty() {
+            draw_text(
+                framebuffer,
+                &timer_row,
+                &PROFONT_7_POINT,
+                Point::new(
+                    size.width as i32 / 2,
+                    line_height + margin + PROFONT_9_POINT.character_size.height as i32,
+                ),
+                Alignment::Center,
+                Baseline::Top,
+            );
+        }
+        if info.is_active() {
+            draw_soft_key_label(framebuffer, "Options");
+        }
+    }
+}
+
+impl Pane for CallPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        let state = match &self.info {
+            Some(info) => info.state.clone(),
+            None => return GuiAction::Nothing,
+        };
+        if let CallState::Ended(_) = state {
+            return GuiAction::PopPane;
+        }
+        if let Some(entry) = &mut self.number_entry {
+            let (helper, is_transfer) = match entry {
+                NumberEntry::NewCall(helper) => (helper, false),
+                NumberEntry::Transfer(helper) => (helper, true),
+            };
+            return match helper.process_input(input) {
+                Some(TextInputResult::Edited(number)) if !number.is_empty() => {
+                    self.number_entry = None;
+                    if is_transfer {
+                        let _ = self.context.user_agent.transfer(self.handle, number);
+                    } else if let Ok(handle) = self.context.user_agent.place_call(number) {
+                        // The user agent puts this one on hold.
+                        self.switch_to(handle);
+                    }
+                    GuiAction::ScreenUpdated
+                }
+                Some(_) => {
+                    self.number_entry = None;
+                    GuiAction::ScreenUpdated
+                }
+                None => GuiAction::ScreenUpdated,
+            };
+        }
+        match input {
+            UserInput::Call | UserInput::Power => {
+                let _ = self.context.user_agent.hangup(self.handle);
+                return GuiAction::Nothing;
+            }
+            _ => {}
+        }
+
+        if let Some(options) = &mut self.options {
+            return match options.process_input(input) {
+                MenuInputEventResult::MenuItemSelected(option) => {
+                    self.options = None;
+                    self.choose(option, &state);
+                    GuiAction::ScreenUpdated
+                }
+                MenuInputEventResult::WrappedGuiAction(action) => action,
+            };
+        }
+
+        match input {
+            UserInput::SoftKey => {
+                self.options = Some(Menu::new(self.options_for(&state)));
+                GuiAction::ScreenUpdated
+            }
+            UserInput::Up => self.choose(CallOption::VolumeUp, &state),
+            UserInput::Down => self.choose(CallOption::VolumeDown, &state),
+            input => match DtmfDigit::from_input(&input) {
+                Some(digit) if state == CallState::Connected => {
+                    let _ = self.context.user_agent.send_dtmf(self.handle, digit);
+                    GuiAction::Nothing
+                }
+                _ => GuiAction::Nothing,
+            },
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        match &self.info {
+            Some(info) => info.is_active(),
+            None => false,
+        }
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        let info = self.context.user_agent.call(self.handle);
+        let info = match info {
+            Some(info) => info,
+            // Ended calls linger in the user agent for a bit, then go.
+            None => return GuiAction::PopPane,
+        };
+        let seconds = match (&info.state, info.connected_at) {
+            (CallState::Connected, Some(connected_at)) => {
+                Some((Instant::now() - connected_at).as_secs())
+            }
+            (CallState::Ended(_), _) => self.shown_seconds,
+            _ => None,
+        };
+        let changed = match &self.info {
+            Some(old) => {
+                old.state != info.state
+                    || old.on_hold != info.on_hold
+                    || old.transfer != info.transfer
+            }
+            None => true,
+        };
+        self.info = Some(info);
+        if let Some(NumberEntry::NewCall(helper)) | Some(NumberEntry::Transfer(helper)) =
+            &mut self.number_entry
+        {
+            return helper.tick();
+        }
+        if changed || seconds != self.shown_seconds {
+            self.shown_seconds = seconds;
+            if self.options.is_none() {
+                return GuiAction::ScreenUpdated;
+            }
+        }
+        GuiAction::Nothing
+    }
+}
+
+impl CallPane {
+    pub fn new(context: GuiContext, handle: CallHandle) -> CallPane {
+        let info = context.user_agent.call(handle);
+        CallPane {
+            context,
+            handle,
+            info,
+            options: None,
+            number_entry: None,
+            shown_seconds: None,
+            received_dtmf: String::new(),
+        }
+    }
+
+    fn switch_to(&mut self, handle: CallHandle) {
+        self.handle = handle;
+        self.info = self.context.user_agent.call(handle);
+        self.shown_seconds = None;
+        self.received_dtmf.clear();
+    }
+
+    /// Shows a key the far end pressed, if it's this call's. True if it is.
+    pub fn show_dtmf(&mut self, handle: CallHandle, digit: DtmfDigit) -> bool {
+        if handle != self.handle {
+            return false;
+        }
+        if self.received_dtmf.len() >= MAX_SHOWN_DTMF_DIGITS {
+            self.received_dtmf.remove(0);
+        }
+        self.received_dtmf.push(digit.to_char());
+        true
+    }
+
+    // The call that isn't this one, if there is one besides a caller still ringing.
+    fn other_call(&self) -> Option<CallInfo> {
+        self.context.user_agent.calls().into_iter().find(|call| {
+            call.handle != self.handle
+                && call.is_active()
+                && !(call.direction == CallDirection::Incoming && call.state == CallState::Ringing)
+        })
+    }
+
+    fn options_for(&self, state: &CallState) -> Vec<CallOption> {
+        let on_hold = match &self.info {
+            Some(info) => info.on_hold,
+            None => false,
+        };
+        let mut options = vec![CallOption::Mute(self.context.audio.is_muted())];
+        if *state == CallState::Connected {
+            options.push(CallOption::Hold(on_hold));
+            match self.other_call() {
+                Some(other) => {
+                    options.push(CallOption::Swap(other.handle));
+                    if other.state == CallState::Connected {
+                        options.push(CallOption::Transfer(other.handle));
+                    }
+                }
+                None => options.push(CallOption::NewCall),
+            }
+            options.push(CallOption::BlindTransfer);
+        }
+        options.push(CallOption::VolumeUp);
+        options.push(CallOption::VolumeDown);
+        options
+    }
+
+    pub fn handle(&self) -> CallHandle {
+        self.handle
+    }
+
+    fn remote_party(&self, info: &CallInfo) -> String {
+        let number = info.remote_user();
+        self.context
+            .contact_name_for(&number)
+            .or_else(|| info.remote_display_name.clone())
+            .unwrap_or(number)
+    }
+
+    fn choose(&mut self, option: CallOption, state: &CallState) -> GuiAction {
+        let audio = &self.context.audio;
+        match option {
+            CallOption::Mute(muted) => audio.set_muted(!muted),
+            CallOption::Hold(true) => {
+                let _ = self.context.user_agent.resume(self.handle);
+            }
+            CallOption::Hold(false) if *state == CallState::Connected => {
+                let _ = self.context.user_agent.hold(self.handle);
+            }
+            CallOption::Hold(false) => return GuiAction::Nothing,
+            CallOption::Swap(other) => {
+                // Resuming one holds the other.
+                let _ = self.context.user_agent.resume(other);
+                self.switch_to(other);
+                return GuiAction::ScreenUpdated;
+            }
+            CallOption::Transfer(other) => {
+                let _ = self
+                    .context
+                    .user_agent
+                    .attended_transfer(other, self.handle);
+                return GuiAction::ScreenUpdated;
+            }
+            CallOption::BlindTransfer => {
+                self.number_entry = Some(NumberEntry::Transfer(Self::number_input("Transfer to:")));
+                return GuiAction::ScreenUpdated;
+            }
+            CallOption::NewCall => {
+                self.number_entry = Some(NumberEntry::NewCall(Self::number_input("Call:")));
+                return GuiAction::ScreenUpdated;
+            }
+            CallOption::VolumeUp => {
+                audio.set_volume(audio.volume().saturating_add(VOLUME_STEP).min(MAX_VOLUME))
+            }
+            CallOption::VolumeDown => audio.set_volume(audio.volume().saturating_sub(VOLUME_STEP)),
+        }
+        println!("Volume {}, muted {}", audio.volume(), audio.is_muted());
+        GuiAction::ScreenUpdated
+    }
+
+    fn number_input(prompt: &str) -> TextInputHelper {
+        TextInputHelper::new(
+            prompt.into(),
+            "".into(),
+            KeyboardType::Numbers,
+            MULTI_PRESS_TIMEOUT,
+        )
+    }
+}
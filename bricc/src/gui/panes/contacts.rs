@@ -1,7 +1,12 @@
-use embedded_graphics::draw_target::DrawTarget;
-use embedded_graphics::pixelcolor::BinaryColor;
-use embedded_graphics::prelude::OriginDimensions;
+use embedded_graphics::prelude::{OriginDimensions, Point, Size};
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::text::{Alignment, Baseline};
+use profont::PROFONT_7_POINT;
 
+use crate::contacts::{Contact, ContactEntry};
+use crate::gui::context::GuiContext;
+use crate::gui::draw::draw_text;
+use crate::gui::framebuffer::Framebuffer;
 use crate::gui::menu::Menu;
 use crate::gui::menu::MenuElement;
 use crate::gui::menu::MenuElementType;
@@ -12,18 +17,24 @@ use crate::gui::traits::Pane;
 use crate::input::traits::UserInput;
 
 use super::edit_contact_pane::EditContactPane;
+use super::speed_dial::SpeedDialPane;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 enum ContactsPaneItem {
-    Contact(Contact),
+    Contact(ContactEntry),
     AddNewButton,
+    SpeedDialButton,
 }
 
 impl ToString for ContactsPaneItem {
     fn to_string(&self) -> String {
         match self {
-            ContactsPaneItem::Contact(contact) => contact.name.clone(),
+            ContactsPaneItem::Contact(entry) => match entry.contact.default_number() {
+                Some(number) if entry.contact.name.trim().is_empty() => number.number.clone(),
+                _ => entry.contact.name.clone(),
+            },
             ContactsPaneItem::AddNewButton => "Add New".into(),
+            ContactsPaneItem::SpeedDialButton => "Speed dial".into(),
         }
     }
 }
@@ -31,54 +42,103 @@ impl ToString for ContactsPaneItem {
 impl MenuElement for ContactsPaneItem {
     fn menu_item_type(&self) -> MenuElementType {
         match self {
-            ContactsPaneItem::Contact(contact) => MenuElementType::Callable,
+            ContactsPaneItem::Contact(_) => MenuElementType::Callable,
             ContactsPaneItem::AddNewButton => MenuElementType::Button,
+            ContactsPaneItem::SpeedDialButton => MenuElementType::Button,
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Contact {
-    pub name: String,
-    pub phone_number: String,
-}
-
+/// Everyone, alphabetically. Typing digits narrows it down T9-style, `*` takes one
+/// back and `Power` clears them.
 pub struct ContactsPane {
+    context: GuiContext,
+    // The digits typed so far.
+    keys: String,
+    items: Vec<ContactsPaneItem>,
     menu: Menu<ContactsPaneItem>,
-    child: Option<EditContactPane>,
 }
 
-impl<Display: OriginDimensions + DrawTarget<Color = BinaryColor>> GuiElement<Display>
-    for ContactsPane
-{
-    fn render(&mut self, framebuffer: &mut Display) {
-        match &mut self.child {
-            Some(c) => c.render(framebuffer),
-            None => self.menu.render(framebuffer),
+impl GuiElement for ContactsPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        if self.keys.is_empty() {
+            self.menu.render(framebuffer);
+            return;
         }
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let header_height = PROFONT_7_POINT.character_size.height;
+        draw_text(
+            framebuffer,
+            &format!("Find: {}", self.keys),
+            &PROFONT_7_POINT,
+            Point::new(margin, 0),
+            Alignment::Left,
+            Baseline::Top,
+        );
+        let size = framebuffer.size();
+        let list_area = Rectangle::new(
+            Point::new(0, header_height as i32),
+            Size::new(size.width, size.height.saturating_sub(header_height)),
+        );
+        framebuffer.within(&list_area, |framebuffer| self.menu.render(framebuffer));
     }
 }
 
 impl Pane for ContactsPane {
-    fn process_input<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
-        &mut self,
-        input: UserInput,
-    ) -> GuiAction {
-        match &mut self.child {
-            Some(c) => c.process_input::<Display>(input),
-            None => match self.menu.process_input(input) {
-                MenuInputEventResult::MenuItemSelected(item) => match item {
-                    ContactsPaneItem::Contact(contact) => GuiAction::Nothing,
-                    ContactsPaneItem::AddNewButton => {
-                        self.child = Some(EditContactPane::new::<Display>(Contact {
-                            name: "".into(),
-                            phone_number: "".into(),
-                        }));
-                        GuiAction::ScreenUpdated
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match input {
+            UserInput::Number(digit) | UserInput::NumberHeld(digit) => {
+                self.keys.push_str(&digit.to_string());
+                self.refilter();
+                return GuiAction::ScreenUpdated;
+            }
+            UserInput::Star if !self.keys.is_empty() => {
+                self.keys.pop();
+                self.refilter();
+                return GuiAction::ScreenUpdated;
+            }
+            UserInput::Power if !self.keys.is_empty() => {
+                self.keys.clear();
+                self.refilter();
+                return GuiAction::ScreenUpdated;
+            }
+            _ => {}
+        }
+        match (&input, self.menu.selected()) {
+            (UserInput::Power, _) => return GuiAction::PopPane,
+            (UserInput::Call, Some(ContactsPaneItem::Contact(entry))) => {
+                match entry.contact.default_number() {
+                    Some(number) => {
+                        if self
+                            .context
+                            .user_agent
+                            .place_call(number.number.clone())
+                            .is_err()
+                        {
+                            println!("User agent is gone, can't call {}", number.number);
+                        }
                     }
-                },
-                MenuInputEventResult::WrappedGuiAction(action) => action,
-            },
+                    None => println!("{} has no number to call", entry.contact.name),
+                }
+                return GuiAction::Nothing;
+            }
+            _ => {}
+        }
+        match self.menu.process_input(input) {
+            MenuInputEventResult::MenuItemSelected(item) => {
+                match item {
+                    ContactsPaneItem::Contact(entry) => GuiAction::Push(Box::new(
+                        EditContactPane::new(self.context.clone(), Some(entry.id), entry.contact),
+                    )),
+                    ContactsPaneItem::AddNewButton => GuiAction::Push(Box::new(
+                        EditContactPane::new(self.context.clone(), None, Contact::default()),
+                    )),
+                    ContactsPaneItem::SpeedDialButton => {
+                        GuiAction::Push(Box::new(SpeedDialPane::new(self.context.clone())))
+                    }
+                }
+            }
+            MenuInputEventResult::WrappedGuiAction(action) => action,
         }
     }
 
@@ -87,47 +147,48 @@ impl Pane for ContactsPane {
     }
 
     fn tick(&mut self) -> GuiAction {
-        match &mut self.child {
-            Some(c) => c.tick(),
-            None => GuiAction::Nothing,
-        }
-    }
-
-    fn pop_deepest(&mut self) -> bool {
-        let child_did_pop = match &mut self.child {
-            Some(c) => c.pop_deepest(),
-            None => return false,
-        };
-        if !child_did_pop {
-            self.child = None;
+        // Saved, renamed or deleted in the edit pane, or added from elsewhere.
+        let items = Self::items_for(&self.context, &self.keys);
+        if items != self.items {
+            self.items = items.clone();
+            self.menu.set_options(items);
+            GuiAction::ScreenUpdated
+        } else {
+            GuiAction::Nothing
         }
-        true
     }
 }
 
 impl ContactsPane {
-    pub fn new<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>() -> ContactsPane {
+    pub fn new(context: GuiContext) -> ContactsPane {
+        let items = Self::items_for(&context, "");
         ContactsPane {
-            menu: Menu::<ContactsPaneItem>::new::<Display>(vec![
-                ContactsPaneItem::Contact(Contact {
-                    name: "John Smith".into(),
-                    phone_number: "".into(),
-                }),
-                ContactsPaneItem::Contact(Contact {
-                    name: "Jane Doe".into(),
-                    phone_number: "".into(),
-                }),
-                ContactsPaneItem::Contact(Contact {
-                    name: "Ellen Poe".into(),
-                    phone_number: "".into(),
-                }),
-                ContactsPaneItem::Contact(Contact {
-                    name: "Whoever Else".into(),
-                    phone_number: "".into(),
-                }),
-                ContactsPaneItem::AddNewButton,
-            ]),
-            child: None,
+            context,
+            keys: String::new(),
+            menu: Menu::<ContactsPaneItem>::new(items.clone()),
+            items,
         }
     }
+
+    // A fresh menu, so the cursor starts from the top of what's left.
+    fn refilter(&mut self) {
+        self.items = Self::items_for(&self.context, &self.keys);
+        self.menu = Menu::new(self.items.clone());
+    }
+
+    fn items_for(context: &GuiContext, keys: &str) -> Vec<ContactsPaneItem> {
+        let mut items: Vec<ContactsPaneItem> = match context.contacts.lock() {
+            Ok(contacts) => contacts
+                .contacts()
+                .iter()
+                .filter(|entry| entry.contact.matches_keys(keys))
+                .cloned()
+                .map(ContactsPaneItem::Contact)
+                .collect(),
+            Err(_) => vec![],
+        };
+        items.push(ContactsPaneItem::AddNewButton);
+        items.push(ContactsPaneItem::SpeedDialButton);
+        items
+    }
 }
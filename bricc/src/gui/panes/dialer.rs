@@ -0,0 +1,125 @@
+use embedded_graphics::prelude::{OriginDimensions, Point};
+use embedded_graphics::text::{Alignment, Baseline};
+use profont::{PROFONT_14_POINT, PROFONT_7_POINT, PROFONT_9_POINT};
+
+use crate::gui::context::GuiContext;
+use crate::gui::draw::{draw_soft_key_label, draw_text};
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::traits::{GuiAction, GuiElement, Pane};
+use crate::input::traits::UserInput;
+
+const MAX_DIALED_LENGTH: usize = 32;
+
+/// Number entry, opened by typing a digit on the idle screen. The soft key deletes,
+/// `Call` dials.
+pub struct DialerPane {
+    context: GuiContext,
+    number: String,
+    contact_name: Option<String>,
+}
+
+impl GuiElement for DialerPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        let size = framebuffer.size();
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let usable_width = size.width as i32 - 2 * margin;
+
+        // As big as still fits, and the end of the number if nothing does.
+        let font = [&PROFONT_14_POINT, &PROFONT_9_POINT]
+            .iter()
+            .find(|font| {
+                (self.number.len() as i32) * (font.character_size.width as i32) <= usable_width
+            })
+            .copied()
+            .unwrap_or(&PROFONT_9_POINT);
+        let fits = (usable_width / font.character_size.width as i32).max(1) as usize;
+        let shown = &self.number[self.number.len().saturating_sub(fits)..];
+        draw_text(
+            framebuffer,
+            shown,
+            font,
+            Point::new(size.width as i32 - margin, margin),
+            Alignment::Right,
+            Baseline::Top,
+        );
+
+        if let Some(name) = &self.contact_name {
+            draw_text(
+                framebuffer,
+                name,
+                &PROFONT_7_POINT,
+                Point::new(
+                    size.width as i32 - margin,
+                    margin + font.character_size.height as i32,
+                ),
+                Alignment::Right,
+                Baseline::Top,
+            );
+        }
+
+        draw_soft_key_label(framebuffer, "Delete");
+    }
+}
+
+impl Pane for DialerPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match input {
+            UserInput::Number(num) => self.push(char::from(b'0' + num)),
+            // For international numbers.
+            UserInput::NumberHeld(0) => self.push('+'),
+            UserInput::NumberHeld(num) => self.push(char::from(b'0' + num)),
+            UserInput::Star => self.push('*'),
+            UserInput::Hash => self.push('#'),
+            UserInput::SoftKey => {
+                self.number.pop();
+                if self.number.is_empty() {
+                    return GuiAction::PopPane;
+                }
+                self.resolve_contact();
+                GuiAction::ScreenUpdated
+            }
+            UserInput::Call => {
+                match self.context.user_agent.place_call(self.number.clone()) {
+                    Ok(handle) => println!("Dialing {} as call {}", self.number, handle),
+                    Err(_) => println!("User agent is gone, can't dial"),
+                }
+                GuiAction::PopPane
+            }
+            UserInput::Power => GuiAction::PopPane,
+            UserInput::Up | UserInput::Down => GuiAction::Nothing,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        GuiAction::Nothing
+    }
+}
+
+impl DialerPane {
+    pub fn new(context: GuiContext, first_key: char) -> DialerPane {
+        let mut pane = DialerPane {
+            context,
+            number: first_key.to_string(),
+            contact_name: None,
+        };
+        pane.resolve_contact();
+        pane
+    }
+
+    fn push(&mut self, key: char) -> GuiAction {
+        if self.number.len() >= MAX_DIALED_LENGTH {
+            return GuiAction::Nothing;
+        }
+        self.number.push(key);
+        self.resolve_contact();
+        GuiAction::ScreenUpdated
+    }
+
+    fn resolve_contact(&mut self) {
+        self.contact_name = self.context.contact_name_for(&self.number);
+    }
+}
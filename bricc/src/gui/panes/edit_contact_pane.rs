@@ -1,29 +1,54 @@
-use std::time::Duration;
-
-use embedded_graphics::{
-    draw_target::DrawTarget, pixelcolor::BinaryColor, prelude::OriginDimensions,
-};
+use std::fmt;
 
 use crate::gui::{
-    menu::{Menu, MenuElement},
-    text_input::TextInputHelper,
-    traits::{GuiAction, GuiElement, Pane},
+    menu::{Menu, MenuElement, MenuInputEventResult},
+    text_input::KeyboardType,
+    traits::{GuiAction, GuiElement, Pane, PaneResult},
 };
 
-use super::contacts::Contact;
+use crate::contacts::{Contact, ContactId, ContactNumber, NumberKind};
+use crate::gui::context::GuiContext;
+use crate::gui::framebuffer::Framebuffer;
 use crate::gui::menu::MenuElementType;
+use crate::input::traits::UserInput;
+
+use super::text_entry::TextEntryPane;
 
 #[derive(Clone)]
 enum ContactAttribute {
-    Name,
-    PhoneNumber,
+    Name(String),
+    /// Which one, what it is and whether it's the default.
+    Number(usize, ContactNumber, bool),
+    AddNumber,
+    AddSipUri,
+    Email(String),
+    Note(String),
+    Delete,
 }
 
 impl ToString for ContactAttribute {
     fn to_string(&self) -> String {
+        // The value once there is one, since it's all there's room for.
+        fn value_or(value: &str, label: &str) -> String {
+            if value.trim().is_empty() {
+                label.into()
+            } else {
+                value.into()
+            }
+        }
         match self {
-            ContactAttribute::Name => "Name".into(),
-            ContactAttribute::PhoneNumber => "Phone#".into(),
+            ContactAttribute::Name(name) => value_or(name, "Name"),
+            ContactAttribute::Number(_, number, is_default) => format!(
+                "{}{} {}",
+                if *is_default { "*" } else { "" },
+                kind_abbreviation(number.kind),
+                number.number
+            ),
+            ContactAttribute::AddNumber => "Add number".into(),
+            ContactAttribute::AddSipUri => "Add SIP URI".into(),
+            ContactAttribute::Email(email) => value_or(email, "Email"),
+            ContactAttribute::Note(note) => value_or(note, "Note"),
+            ContactAttribute::Delete => "Delete".into(),
         }
     }
 }
@@ -31,103 +56,305 @@ impl ToString for ContactAttribute {
 impl MenuElement for ContactAttribute {
     fn menu_item_type(&self) -> MenuElementType {
         match self {
-            ContactAttribute::Name => MenuElementType::TextEntry,
-            ContactAttribute::PhoneNumber => MenuElementType::NumberEntry,
+            ContactAttribute::Name(_) => MenuElementType::TextEntry,
+            ContactAttribute::Number(_, number, _) => match number.kind {
+                NumberKind::Sip => MenuElementType::TextEntry,
+                _ => MenuElementType::NumberEntry,
+            },
+            ContactAttribute::AddNumber => MenuElementType::Button,
+            ContactAttribute::AddSipUri => MenuElementType::Button,
+            ContactAttribute::Email(_) => MenuElementType::TextEntry,
+            ContactAttribute::Note(_) => MenuElementType::TextEntry,
+            ContactAttribute::Delete => MenuElementType::Button,
+        }
+    }
+}
+
+fn kind_abbreviation(kind: NumberKind) -> &'static str {
+    match kind {
+        NumberKind::Mobile => "M",
+        NumberKind::Home => "H",
+        NumberKind::Work => "W",
+        NumberKind::Sip => "S",
+        NumberKind::Other => "O",
+    }
+}
+
+/// What can be done with one of the numbers once it's picked.
+#[derive(Clone)]
+enum NumberOption {
+    Edit,
+    /// Selecting it moves on to the next kind.
+    Kind(NumberKind),
+    MakeDefault,
+    Remove,
+}
+
+impl fmt::Display for NumberOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NumberOption::Edit => f.write_str("Edit"),
+            NumberOption::Kind(kind) => write!(f, "Type: {}", kind),
+            NumberOption::MakeDefault => f.write_str("Make default"),
+            NumberOption::Remove => f.write_str("Remove"),
         }
     }
 }
 
+impl MenuElement for NumberOption {
+    fn menu_item_type(&self) -> MenuElementType {
+        MenuElementType::Button
+    }
+}
+
+// Which field the text entry on top is filling in.
+enum TextField {
+    Name,
+    Number(usize),
+    NewNumber(NumberKind),
+    Email,
+    Note,
+}
+
+/// Edits go straight into the `ContactBook`. A new contact (no `id`) is added there
+/// with its first edit.
 pub struct EditContactPane {
-    name: String,
-    phone_number: String,
+    context: GuiContext,
+    id: Option<ContactId>,
+    contact: Contact,
     menu: Menu<ContactAttribute>,
-    text_edit: Option<(ContactAttribute, TextInputHelper)>,
+    number_options: Option<(usize, Menu<NumberOption>)>,
+    editing: Option<TextField>,
 }
 
 impl EditContactPane {
-    pub fn new<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
-        contact: Contact,
-    ) -> EditContactPane {
+    pub fn new(context: GuiContext, id: Option<ContactId>, contact: Contact) -> EditContactPane {
         EditContactPane {
-            name: contact.name,
-            phone_number: contact.phone_number,
-            menu: Menu::new::<Display>(vec![ContactAttribute::Name, ContactAttribute::PhoneNumber]),
-            text_edit: None,
+            menu: Menu::new(Self::attributes(id, &contact)),
+            context,
+            id,
+            contact,
+            number_options: None,
+            editing: None,
+        }
+    }
+
+    fn attributes(id: Option<ContactId>, contact: &Contact) -> Vec<ContactAttribute> {
+        let default_index = contact.default_index();
+        let mut attributes = vec![ContactAttribute::Name(contact.name.clone())];
+        for (index, number) in contact.numbers.iter().enumerate() {
+            // Only worth pointing out when there's a choice.
+            let is_default = contact.numbers.len() > 1 && Some(index) == default_index;
+            attributes.push(ContactAttribute::Number(index, number.clone(), is_default));
+        }
+        attributes.push(ContactAttribute::AddNumber);
+        attributes.push(ContactAttribute::AddSipUri);
+        attributes.push(ContactAttribute::Email(contact.email.clone()));
+        attributes.push(ContactAttribute::Note(contact.note.clone()));
+        if id.is_some() {
+            attributes.push(ContactAttribute::Delete);
+        }
+        attributes
+    }
+
+    fn number_menu(number: &ContactNumber) -> Menu<NumberOption> {
+        Menu::new(vec![
+            NumberOption::Edit,
+            NumberOption::Kind(number.kind),
+            NumberOption::MakeDefault,
+            NumberOption::Remove,
+        ])
+    }
+
+    fn edit(
+        &mut self,
+        field: TextField,
+        prompt: &str,
+        text: String,
+        keyboard: KeyboardType,
+    ) -> GuiAction {
+        self.editing = Some(field);
+        GuiAction::Push(Box::new(TextEntryPane::new(prompt, text, keyboard)))
+    }
+
+    fn keyboard_for(kind: NumberKind) -> KeyboardType {
+        match kind {
+            NumberKind::Sip => KeyboardType::TextStartLower,
+            _ => KeyboardType::Numbers,
+        }
+    }
+
+    fn fill_in(&mut self, field: TextField, value: String) {
+        match field {
+            TextField::Name => self.contact.name = value,
+            TextField::Number(index) => {
+                if value.trim().is_empty() {
+                    self.contact.remove_number(index);
+                } else if let Some(number) = self.contact.numbers.get_mut(index) {
+                    number.number = value;
+                }
+            }
+            TextField::NewNumber(kind) => {
+                if !value.trim().is_empty() {
+                    self.contact.numbers.push(ContactNumber {
+                        kind,
+                        number: value,
+                    });
+                }
+            }
+            TextField::Email => self.contact.email = value,
+            TextField::Note => self.contact.note = value,
+        }
+        self.save();
+    }
+
+    fn save(&mut self) {
+        if let Ok(mut contacts) = self.context.contacts.lock() {
+            match self.id {
+                Some(id) => {
+                    if !contacts.update(id, self.contact.clone()) {
+                        println!("Contact {} went away while editing", id);
+                    }
+                }
+                None if self.contact.is_empty() => {}
+                None => self.id = Some(contacts.add(self.contact.clone())),
+            }
+        }
+        self.menu
+            .set_options(Self::attributes(self.id, &self.contact));
+    }
+
+    fn delete(&mut self) {
+        if let (Some(id), Ok(mut contacts)) = (self.id, self.context.contacts.lock()) {
+            contacts.remove(id);
+        }
+    }
+
+    fn process_number_option(&mut self, index: usize, option: NumberOption) -> GuiAction {
+        let number = match self.contact.numbers.get_mut(index) {
+            Some(number) => number,
+            None => {
+                self.number_options = None;
+                return GuiAction::ScreenUpdated;
+            }
+        };
+        match option {
+            NumberOption::Edit => {
+                let text = number.number.clone();
+                let keyboard = Self::keyboard_for(number.kind);
+                self.number_options = None;
+                return self.edit(TextField::Number(index), "Enter number:", text, keyboard);
+            }
+            NumberOption::Kind(kind) => {
+                let next = NumberKind::ALL
+                    .iter()
+                    .cycle()
+                    .skip_while(|other| **other != kind)
+                    .nth(1)
+                    .copied()
+                    .unwrap_or(kind);
+                number.kind = next;
+                self.number_options = Some((index, Self::number_menu(number)));
+                self.save();
+            }
+            NumberOption::MakeDefault => {
+                self.contact.default_number = index;
+                self.number_options = None;
+                self.save();
+            }
+            NumberOption::Remove => {
+                self.contact.remove_number(index);
+                self.number_options = None;
+                self.save();
+            }
         }
+        GuiAction::ScreenUpdated
     }
 }
 
-impl<Display: OriginDimensions + DrawTarget<Color = BinaryColor>> GuiElement<Display>
-    for EditContactPane
-{
-    fn render(&mut self, framebuffer: &mut Display) {
-        match &mut self.text_edit {
-            Some((_, helper)) => helper.render(framebuffer),
-            None => self.menu.render(framebuffer),
+impl GuiElement for EditContactPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        if let Some((_, menu)) = &mut self.number_options {
+            menu.render(framebuffer);
+        } else {
+            self.menu.render(framebuffer);
         }
     }
 }
 
 impl Pane for EditContactPane {
-    fn process_input<
-        Display: embedded_graphics::prelude::OriginDimensions
-            + embedded_graphics::draw_target::DrawTarget<
-                Color = embedded_graphics::pixelcolor::BinaryColor,
-            >,
-    >(
-        &mut self,
-        input: crate::input::traits::UserInput,
-    ) -> crate::gui::traits::GuiAction {
-        match &mut self.text_edit {
-            Some((attrib, helper)) => match helper.process_input(input) {
-                Some(result) => match result {
-                    crate::gui::text_input::TextInputResult::Edited(val) => {
-                        match attrib {
-                            ContactAttribute::Name => self.name = val,
-                            ContactAttribute::PhoneNumber => self.phone_number = val,
-                        }
-                        GuiAction::PopPane
-                    }
-                    crate::gui::text_input::TextInputResult::Canceled => GuiAction::PopPane,
-                },
-                None => GuiAction::ScreenUpdated,
-            },
-            None => match self.menu.process_input(input) {
-                crate::gui::menu::MenuInputEventResult::MenuItemSelected(item) => {
-                    let child = match item {
-                        ContactAttribute::Name => TextInputHelper::new::<Display>(
-                            "Enter name:".into(),
-                            self.name.clone(),
-                            crate::gui::text_input::KeyboardType::TextStartLower,
-                            Duration::from_millis(1000),
-                        ),
-                        ContactAttribute::PhoneNumber => TextInputHelper::new::<Display>(
-                            "Enter phone#:".into(),
-                            self.phone_number.clone(),
-                            crate::gui::text_input::KeyboardType::Numbers,
-                            Duration::from_millis(1000),
-                        ),
-                    };
-                    self.text_edit = Some((item, child));
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        if let Some((index, menu)) = &mut self.number_options {
+            let index = *index;
+            return match menu.process_input(input) {
+                MenuInputEventResult::MenuItemSelected(option) => {
+                    self.process_number_option(index, option)
+                }
+                // Back to the contact, not out of it.
+                MenuInputEventResult::WrappedGuiAction(GuiAction::PopPane) => {
+                    self.number_options = None;
                     GuiAction::ScreenUpdated
                 }
-                crate::gui::menu::MenuInputEventResult::WrappedGuiAction(action) => action,
+                MenuInputEventResult::WrappedGuiAction(action) => action,
+            };
+        }
+        match self.menu.process_input(input) {
+            MenuInputEventResult::MenuItemSelected(attribute) => match attribute {
+                ContactAttribute::Name(name) => self.edit(
+                    TextField::Name,
+                    "Enter name:",
+                    name,
+                    KeyboardType::TextStartCaps,
+                ),
+                ContactAttribute::Number(index, number, _) => {
+                    self.number_options = Some((index, Self::number_menu(&number)));
+                    GuiAction::ScreenUpdated
+                }
+                ContactAttribute::AddNumber => self.edit(
+                    TextField::NewNumber(NumberKind::Mobile),
+                    "Enter phone#:",
+                    "".into(),
+                    KeyboardType::Numbers,
+                ),
+                ContactAttribute::AddSipUri => self.edit(
+                    TextField::NewNumber(NumberKind::Sip),
+                    "Enter SIP URI:",
+                    "".into(),
+                    KeyboardType::TextStartLower,
+                ),
+                ContactAttribute::Email(email) => self.edit(
+                    TextField::Email,
+                    "Enter email:",
+                    email,
+                    KeyboardType::TextStartLower,
+                ),
+                ContactAttribute::Note(note) => self.edit(
+                    TextField::Note,
+                    "Enter note:",
+                    note,
+                    KeyboardType::TextStartCaps,
+                ),
+                ContactAttribute::Delete => {
+                    self.delete();
+                    GuiAction::PopPane
+                }
             },
+            MenuInputEventResult::WrappedGuiAction(action) => action,
         }
     }
 
     fn is_preventing_lock(&self) -> bool {
-        todo!()
+        false
     }
 
-    fn tick(&mut self) -> crate::gui::traits::GuiAction {
-        match &mut self.text_edit {
-            Some((_, helper)) => helper.tick(),
-            None => GuiAction::Nothing,
-        }
+    fn tick(&mut self) -> GuiAction {
+        GuiAction::Nothing
     }
 
-    fn pop_deepest(&mut self) -> bool {
-        false
+    fn on_result(&mut self, result: PaneResult) -> GuiAction {
+        if let (Some(field), PaneResult::Text(value)) = (self.editing.take(), result) {
+            self.fill_in(field, value);
+        }
+        GuiAction::ScreenUpdated
     }
 }
@@ -0,0 +1,201 @@
+use embedded_graphics::draw_target::DrawTarget;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::{OriginDimensions, Point, Size};
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle, StyledDrawable};
+use embedded_graphics::text::{Alignment, Baseline};
+use profont::{PROFONT_12_POINT, PROFONT_7_POINT};
+
+use crate::contacts::VOICEMAIL_DIGIT;
+use crate::gui::context::GuiContext;
+use crate::gui::draw::{draw_soft_key_label, draw_text};
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::traits::{GuiAction, GuiElement, Pane};
+use crate::input::traits::UserInput;
+use crate::voip::registration::RegistrationState;
+
+use super::dialer::DialerPane;
+use super::mainmenu::MainMenuPane;
+
+const ENVELOPE_SIZE: Size = Size::new(9, 7);
+
+/// The classic voicemail-waiting envelope, top left corner at `top_left`.
+fn draw_envelope<Display: DrawTarget<Color = BinaryColor>>(
+    framebuffer: &mut Display,
+    top_left: Point,
+) {
+    let style = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    let width = ENVELOPE_SIZE.width as i32;
+    let height = ENVELOPE_SIZE.height as i32;
+    let fold = top_left + Point::new(width / 2, height / 2);
+    let drawn = Rectangle::new(top_left, ENVELOPE_SIZE)
+        .draw_styled(&style, framebuffer)
+        .and_then(|_| Line::new(top_left, fold).draw_styled(&style, framebuffer))
+        .and_then(|_| {
+            Line::new(top_left + Point::new(width - 1, 0), fold).draw_styled(&style, framebuffer)
+        });
+    if drawn.is_err() {
+        println!("Failed to draw envelope");
+    }
+}
+
+/// The home screen once unlocked. Digits start dialing, the soft key opens the menu
+/// and holding 1 calls voicemail. Holding 2 to 9 calls whoever's on that speed dial,
+/// or just dials the digit if nobody is.
+pub struct IdlePane {
+    context: GuiContext,
+    registration: RegistrationState,
+    unread_messages: u32,
+    voicemail_waiting: bool,
+}
+
+impl GuiElement for IdlePane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        let size = framebuffer.size();
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let status = match &self.registration {
+            // The reason is too long for the screen; the log has it.
+            RegistrationState::Failed(_) => "No service".into(),
+            registration => registration.to_string(),
+        };
+        draw_text(
+            framebuffer,
+            &status,
+            &PROFONT_7_POINT,
+            Point::new(margin, 0),
+            Alignment::Left,
+            Baseline::Top,
+        );
+        if self.voicemail_waiting {
+            let x = size.width as i32 - margin - ENVELOPE_SIZE.width as i32;
+            draw_envelope(framebuffer, Point::new(x, 1));
+        }
+        if self.unread_messages > 0 {
+            let unread = match self.unread_messages {
+                1 => "1 new message".into(),
+                count => format!("{} new messages", count),
+            };
+            draw_text(
+                framebuffer,
+                &unread,
+                &PROFONT_7_POINT,
+                Point::new(margin, PROFONT_7_POINT.character_size.height as i32),
+                Alignment::Left,
+                Baseline::Top,
+            );
+        }
+        draw_text(
+            framebuffer,
+            "kyp",
+            &PROFONT_12_POINT,
+            Point::new(size.width as i32 / 2, size.height as i32 / 2),
+            Alignment::Center,
+            Baseline::Middle,
+        );
+        draw_soft_key_label(framebuffer, "Menu");
+    }
+}
+
+impl Pane for IdlePane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        let first_key = match input {
+            UserInput::Number(num) => char::from(b'0' + num),
+            UserInput::NumberHeld(VOICEMAIL_DIGIT) => {
+                self.call_voicemail();
+                return GuiAction::Nothing;
+            }
+            UserInput::NumberHeld(num) if self.call_speed_dial(num) => {
+                return GuiAction::Nothing;
+            }
+            UserInput::NumberHeld(num) => char::from(b'0' + num),
+            UserInput::Star => '*',
+            UserInput::Hash => '#',
+            UserInput::SoftKey => {
+                return GuiAction::Push(Box::new(MainMenuPane::new(self.context.clone())));
+            }
+            _ => return GuiAction::Nothing,
+        };
+        GuiAction::Push(Box::new(DialerPane::new(self.context.clone(), first_key)))
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        let registration = self.context.user_agent.registration_state();
+        let unread_messages = self.context.unread_message_count();
+        let voicemail_waiting = Self::is_voicemail_waiting(&self.context);
+        if registration != self.registration
+            || unread_messages != self.unread_messages
+            || voicemail_waiting != self.voicemail_waiting
+        {
+            self.registration = registration;
+            self.unread_messages = unread_messages;
+            self.voicemail_waiting = voicemail_waiting;
+            GuiAction::ScreenUpdated
+        } else {
+            GuiAction::Nothing
+        }
+    }
+}
+
+impl IdlePane {
+    pub fn new(context: GuiContext) -> IdlePane {
+        let registration = context.user_agent.registration_state();
+        let unread_messages = context.unread_message_count();
+        let voicemail_waiting = Self::is_voicemail_waiting(&context);
+        IdlePane {
+            context,
+            registration,
+            unread_messages,
+            voicemail_waiting,
+        }
+    }
+
+    fn is_voicemail_waiting(context: &GuiContext) -> bool {
+        match context.user_agent.voicemail() {
+            Some(voicemail) => voicemail.waiting || voicemail.new > 0,
+            None => false,
+        }
+    }
+
+    /// False if there's nobody on `digit`.
+    fn call_speed_dial(&mut self, digit: u8) -> bool {
+        let (name, number) = match self.context.contacts.lock() {
+            Ok(contacts) => match contacts.speed_dial(digit) {
+                Some(entry) => match entry.contact.default_number() {
+                    Some(number) => (entry.contact.name.clone(), number.number.clone()),
+                    None => {
+                        println!(
+                            "{} on speed dial {} has no number",
+                            entry.contact.name, digit
+                        );
+                        return true;
+                    }
+                },
+                None => return false,
+            },
+            Err(_) => return false,
+        };
+        match self.context.user_agent.place_call(number) {
+            Ok(handle) => println!("Speed dialing {} as call {}", name, handle),
+            Err(_) => println!("User agent is gone, can't call {}", name),
+        }
+        true
+    }
+
+    /// Long-press 1, as on the phones this one is modelled after.
+    fn call_voicemail(&mut self) {
+        let number = match self.context.user_agent.voicemail_number() {
+            Some(number) => number,
+            None => {
+                println!("No voicemail number to call");
+                return;
+            }
+        };
+        match self.context.user_agent.place_call(number.clone()) {
+            Ok(handle) => println!("Calling voicemail at {} as call {}", number, handle),
+            Err(_) => println!("User agent is gone, can't call voicemail"),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use embedded_graphics::prelude::{OriginDimensions, Point};
+use embedded_graphics::text::{Alignment, Baseline};
+use profont::{PROFONT_7_POINT, PROFONT_9_POINT};
+
+use crate::gui::context::GuiContext;
+use crate::gui::draw::{draw_soft_key_label, draw_text};
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::traits::{GuiAction, GuiElement, Pane};
+use crate::input::traits::UserInput;
+use crate::voip::call::{CallHandle, CallInfo, CallState};
+
+/// Accept or reject a ringing call: `Call` answers, the soft key or `Power` turn it
+/// down. Pops once the call stops ringing, either way. Answering while in another call
+/// puts that one on hold.
+pub struct IncomingCallPane {
+    context: GuiContext,
+    info: CallInfo,
+    contact_name: Option<String>,
+    /// Another call is up, so this one is waiting.
+    is_waiting: bool,
+}
+
+impl GuiElement for IncomingCallPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        let size = framebuffer.size();
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let line_height = PROFONT_7_POINT.character_size.height as i32;
+        let number = self.info.remote_user();
+
+        draw_text(
+            framebuffer,
+            if self.is_waiting {
+                "Call waiting"
+            } else {
+                "Incoming call"
+            },
+            &PROFONT_7_POINT,
+            Point::new(margin, 0),
+            Alignment::Left,
+            Baseline::Top,
+        );
+        let name = self
+            .contact_name
+            .clone()
+            .or_else(|| self.info.remote_display_name.clone());
+        let mut y = line_height + margin;
+        if let Some(name) = name {
+            draw_text(
+                framebuffer,
+                &name,
+                &PROFONT_9_POINT,
+                Point::new(size.width as i32 / 2, y),
+                Alignment::Center,
+                Baseline::Top,
+            );
+            y += PROFONT_9_POINT.character_size.height as i32;
+        }
+        draw_text(
+            framebuffer,
+            &number,
+            &PROFONT_7_POINT,
+            Point::new(size.width as i32 / 2, y),
+            Alignment::Center,
+            Baseline::Top,
+        );
+        draw_soft_key_label(framebuffer, "Reject");
+    }
+}
+
+impl Pane for IncomingCallPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        // Either way, `tick` notices the call has stopped ringing.
+        let sent = match input {
+            UserInput::Call => self.context.user_agent.answer(self.info.handle),
+            UserInput::SoftKey | UserInput::Power => {
+                self.context.user_agent.reject(self.info.handle)
+            }
+            _ => return GuiAction::Nothing,
+        };
+        if sent.is_err() {
+            println!("User agent is gone, can't answer");
+            return GuiAction::PopPane;
+        }
+        GuiAction::Nothing
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        true
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        match self.context.user_agent.call(self.info.handle) {
+            Some(info) if info.state == CallState::Ringing => GuiAction::Nothing,
+            _ => GuiAction::PopPane,
+        }
+    }
+}
+
+impl IncomingCallPane {
+    pub fn new(context: GuiContext, info: CallInfo) -> IncomingCallPane {
+        let contact_name = context.contact_name_for(&info.remote_user());
+        let is_waiting = context
+            .user_agent
+            .calls()
+            .iter()
+            .any(|call| call.handle != info.handle && call.is_active());
+        IncomingCallPane {
+            context,
+            info,
+            contact_name,
+            is_waiting,
+        }
+    }
+
+    pub fn handle(&self) -> CallHandle {
+        self.info.handle
+    }
+}
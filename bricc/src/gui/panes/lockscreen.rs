@@ -1,66 +1,196 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use embedded_graphics::draw_target::DrawTarget;
 use embedded_graphics::pixelcolor::BinaryColor;
-use embedded_graphics::prelude::OriginDimensions;
 
+use crate::gui::context::GuiContext;
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::navigator::Navigator;
 use crate::gui::traits::{GuiAction, GuiElement, Pane};
 use crate::input::traits::UserInput;
+use crate::voip::call::{CallDirection, CallHandle, CallInfo, CallState};
+use crate::voip::dtmf::DtmfDigit;
 
-use super::mainmenu::MainMenuPane;
+use super::call::CallPane;
+use super::idle::IdlePane;
+use super::incoming_call::IncomingCallPane;
 
+const AUTO_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Goes over everything else, the lock screen included, while a call is up. Whatever
+// was underneath is left alone and comes back once the call is over.
+enum CallOverlay {
+    Incoming(IncomingCallPane),
+    InCall(CallPane),
+    None,
+}
+
+/// The lock screen, the calls and, under them, the navigator with everything else.
 pub struct RootPane {
+    context: GuiContext,
     last_input_instant: Instant,
-    child: MainMenuPane,
+    navigator: Navigator,
+    call_overlay: CallOverlay,
     is_unlocked: bool,
 }
 
-impl<Display: OriginDimensions + DrawTarget<Color = BinaryColor>> GuiElement<Display> for RootPane {
-    fn render(&mut self, framebuffer: &mut Display) {
-        if self.is_unlocked {
-            self.child.render(framebuffer)
-        } else {
-            if framebuffer.clear(BinaryColor::Off).is_err() {
-                println!("Failed to clear display!")
+impl GuiElement for RootPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        match &mut self.call_overlay {
+            CallOverlay::Incoming(pane) => pane.render(framebuffer),
+            CallOverlay::InCall(pane) => pane.render(framebuffer),
+            CallOverlay::None => {
+                if self.is_unlocked {
+                    self.navigator.render(framebuffer)
+                } else {
+                    if framebuffer.clear(BinaryColor::Off).is_err() {
+                        println!("Failed to clear display!")
+                    }
+                }
             }
         }
     }
 }
 
 impl Pane for RootPane {
-    fn process_input<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
-        &mut self,
-        input: UserInput,
-    ) -> GuiAction {
-        if self.is_unlocked {
-            self.last_input_instant = Instant::now();
-            self.child.process_input::<Display>(input)
-        } else {
-            // Check if should unlock.
-            self.is_unlocked = true;
-            GuiAction::ScreenUpdated
-        }
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        self.last_input_instant = Instant::now();
+        let action = match &mut self.call_overlay {
+            CallOverlay::Incoming(pane) => pane.process_input(input),
+            CallOverlay::InCall(pane) => pane.process_input(input),
+            CallOverlay::None => {
+                return if self.is_unlocked {
+                    self.navigator.process_input(input)
+                } else {
+                    // Check if should unlock.
+                    self.is_unlocked = true;
+                    GuiAction::ScreenUpdated
+                };
+            }
+        };
+        self.close_call_overlay_on_pop(action)
     }
 
     fn is_preventing_lock(&self) -> bool {
-        todo!()
+        match &self.call_overlay {
+            CallOverlay::Incoming(pane) => pane.is_preventing_lock(),
+            CallOverlay::InCall(pane) if pane.is_preventing_lock() => true,
+            _ => self.navigator.is_preventing_lock(),
+        }
     }
 
     fn tick(&mut self) -> GuiAction {
-        self.child.tick()
-    }
+        let mut action = GuiAction::Nothing;
+        if let CallOverlay::None = self.call_overlay {
+            // Calls we placed, or the one left over after another ended. Incoming ones
+            // come in through `show_incoming_call`.
+            let new_call = self
+                .context
+                .user_agent
+                .calls()
+                .into_iter()
+                .filter(|call| call.is_active() && !is_ringing_in(call))
+                .min_by_key(|call| call.on_hold);
+            if let Some(call) = new_call {
+                self.call_overlay =
+                    CallOverlay::InCall(CallPane::new(self.context.clone(), call.handle));
+                action = GuiAction::ScreenUpdated;
+            }
+        }
+
+        if self.is_unlocked
+            && !self.is_preventing_lock()
+            && self.last_input_instant.elapsed() >= AUTO_LOCK_TIMEOUT
+        {
+            self.is_unlocked = false;
+            action = GuiAction::ScreenUpdated;
+        }
 
-    fn pop_deepest(&mut self) -> bool {
-        self.child.pop_deepest()
+        let child_action = match &mut self.call_overlay {
+            CallOverlay::Incoming(pane) => match pane.tick() {
+                GuiAction::PopPane => {
+                    // Answered, rejected or given up on by the caller.
+                    let handle = pane.handle();
+                    self.call_overlay = match self.context.user_agent.call(handle) {
+                        Some(call) if call.is_active() => {
+                            CallOverlay::InCall(CallPane::new(self.context.clone(), handle))
+                        }
+                        _ => CallOverlay::None,
+                    };
+                    GuiAction::ScreenUpdated
+                }
+                action => action,
+            },
+            CallOverlay::InCall(pane) => {
+                let action = pane.tick();
+                self.close_call_overlay_on_pop(action)
+            }
+            CallOverlay::None => self.navigator.tick(),
+        };
+        match child_action {
+            GuiAction::Nothing => action,
+            child_action => child_action,
+        }
     }
 }
 
 impl RootPane {
-    pub fn new<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>() -> RootPane {
+    pub fn new(context: GuiContext) -> RootPane {
         RootPane {
+            context: context.clone(),
             last_input_instant: Instant::now(),
-            child: MainMenuPane::new::<Display>(),
+            navigator: Navigator::new(Box::new(IdlePane::new(context))),
+            call_overlay: CallOverlay::None,
             is_unlocked: false,
         }
     }
+
+    // The call panes pop when the call's over, or there's no user agent left to ask.
+    fn close_call_overlay_on_pop(&mut self, action: GuiAction) -> GuiAction {
+        match action {
+            GuiAction::PopPane => {
+                self.call_overlay = CallOverlay::None;
+                GuiAction::ScreenUpdated
+            }
+            action => action,
+        }
+    }
+
+    /// Puts the accept/reject pane over whatever is showing, locked or not. If that's
+    /// another call, it comes back once this one is answered or turned down.
+    pub fn show_incoming_call(&mut self, info: CallInfo) {
+        if let CallOverlay::Incoming(pane) = &self.call_overlay {
+            println!(
+                "Call {} already ringing, not showing call {}",
+                pane.handle(),
+                info.handle
+            );
+            return;
+        }
+        // Counts as activity, so auto-lock doesn't kick in the moment the call is over.
+        self.last_input_instant = Instant::now();
+        self.call_overlay =
+            CallOverlay::Incoming(IncomingCallPane::new(self.context.clone(), info));
+    }
+
+    /// Shows why a call we placed never got going. A call pane that's already up placed
+    /// it itself, and shows it already.
+    pub fn show_failed_call(&mut self, handle: CallHandle) {
+        if let CallOverlay::None = self.call_overlay {
+            self.last_input_instant = Instant::now();
+            self.call_overlay = CallOverlay::InCall(CallPane::new(self.context.clone(), handle));
+        }
+    }
+
+    /// Hands a key the far end pressed to the call pane. True if that call is showing.
+    pub fn show_dtmf(&mut self, handle: CallHandle, digit: DtmfDigit) -> bool {
+        match &mut self.call_overlay {
+            CallOverlay::InCall(pane) => pane.show_dtmf(handle, digit),
+            _ => false,
+        }
+    }
+}
+
+fn is_ringing_in(call: &CallInfo) -> bool {
+    call.direction == CallDirection::Incoming && call.state == CallState::Ringing
 }
@@ -1,7 +1,5 @@
-use embedded_graphics::draw_target::DrawTarget;
-use embedded_graphics::pixelcolor::BinaryColor;
-use embedded_graphics::prelude::OriginDimensions;
-
+use crate::gui::context::GuiContext;
+use crate::gui::framebuffer::Framebuffer;
 use crate::gui::menu::Menu;
 use crate::gui::menu::MenuElement;
 use crate::gui::menu::MenuElementType;
@@ -12,17 +10,15 @@ use crate::gui::traits::Pane;
 use crate::input::traits::UserInput;
 
 use super::contacts::ContactsPane;
+use super::messages::MessagesPane;
+use super::recent_calls::RecentCallsPane;
 use super::settings::SettingsPane;
 
-enum ChildPane {
-    Contacts(ContactsPane),
-    Settings(SettingsPane),
-    None,
-}
-
 #[derive(Clone, Copy)]
 pub enum MainMenuOptions {
     Contacts,
+    Messages,
+    RecentCalls,
     Settings,
 }
 
@@ -30,6 +26,8 @@ impl ToString for MainMenuOptions {
     fn to_string(&self) -> String {
         match self {
             MainMenuOptions::Contacts => "Contacts".into(),
+            MainMenuOptions::Messages => "Messages".into(),
+            MainMenuOptions::RecentCalls => "Recent calls".into(),
             MainMenuOptions::Settings => "Settings".into(),
         }
     }
@@ -39,49 +37,40 @@ impl MenuElement for MainMenuOptions {
     fn menu_item_type(&self) -> MenuElementType {
         match self {
             MainMenuOptions::Contacts => MenuElementType::Button,
+            MainMenuOptions::Messages => MenuElementType::Button,
+            MainMenuOptions::RecentCalls => MenuElementType::Button,
             MainMenuOptions::Settings => MenuElementType::Button,
         }
     }
 }
 
 pub struct MainMenuPane {
+    context: GuiContext,
     menu: Menu<MainMenuOptions>,
-    child_pane: ChildPane,
 }
 
-impl<Display: OriginDimensions + DrawTarget<Color = BinaryColor>> GuiElement<Display>
-    for MainMenuPane
-{
-    fn render(&mut self, framebuffer: &mut Display) {
-        match &mut self.child_pane {
-            ChildPane::Contacts(pane) => pane.render(framebuffer),
-            ChildPane::Settings(pane) => pane.render(framebuffer),
-            ChildPane::None => self.menu.render(framebuffer),
-        }
+impl GuiElement for MainMenuPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        self.menu.render(framebuffer)
     }
 }
 
 impl Pane for MainMenuPane {
-    fn process_input<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
-        &mut self,
-        input: UserInput,
-    ) -> GuiAction {
-        match &mut self.child_pane {
-            ChildPane::Contacts(pane) => pane.process_input::<Display>(input),
-            ChildPane::Settings(pane) => pane.process_input::<Display>(input),
-            ChildPane::None => match self.menu.process_input(input) {
-                MenuInputEventResult::MenuItemSelected(item) => match item {
-                    MainMenuOptions::Contacts => {
-                        self.child_pane = ChildPane::Contacts(ContactsPane::new::<Display>());
-                        GuiAction::ScreenUpdated
-                    }
-                    MainMenuOptions::Settings => {
-                        self.child_pane = ChildPane::Settings(SettingsPane::new::<Display>());
-                        GuiAction::ScreenUpdated
-                    }
-                },
-                MenuInputEventResult::WrappedGuiAction(action) => action,
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match self.menu.process_input(input) {
+            MenuInputEventResult::MenuItemSelected(item) => match item {
+                MainMenuOptions::Contacts => {
+                    GuiAction::Push(Box::new(ContactsPane::new(self.context.clone())))
+                }
+                MainMenuOptions::Messages => {
+                    GuiAction::Push(Box::new(MessagesPane::new(self.context.clone())))
+                }
+                MainMenuOptions::RecentCalls => {
+                    GuiAction::Push(Box::new(RecentCallsPane::new(self.context.clone())))
+                }
+                MainMenuOptions::Settings => GuiAction::Push(Box::new(SettingsPane::new())),
             },
+            MenuInputEventResult::WrappedGuiAction(action) => action,
         }
     }
 
@@ -90,34 +79,20 @@ impl Pane for MainMenuPane {
     }
 
     fn tick(&mut self) -> GuiAction {
-        match &mut self.child_pane {
-            ChildPane::Contacts(c) => c.tick(),
-            ChildPane::Settings(s) => s.tick(),
-            ChildPane::None => GuiAction::Nothing,
-        }
-    }
-
-    fn pop_deepest(&mut self) -> bool {
-        let child_did_pop = match &mut self.child_pane {
-            ChildPane::Contacts(p) => p.pop_deepest(),
-            ChildPane::Settings(p) => p.pop_deepest(),
-            ChildPane::None => return false,
-        };
-        if !child_did_pop {
-            self.child_pane = ChildPane::None;
-        }
-        true
+        GuiAction::Nothing
     }
 }
 
 impl MainMenuPane {
-    pub fn new<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>() -> MainMenuPane {
+    pub fn new(context: GuiContext) -> MainMenuPane {
         MainMenuPane {
-            menu: Menu::<MainMenuOptions>::new::<Display>(vec![
+            context,
+            menu: Menu::<MainMenuOptions>::new(vec![
                 MainMenuOptions::Contacts,
+                MainMenuOptions::Messages,
+                MainMenuOptions::RecentCalls,
                 MainMenuOptions::Settings,
             ]),
-            child_pane: ChildPane::None,
         }
     }
 }
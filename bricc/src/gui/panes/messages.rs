@@ -0,0 +1,388 @@
+use std::fmt;
+use std::time::Duration;
+
+use embedded_graphics::prelude::{OriginDimensions, Point};
+use embedded_graphics::text::{Alignment, Baseline};
+use profont::PROFONT_7_POINT;
+
+use crate::gui::context::GuiContext;
+use crate::gui::draw::{draw_soft_key_label, draw_text};
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::menu::{Menu, MenuElement, MenuElementType, MenuInputEventResult};
+use crate::gui::text_input::{KeyboardType, TextInputHelper, TextInputResult};
+use crate::gui::traits::{GuiAction, GuiElement, Pane, PaneResult};
+use crate::input::traits::UserInput;
+use crate::messages::{DeliveryState, MessageDirection, TextMessage};
+
+use super::text_entry::TextEntryPane;
+
+const MULTI_PRESS_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, PartialEq)]
+enum ThreadItem {
+    /// The peer, and what to show for them.
+    Thread(String, String),
+    NewMessage,
+}
+
+impl fmt::Display for ThreadItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThreadItem::Thread(_, label) => f.write_str(label),
+            ThreadItem::NewMessage => f.write_str("New message"),
+        }
+    }
+}
+
+impl MenuElement for ThreadItem {
+    fn menu_item_type(&self) -> MenuElementType {
+        MenuElementType::Button
+    }
+}
+
+fn thread_items(context: &GuiContext) -> Vec<ThreadItem> {
+    let threads: Vec<(String, u32)> = match context.messages.lock() {
+        Ok(messages) => messages
+            .conversations()
+            .iter()
+            .map(|conversation| (conversation.peer.clone(), conversation.unread))
+            .collect(),
+        Err(_) => vec![],
+    };
+    let mut items: Vec<ThreadItem> = threads
+        .into_iter()
+        .map(|(peer, unread)| {
+            let name = context
+                .contact_name_for(&peer)
+                .unwrap_or_else(|| peer.clone());
+            let label = if unread > 0 {
+                format!("{} ({})", name, unread)
+            } else {
+                name
+            };
+            ThreadItem::Thread(peer, label)
+        })
+        .collect();
+    items.push(ThreadItem::NewMessage);
+    items
+}
+
+/// The message threads, most recent first, plus a way to start a new one.
+pub struct MessagesPane {
+    context: GuiContext,
+    menu: Menu<ThreadItem>,
+    items: Vec<ThreadItem>,
+}
+
+impl GuiElement for MessagesPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        self.menu.render(framebuffer)
+    }
+}
+
+impl Pane for MessagesPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match self.menu.process_input(input) {
+            MenuInputEventResult::MenuItemSelected(item) => match item {
+                ThreadItem::Thread(peer, _) => {
+                    GuiAction::Push(Box::new(ConversationPane::new(self.context.clone(), peer)))
+                }
+                ThreadItem::NewMessage => GuiAction::Push(Box::new(TextEntryPane::new(
+                    "Text to:",
+                    "".into(),
+                    KeyboardType::Numbers,
+                ))),
+            },
+            MenuInputEventResult::WrappedGuiAction(action) => action,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        // New threads and unread counts as texts come in.
+        let items = thread_items(&self.context);
+        if items != self.items {
+            self.items = items.clone();
+            self.menu.set_options(items);
+            GuiAction::ScreenUpdated
+        } else {
+            GuiAction::Nothing
+        }
+    }
+
+    // Who a new message is for.
+    fn on_result(&mut self, result: PaneResult) -> GuiAction {
+        match result {
+            PaneResult::Text(number) if !number.is_empty() => GuiAction::Push(Box::new(
+                ComposePane::new(self.context.clone(), number, true),
+            )),
+            _ => GuiAction::ScreenUpdated,
+        }
+    }
+}
+
+impl MessagesPane {
+    pub fn new(context: GuiContext) -> MessagesPane {
+        let items = thread_items(&context);
+        MessagesPane {
+            context,
+            menu: Menu::<ThreadItem>::new(items.clone()),
+            items,
+        }
+    }
+}
+
+/// Breaks `text` into lines of at most `columns` characters, at spaces where it can.
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split(' ') {
+        let mut word: Vec<char> = word.chars().collect();
+        let line_len = line.chars().count();
+        if line_len > 0 && line_len + 1 + word.len() <= columns {
+            line.push(' ');
+            line.extend(word.iter());
+            continue;
+        }
+        if line_len > 0 {
+            lines.push(std::mem::take(&mut line));
+        }
+        while word.len() > columns {
+            lines.push(word.drain(..columns).collect());
+        }
+        line.extend(word.iter());
+    }
+    lines.push(line);
+    lines
+}
+
+// Which way it went, and for ours whether it got there.
+fn marker_of(message: &TextMessage) -> &'static str {
+    match (message.direction, &message.delivery) {
+        (MessageDirection::Incoming, _) => "< ",
+        (MessageDirection::Outgoing, DeliveryState::Sent) => "> ",
+        (MessageDirection::Outgoing, DeliveryState::Sending) => "~ ",
+        (MessageDirection::Outgoing, DeliveryState::Failed(_)) => "! ",
+    }
+}
+
+/// One thread, newest at the bottom. Up/Down scroll back, the soft key replies.
+/// Ours are marked `>` once delivered, `~` while sending and `!` if they failed.
+pub struct ConversationPane {
+    context: GuiContext,
+    peer: String,
+    title: String,
+    // How many lines up from the newest we've scrolled.
+    scroll: usize,
+    // So `tick` notices new messages and delivery reports.
+    shown: Vec<(MessageDirection, DeliveryState)>,
+}
+
+impl GuiElement for ConversationPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        let size = framebuffer.size();
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let line_height = PROFONT_7_POINT.character_size.height as i32;
+        let columns = ((size.width as i32 - 2 * margin) / margin).max(1) as usize;
+
+        draw_text(
+            framebuffer,
+            &self.title,
+            &PROFONT_7_POINT,
+            Point::new(margin, 0),
+            Alignment::Left,
+            Baseline::Top,
+        );
+
+        let mut lines = vec![];
+        if let Ok(messages) = self.context.messages.lock() {
+            if let Some(conversation) = messages.conversation(&self.peer) {
+                for message in conversation.messages.iter() {
+                    let marked = format!("{}{}", marker_of(message), message.text);
+                    lines.extend(wrap(&marked, columns));
+                }
+            }
+        }
+        // Between the title and the soft key label.
+        let visible = ((size.height as i32 - 2 * line_height) / line_height).max(1) as usize;
+        let max_scroll = lines.len().saturating_sub(visible);
+        self.scroll = self.scroll.min(max_scroll);
+        let end = lines.len() - self.scroll;
+        let start = end.saturating_sub(visible);
+        for (row, line) in lines[start..end].iter().enumerate() {
+            draw_text(
+                framebuffer,
+                line,
+                &PROFONT_7_POINT,
+                Point::new(margin, line_height * (row as i32 + 1)),
+                Alignment::Left,
+                Baseline::Top,
+            );
+        }
+        draw_soft_key_label(framebuffer, "Reply");
+    }
+}
+
+impl Pane for ConversationPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match input {
+            UserInput::Up => {
+                // `render` knows how far back there is to go.
+                self.scroll += 1;
+                GuiAction::ScreenUpdated
+            }
+            UserInput::Down => {
+                self.scroll = self.scroll.saturating_sub(1);
+                GuiAction::ScreenUpdated
+            }
+            UserInput::SoftKey => GuiAction::Push(Box::new(ComposePane::new(
+                self.context.clone(),
+                self.peer.clone(),
+                false,
+            ))),
+            UserInput::Call => GuiAction::PopPane,
+            _ => GuiAction::Nothing,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        let shown = self.snapshot();
+        if shown != self.shown {
+            self.shown = shown;
+            self.scroll = 0;
+            self.mark_read();
+            GuiAction::ScreenUpdated
+        } else {
+            GuiAction::Nothing
+        }
+    }
+}
+
+impl ConversationPane {
+    pub fn new(context: GuiContext, peer: String) -> ConversationPane {
+        let title = context
+            .contact_name_for(&peer)
+            .unwrap_or_else(|| peer.clone());
+        let mut pane = ConversationPane {
+            context,
+            peer,
+            title,
+            scroll: 0,
+            shown: vec![],
+        };
+        pane.shown = pane.snapshot();
+        pane.mark_read();
+        pane
+    }
+
+    fn snapshot(&self) -> Vec<(MessageDirection, DeliveryState)> {
+        match self.context.messages.lock() {
+            Ok(messages) => match messages.conversation(&self.peer) {
+                Some(conversation) => conversation
+                    .messages
+                    .iter()
+                    .map(|message| (message.direction, message.delivery.clone()))
+                    .collect(),
+                None => vec![],
+            },
+            Err(_) => vec![],
+        }
+    }
+
+    fn mark_read(&self) {
+        if let Ok(mut messages) = self.context.messages.lock() {
+            messages.mark_read(&self.peer);
+        }
+    }
+}
+
+/// Writes one message to `peer` and hands it to the user agent.
+pub struct ComposePane {
+    context: GuiContext,
+    peer: String,
+    input: TextInputHelper,
+    // Started from the thread list rather than the thread.
+    show_thread: bool,
+}
+
+impl GuiElement for ComposePane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        self.input.render(framebuffer);
+    }
+}
+
+impl Pane for ComposePane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match self.input.process_input(input) {
+            Some(TextInputResult::Edited(text)) => {
+                if text.trim().is_empty() {
+                    return GuiAction::PopPane;
+                }
+                self.send(text);
+                if self.show_thread {
+                    GuiAction::Replace(Box::new(ConversationPane::new(
+                        self.context.clone(),
+                        self.peer.clone(),
+                    )))
+                } else {
+                    GuiAction::PopPane
+                }
+            }
+            Some(TextInputResult::Canceled) => GuiAction::PopPane,
+            None => GuiAction::ScreenUpdated,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        true
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        self.input.tick()
+    }
+}
+
+impl ComposePane {
+    /// With `show_thread`, the thread takes its place once the message is sent.
+    pub fn new(context: GuiContext, peer: String, show_thread: bool) -> ComposePane {
+        let prompt = format!(
+            "To {}:",
+            context
+                .contact_name_for(&peer)
+                .unwrap_or_else(|| peer.clone())
+        );
+        ComposePane {
+            context,
+            peer,
+            input: TextInputHelper::new(
+                prompt,
+                "".into(),
+                KeyboardType::TextStartCaps,
+                MULTI_PRESS_TIMEOUT,
+            ),
+            show_thread,
+        }
+    }
+
+    fn send(&mut self, text: String) {
+        match self
+            .context
+            .user_agent
+            .send_message(self.peer.clone(), text.clone())
+        {
+            Ok(id) => {
+                if let Ok(mut messages) = self.context.messages.lock() {
+                    messages.add_outgoing(&self.peer, text, id);
+                }
+            }
+            Err(_) => println!("User agent is gone, message not sent"),
+        }
+    }
+}
@@ -1,5 +1,13 @@
+pub mod call;
 pub mod contacts;
+pub mod dialer;
 pub mod edit_contact_pane;
+pub mod idle;
+pub mod incoming_call;
 pub mod lockscreen;
 pub mod mainmenu;
+pub mod messages;
+pub mod recent_calls;
 pub mod settings;
+pub mod speed_dial;
+pub mod text_entry;
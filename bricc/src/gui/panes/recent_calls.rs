@@ -0,0 +1,298 @@
+use std::fmt;
+
+use embedded_graphics::prelude::Point;
+use embedded_graphics::text::{Alignment, Baseline};
+use profont::PROFONT_7_POINT;
+
+use crate::call_log::{CallKind, CallLogEntry};
+use crate::contacts::Contact;
+use crate::gui::context::GuiContext;
+use crate::gui::draw::{draw_soft_key_label, draw_text};
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::menu::{Menu, MenuElement, MenuElementType, MenuInputEventResult};
+use crate::gui::traits::{GuiAction, GuiElement, Pane};
+use crate::input::traits::UserInput;
+
+use super::edit_contact_pane::EditContactPane;
+
+#[derive(Clone, Copy)]
+enum CallLogFilter {
+    Missed,
+    Received,
+    Dialed,
+    All,
+}
+
+impl CallLogFilter {
+    fn kind(&self) -> Option<CallKind> {
+        match self {
+            CallLogFilter::Missed => Some(CallKind::Missed),
+            CallLogFilter::Received => Some(CallKind::Received),
+            CallLogFilter::Dialed => Some(CallKind::Dialed),
+            CallLogFilter::All => None,
+        }
+    }
+}
+
+impl fmt::Display for CallLogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            CallLogFilter::Missed => "Missed calls",
+            CallLogFilter::Received => "Received calls",
+            CallLogFilter::Dialed => "Dialed calls",
+            CallLogFilter::All => "All calls",
+        };
+        f.write_str(label)
+    }
+}
+
+impl MenuElement for CallLogFilter {
+    fn menu_item_type(&self) -> MenuElementType {
+        MenuElementType::Button
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct CallLogItem(CallLogEntry);
+
+impl fmt::Display for CallLogItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", marker_of(self.0.kind), self.0.display_name())
+    }
+}
+
+impl MenuElement for CallLogItem {
+    fn menu_item_type(&self) -> MenuElementType {
+        MenuElementType::Callable
+    }
+}
+
+// Same arrows as the message threads, and `!` for what we missed.
+fn marker_of(kind: CallKind) -> &'static str {
+    match kind {
+        CallKind::Received => "< ",
+        CallKind::Dialed => "> ",
+        CallKind::Missed => "! ",
+    }
+}
+
+fn call_back(context: &mut GuiContext, entry: &CallLogEntry) {
+    match context.user_agent.place_call(entry.remote_uri.clone()) {
+        Ok(handle) => println!("Calling back {} as call {}", entry.number, handle),
+        Err(_) => println!("User agent is gone, can't call back"),
+    }
+}
+
+/// The call log, by which calls you want to see.
+pub struct RecentCallsPane {
+    context: GuiContext,
+    menu: Menu<CallLogFilter>,
+}
+
+impl GuiElement for RecentCallsPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        self.menu.render(framebuffer)
+    }
+}
+
+impl Pane for RecentCallsPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match self.menu.process_input(input) {
+            MenuInputEventResult::MenuItemSelected(filter) => {
+                GuiAction::Push(Box::new(CallListPane::new(self.context.clone(), filter)))
+            }
+            MenuInputEventResult::WrappedGuiAction(action) => action,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        GuiAction::Nothing
+    }
+}
+
+impl RecentCallsPane {
+    pub fn new(context: GuiContext) -> RecentCallsPane {
+        RecentCallsPane {
+            context,
+            menu: Menu::new(vec![
+                CallLogFilter::Missed,
+                CallLogFilter::Received,
+                CallLogFilter::Dialed,
+                CallLogFilter::All,
+            ]),
+        }
+    }
+}
+
+/// The calls of one kind, newest first. `Call` calls the highlighted one back, the
+/// soft key shows the details and `Power` goes back.
+struct CallListPane {
+    context: GuiContext,
+    filter: CallLogFilter,
+    items: Vec<CallLogItem>,
+    menu: Menu<CallLogItem>,
+}
+
+impl GuiElement for CallListPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        if !self.items.is_empty() {
+            self.menu.render(framebuffer);
+            return;
+        }
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let line_height = PROFONT_7_POINT.character_size.height as i32;
+        draw_text(
+            framebuffer,
+            &self.filter.to_string(),
+            &PROFONT_7_POINT,
+            Point::new(margin, 0),
+            Alignment::Left,
+            Baseline::Top,
+        );
+        draw_text(
+            framebuffer,
+            "None",
+            &PROFONT_7_POINT,
+            Point::new(margin, line_height + margin),
+            Alignment::Left,
+            Baseline::Top,
+        );
+    }
+}
+
+impl Pane for CallListPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match input {
+            UserInput::Power => return GuiAction::PopPane,
+            UserInput::Call => {
+                if let Some(CallLogItem(entry)) = self.menu.selected().cloned() {
+                    call_back(&mut self.context, &entry);
+                }
+                return GuiAction::Nothing;
+            }
+            _ if self.items.is_empty() => return GuiAction::Nothing,
+            _ => {}
+        }
+        match self.menu.process_input(input) {
+            MenuInputEventResult::MenuItemSelected(CallLogItem(entry)) => {
+                GuiAction::Push(Box::new(CallDetailsPane::new(self.context.clone(), entry)))
+            }
+            MenuInputEventResult::WrappedGuiAction(action) => action,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        // Calls that end while we're looking.
+        let items = Self::items_for(&self.context, self.filter);
+        if items != self.items {
+            self.items = items.clone();
+            self.menu.set_options(items);
+            GuiAction::ScreenUpdated
+        } else {
+            GuiAction::Nothing
+        }
+    }
+}
+
+impl CallListPane {
+    fn new(context: GuiContext, filter: CallLogFilter) -> CallListPane {
+        let items = Self::items_for(&context, filter);
+        CallListPane {
+            context,
+            filter,
+            menu: Menu::new(items.clone()),
+            items,
+        }
+    }
+
+    fn items_for(context: &GuiContext, filter: CallLogFilter) -> Vec<CallLogItem> {
+        match context.call_log.lock() {
+            Ok(call_log) => call_log
+                .entries(filter.kind())
+                .into_iter()
+                .map(CallLogItem)
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+/// Who, when and for how long. `Call` calls back, the soft key saves the number as a
+/// contact.
+struct CallDetailsPane {
+    context: GuiContext,
+    entry: CallLogEntry,
+}
+
+impl GuiElement for CallDetailsPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        let margin = PROFONT_7_POINT.character_size.width as i32;
+        let line_height = PROFONT_7_POINT.character_size.height as i32;
+
+        let mut lines = vec![match self.entry.kind {
+            CallKind::Missed => "Missed call".into(),
+            kind => format!("{} {}", kind, self.entry.formatted_duration()),
+        }];
+        lines.push(self.entry.display_name().into());
+        if self.entry.contact_name.is_some() {
+            lines.push(self.entry.number.clone());
+        }
+        if let Some(time) = self.entry.formatted_time() {
+            lines.push(time);
+        }
+        for (index, line) in lines.iter().enumerate() {
+            draw_text(
+                framebuffer,
+                line,
+                &PROFONT_7_POINT,
+                Point::new(margin, index as i32 * line_height),
+                Alignment::Left,
+                Baseline::Top,
+            );
+        }
+        draw_soft_key_label(framebuffer, "Save");
+    }
+}
+
+impl Pane for CallDetailsPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match input {
+            UserInput::Call => {
+                call_back(&mut self.context, &self.entry);
+                GuiAction::Nothing
+            }
+            UserInput::SoftKey => GuiAction::Push(Box::new(EditContactPane::new(
+                self.context.clone(),
+                None,
+                Contact::new(
+                    self.entry.contact_name.clone().unwrap_or_default(),
+                    self.entry.number.clone(),
+                ),
+            ))),
+            UserInput::Power => GuiAction::PopPane,
+            _ => GuiAction::Nothing,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        GuiAction::Nothing
+    }
+}
+
+impl CallDetailsPane {
+    fn new(context: GuiContext, entry: CallLogEntry) -> CallDetailsPane {
+        CallDetailsPane { context, entry }
+    }
+}
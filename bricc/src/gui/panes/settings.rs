@@ -1,7 +1,4 @@
-use embedded_graphics::draw_target::DrawTarget;
-use embedded_graphics::pixelcolor::BinaryColor;
-use embedded_graphics::prelude::OriginDimensions;
-
+use crate::gui::framebuffer::Framebuffer;
 use crate::gui::menu::Menu;
 use crate::gui::menu::MenuElement;
 use crate::gui::menu::MenuElementType;
@@ -54,19 +51,14 @@ pub struct SettingsPane {
     menu: Menu<SettingsOptions>,
 }
 
-impl<Display: OriginDimensions + DrawTarget<Color = BinaryColor>> GuiElement<Display>
-    for SettingsPane
-{
-    fn render(&mut self, framebuffer: &mut Display) {
+impl GuiElement for SettingsPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
         self.menu.render(framebuffer)
     }
 }
 
 impl Pane for SettingsPane {
-    fn process_input<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
-        &mut self,
-        input: UserInput,
-    ) -> GuiAction {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
         match self.menu.process_input(input) {
             MenuInputEventResult::MenuItemSelected(item) => match item {
                 SettingsOptions::Wifi => todo!(),
@@ -88,16 +80,12 @@ impl Pane for SettingsPane {
     fn tick(&mut self) -> GuiAction {
         GuiAction::Nothing
     }
-
-    fn pop_deepest(&mut self) -> bool {
-        false
-    }
 }
 
 impl SettingsPane {
-    pub fn new<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>() -> SettingsPane {
+    pub fn new() -> SettingsPane {
         SettingsPane {
-            menu: Menu::<SettingsOptions>::new::<Display>(vec![
+            menu: Menu::<SettingsOptions>::new(vec![
                 SettingsOptions::Wifi,
                 SettingsOptions::Cellular,
                 SettingsOptions::Voip,
@@ -109,3 +97,9 @@ impl SettingsPane {
         }
     }
 }
+
+impl Default for SettingsPane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
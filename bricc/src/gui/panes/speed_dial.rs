@@ -0,0 +1,157 @@
+use std::fmt;
+
+use crate::contacts::{ContactId, SPEED_DIAL_DIGITS, VOICEMAIL_DIGIT};
+use crate::gui::context::GuiContext;
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::menu::{Menu, MenuElement, MenuElementType, MenuInputEventResult};
+use crate::gui::traits::{GuiAction, GuiElement, Pane};
+use crate::input::traits::UserInput;
+
+// One per digit from 1, so the menu's own numbering is the digit.
+#[derive(Clone)]
+struct SpeedDialSlot {
+    digit: u8,
+    name: Option<String>,
+}
+
+impl fmt::Display for SpeedDialSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.name, self.digit) {
+            (_, VOICEMAIL_DIGIT) => f.write_str("Voicemail"),
+            (Some(name), _) => f.write_str(name),
+            (None, _) => f.write_str("-"),
+        }
+    }
+}
+
+impl MenuElement for SpeedDialSlot {
+    fn menu_item_type(&self) -> MenuElementType {
+        MenuElementType::Button
+    }
+}
+
+#[derive(Clone)]
+enum SpeedDialChoice {
+    Nobody,
+    Contact(ContactId, String),
+}
+
+impl fmt::Display for SpeedDialChoice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpeedDialChoice::Nobody => f.write_str("Nobody"),
+            SpeedDialChoice::Contact(_, name) => f.write_str(name),
+        }
+    }
+}
+
+impl MenuElement for SpeedDialChoice {
+    fn menu_item_type(&self) -> MenuElementType {
+        MenuElementType::Button
+    }
+}
+
+/// Who's on which digit. Picking one of 2 to 9 lists the contacts to put there; 1 is
+/// always voicemail.
+pub struct SpeedDialPane {
+    context: GuiContext,
+    menu: Menu<SpeedDialSlot>,
+    // The digit being assigned and who could go on it.
+    picker: Option<(u8, Menu<SpeedDialChoice>)>,
+}
+
+impl GuiElement for SpeedDialPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        match &mut self.picker {
+            Some((_, menu)) => menu.render(framebuffer),
+            None => self.menu.render(framebuffer),
+        }
+    }
+}
+
+impl Pane for SpeedDialPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        if let Some((digit, menu)) = &mut self.picker {
+            let digit = *digit;
+            return match menu.process_input(input) {
+                MenuInputEventResult::MenuItemSelected(choice) => {
+                    let id = match choice {
+                        SpeedDialChoice::Nobody => None,
+                        SpeedDialChoice::Contact(id, _) => Some(id),
+                    };
+                    if let Ok(mut contacts) = self.context.contacts.lock() {
+                        contacts.set_speed_dial(digit, id);
+                    }
+                    self.picker = None;
+                    self.refresh();
+                    GuiAction::ScreenUpdated
+                }
+                // Back to the digits, not out of speed dial.
+                MenuInputEventResult::WrappedGuiAction(GuiAction::PopPane) => {
+                    self.picker = None;
+                    GuiAction::ScreenUpdated
+                }
+                MenuInputEventResult::WrappedGuiAction(action) => action,
+            };
+        }
+        match self.menu.process_input(input) {
+            MenuInputEventResult::MenuItemSelected(slot) => {
+                if !SPEED_DIAL_DIGITS.contains(&slot.digit) {
+                    return GuiAction::Nothing;
+                }
+                self.picker = Some((slot.digit, Menu::new(self.choices())));
+                GuiAction::ScreenUpdated
+            }
+            MenuInputEventResult::WrappedGuiAction(action) => action,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        GuiAction::Nothing
+    }
+}
+
+impl SpeedDialPane {
+    pub fn new(context: GuiContext) -> SpeedDialPane {
+        SpeedDialPane {
+            menu: Menu::new(Self::slots_for(&context)),
+            context,
+            picker: None,
+        }
+    }
+
+    fn slots_for(context: &GuiContext) -> Vec<SpeedDialSlot> {
+        let contacts = context.contacts.lock().ok();
+        (VOICEMAIL_DIGIT..=*SPEED_DIAL_DIGITS.end())
+            .map(|digit| SpeedDialSlot {
+                digit,
+                name: contacts
+                    .as_ref()
+                    .and_then(|contacts| contacts.speed_dial(digit))
+                    .map(|entry| entry.contact.name.clone()),
+            })
+            .collect()
+    }
+
+    fn refresh(&mut self) {
+        self.menu.set_options(Self::slots_for(&self.context));
+    }
+
+    fn choices(&self) -> Vec<SpeedDialChoice> {
+        let mut choices = vec![SpeedDialChoice::Nobody];
+        if let Ok(contacts) = self.context.contacts.lock() {
+            choices.extend(
+                contacts
+                    .contacts()
+                    .iter()
+                    .filter(|entry| entry.contact.default_number().is_some())
+                    .map(|entry| SpeedDialChoice::Contact(entry.id, entry.contact.name.clone())),
+            );
+        }
+        choices
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use crate::gui::framebuffer::Framebuffer;
+use crate::gui::text_input::{KeyboardType, TextInputHelper, TextInputResult};
+use crate::gui::traits::{GuiAction, GuiElement, Pane, PaneResult};
+use crate::input::traits::UserInput;
+
+const MULTI_PRESS_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Asks for one line of text. The soft key hands it to the pane underneath as a
+/// `PaneResult::Text`, `Call` goes back without it.
+pub struct TextEntryPane {
+    input: TextInputHelper,
+}
+
+impl GuiElement for TextEntryPane {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
+        self.input.render(framebuffer);
+    }
+}
+
+impl Pane for TextEntryPane {
+    fn process_input(&mut self, input: UserInput) -> GuiAction {
+        match self.input.process_input(input) {
+            Some(TextInputResult::Edited(text)) => GuiAction::PopWith(PaneResult::Text(text)),
+            Some(TextInputResult::Canceled) => GuiAction::PopPane,
+            None => GuiAction::ScreenUpdated,
+        }
+    }
+
+    fn is_preventing_lock(&self) -> bool {
+        true
+    }
+
+    fn tick(&mut self) -> GuiAction {
+        self.input.tick()
+    }
+}
+
+impl TextEntryPane {
+    pub fn new(prompt: &str, text: String, keyboard: KeyboardType) -> TextEntryPane {
+        TextEntryPane {
+            input: TextInputHelper::new(prompt.into(), text, keyboard, MULTI_PRESS_TIMEOUT),
+        }
+    }
+}
@@ -3,10 +3,9 @@ use std::time::{Duration, Instant};
 use embedded_graphics::geometry::Dimensions;
 use embedded_graphics::Drawable;
 use embedded_graphics::{
-    draw_target::DrawTarget,
     mono_font::MonoTextStyle,
     pixelcolor::BinaryColor,
-    prelude::{OriginDimensions, Point, Size},
+    prelude::{Point, Size},
     primitives::Rectangle,
 };
 use embedded_text::plugin::ansi::Ansi;
@@ -19,6 +18,7 @@ use profont::PROFONT_7_POINT;
 
 use crate::input::traits::UserInput;
 
+use super::framebuffer::Framebuffer;
 use super::traits::{GuiAction, GuiElement};
 
 pub enum TextInputResult {
@@ -43,10 +43,8 @@ pub struct TextInputHelper {
     multi_press_sequence: String,
 }
 
-impl<Display: OriginDimensions + DrawTarget<Color = BinaryColor>> GuiElement<Display>
-    for TextInputHelper
-{
-    fn render(&mut self, framebuffer: &mut Display) {
+impl GuiElement for TextInputHelper {
+    fn render(&mut self, framebuffer: &mut Framebuffer) {
         let character_style = MonoTextStyle::new(&PROFONT_7_POINT, BinaryColor::On);
         let textbox_style = TextBoxStyleBuilder::new()
             .height_mode(HeightMode::FitToText)
@@ -98,7 +96,7 @@ impl<Display: OriginDimensions + DrawTarget<Color = BinaryColor>> GuiElement<Dis
 }
 
 impl TextInputHelper {
-    pub fn new<Display: DrawTarget>(
+    pub fn new(
         prompt: String,
         initial_text: String,
         keyboard: KeyboardType,
@@ -119,26 +117,30 @@ impl TextInputHelper {
         if Instant::now() - self.last_input > self.multi_press_timeout
             && self.multi_press_sequence.len() != 0
         {
-            self.last_input = Instant::now();
-            // The old value must be put into the string.
-            let next_char_str = self
-                .multi_press_sequence
-                .get((self.multi_press_count as usize)..((self.multi_press_count as usize) + 1));
-            if next_char_str.is_some() {
-                self.text.push_str(next_char_str.unwrap());
-            }
-            self.multi_press_sequence = "".into();
-            self.multi_press_count = 0;
+            self.commit_multi_press();
             GuiAction::ScreenUpdated
         } else {
             GuiAction::Nothing
         }
     }
 
+    fn commit_multi_press(&mut self) {
+        self.last_input = Instant::now();
+        // The old value must be put into the string.
+        let next_char_str = self
+            .multi_press_sequence
+            .get((self.multi_press_count as usize)..((self.multi_press_count as usize) + 1));
+        if let Some(next_char_str) = next_char_str {
+            self.text.push_str(next_char_str);
+        }
+        self.multi_press_sequence = "".into();
+        self.multi_press_count = 0;
+    }
+
     pub fn process_input(&mut self, input: UserInput) -> Option<TextInputResult> {
         if self.keyboard == KeyboardType::Numbers {
             return match input {
-                UserInput::Number(num) => {
+                UserInput::Number(num) | UserInput::NumberHeld(num) => {
                     self.text.push_str(&format!("{}", num));
                     None
                 }
@@ -151,12 +153,18 @@ impl TextInputHelper {
                 UserInput::Power => None,
             };
         } else {
+            // Holding a key types its digit, like on any phone.
+            if let UserInput::NumberHeld(num) = input {
+                self.commit_multi_press();
+                self.text.push_str(&format!("{}", num));
+                return None;
+            }
             let next_seq = match self.keyboard {
                 KeyboardType::Numbers => panic!(),
                 KeyboardType::TextStartLower => match input {
                     UserInput::Number(num) => Some(String::from(match num {
                         0 => " ",
-                        1 => ".,",
+                        1 => ".,@:",
                         2 => "abcABC",
                         3 => "defDEF",
                         4 => "ghiGHI",
@@ -179,12 +187,13 @@ impl TextInputHelper {
                     UserInput::SoftKey => None,
                     UserInput::Call => None,
                     UserInput::Power => None,
+                    UserInput::NumberHeld(_) => None,
                 },
                 KeyboardType::TextStartCaps => {
                     match input {
                         UserInput::Number(num) => Some(String::from(match num {
                             0 => " ",
-                            1 => ".,",
+                            1 => ".,@:",
                             2 => "ABCabc",
                             3 => "DEFdef",
                             4 => "GHIghi",
@@ -207,11 +216,16 @@ impl TextInputHelper {
                         UserInput::SoftKey => None,
                         UserInput::Call => None,
                         UserInput::Power => None,
+                        UserInput::NumberHeld(_) => None,
                     }
                 }
             };
             match input {
-                UserInput::SoftKey => return Some(TextInputResult::Edited(self.text.clone())),
+                UserInput::SoftKey => {
+                    // Whatever was still being cycled through counts too.
+                    self.commit_multi_press();
+                    return Some(TextInputResult::Edited(self.text.clone()));
+                }
                 UserInput::Call => return Some(TextInputResult::Canceled),
                 _ => {}
             }
@@ -1,26 +1,42 @@
-use embedded_graphics::{
-    draw_target::DrawTarget, pixelcolor::BinaryColor, prelude::OriginDimensions,
-};
-
 use crate::input::traits::UserInput;
 
+use super::framebuffer::Framebuffer;
+
+/// What a pane hands back to the one under it when it closes with
+/// `GuiAction::PopWith`.
+pub enum PaneResult {
+    Text(String),
+}
+
 pub enum GuiAction {
     ScreenUpdated,
     InvalidInput,
+    /// Closes the pane, back to the one it was opened from.
     PopPane,
     Nothing,
+    /// Opens a pane on top of this one.
+    Push(Box<dyn Pane>),
+    /// Closes the pane and opens another in its place.
+    Replace(Box<dyn Pane>),
+    /// Closes panes until this many are left, the bottom one counting as 1.
+    PopTo(usize),
+    /// Closes the pane and gives the one under it the result.
+    PopWith(PaneResult),
 }
 
-pub trait GuiElement<Display: DrawTarget<Color = BinaryColor>> {
-    fn render(&mut self, framebuffer: &mut Display);
+/// Anything that draws itself. `Bricc` copies the framebuffer to the display driver.
+pub trait GuiElement {
+    fn render(&mut self, framebuffer: &mut Framebuffer);
 }
 
-pub trait Pane: Send {
-    fn process_input<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
-        &mut self,
-        input: UserInput,
-    ) -> GuiAction;
+/// A screen on the `Navigator`'s stack. Only the top one gets input and ticks.
+pub trait Pane: GuiElement + Send {
+    fn process_input(&mut self, input: UserInput) -> GuiAction;
     fn is_preventing_lock(&self) -> bool;
     fn tick(&mut self) -> GuiAction;
-    fn pop_deepest(&mut self) -> bool;
+
+    /// Called once the pane on top of this one has closed with a result.
+    fn on_result(&mut self, _result: PaneResult) -> GuiAction {
+        GuiAction::ScreenUpdated
+    }
 }
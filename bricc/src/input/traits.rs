@@ -1,6 +1,8 @@
 #[derive(PartialEq)]
 pub enum UserInput {
     Number(u8),
+    /// A digit key held down, which is a shortcut of its own.
+    NumberHeld(u8),
     Star,
     Hash,
     Up,
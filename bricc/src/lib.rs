@@ -1,38 +1,77 @@
 #![feature(async_closure)]
 
+pub mod audio;
+pub mod call_log;
+pub mod contacts;
 #[cfg(feature = "debug")]
 pub mod debug;
 pub mod display;
+pub mod events;
 pub mod gui;
 pub mod input;
+pub mod messages;
 pub mod network;
 pub mod prefs;
 pub mod realtime;
 pub mod traits;
 pub mod voip;
 
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use audio::ringtone::Ringtone;
+use audio::traits::{AudioFormat, AudioModule};
+use call_log::{CallLog, CallLogEntry};
+use contacts::ContactBook;
 use embedded_graphics::{
-    draw_target::DrawTarget, pixelcolor::BinaryColor, prelude::OriginDimensions,
+    draw_target::DrawTarget,
+    pixelcolor::BinaryColor,
+    prelude::{OriginDimensions, Size},
 };
+use events::SystemEvent;
 use input::traits::InputModule;
+use messages::MessageStore;
 use network::wifi::WifiModule;
-use voip::sip::generate_register;
+use voip::account::SipAccount;
+use voip::call::{CallHandle, CallState};
+use voip::user_agent::{SipUserAgent, UserAgentEvent};
 
 use crate::{
     gui::{
+        context::GuiContext,
+        framebuffer::Framebuffer,
         panes::lockscreen::RootPane,
         traits::{GuiElement, Pane},
     },
     prefs::kv_store::KvStore,
 };
 
-pub struct Bricc<KvStoreImpl: KvStore, WifiModuleImpl: WifiModule, InputModuleImpl: InputModule> {
+// Narrowband calls; the module resamples or we do if it can't.
+const CALL_AUDIO_SAMPLE_RATE: u32 = 8000;
+
+pub struct Bricc<
+    KvStoreImpl: KvStore,
+    WifiModuleImpl: WifiModule,
+    InputModuleImpl: InputModule,
+    AudioModuleImpl: AudioModule,
+> {
     root_pane: RootPane,
+    // Panes draw here, then it's copied to the display in one go.
+    framebuffer: Framebuffer,
     wifi_module: WifiModuleImpl,
     input_module: InputModuleImpl,
+    audio_module: AudioModuleImpl,
     kv_store: KvStoreImpl,
+    user_agent: SipUserAgent,
+    system_events: Receiver<SystemEvent>,
+    messages: Arc<Mutex<MessageStore>>,
+    contacts: Arc<Mutex<ContactBook>>,
+    call_log: Arc<Mutex<CallLog>>,
+    // Ended calls already in the log, while the user agent still lists them.
+    logged_calls: Vec<CallHandle>,
+    connected_call: Option<CallHandle>,
+    ringtone: Option<(CallHandle, Ringtone)>,
     screen_needs_update: bool,
 }
 
@@ -40,46 +79,82 @@ impl<
         KvStoreImpl: KvStore,
         WifiModuleImpl: network::wifi::WifiModule,
         InputModuleImpl: input::traits::InputModule,
-    > Bricc<KvStoreImpl, WifiModuleImpl, InputModuleImpl>
+        AudioModuleImpl: audio::traits::AudioModule,
+    > Bricc<KvStoreImpl, WifiModuleImpl, InputModuleImpl, AudioModuleImpl>
 {
-    pub fn new<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
+    pub fn new(
         kv_store: KvStoreImpl,
         wifi_impl: WifiModuleImpl,
         input_impl: InputModuleImpl,
-    ) -> Bricc<KvStoreImpl, WifiModuleImpl, InputModuleImpl> {
+        audio_impl: AudioModuleImpl,
+    ) -> Bricc<KvStoreImpl, WifiModuleImpl, InputModuleImpl, AudioModuleImpl> {
         println!("Bricc::new");
 
+        let mut kv_store = kv_store;
+        let account = SipAccount::load(&mut kv_store);
+        if account.is_none() {
+            println!("No VoIP account configured");
+        }
+
+        let (system_event_sender, system_events) = channel::<SystemEvent>();
+        let user_agent = SipUserAgent::start(account, system_event_sender);
+        let contacts = Arc::new(Mutex::new(ContactBook::load(&mut kv_store)));
+        let messages = Arc::new(Mutex::new(MessageStore::load(&mut kv_store)));
+        let call_log = Arc::new(Mutex::new(CallLog::load(&mut kv_store)));
+        let context = GuiContext::new(
+            user_agent.get_interface(),
+            contacts.clone(),
+            messages.clone(),
+            call_log.clone(),
+            audio_impl.controls(),
+        );
+
         Bricc {
-            root_pane: RootPane::new::<Display>(),
+            root_pane: RootPane::new(context),
+            // Sized to the display on the first frame.
+            framebuffer: Framebuffer::new(Size::zero()),
             wifi_module: wifi_impl,
             input_module: input_impl,
+            audio_module: audio_impl,
             kv_store,
+            user_agent,
+            system_events,
+            messages,
+            contacts,
+            call_log,
+            logged_calls: vec![],
+            connected_call: None,
+            ringtone: None,
             screen_needs_update: true,
         }
     }
+    /// The contact book, for whatever else wants to read or fill it.
+    pub fn contacts(&self) -> Arc<Mutex<ContactBook>> {
+        self.contacts.clone()
+    }
+
     pub fn bricc_loop<Display: OriginDimensions + DrawTarget<Color = BinaryColor>>(
         &mut self,
         display: &mut Display,
     ) {
-        loop {
-            generate_register();
-            match self.input_module.get_input() {
-                Some(user_input) => match self.root_pane.process_input::<Display>(user_input) {
-                    gui::traits::GuiAction::ScreenUpdated => {
-                        self.screen_needs_update = true;
-                    }
-                    gui::traits::GuiAction::InvalidInput => {
-                        todo!()
-                    }
-                    gui::traits::GuiAction::Nothing => {
-                        continue;
-                    }
-                    gui::traits::GuiAction::PopPane => {
-                        self.root_pane.pop_deepest();
-                        self.screen_needs_update = true;
-                    }
-                },
-                None => break,
+        while let Ok(event) = self.system_events.try_recv() {
+            self.handle_system_event(event);
+        }
+        while let Some(user_input) = self.input_module.get_input() {
+            match self.root_pane.process_input(user_input) {
+                gui::traits::GuiAction::ScreenUpdated => {
+                    self.screen_needs_update = true;
+                }
+                gui::traits::GuiAction::InvalidInput => {
+                    todo!()
+                }
+                gui::traits::GuiAction::Nothing => {
+                    continue;
+                }
+                // Panes were opened or closed on the way up.
+                _ => {
+                    self.screen_needs_update = true;
+                }
             }
         }
         match self.root_pane.tick() {
@@ -87,17 +162,187 @@ impl<
             gui::traits::GuiAction::InvalidInput => {
                 // TODO beep
             }
-            gui::traits::GuiAction::PopPane => {
-                self.root_pane.pop_deepest();
-                self.screen_needs_update = true;
-            }
             gui::traits::GuiAction::Nothing => {}
+            _ => self.screen_needs_update = true,
         }
+        self.update_ringtone();
+        self.route_call_audio();
+        self.save_messages();
+        self.save_contacts();
+        self.log_ended_calls();
+        self.save_call_log();
         if self.screen_needs_update {
             self.screen_needs_update = false;
-            display.clear(BinaryColor::Off);
-            self.root_pane.render(display);
+            if self.framebuffer.size() != display.size() {
+                self.framebuffer = Framebuffer::new(display.size());
+            }
+            let _ = self.framebuffer.clear(BinaryColor::Off);
+            self.root_pane.render(&mut self.framebuffer);
+            if self.framebuffer.draw_to(display).is_err() {
+                println!("Failed to draw to the display");
+            }
         }
         std::thread::sleep(Duration::from_millis(20));
     }
+
+    fn handle_system_event(&mut self, event: SystemEvent) {
+        match event {
+            SystemEvent::UserAgent(UserAgentEvent::IncomingCall(info)) => {
+                let handle = info.handle;
+                self.root_pane.show_incoming_call(info);
+                self.screen_needs_update = true;
+                self.start_ringtone(handle);
+            }
+            SystemEvent::UserAgent(UserAgentEvent::CallFailed(info)) => {
+                self.root_pane.show_failed_call(info.handle);
+                self.screen_needs_update = true;
+            }
+            SystemEvent::UserAgent(UserAgentEvent::Dtmf(handle, digit)) => {
+                if self.root_pane.show_dtmf(handle, digit) {
+                    self.screen_needs_update = true;
+                }
+            }
+            SystemEvent::UserAgent(UserAgentEvent::MessageReceived(text)) => {
+                if let Ok(mut messages) = self.messages.lock() {
+                    messages.add_incoming(&text.from, text.text);
+                }
+                self.screen_needs_update = true;
+            }
+            SystemEvent::UserAgent(UserAgentEvent::MessageSent(id, result)) => {
+                if let Ok(mut messages) = self.messages.lock() {
+                    messages.set_delivery(id, result);
+                }
+                self.screen_needs_update = true;
+            }
+        }
+    }
+
+    fn save_messages(&mut self) {
+        let mut messages = match self.messages.lock() {
+            Ok(messages) => messages,
+            Err(_) => return,
+        };
+        if messages.needs_saving() {
+            if let Err(err) = messages.save(&mut self.kv_store) {
+                println!("Failed to save messages: {}", err);
+            }
+        }
+    }
+
+    fn save_contacts(&mut self) {
+        let mut contacts = match self.contacts.lock() {
+            Ok(contacts) => contacts,
+            Err(_) => return,
+        };
+        if contacts.needs_saving() {
+            if let Err(err) = contacts.save(&mut self.kv_store) {
+                println!("Failed to save contacts: {}", err);
+            }
+        }
+    }
+
+    /// Puts calls in the log as they end.
+    fn log_ended_calls(&mut self) {
+        let calls = self.user_agent.get_interface().calls();
+        // Ended calls linger in the user agent for a bit; forget them once they're gone.
+        self.logged_calls
+            .retain(|handle| calls.iter().any(|call| call.handle == *handle));
+        for call in calls.iter().filter(|call| !call.is_active()) {
+            if self.logged_calls.contains(&call.handle) {
+                continue;
+            }
+            self.logged_calls.push(call.handle);
+            let contact_name = match self.contacts.lock() {
+                Ok(contacts) => contacts
+                    .find_by_number(&call.remote_user())
+                    .map(|contact| contact.name.clone()),
+                Err(_) => None,
+            };
+            if let Ok(mut call_log) = self.call_log.lock() {
+                call_log.record(CallLogEntry::from_call(call, contact_name));
+            }
+            self.screen_needs_update = true;
+        }
+    }
+
+    fn save_call_log(&mut self) {
+        let mut call_log = match self.call_log.lock() {
+            Ok(call_log) => call_log,
+            Err(_) => return,
+        };
+        if call_log.needs_saving() {
+            if let Err(err) = call_log.save(&mut self.kv_store) {
+                println!("Failed to save the call log: {}", err);
+            }
+        }
+    }
+
+    fn start_ringtone(&mut self, handle: CallHandle) {
+        let format = self
+            .audio_module
+            .negotiate_format(AudioFormat::mono(CALL_AUDIO_SAMPLE_RATE));
+        match self.audio_module.open_playback(format) {
+            Ok(playback) => self.ringtone = Some((handle, Ringtone::new(Box::new(playback)))),
+            Err(err) => println!("Can't open speaker to ring: {}", err),
+        }
+    }
+
+    /// Keeps ringing while the call is, and lets go of the speaker once it stops.
+    fn update_ringtone(&mut self) {
+        let handle = match &self.ringtone {
+            Some((handle, _)) => *handle,
+            None => return,
+        };
+        let still_ringing = match self.user_agent.get_interface().call(handle) {
+            Some(call) => call.state == CallState::Ringing,
+            None => false,
+        };
+        if !still_ringing {
+            self.ringtone = None;
+        } else if let Some((_, ringtone)) = &mut self.ringtone {
+            ringtone.pump();
+        }
+    }
+
+    /// Hands the mic and speaker to whichever call is connected and not on hold.
+    fn route_call_audio(&mut self) {
+        let mut interface = self.user_agent.get_interface();
+        let connected = interface
+            .calls()
+            .into_iter()
+            .find(|call| call.state == CallState::Connected && !call.on_hold)
+            .map(|call| call.handle);
+        if connected == self.connected_call {
+            return;
+        }
+        // The old call's streams went away with its media session, or when it went on hold.
+        self.connected_call = connected;
+        let handle = match connected {
+            Some(handle) => handle,
+            None => return,
+        };
+        let format = self
+            .audio_module
+            .negotiate_format(AudioFormat::mono(CALL_AUDIO_SAMPLE_RATE));
+        let capture = match self.audio_module.open_capture(format) {
+            Ok(capture) => capture,
+            Err(err) => {
+                println!("Can't open microphone: {}", err);
+                return;
+            }
+        };
+        let playback = match self.audio_module.open_playback(format) {
+            Ok(playback) => playback,
+            Err(err) => {
+                println!("Can't open speaker: {}", err);
+                return;
+            }
+        };
+        if interface
+            .attach_audio(handle, Box::new(capture), Box::new(playback))
+            .is_err()
+        {
+            println!("User agent is gone, call audio not attached");
+        }
+    }
 }
@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+
+use crate::contacts::numbers_match;
+use crate::prefs::kv_store::KvStore;
+use crate::voip::message::MessageId;
+
+// Which threads there are, most recent first. Each thread's messages live under a
+// key of their own, since the whole lot won't fit in one NVS entry.
+pub const MESSAGE_THREADS_PREFS_KEY: &str = "msg_threads";
+const MESSAGE_THREAD_PREFS_KEY_PREFIX: &str = "msg_";
+// Flash is small; the oldest go first.
+const MAX_THREADS: usize = 16;
+const MAX_MESSAGES_PER_THREAD: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MessageDirection {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DeliveryState {
+    Sending,
+    Sent,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextMessage {
+    pub direction: MessageDirection,
+    pub text: String,
+    pub delivery: DeliveryState,
+    // Only while it's in flight; a reboot loses track of it anyway.
+    #[serde(skip)]
+    id: Option<MessageId>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ThreadEntry {
+    peer: String,
+    slot: u32,
+    unread: u32,
+}
+
+/// Everything said with one peer, oldest first.
+pub struct Conversation {
+    /// Who it's with: a number, or whatever was in the user part of their URI.
+    pub peer: String,
+    pub messages: Vec<TextMessage>,
+    pub unread: u32,
+    slot: u32,
+}
+
+/// The text message threads, kept in the `KvStore` one per peer. Shared between the
+/// GUI and `Bricc` like the `ContactBook`; changes are written back by `save`.
+pub struct MessageStore {
+    conversations: Vec<Conversation>,
+    // Threads changed since the last save, by slot, and whether the list itself did.
+    dirty_slots: Vec<u32>,
+    threads_dirty: bool,
+}
+
+fn same_peer(a: &str, b: &str) -> bool {
+    a == b || numbers_match(a, b)
+}
+
+fn thread_key(slot: u32) -> String {
+    format!("{}{}", MESSAGE_THREAD_PREFS_KEY_PREFIX, slot)
+}
+
+impl MessageStore {
+    pub fn load<KvStoreImpl: KvStore>(kv_store: &mut KvStoreImpl) -> MessageStore {
+        let threads = match kv_store.get::<Vec<ThreadEntry>>(MESSAGE_THREADS_PREFS_KEY.into()) {
+            Ok(Some(threads)) => threads,
+            _ => vec![],
+        };
+        let mut conversations = vec![];
+        for thread in threads {
+            let mut messages = match kv_store.get::<Vec<TextMessage>>(thread_key(thread.slot)) {
+                Ok(Some(messages)) => messages,
+                _ => {
+                    println!("Lost the messages with {}", thread.peer);
+                    vec![]
+                }
+            };
+            for message in messages.iter_mut() {
+                if message.delivery == DeliveryState::Sending {
+                    message.delivery = DeliveryState::Failed("Interrupted".into());
+                }
+            }
+            conversations.push(Conversation {
+                peer: thread.peer,
+                messages,
+                unread: thread.unread,
+                slot: thread.slot,
+            });
+        }
+        MessageStore {
+            conversations,
+            dirty_slots: vec![],
+            threads_dirty: false,
+        }
+    }
+
+    /// Writes back whatever changed since last time.
+    pub fn save<KvStoreImpl: KvStore>(&mut self, kv_store: &mut KvStoreImpl) -> Result<(), String> {
+        let mut slots = std::mem::take(&mut self.dirty_slots);
+        slots.sort_unstable();
+        slots.dedup();
+        for slot in slots {
+            let messages = match self.conversations.iter().find(|c| c.slot == slot) {
+                Some(conversation) => conversation.messages.clone(),
+                // Dropped to make room; don't leave its messages lying around.
+                None => vec![],
+            };
+            kv_store.put(thread_key(slot), &messages)?;
+        }
+        if self.threads_dirty {
+            self.threads_dirty = false;
+            let threads: Vec<ThreadEntry> = self
+                .conversations
+                .iter()
+                .map(|conversation| ThreadEntry {
+                    peer: conversation.peer.clone(),
+                    slot: conversation.slot,
+                    unread: conversation.unread,
+                })
+                .collect();
+            kv_store.put(MESSAGE_THREADS_PREFS_KEY.into(), &threads)?;
+        }
+        Ok(())
+    }
+
+    pub fn needs_saving(&self) -> bool {
+        self.threads_dirty || !self.dirty_slots.is_empty()
+    }
+
+    /// Most recently active first.
+    pub fn conversations(&self) -> &[Conversation] {
+        &self.conversations
+    }
+
+    pub fn conversation(&self, peer: &str) -> Option<&Conversation> {
+        self.conversations
+            .iter()
+            .find(|conversation| same_peer(&conversation.peer, peer))
+    }
+
+    pub fn unread_count(&self) -> u32 {
+        self.conversations
+            .iter()
+            .map(|conversation| conversation.unread)
+            .sum()
+    }
+
+    pub fn add_incoming(&mut self, from: &str, text: String) {
+        let conversation = self.touch(from);
+        conversation.unread += 1;
+        Self::push(
+            conversation,
+            TextMessage {
+                direction: MessageDirection::Incoming,
+                text,
+                delivery: DeliveryState::Sent,
+                id: None,
+            },
+        );
+    }
+
+    /// Records a message handed to the user agent as `id`, until `set_delivery` says
+    /// how it went.
+    pub fn add_outgoing(&mut self, to: &str, text: String, id: MessageId) {
+        let conversation = self.touch(to);
+        Self::push(
+            conversation,
+            TextMessage {
+                direction: MessageDirection::Outgoing,
+                text,
+                delivery: DeliveryState::Sending,
+                id: Some(id),
+            },
+        );
+    }
+
+    pub fn set_delivery(&mut self, id: MessageId, result: Result<(), String>) {
+        for conversation in self.conversations.iter_mut() {
+            let message = conversation
+                .messages
+                .iter_mut()
+                .find(|message| message.id == Some(id));
+            if let Some(message) = message {
+                message.id = None;
+                message.delivery = match result {
+                    Ok(()) => DeliveryState::Sent,
+                    Err(reason) => DeliveryState::Failed(reason),
+                };
+                self.dirty_slots.push(conversation.slot);
+                return;
+            }
+        }
+    }
+
+    pub fn mark_read(&mut self, peer: &str) {
+        let conversation = self
+            .conversations
+            .iter_mut()
+            .find(|conversation| same_peer(&conversation.peer, peer));
+        if let Some(conversation) = conversation {
+            if conversation.unread != 0 {
+                conversation.unread = 0;
+                self.threads_dirty = true;
+            }
+        }
+    }
+
+    // Moves the thread with `peer` to the top, starting one if there isn't one yet.
+    fn touch(&mut self, peer: &str) -> &mut Conversation {
+        let conversation = match self
+            .conversations
+            .iter()
+            .position(|conversation| same_peer(&conversation.peer, peer))
+        {
+            Some(index) => self.conversations.remove(index),
+            None => {
+                if self.conversations.len() >= MAX_THREADS {
+                    if let Some(oldest) = self.conversations.pop() {
+                        self.dirty_slots.push(oldest.slot);
+                    }
+                }
+                // The slot of a thread dropped just now is free again.
+                let slot = (0..)
+                    .find(|slot| !self.conversations.iter().any(|c| c.slot == *slot))
+                    .unwrap_or(0);
+                Conversation {
+                    peer: peer.into(),
+                    messages: vec![],
+                    unread: 0,
+                    slot,
+                }
+            }
+        };
+        self.dirty_slots.push(conversation.slot);
+        self.threads_dirty = true;
+        self.conversations.insert(0, conversation);
+        &mut self.conversations[0]
+    }
+
+    fn push(conversation: &mut Conversation, message: TextMessage) {
+        conversation.messages.push(message);
+        if conversation.messages.len() > MAX_MESSAGES_PER_THREAD {
+            conversation.messages.remove(0);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::prefs::kv_store::KvStore;
+
+use super::negotiation::MediaConfig;
+
+pub const SIP_ACCOUNT_PREFS_KEY: &str = "voip_account";
+pub const DEFAULT_REGISTRATION_EXPIRES_SECS: u32 = 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SipTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+impl SipTransport {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            SipTransport::Udp | SipTransport::Tcp => 5060,
+            SipTransport::Tls => 5061,
+        }
+    }
+
+    pub fn to_rsip(&self) -> rsip::Transport {
+        match self {
+            SipTransport::Udp => rsip::Transport::Udp,
+            SipTransport::Tcp => rsip::Transport::Tcp,
+            SipTransport::Tls => rsip::Transport::Tls,
+        }
+    }
+
+    pub fn from_rsip(transport: &rsip::Transport) -> Option<SipTransport> {
+        match transport {
+            rsip::Transport::Udp => Some(SipTransport::Udp),
+            rsip::Transport::Tcp => Some(SipTransport::Tcp),
+            rsip::Transport::Tls => Some(SipTransport::Tls),
+            _ => None,
+        }
+    }
+
+    /// Stream transports do their own retransmission and need framing.
+    pub fn is_reliable(&self) -> bool {
+        *self != SipTransport::Udp
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            SipTransport::Tls => "sips",
+            _ => "sip",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SipAccount {
+    pub user: String,
+    pub domain: String,
+    // host[:port] of the registrar, if it isn't the account's domain.
+    pub registrar: Option<String>,
+    pub password: String,
+    pub transport: SipTransport,
+    pub display_name: Option<String>,
+    pub expires: u32,
+    #[serde(default)]
+    pub media: MediaConfig,
+    // host[:port] of a STUN server, for when we're behind NAT.
+    #[serde(default)]
+    pub stun_server: Option<String>,
+    // What to dial for voicemail, if the provider's message summary doesn't say.
+    #[serde(default)]
+    pub voicemail: Option<String>,
+}
+
+impl SipAccount {
+    pub fn load<KvStoreImpl: KvStore>(kv_store: &mut KvStoreImpl) -> Option<SipAccount> {
+        kv_store
+            .get::<SipAccount>(SIP_ACCOUNT_PREFS_KEY.into())
+            .unwrap_or_default()
+    }
+
+    pub fn save<KvStoreImpl: KvStore>(&self, kv_store: &mut KvStoreImpl) -> Result<(), String> {
+        kv_store.put(SIP_ACCOUNT_PREFS_KEY.into(), self)
+    }
+
+    /// The address of record, i.e. sip:user@domain.
+    pub fn aor(&self) -> Result<rsip::Uri, rsip::Error> {
+        rsip::Uri::try_from(format!(
+            "{}:{}@{}",
+            self.transport.scheme(),
+            self.user,
+            self.domain
+        ))
+    }
+
+    pub fn registrar_host(&self) -> String {
+        match &self.registrar {
+            Some(registrar) if !registrar.is_empty() => registrar.clone(),
+            _ => self.domain.clone(),
+        }
+    }
+
+    pub fn registrar_uri(&self) -> Result<rsip::Uri, rsip::Error> {
+        rsip::Uri::try_from(format!(
+            "{}:{}",
+            self.transport.scheme(),
+            self.registrar_host()
+        ))
+    }
+}
@@ -0,0 +1,1398 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use rsip::prelude::*;
+
+use super::account::SipAccount;
+use super::dialog::Dialog;
+use super::digest::{answer_challenge, find_challenge, DigestCredentials};
+use super::dtmf::{info_body, parse_info, DtmfDigit, DTMF_RELAY_CONTENT_TYPE};
+use super::negotiation::{MediaNegotiator, NegotiatedSession, RtpAddress};
+use super::sdp::MediaDirection;
+use super::sdp::{SessionDescription, SDP_CONTENT_TYPE};
+use super::sip::{
+    content_type_of, generate_cancel, generate_request, generate_response, local_contact_uri,
+    new_branch, new_call_id, new_tag, RequestParams,
+};
+use super::transaction::{branch_of, cseq_method_of, BackoffTimer, T1, T2};
+use super::transfer::{
+    is_refer_notify, parse_refer_to, parse_sipfrag, refer_to_header, referred_by_header, sipfrag,
+    Replaces, REFER_EVENT, SIPFRAG_CONTENT_TYPE,
+};
+use super::transport::TransportTarget;
+
+const MAX_REDIRECTS: u8 = 3;
+const MAX_AUTH_ATTEMPTS: u8 = 2;
+const ALLOWED_METHODS: &str = "INVITE, ACK, CANCEL, BYE, OPTIONS, INFO, REFER, NOTIFY";
+
+pub type CallHandle = u32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallDirection {
+    Outgoing,
+    Incoming,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallEndReason {
+    LocalHangup,
+    RemoteHangup,
+    /// The other side turned us down with this status code.
+    Rejected(u16),
+    Busy,
+    /// We turned down an incoming call.
+    Declined,
+    /// The caller gave up before we answered.
+    Missed,
+    Timeout,
+    /// Handed over to someone else, by a transfer either way or a Replaces.
+    Transferred,
+    Failed(String),
+}
+
+impl CallEndReason {
+    /// The status code that best says how a call went, for telling whoever referred
+    /// us to it.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            CallEndReason::Rejected(code) => *code,
+            CallEndReason::Busy => 486,
+            CallEndReason::Timeout => 408,
+            CallEndReason::LocalHangup | CallEndReason::Declined | CallEndReason::Missed => 487,
+            _ => 503,
+        }
+    }
+}
+
+impl fmt::Display for CallEndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallEndReason::LocalHangup => write!(f, "Call ended"),
+            CallEndReason::RemoteHangup => write!(f, "Call ended"),
+            CallEndReason::Rejected(code) => write!(f, "Rejected ({})", code),
+            CallEndReason::Busy => write!(f, "Busy"),
+            CallEndReason::Declined => write!(f, "Declined"),
+            CallEndReason::Missed => write!(f, "Missed call"),
+            CallEndReason::Timeout => write!(f, "No answer"),
+            CallEndReason::Transferred => write!(f, "Transferred"),
+            CallEndReason::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallState {
+    Dialing,
+    Ringing,
+    Connected,
+    Terminating,
+    Ended(CallEndReason),
+}
+
+impl fmt::Display for CallState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallState::Dialing => write!(f, "Calling"),
+            CallState::Ringing => write!(f, "Ringing"),
+            CallState::Connected => write!(f, "Connected"),
+            CallState::Terminating => write!(f, "Hanging up"),
+            CallState::Ended(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// How our REFER for a call is getting on. Once it works out the call ends as
+/// `Transferred`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferState {
+    /// Waiting for the far end to take the REFER.
+    Requested,
+    /// They took it and are calling the transfer target.
+    Trying,
+    /// Refused or didn't connect; the call is still ours.
+    Failed,
+}
+
+impl fmt::Display for TransferState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferState::Requested | TransferState::Trying => f.write_str("Transferring"),
+            TransferState::Failed => f.write_str("Transfer failed"),
+        }
+    }
+}
+
+/// What the GUI gets to see of a call.
+#[derive(Clone, Debug)]
+pub struct CallInfo {
+    pub handle: CallHandle,
+    pub direction: CallDirection,
+    pub remote_uri: String,
+    pub remote_display_name: Option<String>,
+    pub state: CallState,
+    /// We put it on hold.
+    pub on_hold: bool,
+    pub transfer: Option<TransferState>,
+    pub started_at: Instant,
+    pub connected_at: Option<Instant>,
+    pub ended_at: Option<Instant>,
+}
+
+impl CallInfo {
+    /// The user part of the remote URI, which is the number for most providers.
+    pub fn remote_user(&self) -> String {
+        let without_scheme = match self.remote_uri.find(':') {
+            Some(idx) => &self.remote_uri[idx + 1..],
+            None => &self.remote_uri,
+        };
+        match without_scheme.find('@') {
+            Some(idx) => without_scheme[..idx].into(),
+            None => without_scheme.into(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !matches!(self.state, CallState::Ended(_))
+    }
+}
+
+/// Things a call asks the user agent to do on its behalf.
+pub enum CallAction {
+    /// Send this request in a new client transaction.
+    StartTransaction(rsip::Request),
+    /// Answer through the server transaction with this branch.
+    Respond(String, rsip::Response),
+    /// Send this outside of any transaction (ACKs to 2xx, 2xx retransmissions).
+    Send(rsip::SipMessage),
+    /// The far end pressed a key, told us by INFO.
+    ReceivedDtmf(DtmfDigit),
+    /// The far end asked us to call here instead (RFC 3515), taking over the given
+    /// dialog there if any. Tell the call how that goes with `referral_progress`.
+    Refer(rsip::Uri, Option<Replaces>),
+}
+
+/// One call, on either side. Drives the INVITE/ACK/BYE/CANCEL exchange on top
+/// of the transaction layer and keeps the dialog.
+pub struct Call {
+    handle: CallHandle,
+    direction: CallDirection,
+    state: CallState,
+    account: SipAccount,
+    local_addr: SocketAddr,
+    // Where an incoming call's INVITE came from, which its responses go back to.
+    peer: Option<TransportTarget>,
+    remote_uri: rsip::Uri,
+    remote_display_name: Option<String>,
+    dialog: Option<Dialog>,
+    invite: rsip::Request,
+    invite_branch: String,
+    /// The dialog at the far end our INVITE takes over, when we were referred there.
+    replaces: Option<Replaces>,
+    got_provisional: bool,
+    cancel_pending: bool,
+    pending_end: Option<CallEndReason>,
+    auth_attempts: u8,
+    redirects: u8,
+    media: MediaNegotiator,
+    on_hold: bool,
+    /// Our re-INVITE is out and unanswered; only one may be at a time.
+    reinvite_pending: bool,
+    transfer: Option<TransferState>,
+    /// We took a REFER on this call and still owe the far end a final NOTIFY.
+    referred: bool,
+    /// Our answer to the offer in the INVITE, sent with the 200.
+    local_answer: Option<SessionDescription>,
+    /// We offered in the 200 because the INVITE had no SDP, so the ACK carries the answer.
+    answer_in_ack: bool,
+    unacked_ok: Option<rsip::Response>,
+    /// Our ACK to the latest 2xx to our INVITE, sent again if that 2xx is.
+    last_ack: Option<rsip::Request>,
+    ok_retransmit: Option<BackoffTimer>,
+    ok_timeout_at: Option<Instant>,
+    started_at: Instant,
+    connected_at: Option<Instant>,
+    ended_at: Option<Instant>,
+}
+
+impl Call {
+    pub fn outgoing(
+        handle: CallHandle,
+        account: SipAccount,
+        local_addr: SocketAddr,
+        rtp: RtpAddress,
+        target: rsip::Uri,
+        replaces: Option<Replaces>,
+        now: Instant,
+    ) -> Result<(Call, Vec<CallAction>), String> {
+        let call_id = new_call_id(&local_addr);
+        let from_tag = new_tag();
+        let mut media = MediaNegotiator::new(account.media.clone(), local_addr.ip(), rtp);
+        let offer = media.create_offer();
+        let invite = Self::build_invite(
+            &account,
+            local_addr,
+            &target,
+            &call_id,
+            &from_tag,
+            1,
+            &offer,
+            replaces.as_ref(),
+            None,
+        )?;
+        let invite_branch = branch_of(&invite.clone().into()).unwrap_or_default();
+        let call = Call {
+            handle,
+            direction: CallDirection::Outgoing,
+            state: CallState::Dialing,
+            account,
+            local_addr,
+            peer: None,
+            remote_uri: target,
+            remote_display_name: None,
+            dialog: None,
+            invite: invite.clone(),
+            invite_branch,
+            replaces,
+            got_provisional: false,
+            cancel_pending: false,
+            pending_end: None,
+            auth_attempts: 0,
+            redirects: 0,
+            media,
+            on_hold: false,
+            reinvite_pending: false,
+            transfer: None,
+            referred: false,
+            local_answer: None,
+            answer_in_ack: false,
+            unacked_ok: None,
+            last_ack: None,
+            ok_retransmit: None,
+            ok_timeout_at: None,
+            started_at: now,
+            connected_at: None,
+            ended_at: None,
+        };
+        Ok((call, vec![CallAction::StartTransaction(invite)]))
+    }
+
+    pub fn incoming(
+        handle: CallHandle,
+        account: SipAccount,
+        local_addr: SocketAddr,
+        rtp: RtpAddress,
+        invite: rsip::Request,
+        source: TransportTarget,
+        now: Instant,
+    ) -> Result<(Call, Vec<CallAction>), String> {
+        let invite_branch = branch_of(&invite.clone().into()).ok_or("INVITE without branch")?;
+        let local_tag = new_tag();
+        let dialog = Dialog::from_uas(&invite, &local_tag)?;
+        let contact = local_contact_uri(&account, &local_addr);
+        let mut media = MediaNegotiator::new(account.media.clone(), local_addr.ip(), rtp);
+
+        let trying = generate_response(&invite, 100, None, None, None, vec![])
+            .map_err(|err| err.to_string())?;
+        // Without an offer in the INVITE we make one in the 200 instead.
+        let (state, local_answer, status_code) = match sdp_of(&invite.body) {
+            Some(Ok(offer)) => match media.answer(&offer) {
+                Ok(answer) => (CallState::Ringing, Some(answer), 180),
+                Err(err) => (CallState::Ended(CallEndReason::Failed(err)), None, 488),
+            },
+            Some(Err(err)) => (CallState::Ended(CallEndReason::Failed(err)), None, 400),
+            None => (CallState::Ringing, None, 180),
+        };
+        let contact = if status_code == 180 {
+            Some(contact)
+        } else {
+            None
+        };
+        let ringing_or_rejected = generate_response(
+            &invite,
+            status_code,
+            Some(&local_tag),
+            contact,
+            None,
+            vec![],
+        )
+        .map_err(|err| err.to_string())?;
+        let ended_at = match state {
+            CallState::Ended(_) => Some(now),
+            _ => None,
+        };
+
+        let call = Call {
+            handle,
+            direction: CallDirection::Incoming,
+            state,
+            account,
+            local_addr,
+            peer: Some(source),
+            remote_uri: dialog.remote_uri.clone(),
+            remote_display_name: dialog.remote_display_name.clone(),
+            dialog: Some(dialog),
+            invite,
+            invite_branch: invite_branch.clone(),
+            replaces: None,
+            got_provisional: true,
+            cancel_pending: false,
+            pending_end: None,
+            auth_attempts: 0,
+            redirects: 0,
+            media,
+            on_hold: false,
+            reinvite_pending: false,
+            transfer: None,
+            referred: false,
+            local_answer,
+            answer_in_ack: false,
+            unacked_ok: None,
+            last_ack: None,
+            ok_retransmit: None,
+            ok_timeout_at: None,
+            started_at: now,
+            connected_at: None,
+            ended_at,
+        };
+        Ok((
+            call,
+            vec![
+                CallAction::Respond(invite_branch.clone(), trying),
+                CallAction::Respond(invite_branch, ringing_or_rejected),
+            ],
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build_invite(
+        account: &SipAccount,
+        local_addr: SocketAddr,
+        target: &rsip::Uri,
+        call_id: &str,
+        from_tag: &str,
+        cseq: u16,
+        offer: &SessionDescription,
+        replaces: Option<&Replaces>,
+        authorization: Option<rsip::Header>,
+    ) -> Result<rsip::Request, String> {
+        let aor = account.aor().map_err(|err| err.to_string())?;
+        let branch = new_branch();
+        let mut extra_headers = vec![rsip::headers::Allow::new(ALLOWED_METHODS).into()];
+        if let Some(replaces) = replaces {
+            extra_headers.push(replaces.header());
+        }
+        if let Some(authorization) = authorization {
+            extra_headers.push(authorization);
+        }
+        Ok(generate_request(RequestParams {
+            method: rsip::Method::Invite,
+            uri: target.clone(),
+            transport: account.transport.to_rsip(),
+            local_addr,
+            branch: &branch,
+            from: rsip::typed::From {
+                display_name: account.display_name.clone(),
+                uri: aor,
+                params: vec![rsip::Param::Tag(rsip::param::Tag::new(from_tag))],
+            },
+            to: rsip::typed::To {
+                display_name: None,
+                uri: target.clone(),
+                params: Default::default(),
+            },
+            call_id,
+            cseq,
+            contact: Some(local_contact_uri(account, &local_addr)),
+            extra_headers,
+            content_type: Some(SDP_CONTENT_TYPE),
+            body: offer.to_string().into_bytes(),
+        }))
+    }
+
+    /// Sends the INVITE again (after a challenge or a redirect) with the next CSeq.
+    fn resend_invite(
+        &mut self,
+        target: rsip::Uri,
+        authorization: Option<rsip::Header>,
+    ) -> Vec<CallAction> {
+        let (call_id, from_tag, cseq) = match (
+            self.invite.call_id_header(),
+            self.invite.from_header(),
+            self.invite.cseq_header().and_then(|cseq| cseq.typed()),
+        ) {
+            (Ok(call_id), Ok(from), Ok(cseq)) => (
+                call_id.value().to_string(),
+                super::sip::tag_of(from.value()).unwrap_or_default(),
+                cseq.seq,
+            ),
+            _ => return self.end(CallEndReason::Failed("Bad INVITE".into())),
+        };
+        let offer = self.media.create_offer();
+        match Self::build_invite(
+            &self.account,
+            self.local_addr,
+            &target,
+            &call_id,
+            &from_tag,
+            cseq + 1,
+            &offer,
+            self.replaces.as_ref(),
+            authorization,
+        ) {
+            Ok(invite) => {
+                self.invite_branch = branch_of(&invite.clone().into()).unwrap_or_default();
+                self.invite = invite.clone();
+                self.got_provisional = false;
+                self.dialog = None;
+                vec![CallAction::StartTransaction(invite)]
+            }
+            Err(err) => self.end(CallEndReason::Failed(err)),
+        }
+    }
+
+    pub fn handle(&self) -> CallHandle {
+        self.handle
+    }
+
+    pub fn direction(&self) -> CallDirection {
+        self.direction
+    }
+
+    pub fn state(&self) -> &CallState {
+        &self.state
+    }
+
+    pub fn peer(&self) -> Option<TransportTarget> {
+        self.peer
+    }
+
+    pub fn invite_branch(&self) -> &str {
+        &self.invite_branch
+    }
+
+    pub fn is_ended(&self) -> bool {
+        matches!(self.state, CallState::Ended(_))
+    }
+
+    pub fn ended_at(&self) -> Option<Instant> {
+        self.ended_at
+    }
+
+    pub fn remote_uri(&self) -> &rsip::Uri {
+        &self.remote_uri
+    }
+
+    /// This call's dialog the way the far end sees it, so someone else can be sent
+    /// there to take it over.
+    pub fn as_replaces(&self) -> Option<Replaces> {
+        self.dialog.as_ref().map(|dialog| Replaces {
+            call_id: dialog.call_id.clone(),
+            to_tag: dialog.remote_tag.clone(),
+            from_tag: dialog.local_tag.clone(),
+        })
+    }
+
+    /// Whether an incoming INVITE's Replaces names this call.
+    pub fn is_replaced_by(&self, replaces: &Replaces) -> bool {
+        match &self.dialog {
+            Some(dialog) => {
+                dialog.call_id == replaces.call_id
+                    && dialog.local_tag == replaces.to_tag
+                    && dialog.remote_tag == replaces.from_tag
+            }
+            None => false,
+        }
+    }
+
+    pub fn matches_dialog(&self, request: &rsip::Request) -> bool {
+        match &self.dialog {
+            Some(dialog) => dialog.matches_request(request),
+            None => false,
+        }
+    }
+
+    /// What the media layer should be doing, once offer/answer has completed.
+    pub fn media_session(&self) -> Option<&NegotiatedSession> {
+        self.media.session()
+    }
+
+    pub fn info(&self) -> CallInfo {
+        CallInfo {
+            handle: self.handle,
+            direction: self.direction,
+            remote_uri: self.remote_uri.to_string(),
+            remote_display_name: self.remote_display_name.clone(),
+            state: self.state.clone(),
+            on_hold: self.on_hold,
+            transfer: self.transfer.clone(),
+            started_at: self.started_at,
+            connected_at: self.connected_at,
+            ended_at: self.ended_at,
+        }
+    }
+
+    fn end(&mut self, reason: CallEndReason) -> Vec<CallAction> {
+        if !self.is_ended() {
+            println!("Call {} ended: {}", self.handle, reason);
+            self.state = CallState::Ended(reason);
+            self.ended_at = Some(Instant::now());
+            self.ok_retransmit = None;
+            self.ok_timeout_at = None;
+        }
+        vec![]
+    }
+
+    fn contact(&self) -> rsip::Uri {
+        local_contact_uri(&self.account, &self.local_addr)
+    }
+
+    fn send_bye(&mut self, reason: CallEndReason) -> Vec<CallAction> {
+        let transport = self.account.transport.to_rsip();
+        let contact = self.contact();
+        let local_addr = self.local_addr;
+        match &mut self.dialog {
+            Some(dialog) => {
+                let bye = dialog.create_request(
+                    rsip::Method::Bye,
+                    transport,
+                    local_addr,
+                    contact,
+                    vec![],
+                    None,
+                    vec![],
+                );
+                self.state = CallState::Terminating;
+                self.pending_end = Some(reason);
+                self.ok_retransmit = None;
+                self.ok_timeout_at = None;
+                vec![CallAction::StartTransaction(bye)]
+            }
+            None => self.end(reason),
+        }
+    }
+
+    /// Hang up whatever stage the call is in.
+    pub fn hangup(&mut self) -> Vec<CallAction> {
+        match (&self.state, self.direction) {
+            (CallState::Dialing, CallDirection::Outgoing)
+            | (CallState::Ringing, CallDirection::Outgoing) => {
+                self.state = CallState::Terminating;
+                self.pending_end = Some(CallEndReason::LocalHangup);
+                // A CANCEL may only go out once the far end has said something.
+                if self.got_provisional {
+                    match generate_cancel(&self.invite) {
+                        Ok(cancel) => vec![CallAction::StartTransaction(cancel)],
+                        Err(err) => self.end(CallEndReason::Failed(err.to_string())),
+                    }
+                } else {
+                    self.cancel_pending = true;
+                    vec![]
+                }
+            }
+            (CallState::Ringing, CallDirection::Incoming) => self.reject(603),
+            (CallState::Connected, _) => self.send_bye(CallEndReason::LocalHangup),
+            _ => vec![],
+        }
+    }
+
+    pub fn answer(&mut self, now: Instant) -> Vec<CallAction> {
+        if self.direction != CallDirection::Incoming || self.state != CallState::Ringing {
+            return vec![];
+        }
+        let local_tag = match &self.dialog {
+            Some(dialog) => dialog.local_tag.clone(),
+            None => return vec![],
+        };
+        let sdp = match self.local_answer.take() {
+            Some(answer) => answer,
+            None => {
+                self.answer_in_ack = true;
+                self.media.create_offer()
+            }
+        };
+        match generate_response(
+            &self.invite,
+            200,
+            Some(&local_tag),
+            Some(self.contact()),
+            Some(SDP_CONTENT_TYPE),
+            sdp.to_string().into_bytes(),
+        ) {
+            Ok(ok) => {
+                self.state = CallState::Connected;
+                self.connected_at = Some(now);
+                self.await_ack(ok.clone(), now);
+                vec![CallAction::Respond(self.invite_branch.clone(), ok)]
+            }
+            Err(err) => self.end(CallEndReason::Failed(err.to_string())),
+        }
+    }
+
+    /// Puts the call on hold or takes it off, by re-INVITE with a sendonly or sendrecv offer.
+    pub fn set_hold(&mut self, hold: bool) -> Vec<CallAction> {
+        if self.state != CallState::Connected || self.on_hold == hold || self.reinvite_pending {
+            return vec![];
+        }
+        let transport = self.account.transport.to_rsip();
+        let contact = self.contact();
+        let local_addr = self.local_addr;
+        self.on_hold = hold;
+        self.media.set_direction(self.wanted_direction());
+        let offer = self.media.create_offer();
+        match &mut self.dialog {
+            Some(dialog) => {
+                let reinvite = dialog.create_request(
+                    rsip::Method::Invite,
+                    transport,
+                    local_addr,
+                    contact,
+                    vec![rsip::headers::Allow::new(ALLOWED_METHODS).into()],
+                    Some(SDP_CONTENT_TYPE),
+                    offer.to_string().into_bytes(),
+                );
+                self.reinvite_pending = true;
+                vec![CallAction::StartTransaction(reinvite)]
+            }
+            None => {
+                self.on_hold = !hold;
+                self.media.set_direction(self.wanted_direction());
+                vec![]
+            }
+        }
+    }
+
+    // Holding is sendonly, or inactive if they already have us on hold too.
+    fn wanted_direction(&self) -> MediaDirection {
+        if !self.on_hold {
+            return MediaDirection::SendRecv;
+        }
+        match self.media.session().map(|session| session.direction) {
+            Some(MediaDirection::RecvOnly) | Some(MediaDirection::Inactive) => {
+                MediaDirection::Inactive
+            }
+            _ => MediaDirection::SendOnly,
+        }
+    }
+
+    /// Asks the far end to call `target` instead (RFC 3515), and to take over the
+    /// `replaces` dialog there for an attended transfer. We hang up once they're through.
+    pub fn transfer(&mut self, target: rsip::Uri, replaces: Option<Replaces>) -> Vec<CallAction> {
+        let transferring = matches!(
+            self.transfer,
+            Some(TransferState::Requested) | Some(TransferState::Trying)
+        );
+        if self.state != CallState::Connected || transferring {
+            return vec![];
+        }
+        let aor = match self.account.aor() {
+            Ok(aor) => aor,
+            Err(_) => return vec![],
+        };
+        let transport = self.account.transport.to_rsip();
+        let contact = self.contact();
+        let local_addr = self.local_addr;
+        match &mut self.dialog {
+            Some(dialog) => {
+                let refer = dialog.create_request(
+                    rsip::Method::Refer,
+                    transport,
+                    local_addr,
+                    contact,
+                    vec![
+                        refer_to_header(&target, replaces.as_ref()),
+                        referred_by_header(&aor),
+                    ],
+                    None,
+                    vec![],
+                );
+                self.transfer = Some(TransferState::Requested);
+                vec![CallAction::StartTransaction(refer)]
+            }
+            None => vec![],
+        }
+    }
+
+    /// How the call we were referred to is going, reported back to whoever referred us
+    /// in a NOTIFY. The first final status ends the subscription.
+    pub fn referral_progress(&mut self, status_code: u16) -> Vec<CallAction> {
+        if !self.referred || self.is_ended() {
+            return vec![];
+        }
+        let subscription_state = if status_code >= 200 {
+            self.referred = false;
+            "terminated;reason=noresource"
+        } else {
+            "active"
+        };
+        let transport = self.account.transport.to_rsip();
+        let contact = self.contact();
+        let local_addr = self.local_addr;
+        match &mut self.dialog {
+            Some(dialog) => vec![CallAction::StartTransaction(dialog.create_request(
+                rsip::Method::Notify,
+                transport,
+                local_addr,
+                contact,
+                vec![
+                    rsip::headers::Event::new(REFER_EVENT).into(),
+                    rsip::headers::SubscriptionState::new(subscription_state).into(),
+                ],
+                Some(SIPFRAG_CONTENT_TYPE),
+                sipfrag(status_code).into_bytes(),
+            ))],
+            None => vec![],
+        }
+    }
+
+    /// Someone else took this call over with an INVITE carrying Replaces.
+    pub fn replaced(&mut self) -> Vec<CallAction> {
+        if self.state != CallState::Connected {
+            return vec![];
+        }
+        self.send_bye(CallEndReason::Transferred)
+    }
+
+    /// Sends a digit in a SIP INFO, for peers that didn't negotiate telephone-event.
+    pub fn send_dtmf_info(&mut self, digit: DtmfDigit) -> Vec<CallAction> {
+        if self.state != CallState::Connected {
+            return vec![];
+        }
+        let transport = self.account.transport.to_rsip();
+        let contact = self.contact();
+        let local_addr = self.local_addr;
+        match &mut self.dialog {
+            Some(dialog) => vec![CallAction::StartTransaction(dialog.create_request(
+                rsip::Method::Info,
+                transport,
+                local_addr,
+                contact,
+                vec![],
+                Some(DTMF_RELAY_CONTENT_TYPE),
+                info_body(digit).into_bytes(),
+            ))],
+            None => vec![],
+        }
+    }
+
+    pub fn reject(&mut self, status_code: u16) -> Vec<CallAction> {
+        if self.direction != CallDirection::Incoming || self.state != CallState::Ringing {
+            return vec![];
+        }
+        let local_tag = self.dialog.as_ref().map(|dialog| dialog.local_tag.clone());
+        let actions = match generate_response(
+            &self.invite,
+            status_code,
+            local_tag.as_deref(),
+            None,
+            None,
+            vec![],
+        ) {
+            Ok(response) => vec![CallAction::Respond(self.invite_branch.clone(), response)],
+            Err(_) => vec![],
+        };
+        self.end(CallEndReason::Declined);
+        actions
+    }
+
+    // The UAS core keeps retransmitting a 2xx until the ACK shows up, section 13.3.1.4.
+    fn await_ack(&mut self, ok: rsip::Response, now: Instant) {
+        self.unacked_ok = Some(ok);
+        self.ok_retransmit = Some(BackoffTimer::new(now, T1, Some(T2)));
+        self.ok_timeout_at = Some(now + 64 * T1);
+    }
+
+    /// The caller sent CANCEL for the INVITE that set up this call.
+    pub fn on_cancel(&mut self) -> Vec<CallAction> {
+        if self.direction != CallDirection::Incoming || self.state != CallState::Ringing {
+            return vec![];
+        }
+        let local_tag = self.dialog.as_ref().map(|dialog| dialog.local_tag.clone());
+        let actions =
+            match generate_response(&self.invite, 487, local_tag.as_deref(), None, None, vec![]) {
+                Ok(response) => vec![CallAction::Respond(self.invite_branch.clone(), response)],
+                Err(_) => vec![],
+            };
+        self.end(CallEndReason::Missed);
+        actions
+    }
+
+    /// A request inside our dialog. Its server transaction already exists under `branch`.
+    pub fn on_request(&mut self, request: rsip::Request, now: Instant) -> Vec<CallAction> {
+        let branch = branch_of(&request.clone().into()).unwrap_or_default();
+        if request.method == rsip::Method::Ack {
+            self.unacked_ok = None;
+            self.ok_retransmit = None;
+            self.ok_timeout_at = None;
+            if self.answer_in_ack {
+                self.answer_in_ack = false;
+                let accepted = match sdp_of(&request.body) {
+                    Some(Ok(answer)) => self.media.accept_answer(&answer),
+                    Some(Err(err)) => Err(err),
+                    None => Err("ACK without answer".into()),
+                };
+                if let Err(err) = accepted {
+                    return self.send_bye(CallEndReason::Failed(err));
+                }
+            }
+            return vec![];
+        }
+        let in_order = match &mut self.dialog {
+            Some(dialog) => dialog.accept_remote_seq(&request),
+            None => false,
+        };
+        if !in_order {
+            return Self::respond(&request, &branch, 500, None);
+        }
+        match request.method {
+            rsip::Method::Bye => {
+                let actions = Self::respond(&request, &branch, 200, None);
+                self.end(CallEndReason::RemoteHangup);
+                actions
+            }
+            // RFC 3261 section 14.2: ours crossed theirs, so both back off and retry.
+            rsip::Method::Invite if self.reinvite_pending => {
+                Self::respond(&request, &branch, 491, None)
+            }
+            rsip::Method::Invite => {
+                if let Some(dialog) = &mut self.dialog {
+                    dialog.remote_target_updated(&request.headers);
+                }
+                let sdp = match sdp_of(&request.body) {
+                    Some(Ok(offer)) => match self.media.answer(&offer) {
+                        Ok(answer) => answer,
+                        Err(_) => return Self::respond(&request, &branch, 488, None),
+                    },
+                    Some(Err(_)) => return Self::respond(&request, &branch, 400, None),
+                    None => {
+                        self.answer_in_ack = true;
+                        self.media.create_offer()
+                    }
+                };
+                match generate_response(
+                    &request,
+                    200,
+                    None,
+                    Some(self.contact()),
+                    Some(SDP_CONTENT_TYPE),
+                    sdp.to_string().into_bytes(),
+                ) {
+                    Ok(ok) => {
+                        self.await_ack(ok.clone(), now);
+                        vec![CallAction::Respond(branch, ok)]
+                    }
+                    Err(_) => vec![],
+                }
+            }
+            rsip::Method::Options => Self::respond(&request, &branch, 200, None),
+            rsip::Method::Info => {
+                let digit = content_type_of(&request.headers)
+                    .and_then(|content_type| parse_info(&content_type, &request.body));
+                match digit {
+                    Some(digit) => {
+                        let mut actions = Self::respond(&request, &branch, 200, None);
+                        actions.push(CallAction::ReceivedDtmf(digit));
+                        actions
+                    }
+                    // INFO for some other package we don't know.
+                    None => Self::respond(&request, &branch, 415, None),
+                }
+            }
+            rsip::Method::Refer => self.on_refer(&request, &branch),
+            rsip::Method::Notify if is_refer_notify(&request) && self.transfer.is_some() => {
+                let mut actions = Self::respond(&request, &branch, 200, None);
+                match parse_sipfrag(&request.body) {
+                    Some(200..=299) => {
+                        println!("Call {} transferred", self.handle);
+                        self.transfer = None;
+                        actions.extend(self.send_bye(CallEndReason::Transferred));
+                    }
+                    Some(code) if code >= 300 => {
+                        println!("Call {} transfer failed with {}", self.handle, code);
+                        self.transfer = Some(TransferState::Failed);
+                    }
+                    _ => self.transfer = Some(TransferState::Trying),
+                }
+                actions
+            }
+            // RFC 3265 section 3.2.4: not an event package we have a subscription in.
+            rsip::Method::Notify => Self::respond(&request, &branch, 481, None),
+            _ => Self::respond(&request, &branch, 501, None),
+        }
+    }
+
+    fn on_refer(&mut self, request: &rsip::Request, branch: &str) -> Vec<CallAction> {
+        if self.state != CallState::Connected || self.referred {
+            return Self::respond(request, branch, 603, None);
+        }
+        let (target, replaces) = match parse_refer_to(&request.headers) {
+            Ok(refer_to) => refer_to,
+            Err(err) => {
+                println!("Call {} got a bad REFER: {}", self.handle, err);
+                return Self::respond(request, branch, 400, None);
+            }
+        };
+        println!("Call {} referred to {}", self.handle, target);
+        self.referred = true;
+        let mut actions = Self::respond(request, branch, 202, None);
+        // RFC 3515 section 2.4.5: the first NOTIFY goes out right away.
+        actions.extend(self.referral_progress(100));
+        actions.push(CallAction::Refer(target, replaces));
+        actions
+    }
+
+    fn respond(
+        request: &rsip::Request,
+        branch: &str,
+        status_code: u16,
+        contact: Option<rsip::Uri>,
+    ) -> Vec<CallAction> {
+        match generate_response(request, status_code, None, contact, None, vec![]) {
+            Ok(response) => vec![CallAction::Respond(branch.into(), response)],
+            Err(_) => vec![],
+        }
+    }
+
+    /// A response from one of the client transactions this call started.
+    pub fn on_response(&mut self, response: rsip::Response, now: Instant) -> Vec<CallAction> {
+        match cseq_method_of(&response.clone().into()) {
+            Some(rsip::Method::Invite) => self.on_invite_response(response, now),
+            Some(rsip::Method::Bye) => {
+                if response.status_code.code() >= 200 {
+                    let reason = self
+                        .pending_end
+                        .take()
+                        .unwrap_or(CallEndReason::LocalHangup);
+                    self.end(reason);
+                }
+                vec![]
+            }
+            Some(rsip::Method::Refer) => {
+                let code = response.status_code.code();
+                if code >= 300 {
+                    println!("Call {} transfer refused with {}", self.handle, code);
+                    self.transfer = Some(TransferState::Failed);
+                } else if code >= 200 && self.transfer == Some(TransferState::Requested) {
+                    self.transfer = Some(TransferState::Trying);
+                }
+                vec![]
+            }
+            // The 487 to the INVITE ends things after a CANCEL, not the 200 to the CANCEL.
+            _ => vec![],
+        }
+    }
+
+    fn on_invite_response(&mut self, response: rsip::Response, now: Instant) -> Vec<CallAction> {
+        let code = response.status_code.code();
+        let is_current =
+            branch_of(&response.clone().into()).as_deref() == Some(self.invite_branch.as_str());
+        if !is_current {
+            return self.on_reinvite_response(response);
+        }
+        match code {
+            100 => vec![],
+            101..=199 => {
+                self.got_provisional = true;
+                if self.dialog.is_none() {
+                    self.dialog = Dialog::from_uac(&self.invite, &response).ok();
+                }
+                // Early media; the 200 will repeat it if this fails.
+                let _ = self.accept_answer(&response);
+                if self.state == CallState::Dialing {
+                    self.state = CallState::Ringing;
+                }
+                if self.cancel_pending {
+                    self.cancel_pending = false;
+                    if let Ok(cancel) = generate_cancel(&self.invite) {
+                        return vec![CallAction::StartTransaction(cancel)];
+                    }
+                }
+                vec![]
+            }
+            200..=299 => {
+                self.dialog = match Dialog::from_uac(&self.invite, &response) {
+                    Ok(dialog) => Some(dialog),
+                    Err(err) => return self.end(CallEndReason::Failed(err)),
+                };
+                let mut actions = self.ack_ok(&response);
+                if let Err(err) = self.accept_answer(&response) {
+                    actions.extend(self.send_bye(CallEndReason::Failed(err)));
+                    return actions;
+                }
+                if self.state == CallState::Terminating {
+                    // We hung up but the answer crossed our CANCEL.
+                    let reason = self
+                        .pending_end
+                        .take()
+                        .unwrap_or(CallEndReason::LocalHangup);
+                    actions.extend(self.send_bye(reason));
+                } else {
+                    self.state = CallState::Connected;
+                    self.connected_at = Some(now);
+                }
+                actions
+            }
+            300..=399 if self.state != CallState::Terminating => {
+                let contact = response.contact_header().and_then(|c| c.typed());
+                match contact {
+                    Ok(contact) if self.redirects < MAX_REDIRECTS => {
+                        self.redirects += 1;
+                        self.remote_uri = contact.uri.clone();
+                        self.resend_invite(contact.uri, None)
+                    }
+                    _ => self.end(CallEndReason::Rejected(code)),
+                }
+            }
+            401 | 407 if self.state != CallState::Terminating => {
+                if self.auth_attempts >= MAX_AUTH_ATTEMPTS {
+                    return self.end(CallEndReason::Failed("Authentication failed".into()));
+                }
+                self.auth_attempts += 1;
+                let authorization = match find_challenge(&response) {
+                    Some((kind, challenge)) => answer_challenge(
+                        kind,
+                        &challenge,
+                        &DigestCredentials {
+                            username: &self.account.user,
+                            password: &self.account.password,
+                        },
+                        &rsip::Method::Invite,
+                        &self.invite.uri,
+                    ),
+                    None => Err("Challenge without credentials".into()),
+                };
+                match authorization {
+                    Ok(authorization) => {
+                        let target = self.invite.uri.clone();
+                        self.resend_invite(target, Some(authorization))
+                    }
+                    Err(err) => self.end(CallEndReason::Failed(err)),
+                }
+            }
+            _ => {
+                let reason = if self.state == CallState::Terminating {
+                    self.pending_end
+                        .take()
+                        .unwrap_or(CallEndReason::LocalHangup)
+                } else {
+                    match code {
+                        486 | 600 => CallEndReason::Busy,
+                        408 | 480 => CallEndReason::Timeout,
+                        _ => CallEndReason::Rejected(code),
+                    }
+                };
+                self.end(reason)
+            }
+        }
+    }
+
+    fn on_reinvite_response(&mut self, response: rsip::Response) -> Vec<CallAction> {
+        let code = response.status_code.code();
+        if code < 200 {
+            return vec![];
+        }
+        self.reinvite_pending = false;
+        match code {
+            // A 2xx only needs acknowledging.
+            200..=299 => {
+                let mut actions = self.ack_ok(&response);
+                if let Err(err) = self.accept_answer(&response) {
+                    actions.extend(self.send_bye(CallEndReason::Failed(err)));
+                }
+                actions
+            }
+            // RFC 3261 section 14.1: the dialog is gone.
+            408 | 481 => self.send_bye(CallEndReason::Failed(format!(
+                "Re-INVITE failed ({})",
+                code
+            ))),
+            _ => {
+                // The old session stands, so undo what we asked for.
+                println!("Call {} re-INVITE refused with {}", self.handle, code);
+                self.on_hold = !self.on_hold;
+                self.media.set_direction(self.wanted_direction());
+                vec![]
+            }
+        }
+    }
+
+    /// Takes the answer to our offer out of a response. A response without SDP is fine
+    /// as long as an earlier one already had it.
+    fn accept_answer(&mut self, response: &rsip::Response) -> Result<(), String> {
+        match sdp_of(&response.body) {
+            Some(Ok(answer)) => self.media.accept_answer(&answer),
+            Some(Err(err)) => Err(err),
+            None if self.media.session().is_some() => Ok(()),
+            None => Err("Answer without SDP".into()),
+        }
+    }
+
+    fn ack_ok(&mut self, response: &rsip::Response) -> Vec<CallAction> {
+        let seq = match response.cseq_header().and_then(|cseq| cseq.typed()) {
+            Ok(cseq) => cseq.seq,
+            Err(_) => return vec![],
+        };
+        match &self.dialog {
+            Some(dialog) => {
+                let ack = dialog.create_ack(
+                    seq,
+                    self.account.transport.to_rsip(),
+                    self.local_addr,
+                    None,
+                    vec![],
+                );
+                self.last_ack = Some(ack.clone());
+                vec![CallAction::Send(ack.into())]
+            }
+            None => vec![],
+        }
+    }
+
+    /// A 2xx to our INVITE after its transaction ended. The far end keeps sending it
+    /// until it gets an ACK, so ours went missing.
+    pub fn on_retransmitted_ok(&self, response: &rsip::Response) -> Vec<CallAction> {
+        let ack = match (&self.dialog, &self.last_ack) {
+            (Some(dialog), Some(ack)) if dialog.matches_response(response) => ack,
+            _ => return vec![],
+        };
+        let seq_of = |headers: Result<&rsip::headers::CSeq, rsip::Error>| {
+            headers
+                .and_then(|cseq| cseq.typed())
+                .map(|cseq| cseq.seq)
+                .ok()
+        };
+        match seq_of(response.cseq_header()) {
+            Some(seq) if Some(seq) == seq_of(ack.cseq_header()) => {
+                vec![CallAction::Send(ack.clone().into())]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Timer B/F fired on one of our transactions.
+    pub fn on_timeout(&mut self, method: &rsip::Method) -> Vec<CallAction> {
+        match method {
+            rsip::Method::Invite => match self.state {
+                CallState::Dialing | CallState::Ringing => self.end(CallEndReason::Timeout),
+                CallState::Terminating => {
+                    let reason = self
+                        .pending_end
+                        .take()
+                        .unwrap_or(CallEndReason::LocalHangup);
+                    self.end(reason)
+                }
+                CallState::Connected if self.reinvite_pending => {
+                    self.reinvite_pending = false;
+                    self.send_bye(CallEndReason::Failed("Re-INVITE timed out".into()))
+                }
+                _ => vec![],
+            },
+            rsip::Method::Bye => {
+                let reason = self
+                    .pending_end
+                    .take()
+                    .unwrap_or(CallEndReason::LocalHangup);
+                self.end(reason)
+            }
+            rsip::Method::Refer => {
+                self.transfer = Some(TransferState::Failed);
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn on_tick(&mut self, now: Instant) -> Vec<CallAction> {
+        if let Some(timeout_at) = self.ok_timeout_at {
+            if now >= timeout_at {
+                self.ok_timeout_at = None;
+                self.unacked_ok = None;
+                self.ok_retransmit = None;
+                return self.send_bye(CallEndReason::Failed("No ACK".into()));
+            }
+        }
+        if let (Some(timer), Some(ok)) = (&mut self.ok_retransmit, &self.unacked_ok) {
+            if timer.fire_if_due(now) {
+                return vec![CallAction::Send(ok.clone().into())];
+            }
+        }
+        vec![]
+    }
+}
+
+fn sdp_of(body: &[u8]) -> Option<Result<SessionDescription, String>> {
+    if body.is_empty() {
+        None
+    } else {
+        Some(SessionDescription::parse_bytes(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::voip::account::SipTransport;
+    use crate::voip::negotiation::MediaConfig;
+
+    fn account() -> SipAccount {
+        SipAccount {
+            user: "alice".into(),
+            domain: "atlanta.example.com".into(),
+            registrar: None,
+            password: "wonderland".into(),
+            transport: SipTransport::Udp,
+            display_name: None,
+            expires: 3600,
+            media: MediaConfig::default(),
+            stun_server: None,
+            voicemail: None,
+        }
+    }
+
+    fn rtp(local_port: u16) -> RtpAddress {
+        RtpAddress {
+            local_port,
+            public: None,
+        }
+    }
+
+    fn placed_call(now: Instant) -> (Call, rsip::Request) {
+        let (call, actions) = Call::outgoing(
+            1,
+            account(),
+            "192.0.2.10:5060".parse().unwrap(),
+            rtp(16000),
+            rsip::Uri::try_from("sip:bob@biloxi.example.com").unwrap(),
+            None,
+            now,
+        )
+        .unwrap();
+        match actions.as_slice() {
+            [CallAction::StartTransaction(invite)] => {
+                let invite = invite.clone();
+                (call, invite)
+            }
+            _ => panic!("expected the INVITE"),
+        }
+    }
+
+    fn ok_for(invite: &rsip::Request, to_tag: &str) -> rsip::Response {
+        let offer = SessionDescription::parse_bytes(&invite.body).unwrap();
+        let mut bob = MediaNegotiator::new(
+            MediaConfig::default(),
+            "192.0.2.20".parse().unwrap(),
+            rtp(17000),
+        );
+        let answer = bob.answer(&offer).unwrap();
+        generate_response(
+            invite,
+            200,
+            Some(to_tag),
+            Some(rsip::Uri::try_from("sip:bob@192.0.2.20:5060").unwrap()),
+            Some(SDP_CONTENT_TYPE),
+            answer.to_string().into_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn sent_acks(actions: &[CallAction]) -> Vec<rsip::Request> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                CallAction::Send(rsip::SipMessage::Request(ack))
+                    if ack.method == rsip::Method::Ack =>
+                {
+                    Some(ack.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn acks_the_answer_and_its_retransmissions() {
+        let now = Instant::now();
+        let (mut call, invite) = placed_call(now);
+        let ok = ok_for(&invite, "314159");
+
+        let acks = sent_acks(&call.on_response(ok.clone(), now));
+        assert_eq!(acks.len(), 1);
+        assert_eq!(call.state(), &CallState::Connected);
+
+        // The INVITE transaction is gone by now, so this comes straight to the call.
+        let again = sent_acks(&call.on_retransmitted_ok(&ok));
+        assert_eq!(again, acks);
+    }
+
+    /// A re-INVITE from Bob, in the dialog `invite` set up.
+    fn reinvite_from_bob(invite: &rsip::Request, to_tag: &str) -> rsip::Request {
+        let from = invite.from_header().unwrap().typed().unwrap();
+        let to = invite.to_header().unwrap().typed().unwrap();
+        generate_request(RequestParams {
+            method: rsip::Method::Invite,
+            uri: from.uri.clone(),
+            transport: rsip::Transport::Udp,
+            local_addr: "192.0.2.20:5060".parse().unwrap(),
+            branch: &new_branch(),
+            from: rsip::typed::From {
+                display_name: None,
+                uri: to.uri,
+                params: vec![rsip::Param::Tag(rsip::param::Tag::new(to_tag))],
+            },
+            to: rsip::typed::To {
+                display_name: None,
+                uri: from.uri,
+                params: from.params,
+            },
+            call_id: invite.call_id_header().unwrap().value(),
+            cseq: 1,
+            contact: Some(rsip::Uri::try_from("sip:bob@192.0.2.20:5060").unwrap()),
+            extra_headers: vec![],
+            content_type: None,
+            body: vec![],
+        })
+    }
+
+    fn responses(actions: &[CallAction]) -> Vec<u16> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                CallAction::Respond(_, response) => Some(response.status_code.code()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn turns_away_a_reinvite_that_crosses_ours() {
+        let now = Instant::now();
+        let (mut call, invite) = placed_call(now);
+        call.on_response(ok_for(&invite, "314159"), now);
+        assert!(!call.set_hold(true).is_empty());
+
+        let reinvite = reinvite_from_bob(&invite, "314159");
+        assert_eq!(responses(&call.on_request(reinvite, now)), vec![491]);
+    }
+
+    #[test]
+    fn answers_a_reinvite_when_ours_isnt_out() {
+        let now = Instant::now();
+        let (mut call, invite) = placed_call(now);
+        call.on_response(ok_for(&invite, "314159"), now);
+
+        let reinvite = reinvite_from_bob(&invite, "314159");
+        assert_eq!(responses(&call.on_request(reinvite, now)), vec![200]);
+    }
+
+    #[test]
+    fn ignores_a_2xx_from_another_dialog() {
+        let now = Instant::now();
+        let (mut call, invite) = placed_call(now);
+        call.on_response(ok_for(&invite, "314159"), now);
+        assert!(call
+            .on_retransmitted_ok(&ok_for(&invite, "271828"))
+            .is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use super::Codec;
+
+const G711_CLOCK_RATE: u32 = 8000;
+const G711_FRAME_DURATION: Duration = Duration::from_micros(125);
+const MU_LAW_BIAS: i32 = 0x21;
+const MU_LAW_CLIP: i32 = 8159;
+// Concealment fades the last good frame out over this many lost frames.
+const CONCEALMENT_FADE_FRAMES: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum G711Variant {
+    MuLaw,
+    ALaw,
+}
+
+/// ITU-T G.711, PCMU (µ-law) and PCMA (A-law). One byte per sample at 8 kHz.
+pub struct G711 {
+    variant: G711Variant,
+    last_frame: Vec<i16>,
+    concealed_frames: u32,
+}
+
+impl G711 {
+    pub fn new(variant: G711Variant) -> G711 {
+        G711 {
+            variant,
+            last_frame: vec![],
+            concealed_frames: 0,
+        }
+    }
+}
+
+impl Codec for G711 {
+    fn name(&self) -> &'static str {
+        match self.variant {
+            G711Variant::MuLaw => "PCMU",
+            G711Variant::ALaw => "PCMA",
+        }
+    }
+
+    fn payload_type(&self) -> u8 {
+        match self.variant {
+            G711Variant::MuLaw => 0,
+            G711Variant::ALaw => 8,
+        }
+    }
+
+    fn clock_rate(&self) -> u32 {
+        G711_CLOCK_RATE
+    }
+
+    fn frame_duration(&self) -> Duration {
+        G711_FRAME_DURATION
+    }
+
+    fn encode(&mut self, pcm: &[i16], payload: &mut Vec<u8>) {
+        payload.reserve(pcm.len());
+        match self.variant {
+            G711Variant::MuLaw => {
+                payload.extend(pcm.iter().map(|sample| linear_to_mu_law(*sample)))
+            }
+            G711Variant::ALaw => payload.extend(pcm.iter().map(|sample| linear_to_a_law(*sample))),
+        }
+    }
+
+    fn decode(&mut self, payload: &[u8], pcm: &mut Vec<i16>) {
+        let start = pcm.len();
+        pcm.reserve(payload.len());
+        match self.variant {
+            G711Variant::MuLaw => pcm.extend(payload.iter().map(|byte| mu_law_to_linear(*byte))),
+            G711Variant::ALaw => pcm.extend(payload.iter().map(|byte| a_law_to_linear(*byte))),
+        }
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(&pcm[start..]);
+        self.concealed_frames = 0;
+    }
+
+    /// Repeats the last good frame, quieter each time, which hides a lost packet or
+    /// two far better than a gap does.
+    fn conceal(&mut self, samples: usize, pcm: &mut Vec<i16>) {
+        self.concealed_frames += 1;
+        if self.last_frame.is_empty() || self.concealed_frames > CONCEALMENT_FADE_FRAMES {
+            pcm.resize(pcm.len() + samples, 0);
+            return;
+        }
+        let remaining = CONCEALMENT_FADE_FRAMES - self.concealed_frames;
+        for i in 0..samples {
+            let sample = self.last_frame[i % self.last_frame.len()] as i32;
+            pcm.push((sample * remaining as i32 / CONCEALMENT_FADE_FRAMES as i32) as i16);
+        }
+    }
+}
+
+pub fn linear_to_mu_law(sample: i16) -> u8 {
+    // µ-law works on 14 bit samples.
+    let mut sample = (sample as i32) >> 2;
+    let mask = if sample < 0 {
+        sample = -sample;
+        0x7f
+    } else {
+        0xff
+    };
+    sample = sample.min(MU_LAW_CLIP) + MU_LAW_BIAS;
+    let exponent = (32 - sample.leading_zeros() as i32 - 6).max(0);
+    if exponent > 7 {
+        return 0x7f ^ mask;
+    }
+    let mantissa = (sample >> (exponent + 1)) & 0x0f;
+    (((exponent << 4) | mantissa) as u8) ^ mask
+}
+
+pub fn mu_law_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = ((byte >> 4) & 0x07) as i32;
+    let mantissa = (byte & 0x0f) as i32;
+    // Back to 16 bits, so the bias is too.
+    let magnitude = (((mantissa << 3) + (MU_LAW_BIAS << 2)) << exponent) - (MU_LAW_BIAS << 2);
+    if byte & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+pub fn linear_to_a_law(sample: i16) -> u8 {
+    // A-law works on 13 bit samples.
+    let mut sample = (sample as i32) >> 3;
+    let sign = if sample >= 0 {
+        0x80
+    } else {
+        sample = -sample - 1;
+        0
+    };
+    let encoded = if sample < 32 {
+        (sample >> 1) as u8
+    } else {
+        let exponent = (31 - (sample as u32).leading_zeros() as i32 - 4).clamp(1, 7);
+        let mantissa = (sample >> exponent) & 0x0f;
+        ((exponent << 4) as u8) | mantissa as u8
+    };
+    (sign | encoded) ^ 0x55
+}
+
+pub fn a_law_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let exponent = ((byte >> 4) & 0x07) as i32;
+    let mantissa = (byte & 0x0f) as i32;
+    let magnitude = if exponent == 0 {
+        (mantissa << 4) + 8
+    } else {
+        ((mantissa << 4) + 0x108) << (exponent - 1)
+    };
+    if byte & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_tables() {
+        assert_eq!(linear_to_mu_law(0), 0xff);
+        assert_eq!(linear_to_a_law(0), 0xd5);
+        assert_eq!(mu_law_to_linear(0xff), 0);
+        assert_eq!(mu_law_to_linear(0x00), -32124);
+        assert_eq!(a_law_to_linear(0x2a), -32256);
+    }
+
+    #[test]
+    fn round_trips_within_a_step() {
+        for sample in (i16::MIN..=i16::MAX).step_by(97) {
+            // Quantisation steps grow with the magnitude; 1/16 of it bounds both laws.
+            let tolerance = (sample as i32).abs() / 16 + 16;
+            let mu_law = mu_law_to_linear(linear_to_mu_law(sample)) as i32;
+            let a_law = a_law_to_linear(linear_to_a_law(sample)) as i32;
+            assert!(
+                (mu_law - sample as i32).abs() <= tolerance,
+                "µ-law {}",
+                sample
+            );
+            assert!(
+                (a_law - sample as i32).abs() <= tolerance,
+                "A-law {}",
+                sample
+            );
+        }
+    }
+
+    #[test]
+    fn conceals_by_fading_the_last_frame() {
+        let mut codec = G711::new(G711Variant::MuLaw);
+        let mut pcm = vec![];
+        codec.decode(&[linear_to_mu_law(4000); 4], &mut pcm);
+        let last = pcm[0] as i32;
+
+        let mut concealed = vec![];
+        codec.conceal(4, &mut concealed);
+        assert_eq!(concealed[0] as i32, last * 3 / 4);
+        for _ in 1..CONCEALMENT_FADE_FRAMES {
+            concealed.clear();
+            codec.conceal(4, &mut concealed);
+        }
+        assert_eq!(concealed, vec![0; 4]);
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+pub mod g711;
+
+use g711::{G711Variant, G711};
+
+/// Turns PCM frames into RTP payloads and back. One instance per direction of a call,
+/// since codecs may keep state between frames.
+pub trait Codec: Send {
+    /// The encoding name used in SDP rtpmap lines.
+    fn name(&self) -> &'static str;
+    /// The static payload type, or the dynamic one we offer it as.
+    fn payload_type(&self) -> u8;
+    fn clock_rate(&self) -> u32;
+    /// The sample rate of the PCM side, which isn't always the RTP clock rate (G.722).
+    fn sample_rate(&self) -> u32 {
+        self.clock_rate()
+    }
+    /// The shortest frame the codec can do. Packets carry whole multiples of it.
+    fn frame_duration(&self) -> Duration;
+
+    fn samples_per_packet(&self, ptime: Duration) -> usize {
+        (self.sample_rate() as u64 * ptime.as_millis() as u64 / 1000) as usize
+    }
+
+    fn encode(&mut self, pcm: &[i16], payload: &mut Vec<u8>);
+    fn decode(&mut self, payload: &[u8], pcm: &mut Vec<i16>);
+
+    /// Makes up `samples` samples for a lost packet. Silence unless the codec knows better.
+    fn conceal(&mut self, samples: usize, pcm: &mut Vec<i16>) {
+        pcm.resize(pcm.len() + samples, 0);
+    }
+}
+
+/// Every codec we can do, by SDP encoding name.
+pub const SUPPORTED_CODECS: &[&str] = &["PCMU", "PCMA"];
+
+pub fn codec_by_name(name: &str) -> Option<Box<dyn Codec>> {
+    match name.to_ascii_uppercase().as_str() {
+        "PCMU" => Some(Box::new(G711::new(G711Variant::MuLaw))),
+        "PCMA" => Some(Box::new(G711::new(G711Variant::ALaw))),
+        _ => None,
+    }
+}
+
+/// The codec for an rtpmap, if we have it at that clock rate.
+pub fn create_codec(name: &str, clock_rate: u32) -> Option<Box<dyn Codec>> {
+    codec_by_name(name).filter(|codec| codec.clock_rate() == clock_rate)
+}
@@ -0,0 +1,263 @@
+use std::net::SocketAddr;
+
+use rsip::prelude::*;
+
+use super::sip::{generate_request, new_branch, tag_of, RequestParams};
+
+/// The state RFC 3261 section 12 asks both ends of a call to keep.
+#[derive(Clone)]
+pub struct Dialog {
+    pub call_id: String,
+    pub local_tag: String,
+    pub remote_tag: String,
+    pub local_uri: rsip::Uri,
+    pub local_display_name: Option<String>,
+    pub remote_uri: rsip::Uri,
+    pub remote_display_name: Option<String>,
+    pub remote_target: rsip::Uri,
+    route_set: Vec<String>,
+    pub local_seq: u16,
+    pub remote_seq: Option<u16>,
+}
+
+impl Dialog {
+    /// The dialog a 1xx (with a tag) or 2xx to our INVITE creates.
+    pub fn from_uac(invite: &rsip::Request, response: &rsip::Response) -> Result<Dialog, String> {
+        let from = invite
+            .from_header()
+            .and_then(|from| from.typed())
+            .map_err(|err| err.to_string())?;
+        let to = response
+            .to_header()
+            .and_then(|to| to.typed())
+            .map_err(|err| err.to_string())?;
+        let remote_tag = match response.to_header() {
+            Ok(to) => tag_of(to.value()),
+            Err(_) => None,
+        };
+        let local_tag = match invite.from_header() {
+            Ok(from) => tag_of(from.value()),
+            Err(_) => None,
+        };
+        let cseq = invite
+            .cseq_header()
+            .and_then(|cseq| cseq.typed())
+            .map_err(|err| err.to_string())?;
+        let remote_target = match response.contact_header().and_then(|c| c.typed()) {
+            Ok(contact) => contact.uri,
+            Err(_) => invite.uri.clone(),
+        };
+        // A UAC uses the Record-Route set in reverse.
+        let mut route_set = Self::record_routes(&response.headers);
+        route_set.reverse();
+
+        Ok(Dialog {
+            call_id: invite
+                .call_id_header()
+                .map_err(|err| err.to_string())?
+                .value()
+                .into(),
+            local_tag: local_tag.ok_or("INVITE without From tag")?,
+            remote_tag: remote_tag.ok_or("Response without To tag")?,
+            local_uri: from.uri,
+            local_display_name: from.display_name,
+            remote_uri: to.uri,
+            remote_display_name: to.display_name,
+            remote_target,
+            route_set,
+            local_seq: cseq.seq,
+            remote_seq: None,
+        })
+    }
+
+    /// The dialog we create by answering an INVITE with `local_tag`.
+    pub fn from_uas(invite: &rsip::Request, local_tag: &str) -> Result<Dialog, String> {
+        let from = invite
+            .from_header()
+            .and_then(|from| from.typed())
+            .map_err(|err| err.to_string())?;
+        let to = invite
+            .to_header()
+            .and_then(|to| to.typed())
+            .map_err(|err| err.to_string())?;
+        let remote_tag = match invite.from_header() {
+            Ok(from) => tag_of(from.value()),
+            Err(_) => None,
+        };
+        let cseq = invite
+            .cseq_header()
+            .and_then(|cseq| cseq.typed())
+            .map_err(|err| err.to_string())?;
+        let remote_target = match invite.contact_header().and_then(|c| c.typed()) {
+            Ok(contact) => contact.uri,
+            Err(_) => from.uri.clone(),
+        };
+
+        Ok(Dialog {
+            call_id: invite
+                .call_id_header()
+                .map_err(|err| err.to_string())?
+                .value()
+                .into(),
+            local_tag: local_tag.into(),
+            remote_tag: remote_tag.ok_or("INVITE without From tag")?,
+            local_uri: to.uri,
+            local_display_name: to.display_name,
+            remote_uri: from.uri,
+            remote_display_name: from.display_name,
+            remote_target,
+            route_set: Self::record_routes(&invite.headers),
+            local_seq: 0,
+            remote_seq: Some(cseq.seq),
+        })
+    }
+
+    fn record_routes(headers: &rsip::Headers) -> Vec<String> {
+        headers
+            .iter()
+            .filter_map(|header| match header {
+                rsip::Header::RecordRoute(record_route) => Some(record_route.value().to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether an incoming request belongs to this dialog.
+    pub fn matches_request(&self, request: &rsip::Request) -> bool {
+        let call_id = match request.call_id_header() {
+            Ok(call_id) => call_id.value().to_string(),
+            Err(_) => return false,
+        };
+        let from_tag = request.from_header().ok().and_then(|f| tag_of(f.value()));
+        let to_tag = request.to_header().ok().and_then(|t| tag_of(t.value()));
+        call_id == self.call_id
+            && from_tag.as_deref() == Some(self.remote_tag.as_str())
+            && to_tag.as_deref() == Some(self.local_tag.as_str())
+    }
+
+    /// Whether a response to one of our requests belongs to this dialog.
+    pub fn matches_response(&self, response: &rsip::Response) -> bool {
+        let call_id = match response.call_id_header() {
+            Ok(call_id) => call_id.value().to_string(),
+            Err(_) => return false,
+        };
+        let from_tag = response.from_header().ok().and_then(|f| tag_of(f.value()));
+        let to_tag = response.to_header().ok().and_then(|t| tag_of(t.value()));
+        call_id == self.call_id
+            && from_tag.as_deref() == Some(self.local_tag.as_str())
+            && to_tag.as_deref() == Some(self.remote_tag.as_str())
+    }
+
+    /// Rejects requests that arrive out of order, section 12.2.2.
+    pub fn accept_remote_seq(&mut self, request: &rsip::Request) -> bool {
+        let seq = match request.cseq_header().and_then(|cseq| cseq.typed()) {
+            Ok(cseq) => cseq.seq,
+            Err(_) => return false,
+        };
+        // ACK and CANCEL reuse the INVITE's number.
+        if request.method == rsip::Method::Ack || request.method == rsip::Method::Cancel {
+            return true;
+        }
+        match self.remote_seq {
+            Some(remote_seq) if seq <= remote_seq => false,
+            _ => {
+                self.remote_seq = Some(seq);
+                true
+            }
+        }
+    }
+
+    pub fn remote_target_updated(&mut self, message_headers: &rsip::Headers) {
+        for header in message_headers.iter() {
+            if let rsip::Header::Contact(contact) = header {
+                if let Ok(contact) = contact.typed() {
+                    self.remote_target = contact.uri;
+                }
+            }
+        }
+    }
+
+    fn route_headers(&self) -> Vec<rsip::Header> {
+        self.route_set
+            .iter()
+            .map(|route| rsip::headers::Route::new(route.clone()).into())
+            .collect()
+    }
+
+    fn address_headers(&self) -> (rsip::typed::From, rsip::typed::To) {
+        (
+            rsip::typed::From {
+                display_name: self.local_display_name.clone(),
+                uri: self.local_uri.clone(),
+                params: vec![rsip::Param::Tag(rsip::param::Tag::new(&self.local_tag))],
+            },
+            rsip::typed::To {
+                display_name: self.remote_display_name.clone(),
+                uri: self.remote_uri.clone(),
+                params: vec![rsip::Param::Tag(rsip::param::Tag::new(&self.remote_tag))],
+            },
+        )
+    }
+
+    /// A new in-dialog request (BYE, re-INVITE, INFO, ...), with the next CSeq.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_request(
+        &mut self,
+        method: rsip::Method,
+        transport: rsip::Transport,
+        local_addr: SocketAddr,
+        contact: rsip::Uri,
+        extra_headers: Vec<rsip::Header>,
+        content_type: Option<&str>,
+        body: Vec<u8>,
+    ) -> rsip::Request {
+        self.local_seq = self.local_seq.wrapping_add(1);
+        let (from, to) = self.address_headers();
+        let mut headers = self.route_headers();
+        headers.extend(extra_headers);
+        let branch = new_branch();
+        generate_request(RequestParams {
+            method,
+            uri: self.remote_target.clone(),
+            transport,
+            local_addr,
+            branch: &branch,
+            from,
+            to,
+            call_id: &self.call_id,
+            cseq: self.local_seq,
+            contact: Some(contact),
+            extra_headers: headers,
+            content_type,
+            body,
+        })
+    }
+
+    /// The ACK for a 2xx, which is its own transaction but reuses the INVITE's CSeq.
+    pub fn create_ack(
+        &self,
+        invite_seq: u16,
+        transport: rsip::Transport,
+        local_addr: SocketAddr,
+        content_type: Option<&str>,
+        body: Vec<u8>,
+    ) -> rsip::Request {
+        let (from, to) = self.address_headers();
+        let branch = new_branch();
+        generate_request(RequestParams {
+            method: rsip::Method::Ack,
+            uri: self.remote_target.clone(),
+            transport,
+            local_addr,
+            branch: &branch,
+            from,
+            to,
+            call_id: &self.call_id,
+            cseq: invite_seq,
+            contact: None,
+            extra_headers: self.route_headers(),
+            content_type,
+            body,
+        })
+    }
+}
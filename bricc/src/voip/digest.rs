@@ -0,0 +1,269 @@
+use sha2::{Digest, Sha256};
+
+use rsip::headers::auth::{Algorithm, AuthQop, Qop};
+use rsip::prelude::*;
+
+use super::sip::random_token;
+
+/// Which header a challenge arrived in, which decides the header the answer goes in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChallengeKind {
+    WwwAuthenticate,
+    ProxyAuthenticate,
+}
+
+pub struct DigestCredentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+fn hash(algorithm: &Algorithm, data: &str) -> Result<String, String> {
+    match algorithm {
+        Algorithm::Md5 | Algorithm::Md5Sess => Ok(format!("{:x}", md5::compute(data))),
+        Algorithm::Sha256 | Algorithm::Sha256Sess => {
+            Ok(format!("{:x}", Sha256::digest(data.as_bytes())))
+        }
+        _ => Err(format!("Unsupported digest algorithm {}", algorithm)),
+    }
+}
+
+fn is_sess(algorithm: &Algorithm) -> bool {
+    matches!(algorithm, Algorithm::Md5Sess | Algorithm::Sha256Sess)
+}
+
+/// Computes the RFC 2617/7616 `response` value.
+pub fn compute_response(
+    algorithm: &Algorithm,
+    credentials: &DigestCredentials,
+    realm: &str,
+    nonce: &str,
+    method: &rsip::Method,
+    uri: &rsip::Uri,
+    qop: Option<&AuthQop>,
+) -> Result<String, String> {
+    let mut ha1 = hash(
+        algorithm,
+        &format!(
+            "{}:{}:{}",
+            credentials.username, realm, credentials.password
+        ),
+    )?;
+    let cnonce = match qop {
+        Some(AuthQop::Auth { cnonce, .. }) => Some(cnonce.as_str()),
+        Some(AuthQop::AuthInt { cnonce, .. }) => Some(cnonce.as_str()),
+        None => None,
+    };
+    if is_sess(algorithm) {
+        ha1 = hash(
+            algorithm,
+            &format!("{}:{}:{}", ha1, nonce, cnonce.unwrap_or("")),
+        )?;
+    }
+    let ha2 = hash(algorithm, &format!("{}:{}", method, uri))?;
+
+    match qop {
+        Some(AuthQop::Auth { cnonce, nc }) => hash(
+            algorithm,
+            &format!("{}:{}:{:08x}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2),
+        ),
+        // We never offer auth-int, the body would have to be hashed into ha2.
+        Some(AuthQop::AuthInt { .. }) => Err("auth-int is not supported".into()),
+        None => hash(algorithm, &format!("{}:{}:{}", ha1, nonce, ha2)),
+    }
+}
+
+/// Builds the Authorization (or Proxy-Authorization) header answering `challenge`.
+pub fn answer_challenge(
+    kind: ChallengeKind,
+    challenge: &rsip::typed::WwwAuthenticate,
+    credentials: &DigestCredentials,
+    method: &rsip::Method,
+    uri: &rsip::Uri,
+) -> Result<rsip::Header, String> {
+    let algorithm = challenge.algorithm.unwrap_or(Algorithm::Md5);
+    let qop = match &challenge.qop {
+        Some(Qop::Auth) => Some(AuthQop::Auth {
+            cnonce: random_token(16),
+            nc: 1,
+        }),
+        Some(Qop::AuthInt) => return Err("Registrar demands auth-int".into()),
+        None => None,
+    };
+    let response = compute_response(
+        &algorithm,
+        credentials,
+        &challenge.realm,
+        &challenge.nonce,
+        method,
+        uri,
+        qop.as_ref(),
+    )?;
+
+    let authorization = rsip::typed::Authorization {
+        scheme: rsip::headers::auth::Scheme::Digest,
+        username: credentials.username.into(),
+        realm: challenge.realm.clone(),
+        nonce: challenge.nonce.clone(),
+        uri: uri.clone(),
+        response,
+        algorithm: Some(algorithm),
+        opaque: challenge.opaque.clone(),
+        qop,
+    };
+
+    Ok(match kind {
+        ChallengeKind::WwwAuthenticate => authorization.into(),
+        // rsip has no typed Proxy-Authorization, but the value is the same.
+        ChallengeKind::ProxyAuthenticate => {
+            rsip::headers::ProxyAuthorization::new(authorization.to_string()).into()
+        }
+    })
+}
+
+/// Pulls the first digest challenge out of a 401/407 response.
+pub fn find_challenge(
+    response: &rsip::Response,
+) -> Option<(ChallengeKind, rsip::typed::WwwAuthenticate)> {
+    for header in response.headers.iter() {
+        match header {
+            rsip::Header::WwwAuthenticate(header) => {
+                if let Ok(typed) = header.typed() {
+                    return Some((ChallengeKind::WwwAuthenticate, typed));
+                }
+            }
+            // Same syntax as WWW-Authenticate, which rsip can parse.
+            rsip::Header::ProxyAuthenticate(header) => {
+                if let Ok(typed) = rsip::headers::WwwAuthenticate::new(header.value()).typed() {
+                    return Some((ChallengeKind::ProxyAuthenticate, typed));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn credentials() -> DigestCredentials<'static> {
+        DigestCredentials {
+            username: "bob",
+            password: "zanzibar",
+        }
+    }
+
+    fn uri() -> rsip::Uri {
+        rsip::Uri::try_from("sip:biloxi.example.com").unwrap()
+    }
+
+    fn challenge_response(header: &str) -> rsip::Response {
+        let response = format!(
+            "SIP/2.0 407 Proxy Authentication Required\r\n\
+             Via: SIP/2.0/UDP 192.0.2.4;branch=z9hG4bKnashds7\r\n\
+             Call-ID: a84b4c76e66710\r\n\
+             CSeq: 1 INVITE\r\n\
+             {}\r\n\
+             Content-Length: 0\r\n\r\n",
+            header
+        );
+        rsip::Response::try_from(response.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn md5_response_without_qop() {
+        let response = compute_response(
+            &Algorithm::Md5,
+            &credentials(),
+            "biloxi.example.com",
+            "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+            &rsip::Method::Register,
+            &uri(),
+            None,
+        )
+        .unwrap();
+        let ha1 = md5::compute("bob:biloxi.example.com:zanzibar");
+        let ha2 = md5::compute("REGISTER:sip:biloxi.example.com");
+        let expected = md5::compute(format!(
+            "{:x}:dcd98b7102dd2f0e8b11d0f600bfb0c093:{:x}",
+            ha1, ha2
+        ));
+        assert_eq!(response, format!("{:x}", expected));
+    }
+
+    #[test]
+    fn sha256_response_with_qop() {
+        let qop = AuthQop::Auth {
+            cnonce: "0a4f113b".into(),
+            nc: 1,
+        };
+        let response = compute_response(
+            &Algorithm::Sha256,
+            &credentials(),
+            "biloxi.example.com",
+            "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+            &rsip::Method::Register,
+            &uri(),
+            Some(&qop),
+        )
+        .unwrap();
+        let hex = |data: String| format!("{:x}", Sha256::digest(data.as_bytes()));
+        let ha1 = hex("bob:biloxi.example.com:zanzibar".into());
+        let ha2 = hex("REGISTER:sip:biloxi.example.com".into());
+        let expected = hex(format!(
+            "{}:7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v:00000001:0a4f113b:auth:{}",
+            ha1, ha2
+        ));
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn answers_a_proxy_challenge_with_proxy_authorization() {
+        let response = challenge_response(
+            "Proxy-Authenticate: Digest realm=\"biloxi.example.com\", \
+             nonce=\"f84f1cec41e6cbe5aea9c8e88d359\", algorithm=MD5, qop=\"auth\"",
+        );
+        let (kind, challenge) = find_challenge(&response).expect("a challenge");
+        assert_eq!(kind, ChallengeKind::ProxyAuthenticate);
+        assert_eq!(challenge.realm, "biloxi.example.com");
+
+        let header = answer_challenge(
+            kind,
+            &challenge,
+            &credentials(),
+            &rsip::Method::Invite,
+            &uri(),
+        )
+        .unwrap();
+        match header {
+            rsip::Header::ProxyAuthorization(header) => {
+                let value = header.value();
+                assert!(value.starts_with("Digest "));
+                assert!(value.contains("username=\"bob\""));
+                assert!(value.contains("nonce=\"f84f1cec41e6cbe5aea9c8e88d359\""));
+            }
+            other => panic!("expected Proxy-Authorization, got {}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_auth_int() {
+        let response = challenge_response(
+            "WWW-Authenticate: Digest realm=\"biloxi.example.com\", \
+             nonce=\"f84f1cec41e6cbe5aea9c8e88d359\", qop=\"auth-int\"",
+        );
+        let (kind, challenge) = find_challenge(&response).expect("a challenge");
+        assert_eq!(kind, ChallengeKind::WwwAuthenticate);
+        assert!(answer_challenge(
+            kind,
+            &challenge,
+            &credentials(),
+            &rsip::Method::Register,
+            &uri(),
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use super::{DnsRecord, DnsResolver, RecordData, RecordType};
+use crate::voip::account::SipTransport;
+use crate::voip::transport::TransportTarget;
+
+// Failed lookups are retried no sooner than this.
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// RFC 3263 section 4: turns a SIP URI into the servers to try, best first.
+/// Lookups are cached for their TTL.
+pub struct ServerLocator {
+    resolver: Box<dyn DnsResolver>,
+    cache: HashMap<(String, RecordType), (Instant, Vec<DnsRecord>)>,
+}
+
+impl ServerLocator {
+    pub fn new(resolver: Box<dyn DnsResolver>) -> ServerLocator {
+        ServerLocator {
+            resolver,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Every address to try for `uri` over `transport`, in failover order. The
+    /// transport is the account's; we don't switch based on what NAPTR prefers.
+    pub fn locate(
+        &mut self,
+        uri: &rsip::Uri,
+        transport: SipTransport,
+    ) -> Result<Vec<TransportTarget>, String> {
+        let transport = TransportTarget::transport_for_uri(uri, transport)?;
+        let host = uri.host_with_port.host.to_string();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = uri
+            .host_with_port
+            .port
+            .as_ref()
+            .and_then(|port| port.to_string().parse::<u16>().ok());
+
+        // Section 4.2: a literal address or an explicit port skips NAPTR and SRV.
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![TransportTarget {
+                transport,
+                addr: SocketAddr::new(ip, port.unwrap_or_else(|| transport.default_port())),
+            }]);
+        }
+        if let Some(port) = port {
+            return self.addresses(host, port, transport);
+        }
+
+        let srv_name = self
+            .naptr_srv_name(host, transport)?
+            .unwrap_or_else(|| format!("{}.{}", srv_prefix(transport), host));
+        let mut targets = vec![];
+        for (target, port) in self.srv_targets(&srv_name)? {
+            // One dead SRV target shouldn't hide the rest.
+            match self.addresses(&target, port, transport) {
+                Ok(addresses) => targets.extend(addresses),
+                Err(err) => println!("Skipping SIP server {}: {}", target, err),
+            }
+        }
+        if targets.is_empty() {
+            return self.addresses(host, transport.default_port(), transport);
+        }
+        Ok(targets)
+    }
+
+    /// Addresses for some other UDP service given as host[:port], e.g. STUN: the SRV
+    /// records for `service` if there's no port, then the host itself.
+    pub fn locate_service(
+        &mut self,
+        service: &str,
+        server: &str,
+        default_port: u16,
+    ) -> Result<Vec<SocketAddr>, String> {
+        if let Ok(addr) = server.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        let (host, port) = match server.rfind(':') {
+            Some(colon) if !server.ends_with(']') => {
+                let port = server[colon + 1..]
+                    .parse::<u16>()
+                    .map_err(|_| format!("Bad port in {}", server))?;
+                (&server[..colon], Some(port))
+            }
+            _ => (server, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port.unwrap_or(default_port))]);
+        }
+        let to_addrs = |targets: Vec<TransportTarget>| -> Vec<SocketAddr> {
+            targets.into_iter().map(|target| target.addr).collect()
+        };
+        if let Some(port) = port {
+            return self.addresses(host, port, SipTransport::Udp).map(to_addrs);
+        }
+        let mut addrs = vec![];
+        for (target, port) in self.srv_targets(&format!("{}.{}", service, host))? {
+            match self.addresses(&target, port, SipTransport::Udp) {
+                Ok(targets) => addrs.extend(to_addrs(targets)),
+                Err(err) => println!("Skipping {} server {}: {}", service, target, err),
+            }
+        }
+        if addrs.is_empty() {
+            return self
+                .addresses(host, default_port, SipTransport::Udp)
+                .map(to_addrs);
+        }
+        Ok(addrs)
+    }
+
+    /// The SRV name the domain's best NAPTR record for `transport` points at, if any.
+    fn naptr_srv_name(
+        &mut self,
+        host: &str,
+        transport: SipTransport,
+    ) -> Result<Option<String>, String> {
+        let mut naptrs: Vec<(u16, u16, String)> = self
+            .lookup(host, RecordType::Naptr)?
+            .into_iter()
+            .filter_map(|record| match record.data {
+                RecordData::Naptr {
+                    order,
+                    preference,
+                    flags,
+                    services,
+                    replacement,
+                } if flags.eq_ignore_ascii_case("s")
+                    && services.eq_ignore_ascii_case(naptr_service(transport)) =>
+                {
+                    Some((order, preference, replacement))
+                }
+                _ => None,
+            })
+            .collect();
+        naptrs.sort_by_key(|(order, preference, _)| (*order, *preference));
+        Ok(naptrs
+            .into_iter()
+            .next()
+            .map(|(_, _, replacement)| replacement))
+    }
+
+    /// RFC 2782 ordering: by priority, then a weighted shuffle within each priority.
+    fn srv_targets(&mut self, srv_name: &str) -> Result<Vec<(String, u16)>, String> {
+        let mut srvs: Vec<(u16, u16, u16, String)> = self
+            .lookup(srv_name, RecordType::Srv)?
+            .into_iter()
+            .filter_map(|record| match record.data {
+                RecordData::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } => Some((priority, weight, port, target)),
+                _ => None,
+            })
+            .collect();
+        // "." means the service is deliberately not offered.
+        if srvs.len() == 1 && (srvs[0].3.is_empty() || srvs[0].3 == ".") {
+            return Ok(vec![]);
+        }
+        srvs.sort_by_key(|(priority, _, _, _)| *priority);
+
+        let mut ordered = vec![];
+        let mut rng = rand::thread_rng();
+        while !srvs.is_empty() {
+            let priority = srvs[0].0;
+            let same_priority = srvs.iter().take_while(|srv| srv.0 == priority).count();
+            let mut group: Vec<_> = srvs.drain(..same_priority).collect();
+            while !group.is_empty() {
+                let total: u32 = group.iter().map(|srv| srv.1 as u32).sum();
+                let mut pick = rng.gen_range(0..=total);
+                let index = group
+                    .iter()
+                    .position(|srv| {
+                        if pick <= srv.1 as u32 {
+                            true
+                        } else {
+                            pick -= srv.1 as u32;
+                            false
+                        }
+                    })
+                    .unwrap_or(0);
+                let (_, _, port, target) = group.remove(index);
+                ordered.push((target, port));
+            }
+        }
+        Ok(ordered)
+    }
+
+    fn addresses(
+        &mut self,
+        host: &str,
+        port: u16,
+        transport: SipTransport,
+    ) -> Result<Vec<TransportTarget>, String> {
+        let mut records = self.lookup(host, RecordType::A)?;
+        if records.is_empty() {
+            records = self.lookup(host, RecordType::Aaaa)?;
+        }
+        let targets: Vec<TransportTarget> = records
+            .into_iter()
+            .filter_map(|record| match record.data {
+                RecordData::Address(ip) => Some(TransportTarget {
+                    transport,
+                    addr: SocketAddr::new(ip, port),
+                }),
+                _ => None,
+            })
+            .collect();
+        if targets.is_empty() {
+            return Err(format!("No address for {}", host));
+        }
+        Ok(targets)
+    }
+
+    fn lookup(&mut self, name: &str, record_type: RecordType) -> Result<Vec<DnsRecord>, String> {
+        let now = Instant::now();
+        let key = (name.to_ascii_lowercase(), record_type);
+        if let Some((expires_at, records)) = self.cache.get(&key) {
+            if now < *expires_at {
+                return Ok(records.clone());
+            }
+        }
+        let records = self.resolver.query(name, record_type)?;
+        let ttl = match records.iter().map(|record| record.ttl).min() {
+            Some(ttl) => Duration::from_secs(ttl as u64),
+            None => NEGATIVE_CACHE_TTL,
+        };
+        self.cache.insert(key, (now + ttl, records.clone()));
+        Ok(records)
+    }
+}
+
+fn naptr_service(transport: SipTransport) -> &'static str {
+    match transport {
+        SipTransport::Udp => "SIP+D2U",
+        SipTransport::Tcp => "SIP+D2T",
+        SipTransport::Tls => "SIPS+D2T",
+    }
+}
+
+fn srv_prefix(transport: SipTransport) -> &'static str {
+    match transport {
+        SipTransport::Udp => "_sip._udp",
+        SipTransport::Tcp => "_sip._tcp",
+        SipTransport::Tls => "_sips._tcp",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    // Every query made, in order, shared with the test once the resolver's boxed up.
+    type QueryLog = Arc<Mutex<Vec<(String, RecordType)>>>;
+
+    /// Answers from a table instead of the network, and notes what it was asked.
+    #[derive(Default)]
+    struct FakeResolver {
+        records: HashMap<(String, RecordType), Vec<DnsRecord>>,
+        queries: QueryLog,
+    }
+
+    impl FakeResolver {
+        fn with(mut self, name: &str, record_type: RecordType, data: RecordData) -> Self {
+            self.records
+                .entry((name.into(), record_type))
+                .or_default()
+                .push(DnsRecord { ttl: 60, data });
+            self
+        }
+
+        fn address(self, name: &str, ip: &str) -> Self {
+            self.with(
+                name,
+                RecordType::A,
+                RecordData::Address(ip.parse().unwrap()),
+            )
+        }
+
+        fn srv(self, name: &str, priority: u16, port: u16, target: &str) -> Self {
+            let data = RecordData::Srv {
+                priority,
+                weight: 0,
+                port,
+                target: target.into(),
+            };
+            self.with(name, RecordType::Srv, data)
+        }
+
+        fn naptr(self, name: &str, order: u16, services: &str, replacement: &str) -> Self {
+            let data = RecordData::Naptr {
+                order,
+                preference: 10,
+                flags: "S".into(),
+                services: services.into(),
+                replacement: replacement.into(),
+            };
+            self.with(name, RecordType::Naptr, data)
+        }
+
+        fn locator(self) -> (ServerLocator, QueryLog) {
+            let queries = self.queries.clone();
+            (ServerLocator::new(Box::new(self)), queries)
+        }
+    }
+
+    impl DnsResolver for FakeResolver {
+        fn query(&mut self, name: &str, record_type: RecordType) -> Result<Vec<DnsRecord>, String> {
+            self.queries
+                .lock()
+                .unwrap()
+                .push((name.into(), record_type));
+            Ok(self
+                .records
+                .get(&(name.into(), record_type))
+                .cloned()
+                .unwrap_or_default())
+        }
+    }
+
+    fn locate(locator: &mut ServerLocator, uri: &str) -> Result<Vec<String>, String> {
+        let uri = rsip::Uri::try_from(uri).unwrap();
+        Ok(locator
+            .locate(&uri, SipTransport::Udp)?
+            .iter()
+            .map(|target| target.addr.to_string())
+            .collect())
+    }
+
+    #[test]
+    fn follows_naptr_then_srv_then_addresses() {
+        let (mut locator, _) = FakeResolver::default()
+            .naptr("example.com", 20, "SIP+D2U", "_sip._udp.example.com")
+            .naptr("example.com", 10, "SIP+D2T", "_sip._tcp.example.com")
+            .srv("_sip._udp.example.com", 20, 5070, "backup.example.com")
+            .srv("_sip._udp.example.com", 10, 5060, "main.example.com")
+            .address("main.example.com", "192.0.2.1")
+            .address("backup.example.com", "192.0.2.2")
+            .locator();
+        assert_eq!(
+            locate(&mut locator, "sip:alice@example.com"),
+            Ok(vec!["192.0.2.1:5060".into(), "192.0.2.2:5070".into()])
+        );
+    }
+
+    #[test]
+    fn skips_srv_targets_without_addresses() {
+        let (mut locator, _) = FakeResolver::default()
+            .srv("_sip._udp.example.com", 10, 5060, "gone.example.com")
+            .srv("_sip._udp.example.com", 20, 5060, "main.example.com")
+            .address("main.example.com", "192.0.2.1")
+            .locator();
+        assert_eq!(
+            locate(&mut locator, "sip:example.com"),
+            Ok(vec!["192.0.2.1:5060".into()])
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_host_on_the_default_port() {
+        let (mut locator, _) = FakeResolver::default()
+            .address("example.com", "192.0.2.3")
+            .locator();
+        assert_eq!(
+            locate(&mut locator, "sip:example.com"),
+            Ok(vec!["192.0.2.3:5060".into()])
+        );
+        assert!(locate(&mut locator, "sip:nowhere.example.com").is_err());
+    }
+
+    #[test]
+    fn skips_dns_for_literals_and_srv_for_ports() {
+        let (mut locator, queries) = FakeResolver::default()
+            .srv("_sip._udp.example.com", 10, 5080, "main.example.com")
+            .address("example.com", "192.0.2.3")
+            .locator();
+        assert_eq!(
+            locate(&mut locator, "sip:192.0.2.9:5062"),
+            Ok(vec!["192.0.2.9:5062".into()])
+        );
+        assert!(queries.lock().unwrap().is_empty());
+        assert_eq!(
+            locate(&mut locator, "sip:example.com:5090"),
+            Ok(vec!["192.0.2.3:5090".into()])
+        );
+        assert_eq!(
+            *queries.lock().unwrap(),
+            vec![("example.com".to_string(), RecordType::A)]
+        );
+    }
+
+    #[test]
+    fn caches_answers_and_misses() {
+        let (mut locator, queries) = FakeResolver::default()
+            .address("example.com", "192.0.2.3")
+            .locator();
+        locate(&mut locator, "sip:example.com").unwrap();
+        let asked = queries.lock().unwrap().len();
+        locate(&mut locator, "sip:EXAMPLE.com").unwrap();
+        assert_eq!(queries.lock().unwrap().len(), asked);
+    }
+
+    #[test]
+    fn locates_other_services_by_srv() {
+        let (mut locator, _) = FakeResolver::default()
+            .srv("_stun._udp.example.com", 0, 3479, "stun.example.com")
+            .address("stun.example.com", "192.0.2.4")
+            .address("example.com", "192.0.2.3")
+            .locator();
+        let addrs = |locator: &mut ServerLocator, server: &str| {
+            locator
+                .locate_service("_stun._udp", server, 3478)
+                .map(|addrs| {
+                    addrs
+                        .iter()
+                        .map(|addr| addr.to_string())
+                        .collect::<Vec<_>>()
+                })
+        };
+        assert_eq!(
+            addrs(&mut locator, "example.com"),
+            Ok(vec!["192.0.2.4:3479".into()])
+        );
+        assert_eq!(
+            addrs(&mut locator, "example.com:3480"),
+            Ok(vec!["192.0.2.3:3480".into()])
+        );
+        assert_eq!(
+            addrs(&mut locator, "[2001:db8::1]:3478"),
+            Ok(vec!["[2001:db8::1]:3478".into()])
+        );
+    }
+}
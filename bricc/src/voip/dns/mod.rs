@@ -0,0 +1,157 @@
+pub mod locate;
+pub mod wire;
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const DNS_RECEIVE_BUFFER_BYTES: usize = 1500;
+// getaddrinfo doesn't tell us the real TTL.
+const SYSTEM_ADDRESS_TTL_SECS: u32 = 300;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Srv,
+    Naptr,
+}
+
+impl RecordType {
+    pub fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+            RecordType::Srv => 33,
+            RecordType::Naptr => 35,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordData {
+    Address(IpAddr),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Naptr {
+        order: u16,
+        preference: u16,
+        flags: String,
+        services: String,
+        replacement: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsRecord {
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// Answers DNS questions. A name that doesn't exist is no records, not an error;
+/// errors are for not getting an answer at all. Anything that implements this can
+/// stand in for the network, so lookups can be pointed at a fake.
+pub trait DnsResolver: Send {
+    fn query(&mut self, name: &str, record_type: RecordType) -> Result<Vec<DnsRecord>, String>;
+}
+
+/// Asks the nameservers in /etc/resolv.conf directly. Where there aren't any (the
+/// ESP32), addresses come from the system's own lookup and there are no NAPTR or
+/// SRV records.
+pub struct SystemResolver {
+    servers: Vec<SocketAddr>,
+}
+
+impl SystemResolver {
+    pub fn new(servers: Vec<SocketAddr>) -> SystemResolver {
+        SystemResolver { servers }
+    }
+
+    pub fn from_system() -> SystemResolver {
+        let servers = match std::fs::read_to_string(RESOLV_CONF_PATH) {
+            Ok(conf) => conf
+                .lines()
+                .filter_map(|line| {
+                    let mut words = line.split_whitespace();
+                    match words.next() {
+                        Some("nameserver") => words.next()?.parse::<IpAddr>().ok(),
+                        _ => None,
+                    }
+                })
+                .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                .collect(),
+            Err(_) => vec![],
+        };
+        SystemResolver::new(servers)
+    }
+
+    fn query_server(
+        server: SocketAddr,
+        name: &str,
+        record_type: RecordType,
+    ) -> Result<Vec<DnsRecord>, String> {
+        let bind_addr = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).map_err(|err| err.to_string())?;
+        socket
+            .set_read_timeout(Some(DNS_QUERY_TIMEOUT))
+            .map_err(|err| err.to_string())?;
+        let id = rand::random::<u16>();
+        socket
+            .send_to(&wire::encode_query(id, name, record_type)?, server)
+            .map_err(|err| err.to_string())?;
+        let mut buffer = vec![0u8; DNS_RECEIVE_BUFFER_BYTES];
+        loop {
+            let (len, from) = socket
+                .recv_from(&mut buffer)
+                .map_err(|err| format!("No answer from {}: {}", server, err))?;
+            if from != server {
+                continue;
+            }
+            match wire::parse_response(&buffer[..len], id, record_type) {
+                Err(wire::ParseError::WrongId) => continue,
+                Err(wire::ParseError::Invalid(err)) => return Err(err),
+                Ok(records) => return Ok(records),
+            }
+        }
+    }
+}
+
+impl DnsResolver for SystemResolver {
+    fn query(&mut self, name: &str, record_type: RecordType) -> Result<Vec<DnsRecord>, String> {
+        if self.servers.is_empty() {
+            return match record_type {
+                RecordType::A | RecordType::Aaaa => {
+                    let addrs = (name, 0u16)
+                        .to_socket_addrs()
+                        .map_err(|err| format!("Can't resolve {}: {}", name, err))?;
+                    Ok(addrs
+                        .filter(|addr| addr.is_ipv4() == (record_type == RecordType::A))
+                        .map(|addr| DnsRecord {
+                            ttl: SYSTEM_ADDRESS_TTL_SECS,
+                            data: RecordData::Address(addr.ip()),
+                        })
+                        .collect())
+                }
+                RecordType::Srv | RecordType::Naptr => Ok(vec![]),
+            };
+        }
+        let mut last_err = String::new();
+        for server in self.servers.iter() {
+            match Self::query_server(*server, name, record_type) {
+                Ok(records) => return Ok(records),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+}
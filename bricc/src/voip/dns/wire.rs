@@ -0,0 +1,324 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{DnsRecord, RecordData, RecordType};
+
+const HEADER_BYTES: usize = 12;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u16 = 3;
+const MAX_LABEL_BYTES: usize = 63;
+// More jumps than this is a compression loop.
+const MAX_NAME_POINTERS: usize = 16;
+
+pub enum ParseError {
+    /// Not the answer to our question; keep waiting.
+    WrongId,
+    Invalid(String),
+}
+
+/// RFC 1035 section 4.1: one question, recursion please.
+pub fn encode_query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(HEADER_BYTES + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query.extend_from_slice(&[0; 6]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_BYTES {
+            return Err(format!("{} is not a valid DNS name", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.code().to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// The answers of type `record_type`, skipping anything else (CNAMEs on the way, say).
+pub fn parse_response(
+    message: &[u8],
+    id: u16,
+    record_type: RecordType,
+) -> Result<Vec<DnsRecord>, ParseError> {
+    let invalid = |reason: &str| ParseError::Invalid(format!("Bad DNS response: {}", reason));
+    if message.len() < HEADER_BYTES {
+        return Err(invalid("too short"));
+    }
+    let u16_at = |at: usize| -> Result<u16, ParseError> {
+        match message.get(at..at + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+            None => Err(invalid("truncated")),
+        }
+    };
+    if u16_at(0)? != id {
+        return Err(ParseError::WrongId);
+    }
+    let flags = u16_at(2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(ParseError::WrongId);
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Ok(vec![]),
+        rcode => {
+            return Err(ParseError::Invalid(format!(
+                "DNS server answered with error {}",
+                rcode
+            )))
+        }
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut at = HEADER_BYTES;
+    for _ in 0..questions {
+        at = read_name(message, at).map_err(|err| invalid(&err))?.1 + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        at = read_name(message, at).map_err(|err| invalid(&err))?.1;
+        let rr_type = u16_at(at)?;
+        let ttl = match message.get(at + 4..at + 8) {
+            Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            None => return Err(invalid("truncated")),
+        };
+        let rdata_len = u16_at(at + 8)? as usize;
+        let rdata_at = at + 10;
+        let rdata = message
+            .get(rdata_at..rdata_at + rdata_len)
+            .ok_or_else(|| invalid("truncated"))?;
+        at = rdata_at + rdata_len;
+        if rr_type != record_type.code() {
+            continue;
+        }
+        let data =
+            parse_rdata(message, rdata_at, rdata, record_type).map_err(|err| invalid(&err))?;
+        records.push(DnsRecord { ttl, data });
+    }
+    Ok(records)
+}
+
+fn parse_rdata(
+    message: &[u8],
+    rdata_at: usize,
+    rdata: &[u8],
+    record_type: RecordType,
+) -> Result<RecordData, String> {
+    let u16_at = |at: usize| -> Result<u16, String> {
+        match rdata.get(at..at + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+            None => Err("short record".into()),
+        }
+    };
+    match record_type {
+        RecordType::A => {
+            let octets = <[u8; 4]>::try_from(rdata).map_err(|_| "A record of the wrong length")?;
+            Ok(RecordData::Address(IpAddr::V4(Ipv4Addr::from(octets))))
+        }
+        RecordType::Aaaa => {
+            let octets =
+                <[u8; 16]>::try_from(rdata).map_err(|_| "AAAA record of the wrong length")?;
+            Ok(RecordData::Address(IpAddr::V6(Ipv6Addr::from(octets))))
+        }
+        RecordType::Srv => Ok(RecordData::Srv {
+            priority: u16_at(0)?,
+            weight: u16_at(2)?,
+            port: u16_at(4)?,
+            target: read_name(message, rdata_at + 6)?.0,
+        }),
+        RecordType::Naptr => {
+            let (flags, at) = read_character_string(rdata, 4)?;
+            let (services, at) = read_character_string(rdata, at)?;
+            let (_regexp, at) = read_character_string(rdata, at)?;
+            Ok(RecordData::Naptr {
+                order: u16_at(0)?,
+                preference: u16_at(2)?,
+                flags,
+                services,
+                replacement: read_name(message, rdata_at + at)?.0,
+            })
+        }
+    }
+}
+
+fn read_character_string(bytes: &[u8], at: usize) -> Result<(String, usize), String> {
+    let len = *bytes.get(at).ok_or("short record")? as usize;
+    let text = bytes.get(at + 1..at + 1 + len).ok_or("short record")?;
+    Ok((String::from_utf8_lossy(text).into_owned(), at + 1 + len))
+}
+
+/// A possibly compressed name, and where whatever follows it starts.
+fn read_name(message: &[u8], start: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = vec![];
+    let mut at = start;
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(at).ok_or("name runs off the end")? as usize;
+        if len & 0xc0 == 0xc0 {
+            let low = *message.get(at + 1).ok_or("name runs off the end")? as usize;
+            end.get_or_insert(at + 2);
+            pointers += 1;
+            if pointers > MAX_NAME_POINTERS {
+                return Err("name compression loop".into());
+            }
+            at = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        if len == 0 {
+            end.get_or_insert(at + 1);
+            break;
+        }
+        let label = message
+            .get(at + 1..at + 1 + len)
+            .ok_or("name runs off the end")?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        at += 1 + len;
+    }
+    Ok((labels.join("."), end.unwrap_or(at + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0xbeef;
+    // Points back at the name in the question.
+    const QUESTION_NAME: [u8; 2] = [0xc0, HEADER_BYTES as u8];
+
+    /// Our query turned into its response, with the given answers.
+    fn response(name: &str, record_type: RecordType, answers: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut message = encode_query(ID, name, record_type).unwrap();
+        message[2] |= 0x80;
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for (rr_type, rdata) in answers.iter() {
+            message.extend_from_slice(&QUESTION_NAME);
+            message.extend_from_slice(&rr_type.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&300u32.to_be_bytes());
+            message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            message.extend_from_slice(rdata);
+        }
+        message
+    }
+
+    fn records(message: &[u8], record_type: RecordType) -> Vec<DnsRecord> {
+        match parse_response(message, ID, record_type) {
+            Ok(records) => records,
+            Err(ParseError::WrongId) => panic!("wrong id"),
+            Err(ParseError::Invalid(err)) => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn encodes_a_query() {
+        let query = encode_query(0x1234, "sip.example.com.", RecordType::Srv).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x03sip\x07example\x03com\x00\x00\x21\x00\x01");
+        assert_eq!(query, expected);
+        assert!(encode_query(1, "a..b", RecordType::A).is_err());
+        assert!(encode_query(1, &"x".repeat(64), RecordType::A).is_err());
+    }
+
+    #[test]
+    fn reads_addresses_and_skips_other_types() {
+        let cname = b"\x03www\xc0\x0c".to_vec();
+        let message = response(
+            "example.com",
+            RecordType::A,
+            &[(5, cname), (1, vec![192, 0, 2, 1])],
+        );
+        let records = records(&message, RecordType::A);
+        assert_eq!(
+            records,
+            vec![DnsRecord {
+                ttl: 300,
+                data: RecordData::Address("192.0.2.1".parse().unwrap()),
+            }]
+        );
+    }
+
+    #[test]
+    fn reads_srv_and_naptr_records() {
+        let mut srv = vec![0, 10, 0, 20, 0x13, 0xc4];
+        srv.extend_from_slice(b"\x04main\xc0\x0c");
+        let message = response("example.com", RecordType::Srv, &[(33, srv)]);
+        assert_eq!(
+            records(&message, RecordType::Srv)[0].data,
+            RecordData::Srv {
+                priority: 10,
+                weight: 20,
+                port: 5060,
+                target: "main.example.com".into(),
+            }
+        );
+
+        let mut naptr = vec![0, 10, 0, 50];
+        naptr.extend_from_slice(b"\x01S\x07SIP+D2U\x00\x04_sip\x04_udp\xc0\x0c");
+        let message = response("example.com", RecordType::Naptr, &[(35, naptr)]);
+        assert_eq!(
+            records(&message, RecordType::Naptr)[0].data,
+            RecordData::Naptr {
+                order: 10,
+                preference: 50,
+                flags: "S".into(),
+                services: "SIP+D2U".into(),
+                replacement: "_sip._udp.example.com".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn treats_a_missing_name_as_no_records() {
+        let mut message = response("nowhere.example.com", RecordType::A, &[]);
+        message[3] |= RCODE_NAME_ERROR as u8;
+        assert_eq!(records(&message, RecordType::A), vec![]);
+    }
+
+    #[test]
+    fn waits_out_other_ids_and_queries() {
+        let message = response("example.com", RecordType::A, &[]);
+        assert!(matches!(
+            parse_response(&message, ID + 1, RecordType::A),
+            Err(ParseError::WrongId)
+        ));
+        let query = encode_query(ID, "example.com", RecordType::A).unwrap();
+        assert!(matches!(
+            parse_response(&query, ID, RecordType::A),
+            Err(ParseError::WrongId)
+        ));
+    }
+
+    #[test]
+    fn rejects_broken_responses() {
+        let invalid = |message: &[u8]| {
+            matches!(
+                parse_response(message, ID, RecordType::A),
+                Err(ParseError::Invalid(_))
+            )
+        };
+        let message = response("example.com", RecordType::A, &[(1, vec![192, 0, 2, 1])]);
+        assert!(invalid(&message[..message.len() - 1]));
+        assert!(invalid(&message[..5]));
+
+        let mut server_failure = response("example.com", RecordType::A, &[]);
+        server_failure[3] |= 2;
+        assert!(invalid(&server_failure));
+
+        // An answer whose name points at itself.
+        let mut looped = response("example.com", RecordType::A, &[(1, vec![192, 0, 2, 1])]);
+        let answer_at = encode_query(ID, "example.com", RecordType::A)
+            .unwrap()
+            .len();
+        looped[answer_at + 1] = answer_at as u8;
+        assert!(invalid(&looped));
+
+        let short_a = response("example.com", RecordType::A, &[(1, vec![192, 0, 2])]);
+        assert!(invalid(&short_a));
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::media::rtp::{RtpPacket, RtpSender};
+use crate::input::traits::UserInput;
+
+pub const DTMF_RELAY_CONTENT_TYPE: &str = "application/dtmf-relay";
+const DTMF_CONTENT_TYPE: &str = "application/dtmf";
+pub const DTMF_TONE_DURATION: Duration = Duration::from_millis(100);
+const DTMF_INTER_DIGIT_GAP: Duration = Duration::from_millis(50);
+// RFC 4733 section 2.5.1.4: the end packet goes out three times.
+const DTMF_END_PACKETS: u8 = 3;
+// In -dBm0, what most phones send.
+const DTMF_VOLUME: u8 = 10;
+const TELEPHONE_EVENT_BYTES: usize = 4;
+
+/// One of the 16 DTMF keys, as its RFC 4733 event code (0-9, * is 10, # is 11, A-D are 12-15).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DtmfDigit(u8);
+
+impl DtmfDigit {
+    pub fn from_event(event: u8) -> Option<DtmfDigit> {
+        if event <= 15 {
+            Some(DtmfDigit(event))
+        } else {
+            None
+        }
+    }
+
+    pub fn from_char(key: char) -> Option<DtmfDigit> {
+        match key.to_ascii_uppercase() {
+            digit @ '0'..='9' => Some(DtmfDigit(digit as u8 - b'0')),
+            '*' => Some(DtmfDigit(10)),
+            '#' => Some(DtmfDigit(11)),
+            letter @ 'A'..='D' => Some(DtmfDigit(letter as u8 - b'A' + 12)),
+            _ => None,
+        }
+    }
+
+    pub fn from_input(input: &UserInput) -> Option<DtmfDigit> {
+        match input {
+            UserInput::Number(number) => DtmfDigit::from_event(*number).filter(|d| d.0 <= 9),
+            UserInput::Star => Some(DtmfDigit(10)),
+            UserInput::Hash => Some(DtmfDigit(11)),
+            _ => None,
+        }
+    }
+
+    pub fn event(&self) -> u8 {
+        self.0
+    }
+
+    pub fn to_char(&self) -> char {
+        match self.0 {
+            0..=9 => (b'0' + self.0) as char,
+            10 => '*',
+            11 => '#',
+            _ => (b'A' + self.0 - 12) as char,
+        }
+    }
+}
+
+/// The payload of an RFC 4733 telephone-event packet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TelephoneEvent {
+    pub event: u8,
+    pub end: bool,
+    pub volume: u8,
+    /// In RTP timestamp units, from the start of the event.
+    pub duration: u16,
+}
+
+impl TelephoneEvent {
+    pub fn parse(payload: &[u8]) -> Result<TelephoneEvent, String> {
+        if payload.len() < TELEPHONE_EVENT_BYTES {
+            return Err("Telephone event too short".into());
+        }
+        Ok(TelephoneEvent {
+            event: payload[0],
+            end: payload[1] & 0x80 != 0,
+            volume: payload[1] & 0x3f,
+            duration: u16::from_be_bytes([payload[2], payload[3]]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(TELEPHONE_EVENT_BYTES);
+        bytes.push(self.event);
+        bytes.push(((self.end as u8) << 7) | (self.volume & 0x3f));
+        bytes.extend_from_slice(&self.duration.to_be_bytes());
+        bytes
+    }
+}
+
+struct ToneInProgress {
+    digit: DtmfDigit,
+    timestamp: u32,
+    started_at: Instant,
+    end_packets_sent: u8,
+}
+
+/// Turns queued digits into telephone-event packets, one tone at a time. Audio
+/// should stay quiet while `is_sending`.
+pub struct DtmfSender {
+    queue: VecDeque<DtmfDigit>,
+    current: Option<ToneInProgress>,
+    next_at: Instant,
+}
+
+impl DtmfSender {
+    pub fn new() -> DtmfSender {
+        DtmfSender {
+            queue: VecDeque::new(),
+            current: None,
+            next_at: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, digit: DtmfDigit) {
+        self.queue.push_back(digit);
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// The next packet, if one is due. Call more often than every `packet_interval`.
+    pub fn poll(
+        &mut self,
+        now: Instant,
+        sender: &mut RtpSender,
+        payload_type: u8,
+        clock_rate: u32,
+        packet_interval: Duration,
+    ) -> Option<RtpPacket> {
+        if now < self.next_at {
+            return None;
+        }
+        let samples = |duration: Duration| {
+            (duration.as_micros() as u64 * clock_rate as u64 / 1_000_000).min(u16::MAX as u64)
+                as u16
+        };
+        self.next_at = now + packet_interval;
+
+        let tone = match &mut self.current {
+            Some(tone) => tone,
+            None => {
+                let digit = self.queue.pop_front()?;
+                let timestamp = sender.next_timestamp();
+                self.current = Some(ToneInProgress {
+                    digit,
+                    timestamp,
+                    started_at: now,
+                    end_packets_sent: 0,
+                });
+                let event = TelephoneEvent {
+                    event: digit.event(),
+                    end: false,
+                    volume: DTMF_VOLUME,
+                    duration: samples(packet_interval),
+                };
+                return Some(sender.packetize_at(payload_type, event.to_bytes(), timestamp, true));
+            }
+        };
+
+        let elapsed = now - tone.started_at + packet_interval;
+        let end = elapsed >= DTMF_TONE_DURATION;
+        let event = TelephoneEvent {
+            event: tone.digit.event(),
+            end,
+            volume: DTMF_VOLUME,
+            duration: samples(elapsed.min(DTMF_TONE_DURATION)),
+        };
+        let packet = sender.packetize_at(payload_type, event.to_bytes(), tone.timestamp, false);
+        if end {
+            tone.end_packets_sent += 1;
+            if tone.end_packets_sent >= DTMF_END_PACKETS {
+                // Audio after the tone has to be timestamped after it, even if none
+                // was sent (and so skipped) while it played.
+                let played = sender.next_timestamp().wrapping_sub(tone.timestamp);
+                let duration = event.duration as u32;
+                if played < duration {
+                    sender.skip(duration - played);
+                }
+                self.current = None;
+                self.next_at = now + DTMF_INTER_DIGIT_GAP;
+            }
+        }
+        Some(packet)
+    }
+}
+
+impl Default for DtmfSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Picks digits out of incoming telephone-event packets. An event is repeated
+/// across many packets, all with its start timestamp, so each is reported once.
+pub struct DtmfReceiver {
+    last_event: Option<(u32, u32)>,
+}
+
+impl DtmfReceiver {
+    pub fn new() -> DtmfReceiver {
+        DtmfReceiver { last_event: None }
+    }
+
+    pub fn on_packet(&mut self, packet: &RtpPacket) -> Option<DtmfDigit> {
+        let event = TelephoneEvent::parse(&packet.payload).ok()?;
+        let key = (packet.ssrc, packet.timestamp);
+        if self.last_event == Some(key) {
+            return None;
+        }
+        self.last_event = Some(key);
+        DtmfDigit::from_event(event.event)
+    }
+}
+
+impl Default for DtmfReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The body of a SIP INFO carrying a digit, for when the far end has no telephone-event.
+pub fn info_body(digit: DtmfDigit) -> String {
+    format!(
+        "Signal={}\r\nDuration={}\r\n",
+        digit.to_char(),
+        DTMF_TONE_DURATION.as_millis()
+    )
+}
+
+/// The digit in an INFO body, in either of the two formats seen in the wild.
+pub fn parse_info(content_type: &str, body: &[u8]) -> Option<DtmfDigit> {
+    let body = std::str::from_utf8(body).ok()?;
+    if content_type.eq_ignore_ascii_case(DTMF_RELAY_CONTENT_TYPE) {
+        body.lines().find_map(|line| {
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next()?.trim();
+            if !key.eq_ignore_ascii_case("Signal") {
+                return None;
+            }
+            DtmfDigit::from_char(kv.next()?.trim().chars().next()?)
+        })
+    } else if content_type.eq_ignore_ascii_case(DTMF_CONTENT_TYPE) {
+        // Just the event code.
+        DtmfDigit::from_event(body.trim().parse().ok()?)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET_INTERVAL: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn maps_keys_to_events() {
+        for (key, event) in [
+            ('0', 0),
+            ('9', 9),
+            ('*', 10),
+            ('#', 11),
+            ('a', 12),
+            ('D', 15),
+        ]
+        .iter()
+        {
+            let digit = DtmfDigit::from_char(*key).unwrap();
+            assert_eq!(digit.event(), *event);
+            assert_eq!(digit.to_char(), key.to_ascii_uppercase());
+        }
+        assert_eq!(DtmfDigit::from_char('E'), None);
+        assert_eq!(DtmfDigit::from_event(16), None);
+        assert_eq!(DtmfDigit::from_input(&UserInput::Number(10)), None);
+        assert_eq!(
+            DtmfDigit::from_input(&UserInput::Hash),
+            DtmfDigit::from_char('#')
+        );
+    }
+
+    #[test]
+    fn round_trips_telephone_events() {
+        let event = TelephoneEvent {
+            event: 11,
+            end: true,
+            volume: 10,
+            duration: 800,
+        };
+        assert_eq!(TelephoneEvent::parse(&event.to_bytes()), Ok(event));
+        assert!(TelephoneEvent::parse(&[11, 0x8a, 3]).is_err());
+    }
+
+    #[test]
+    fn sends_a_tone_then_three_ends() {
+        let mut dtmf = DtmfSender::new();
+        let mut rtp = RtpSender::new();
+        let start = rtp.next_timestamp();
+        dtmf.push(DtmfDigit::from_char('5').unwrap());
+
+        let mut now = Instant::now();
+        let mut packets = vec![];
+        while let Some(packet) = dtmf.poll(now, &mut rtp, 101, 8000, PACKET_INTERVAL) {
+            packets.push(packet);
+            now += PACKET_INTERVAL;
+        }
+        assert!(!dtmf.is_sending());
+        assert!(packets[0].marker);
+        assert!(packets.iter().all(|packet| packet.timestamp == start));
+
+        let events: Vec<TelephoneEvent> = packets
+            .iter()
+            .map(|packet| TelephoneEvent::parse(&packet.payload).unwrap())
+            .collect();
+        assert!(events.iter().all(|event| event.event == 5));
+        let ends = events.iter().filter(|event| event.end).count();
+        assert_eq!(ends, DTMF_END_PACKETS as usize);
+        assert_eq!(events.last().unwrap().duration, 800);
+        // Audio carries on after the tone.
+        assert_eq!(rtp.next_timestamp(), start.wrapping_add(800));
+    }
+
+    #[test]
+    fn receives_each_event_once() {
+        let mut dtmf = DtmfSender::new();
+        let mut rtp = RtpSender::new();
+        let mut receiver = DtmfReceiver::new();
+        dtmf.push(DtmfDigit::from_char('#').unwrap());
+        dtmf.push(DtmfDigit::from_char('#').unwrap());
+
+        let mut now = Instant::now();
+        let mut received = vec![];
+        for _ in 0..50 {
+            if let Some(packet) = dtmf.poll(now, &mut rtp, 101, 8000, PACKET_INTERVAL) {
+                received.extend(receiver.on_packet(&packet));
+            }
+            now += Duration::from_millis(10);
+        }
+        assert_eq!(received.len(), 2);
+        assert!(received.iter().all(|digit| digit.to_char() == '#'));
+    }
+
+    #[test]
+    fn reads_info_bodies() {
+        let digit = DtmfDigit::from_char('7').unwrap();
+        assert_eq!(
+            parse_info(DTMF_RELAY_CONTENT_TYPE, info_body(digit).as_bytes()),
+            Some(digit)
+        );
+        assert_eq!(
+            parse_info("Application/DTMF-Relay", b"signal= *\r\nduration=160\r\n"),
+            DtmfDigit::from_char('*')
+        );
+        assert_eq!(
+            parse_info("application/dtmf", b"11\r\n"),
+            DtmfDigit::from_char('#')
+        );
+        assert_eq!(parse_info("text/plain", b"Signal=1"), None);
+    }
+}
@@ -0,0 +1,266 @@
+use std::time::{Duration, Instant};
+
+use super::rtp::{sequence_before, RtpPacket};
+
+const DEFAULT_CAPACITY_FRAMES: usize = 16;
+const MIN_DEPTH_FRAMES: usize = 1;
+// After this many frames in a row with nothing to play, stop concealing and rebuffer.
+const MAX_CONCEALED_FRAMES: u32 = 5;
+
+/// What to play for the next frame.
+#[derive(Debug, PartialEq)]
+pub enum Playout {
+    Packet(RtpPacket),
+    /// A packet is missing. The decoder should make something up (packet loss concealment).
+    Conceal,
+    /// Nothing is coming in, or we're still filling up.
+    Silence,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct JitterBufferStats {
+    pub late: u32,
+    pub duplicate: u32,
+    pub overflowed: u32,
+    pub concealed: u32,
+    pub dropped_for_latency: u32,
+}
+
+/// Reorders packets and evens out their arrival times. The playout delay follows
+/// the measured arrival jitter, between one frame and most of the capacity.
+pub struct JitterBuffer {
+    packets: Vec<RtpPacket>,
+    capacity: usize,
+    frame_duration: Duration,
+    next_sequence: Option<u16>,
+    playing: bool,
+    target_depth: usize,
+    last_arrival: Option<Instant>,
+    /// Smoothed deviation of arrival spacing from the frame duration, in microseconds.
+    arrival_jitter_us: u32,
+    concealed_in_a_row: u32,
+    stats: JitterBufferStats,
+}
+
+impl JitterBuffer {
+    pub fn new(frame_duration: Duration) -> JitterBuffer {
+        Self::with_capacity(frame_duration, DEFAULT_CAPACITY_FRAMES)
+    }
+
+    pub fn with_capacity(frame_duration: Duration, capacity: usize) -> JitterBuffer {
+        JitterBuffer {
+            packets: Vec::with_capacity(capacity),
+            capacity,
+            frame_duration,
+            next_sequence: None,
+            playing: false,
+            target_depth: MIN_DEPTH_FRAMES + 1,
+            last_arrival: None,
+            arrival_jitter_us: 0,
+            concealed_in_a_row: 0,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> JitterBufferStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn target_depth(&self) -> usize {
+        self.target_depth
+    }
+
+    pub fn reset(&mut self) {
+        self.packets.clear();
+        self.next_sequence = None;
+        self.playing = false;
+        self.last_arrival = None;
+        self.concealed_in_a_row = 0;
+    }
+
+    pub fn push(&mut self, packet: RtpPacket, now: Instant) {
+        self.update_jitter(now);
+
+        if let Some(next_sequence) = self.next_sequence {
+            if self.playing && sequence_before(packet.sequence_number, next_sequence) {
+                self.stats.late += 1;
+                return;
+            }
+        }
+        let position = self
+            .packets
+            .iter()
+            .position(|queued| !sequence_before(queued.sequence_number, packet.sequence_number));
+        let position = match position {
+            Some(position) if self.packets[position].sequence_number == packet.sequence_number => {
+                self.stats.duplicate += 1;
+                return;
+            }
+            Some(position) => position,
+            None => self.packets.len(),
+        };
+        self.packets.insert(position, packet);
+
+        if self.packets.len() > self.capacity {
+            self.packets.remove(0);
+            self.stats.overflowed += 1;
+            if let Some(first) = self.packets.first() {
+                self.next_sequence = Some(first.sequence_number);
+            }
+        }
+    }
+
+    /// Call once per frame duration.
+    pub fn pop(&mut self) -> Playout {
+        if !self.playing {
+            if self.packets.len() < self.target_depth {
+                return Playout::Silence;
+            }
+            self.playing = true;
+            self.next_sequence = self.packets.first().map(|first| first.sequence_number);
+        }
+
+        // We've fallen behind (the sender's clock runs fast, or a burst came in).
+        if self.packets.len() > self.target_depth + 2 {
+            self.packets.remove(0);
+            self.stats.dropped_for_latency += 1;
+            self.next_sequence = self.packets.first().map(|first| first.sequence_number);
+        }
+
+        let next_sequence = match self.next_sequence {
+            Some(next_sequence) => next_sequence,
+            None => return Playout::Silence,
+        };
+        self.next_sequence = Some(next_sequence.wrapping_add(1));
+
+        match self.packets.first() {
+            Some(first) if first.sequence_number == next_sequence => {
+                self.concealed_in_a_row = 0;
+                Playout::Packet(self.packets.remove(0))
+            }
+            _ => {
+                self.concealed_in_a_row += 1;
+                if self.concealed_in_a_row > MAX_CONCEALED_FRAMES && self.packets.is_empty() {
+                    // The other side went quiet (or on hold); wait for a new talkspurt.
+                    self.playing = false;
+                    self.concealed_in_a_row = 0;
+                    return Playout::Silence;
+                }
+                self.stats.concealed += 1;
+                Playout::Conceal
+            }
+        }
+    }
+
+    fn update_jitter(&mut self, now: Instant) {
+        if let Some(last_arrival) = self.last_arrival {
+            let spacing = (now - last_arrival).as_micros() as i64;
+            let deviation =
+                (spacing - self.frame_duration.as_micros() as i64).unsigned_abs() as u32;
+            // Same 1/16 smoothing RTCP uses.
+            self.arrival_jitter_us =
+                self.arrival_jitter_us - self.arrival_jitter_us / 16 + deviation / 16;
+            let frame_us = self.frame_duration.as_micros().max(1) as u32;
+            let wanted = MIN_DEPTH_FRAMES + (2 * self.arrival_jitter_us / frame_us) as usize;
+            self.target_depth = wanted
+                .min(self.capacity.saturating_sub(2))
+                .max(MIN_DEPTH_FRAMES);
+        }
+        self.last_arrival = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(20);
+
+    fn packet(sequence_number: u16) -> RtpPacket {
+        RtpPacket {
+            marker: false,
+            payload_type: 0,
+            sequence_number,
+            timestamp: sequence_number as u32 * 160,
+            ssrc: 1,
+            payload: vec![],
+        }
+    }
+
+    fn sequence(playout: Playout) -> Option<u16> {
+        match playout {
+            Playout::Packet(packet) => Some(packet.sequence_number),
+            _ => None,
+        }
+    }
+
+    /// Pushes packets a frame apart, so the target depth stays put.
+    fn push_all(buffer: &mut JitterBuffer, sequence_numbers: &[u16]) {
+        let start = Instant::now();
+        for (i, sequence_number) in sequence_numbers.iter().enumerate() {
+            buffer.push(packet(*sequence_number), start + FRAME * i as u32);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_target_depth_then_plays_in_order() {
+        let mut buffer = JitterBuffer::new(FRAME);
+        push_all(&mut buffer, &[11]);
+        assert_eq!(buffer.pop(), Playout::Silence);
+        push_all(&mut buffer, &[10]);
+        assert_eq!(sequence(buffer.pop()), Some(10));
+        assert_eq!(sequence(buffer.pop()), Some(11));
+    }
+
+    #[test]
+    fn conceals_a_missing_packet() {
+        let mut buffer = JitterBuffer::new(FRAME);
+        push_all(&mut buffer, &[1, 3]);
+        assert_eq!(sequence(buffer.pop()), Some(1));
+        assert_eq!(buffer.pop(), Playout::Conceal);
+        assert_eq!(sequence(buffer.pop()), Some(3));
+        assert_eq!(buffer.stats().concealed, 1);
+    }
+
+    #[test]
+    fn drops_late_and_duplicate_packets() {
+        let mut buffer = JitterBuffer::new(FRAME);
+        push_all(&mut buffer, &[1, 2, 2]);
+        assert_eq!(buffer.stats().duplicate, 1);
+        assert_eq!(sequence(buffer.pop()), Some(1));
+        push_all(&mut buffer, &[0]);
+        assert_eq!(buffer.stats().late, 1);
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn rebuffers_after_the_sender_goes_quiet() {
+        let mut buffer = JitterBuffer::new(FRAME);
+        push_all(&mut buffer, &[1, 2]);
+        buffer.pop();
+        buffer.pop();
+        for _ in 0..MAX_CONCEALED_FRAMES {
+            assert_eq!(buffer.pop(), Playout::Conceal);
+        }
+        assert_eq!(buffer.pop(), Playout::Silence);
+        // A new talkspurt plays from wherever it starts.
+        push_all(&mut buffer, &[500, 501]);
+        assert_eq!(sequence(buffer.pop()), Some(500));
+    }
+
+    #[test]
+    fn overflows_from_the_front() {
+        let mut buffer = JitterBuffer::with_capacity(FRAME, 4);
+        push_all(&mut buffer, &[1, 2, 3, 4, 5]);
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.stats().overflowed, 1);
+    }
+}
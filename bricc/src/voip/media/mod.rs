@@ -0,0 +1,4 @@
+pub mod jitter_buffer;
+pub mod rtcp;
+pub mod rtp;
+pub mod session;
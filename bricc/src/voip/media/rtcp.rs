@@ -0,0 +1,449 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::rtp::RTP_VERSION;
+
+const RTCP_SENDER_REPORT: u8 = 200;
+const RTCP_RECEIVER_REPORT: u8 = 201;
+const RTCP_BYE: u8 = 203;
+const REPORT_BLOCK_BYTES: usize = 24;
+// Seconds between 1900 (NTP) and 1970 (Unix).
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+
+/// The current wall clock as a 64 bit NTP timestamp.
+pub fn ntp_now() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs() + NTP_UNIX_OFFSET_SECS;
+    let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (seconds << 32) | fraction
+}
+
+/// The middle 32 bits of an NTP timestamp, the unit LSR and DLSR are in.
+pub fn ntp_middle(ntp: u64) -> u32 {
+    (ntp >> 16) as u32
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    pub fraction_lost: u8,
+    /// 24 bits on the wire.
+    pub cumulative_lost: i32,
+    pub highest_sequence: u32,
+    pub jitter: u32,
+    pub last_sender_report: u32,
+    /// In units of 1/65536 seconds.
+    pub delay_since_last_sender_report: u32,
+}
+
+impl ReportBlock {
+    fn parse(bytes: &[u8]) -> ReportBlock {
+        let word = |at: usize| {
+            u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let lost = word(4) & 0x00ff_ffff;
+        // Sign extend the 24 bit count.
+        let cumulative_lost = ((lost << 8) as i32) >> 8;
+        ReportBlock {
+            ssrc: word(0),
+            fraction_lost: bytes[4],
+            cumulative_lost,
+            highest_sequence: word(8),
+            jitter: word(12),
+            last_sender_report: word(16),
+            delay_since_last_sender_report: word(20),
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.ssrc.to_be_bytes());
+        let lost = (self.cumulative_lost as u32) & 0x00ff_ffff;
+        bytes.extend_from_slice(&(((self.fraction_lost as u32) << 24) | lost).to_be_bytes());
+        bytes.extend_from_slice(&self.highest_sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.jitter.to_be_bytes());
+        bytes.extend_from_slice(&self.last_sender_report.to_be_bytes());
+        bytes.extend_from_slice(&self.delay_since_last_sender_report.to_be_bytes());
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderInfo {
+    pub ntp_timestamp: u64,
+    pub rtp_timestamp: u32,
+    pub packet_count: u32,
+    pub octet_count: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RtcpPacket {
+    SenderReport {
+        ssrc: u32,
+        info: SenderInfo,
+        reports: Vec<ReportBlock>,
+    },
+    ReceiverReport {
+        ssrc: u32,
+        reports: Vec<ReportBlock>,
+    },
+    Bye {
+        ssrcs: Vec<u32>,
+    },
+    /// SDES, APP and friends, which we don't use.
+    Other(u8),
+}
+
+impl RtcpPacket {
+    /// Splits a compound packet into its parts.
+    pub fn parse_compound(bytes: &[u8]) -> Result<Vec<RtcpPacket>, String> {
+        let mut packets = vec![];
+        let mut at = 0;
+        while at + 4 <= bytes.len() {
+            if bytes[at] >> 6 != RTP_VERSION {
+                return Err("Not RTCP version 2".into());
+            }
+            let count = (bytes[at] & 0x1f) as usize;
+            let packet_type = bytes[at + 1];
+            let length = 4 * (u16::from_be_bytes([bytes[at + 2], bytes[at + 3]]) as usize + 1);
+            if at + length > bytes.len() {
+                return Err("RTCP packet truncated".into());
+            }
+            let body = &bytes[at + 4..at + length];
+            packets.push(Self::parse_one(packet_type, count, body)?);
+            at += length;
+        }
+        Ok(packets)
+    }
+
+    fn parse_one(packet_type: u8, count: usize, body: &[u8]) -> Result<RtcpPacket, String> {
+        let word =
+            |at: usize| u32::from_be_bytes([body[at], body[at + 1], body[at + 2], body[at + 3]]);
+        let reports_at = |start: usize| -> Result<Vec<ReportBlock>, String> {
+            if body.len() < start + count * REPORT_BLOCK_BYTES {
+                return Err("RTCP report blocks truncated".into());
+            }
+            Ok((0..count)
+                .map(|i| ReportBlock::parse(&body[start + i * REPORT_BLOCK_BYTES..]))
+                .collect())
+        };
+        match packet_type {
+            RTCP_SENDER_REPORT => {
+                if body.len() < 24 {
+                    return Err("RTCP sender report truncated".into());
+                }
+                Ok(RtcpPacket::SenderReport {
+                    ssrc: word(0),
+                    info: SenderInfo {
+                        ntp_timestamp: ((word(4) as u64) << 32) | word(8) as u64,
+                        rtp_timestamp: word(12),
+                        packet_count: word(16),
+                        octet_count: word(20),
+                    },
+                    reports: reports_at(24)?,
+                })
+            }
+            RTCP_RECEIVER_REPORT => {
+                if body.len() < 4 {
+                    return Err("RTCP receiver report truncated".into());
+                }
+                Ok(RtcpPacket::ReceiverReport {
+                    ssrc: word(0),
+                    reports: reports_at(4)?,
+                })
+            }
+            RTCP_BYE => {
+                if body.len() < count * 4 {
+                    return Err("RTCP BYE truncated".into());
+                }
+                Ok(RtcpPacket::Bye {
+                    ssrcs: (0..count).map(|i| word(i * 4)).collect(),
+                })
+            }
+            other => Ok(RtcpPacket::Other(other)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = vec![];
+        let (packet_type, count) = match self {
+            RtcpPacket::SenderReport {
+                ssrc,
+                info,
+                reports,
+            } => {
+                body.extend_from_slice(&ssrc.to_be_bytes());
+                body.extend_from_slice(&info.ntp_timestamp.to_be_bytes());
+                body.extend_from_slice(&info.rtp_timestamp.to_be_bytes());
+                body.extend_from_slice(&info.packet_count.to_be_bytes());
+                body.extend_from_slice(&info.octet_count.to_be_bytes());
+                for report in reports.iter() {
+                    report.write(&mut body);
+                }
+                (RTCP_SENDER_REPORT, reports.len())
+            }
+            RtcpPacket::ReceiverReport { ssrc, reports } => {
+                body.extend_from_slice(&ssrc.to_be_bytes());
+                for report in reports.iter() {
+                    report.write(&mut body);
+                }
+                (RTCP_RECEIVER_REPORT, reports.len())
+            }
+            RtcpPacket::Bye { ssrcs } => {
+                for ssrc in ssrcs.iter() {
+                    body.extend_from_slice(&ssrc.to_be_bytes());
+                }
+                (RTCP_BYE, ssrcs.len())
+            }
+            RtcpPacket::Other(_) => return vec![],
+        };
+        let mut bytes = Vec::with_capacity(4 + body.len());
+        bytes.push((RTP_VERSION << 6) | (count as u8 & 0x1f));
+        bytes.push(packet_type);
+        bytes.extend_from_slice(&((body.len() / 4) as u16).to_be_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+}
+
+/// What we know about the stream we receive, kept the way RFC 3550 appendix A does.
+pub struct ReceptionStats {
+    clock_rate: u32,
+    started_at: Instant,
+    ssrc: Option<u32>,
+    base_sequence: u16,
+    max_sequence: u16,
+    cycles: u32,
+    received: u32,
+    expected_prior: u32,
+    received_prior: u32,
+    last_transit: Option<i64>,
+    /// Interarrival jitter in timestamp units, times 16.
+    jitter_scaled: u32,
+    last_sender_report: Option<(u32, Instant)>,
+}
+
+impl ReceptionStats {
+    pub fn new(clock_rate: u32) -> ReceptionStats {
+        ReceptionStats {
+            clock_rate,
+            started_at: Instant::now(),
+            ssrc: None,
+            base_sequence: 0,
+            max_sequence: 0,
+            cycles: 0,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            last_transit: None,
+            jitter_scaled: 0,
+            last_sender_report: None,
+        }
+    }
+
+    pub fn ssrc(&self) -> Option<u32> {
+        self.ssrc
+    }
+
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    pub fn on_packet(&mut self, ssrc: u32, sequence_number: u16, timestamp: u32, now: Instant) {
+        if self.ssrc != Some(ssrc) {
+            // New source, start over.
+            *self = ReceptionStats::new(self.clock_rate);
+            self.ssrc = Some(ssrc);
+            self.base_sequence = sequence_number;
+            self.max_sequence = sequence_number;
+        }
+
+        let delta = sequence_number.wrapping_sub(self.max_sequence);
+        if delta < MAX_DROPOUT {
+            if sequence_number < self.max_sequence {
+                self.cycles += 1 << 16;
+            }
+            self.max_sequence = sequence_number;
+        } else if delta <= u16::MAX - MAX_MISORDER {
+            // A big jump, probably a restarted sender.
+            self.base_sequence = sequence_number;
+            self.max_sequence = sequence_number;
+            self.cycles = 0;
+            self.received = 0;
+            self.expected_prior = 0;
+            self.received_prior = 0;
+        }
+        self.received += 1;
+
+        let arrival =
+            (now - self.started_at).as_micros() as i64 * self.clock_rate as i64 / 1_000_000;
+        let transit = arrival - timestamp as i64;
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit - last_transit).unsigned_abs() as u32;
+            // J += (|D| - J) / 16, kept scaled by 16 to stay in integers.
+            self.jitter_scaled = self.jitter_scaled + d - ((self.jitter_scaled + 8) >> 4);
+        }
+        self.last_transit = Some(transit);
+    }
+
+    /// Interarrival jitter in timestamp units.
+    pub fn jitter(&self) -> u32 {
+        self.jitter_scaled >> 4
+    }
+
+    pub fn extended_highest_sequence(&self) -> u32 {
+        self.cycles + self.max_sequence as u32
+    }
+
+    pub fn expected(&self) -> u32 {
+        self.extended_highest_sequence()
+            .wrapping_sub(self.base_sequence as u32)
+            .wrapping_add(1)
+    }
+
+    pub fn cumulative_lost(&self) -> i32 {
+        self.expected() as i32 - self.received as i32
+    }
+
+    pub fn on_sender_report(&mut self, ntp_timestamp: u64, now: Instant) {
+        self.last_sender_report = Some((ntp_middle(ntp_timestamp), now));
+    }
+
+    /// A report block covering everything since the last one.
+    pub fn report_block(&mut self, now: Instant) -> Option<ReportBlock> {
+        let ssrc = self.ssrc?;
+        let expected = self.expected();
+        let expected_interval = expected.wrapping_sub(self.expected_prior);
+        let received_interval = self.received.wrapping_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+        let lost_interval = expected_interval as i64 - received_interval as i64;
+        let fraction_lost = if expected_interval == 0 || lost_interval <= 0 {
+            0
+        } else {
+            ((lost_interval << 8) / expected_interval as i64) as u8
+        };
+        let (last_sender_report, delay_since_last_sender_report) = match self.last_sender_report {
+            Some((lsr, received_at)) => {
+                let delay = now - received_at;
+                (lsr, (delay.as_micros() as u64 * 65536 / 1_000_000) as u32)
+            }
+            None => (0, 0),
+        };
+        Some(ReportBlock {
+            ssrc,
+            fraction_lost,
+            cumulative_lost: self.cumulative_lost(),
+            highest_sequence: self.extended_highest_sequence(),
+            jitter: self.jitter(),
+            last_sender_report,
+            delay_since_last_sender_report,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn report() -> ReportBlock {
+        ReportBlock {
+            ssrc: 0x0102_0304,
+            fraction_lost: 64,
+            cumulative_lost: -3,
+            highest_sequence: 0x0001_0005,
+            jitter: 12,
+            last_sender_report: 0x1111_2222,
+            delay_since_last_sender_report: 65536,
+        }
+    }
+
+    #[test]
+    fn round_trips_a_compound_packet() {
+        let packets = vec![
+            RtcpPacket::SenderReport {
+                ssrc: 42,
+                info: SenderInfo {
+                    ntp_timestamp: 0x0123_4567_89ab_cdef,
+                    rtp_timestamp: 8000,
+                    packet_count: 50,
+                    octet_count: 8000,
+                },
+                reports: vec![report()],
+            },
+            RtcpPacket::Bye {
+                ssrcs: vec![42, 43],
+            },
+        ];
+        let bytes: Vec<u8> = packets
+            .iter()
+            .flat_map(|packet| packet.to_bytes())
+            .collect();
+        assert_eq!(RtcpPacket::parse_compound(&bytes), Ok(packets));
+    }
+
+    #[test]
+    fn keeps_unknown_packets_and_rejects_truncated_ones() {
+        // An empty SDES.
+        let sdes = [0x80, 202, 0, 0];
+        assert_eq!(
+            RtcpPacket::parse_compound(&sdes),
+            Ok(vec![RtcpPacket::Other(202)])
+        );
+
+        let receiver_report = RtcpPacket::ReceiverReport {
+            ssrc: 7,
+            reports: vec![report()],
+        }
+        .to_bytes();
+        let cut = receiver_report.len() - 4;
+        assert!(RtcpPacket::parse_compound(&receiver_report[..cut]).is_err());
+        assert!(RtcpPacket::parse_compound(&[0x40, 201, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn counts_losses_across_wraparound() {
+        let mut stats = ReceptionStats::new(8000);
+        let now = Instant::now();
+        for (i, sequence_number) in [0xfffe, 0xffff, 1, 2].iter().enumerate() {
+            stats.on_packet(9, *sequence_number, 160 * i as u32, now);
+        }
+        assert_eq!(stats.extended_highest_sequence(), 0x0001_0002);
+        assert_eq!(stats.expected(), 5);
+        assert_eq!(stats.cumulative_lost(), 1);
+
+        let block = stats.report_block(now).unwrap();
+        assert_eq!(block.ssrc, 9);
+        // One in five, in 1/256ths.
+        assert_eq!(block.fraction_lost, 51);
+        // Nothing new since the last report.
+        assert_eq!(stats.report_block(now).unwrap().fraction_lost, 0);
+    }
+
+    #[test]
+    fn starts_over_for_a_new_source() {
+        let mut stats = ReceptionStats::new(8000);
+        let now = Instant::now();
+        stats.on_packet(1, 100, 0, now);
+        stats.on_packet(1, 105, 800, now);
+        stats.on_packet(2, 7, 0, now);
+        assert_eq!(stats.ssrc(), Some(2));
+        assert_eq!(stats.received(), 1);
+        assert_eq!(stats.cumulative_lost(), 0);
+    }
+
+    #[test]
+    fn measures_delay_since_the_sender_report() {
+        let mut stats = ReceptionStats::new(8000);
+        let then = Instant::now();
+        stats.on_packet(1, 100, 0, then);
+        stats.on_sender_report(0x0000_1234_5678_0000, then);
+        let block = stats
+            .report_block(then + Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(block.last_sender_report, 0x1234_5678);
+        assert_eq!(block.delay_since_last_sender_report, 32768);
+    }
+}
@@ -0,0 +1,237 @@
+pub const RTP_VERSION: u8 = 2;
+pub const RTP_HEADER_BYTES: usize = 12;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpPacket {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: Vec<u8>,
+}
+
+impl RtpPacket {
+    /// Parses an RFC 3550 packet. CSRCs and header extensions are skipped, not kept.
+    pub fn parse(bytes: &[u8]) -> Result<RtpPacket, String> {
+        if bytes.len() < RTP_HEADER_BYTES {
+            return Err("RTP packet too short".into());
+        }
+        if bytes[0] >> 6 != RTP_VERSION {
+            return Err("Not RTP version 2".into());
+        }
+        let has_padding = bytes[0] & 0x20 != 0;
+        let has_extension = bytes[0] & 0x10 != 0;
+        let csrc_count = (bytes[0] & 0x0f) as usize;
+
+        let mut payload_start = RTP_HEADER_BYTES + 4 * csrc_count;
+        if has_extension {
+            if bytes.len() < payload_start + 4 {
+                return Err("RTP extension header truncated".into());
+            }
+            let extension_words =
+                u16::from_be_bytes([bytes[payload_start + 2], bytes[payload_start + 3]]) as usize;
+            payload_start += 4 + 4 * extension_words;
+        }
+        let mut payload_end = bytes.len();
+        if has_padding {
+            let padding = bytes[bytes.len() - 1] as usize;
+            payload_end = payload_end.saturating_sub(padding);
+        }
+        if payload_start > payload_end {
+            return Err("RTP packet truncated".into());
+        }
+
+        Ok(RtpPacket {
+            marker: bytes[1] & 0x80 != 0,
+            payload_type: bytes[1] & 0x7f,
+            sequence_number: u16::from_be_bytes([bytes[2], bytes[3]]),
+            timestamp: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            ssrc: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            payload: bytes[payload_start..payload_end].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RTP_HEADER_BYTES + self.payload.len());
+        bytes.push(RTP_VERSION << 6);
+        bytes.push(((self.marker as u8) << 7) | (self.payload_type & 0x7f));
+        bytes.extend_from_slice(&self.sequence_number.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.ssrc.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wraparound.
+pub fn sequence_before(a: u16, b: u16) -> bool {
+    a != b && (b.wrapping_sub(a) as i16) > 0
+}
+
+/// Numbers and timestamps the packets of one outgoing stream.
+pub struct RtpSender {
+    ssrc: u32,
+    sequence_number: u16,
+    timestamp: u32,
+    packets_sent: u32,
+    octets_sent: u32,
+    last_timestamp: u32,
+}
+
+impl RtpSender {
+    pub fn new() -> RtpSender {
+        // Random starting points, RFC 3550 section 5.1.
+        let timestamp = rand::random();
+        RtpSender {
+            ssrc: rand::random(),
+            sequence_number: rand::random(),
+            timestamp,
+            packets_sent: 0,
+            octets_sent: 0,
+            last_timestamp: timestamp,
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    pub fn packets_sent(&self) -> u32 {
+        self.packets_sent
+    }
+
+    pub fn octets_sent(&self) -> u32 {
+        self.octets_sent
+    }
+
+    /// The RTP timestamp of the last packet sent, for sender reports.
+    pub fn last_timestamp(&self) -> u32 {
+        self.last_timestamp
+    }
+
+    /// Wraps a payload covering `samples` samples and moves the clock past it.
+    pub fn packetize(
+        &mut self,
+        payload_type: u8,
+        payload: Vec<u8>,
+        samples: u32,
+        marker: bool,
+    ) -> RtpPacket {
+        let packet = RtpPacket {
+            marker,
+            payload_type,
+            sequence_number: self.sequence_number,
+            timestamp: self.timestamp,
+            ssrc: self.ssrc,
+            payload,
+        };
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.last_timestamp = self.timestamp;
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        self.packets_sent = self.packets_sent.wrapping_add(1);
+        self.octets_sent = self.octets_sent.wrapping_add(packet.payload.len() as u32);
+        packet
+    }
+
+    /// Like `packetize`, but keeps the timestamp where it is. Packets that are part of one
+    /// event (RFC 4733) all carry the timestamp of its start.
+    pub fn packetize_at(
+        &mut self,
+        payload_type: u8,
+        payload: Vec<u8>,
+        timestamp: u32,
+        marker: bool,
+    ) -> RtpPacket {
+        let packet = RtpPacket {
+            marker,
+            payload_type,
+            sequence_number: self.sequence_number,
+            timestamp,
+            ssrc: self.ssrc,
+            payload,
+        };
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.packets_sent = self.packets_sent.wrapping_add(1);
+        self.octets_sent = self.octets_sent.wrapping_add(packet.payload.len() as u32);
+        packet
+    }
+
+    /// The timestamp the next packet will get.
+    pub fn next_timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Lets the clock run on without sending, e.g. while on hold or muted.
+    pub fn skip(&mut self, samples: u32) {
+        self.timestamp = self.timestamp.wrapping_add(samples);
+    }
+}
+
+impl Default for RtpSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let packet = RtpPacket {
+            marker: true,
+            payload_type: 8,
+            sequence_number: 0xfffe,
+            timestamp: 0xdead_beef,
+            ssrc: 0x1234_5678,
+            payload: vec![0xd5; 160],
+        };
+        assert_eq!(RtpPacket::parse(&packet.to_bytes()), Ok(packet));
+    }
+
+    #[test]
+    fn skips_csrcs_extensions_and_padding() {
+        let mut bytes = vec![0xb1, 0x00, 0x00, 0x01, 0, 0, 0, 2, 0, 0, 0, 3];
+        bytes.extend_from_slice(&[0, 0, 0, 4]); // one CSRC
+        bytes.extend_from_slice(&[0xbe, 0xde, 0x00, 0x01, 1, 2, 3, 4]); // one word extension
+        bytes.extend_from_slice(&[0x7f, 0x7f, 0, 0, 3]); // payload, then 3 padding bytes
+        let packet = RtpPacket::parse(&bytes).unwrap();
+        assert_eq!(packet.sequence_number, 1);
+        assert_eq!(packet.ssrc, 3);
+        assert_eq!(packet.payload, vec![0x7f, 0x7f]);
+    }
+
+    #[test]
+    fn rejects_what_isnt_rtp() {
+        assert!(RtpPacket::parse(&[0x80; 11]).is_err());
+        assert!(RtpPacket::parse(&[0x40; 12]).is_err());
+        // Claims an extension it doesn't have.
+        assert!(RtpPacket::parse(&[0x90, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]).is_err());
+    }
+
+    #[test]
+    fn orders_sequence_numbers_across_wraparound() {
+        assert!(sequence_before(1, 2));
+        assert!(sequence_before(0xffff, 0));
+        assert!(!sequence_before(0, 0xffff));
+        assert!(!sequence_before(7, 7));
+    }
+
+    #[test]
+    fn sender_counts_and_keeps_event_timestamps() {
+        let mut sender = RtpSender::new();
+        let start = sender.next_timestamp();
+        let first = sender.packetize(0, vec![0; 160], 160, true);
+        let second = sender.packetize_at(101, vec![0; 4], start, false);
+        assert_eq!(
+            second.sequence_number,
+            first.sequence_number.wrapping_add(1)
+        );
+        assert_eq!(second.timestamp, start);
+        assert_eq!(sender.next_timestamp(), start.wrapping_add(160));
+        assert_eq!(sender.packets_sent(), 2);
+        assert_eq!(sender.octets_sent(), 164);
+    }
+}
@@ -0,0 +1,665 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::jitter_buffer::{JitterBuffer, Playout};
+use super::rtcp::{ntp_middle, ntp_now, ReceptionStats, RtcpPacket, SenderInfo};
+use super::rtp::{RtpPacket, RtpSender};
+use crate::audio::resample;
+use crate::audio::traits::{CaptureStream, PlaybackStream};
+use crate::voip::codec::{create_codec, Codec};
+use crate::voip::dtmf::{DtmfDigit, DtmfReceiver, DtmfSender};
+use crate::voip::negotiation::NegotiatedSession;
+use crate::voip::stun::{binding_request, is_stun, TransactionId, STUN_KEEPALIVE_INTERVAL};
+
+const MEDIA_THREAD_STACK_SIZE_BYTES: usize = 12288usize;
+const MEDIA_RECEIVE_BUFFER_BYTES: usize = 1500usize;
+const MEDIA_SOCKET_POLL_PERIOD: Duration = Duration::from_millis(2);
+const RTCP_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// Frames waiting for the audio side; beyond this we drop rather than grow.
+const PLAYOUT_QUEUE_FRAMES: usize = 8;
+const CAPTURE_CHUNK_SAMPLES: usize = 160;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MediaStats {
+    pub packets_sent: u32,
+    pub packets_received: u32,
+    pub packets_lost: i32,
+    /// Interarrival jitter of what we receive, in milliseconds.
+    pub jitter_ms: u32,
+    /// What the other side last told us about our stream.
+    pub remote_fraction_lost: u8,
+    pub round_trip: Option<Duration>,
+}
+
+pub enum MediaCommand {
+    /// One frame of PCM at the codec's sample rate.
+    SendAudio(Vec<i16>),
+    /// A packet the caller already built, e.g. RFC 4733 events that keep their own timestamp.
+    SendPacket(Box<dyn FnOnce(&mut RtpSender) -> RtpPacket + Send>),
+    /// Queue an RFC 4733 telephone-event. Only for sessions that negotiated one.
+    SendDtmf(DtmfDigit),
+    Update(NegotiatedSession),
+    /// From now on, take the mic from here and play to there instead of `receive`.
+    AttachAudio(Box<dyn CaptureStream>, Box<dyn PlaybackStream>),
+    /// Let go of the mic and speaker again, e.g. when the call goes on hold for another.
+    DetachAudio,
+    Stop,
+}
+
+/// The RTP and RTCP streams of one call, run on their own thread.
+pub struct MediaSession {
+    thread: JoinHandle<()>,
+    command_sender: Sender<MediaCommand>,
+    playout_receiver: Receiver<Vec<i16>>,
+    dtmf_receiver: Receiver<DtmfDigit>,
+    stats: Arc<Mutex<MediaStats>>,
+    sample_rate: u32,
+    frame_samples: usize,
+}
+
+fn codec_for(session: &NegotiatedSession) -> Result<Box<dyn Codec>, String> {
+    create_codec(&session.encoding, session.clock_rate)
+        .ok_or_else(|| format!("No codec for {}/{}", session.encoding, session.clock_rate))
+}
+
+impl MediaSession {
+    /// Binds the RTP port from the negotiated session, and the RTCP port above it.
+    /// With a STUN server, both get refreshed there so the NAT keeps their mappings.
+    pub fn start(
+        session: NegotiatedSession,
+        stun_server: Option<SocketAddr>,
+    ) -> Result<MediaSession, String> {
+        let codec = codec_for(&session)?;
+        let sample_rate = codec.sample_rate();
+        let frame_samples = codec.samples_per_packet(Duration::from_millis(session.ptime as u64));
+        let rtp_socket = UdpSocket::bind(("0.0.0.0", session.local_port))
+            .map_err(|err| format!("Can't bind RTP port {}: {}", session.local_port, err))?;
+        rtp_socket
+            .set_read_timeout(Some(MEDIA_SOCKET_POLL_PERIOD))
+            .map_err(|err| err.to_string())?;
+        let rtcp_port = session
+            .local_port
+            .checked_add(1)
+            .ok_or_else(|| format!("No RTCP port above RTP port {}", session.local_port))?;
+        let rtcp_socket = UdpSocket::bind(("0.0.0.0", rtcp_port))
+            .map_err(|err| format!("Can't bind RTCP port {}: {}", rtcp_port, err))?;
+        rtcp_socket
+            .set_nonblocking(true)
+            .map_err(|err| err.to_string())?;
+
+        // Codecs may keep state, so each direction gets its own.
+        let decoder = codec_for(&session)?;
+        let (command_sender, command_receiver) = channel::<MediaCommand>();
+        let (playout_sender, playout_receiver) = sync_channel::<Vec<i16>>(PLAYOUT_QUEUE_FRAMES);
+        let (dtmf_sender, dtmf_receiver) = channel::<DtmfDigit>();
+        let stats = Arc::new(Mutex::new(MediaStats::default()));
+        let thread_stats = stats.clone();
+        let thread_builder = thread::Builder::new().stack_size(MEDIA_THREAD_STACK_SIZE_BYTES);
+
+        let thread = thread_builder
+            .spawn(move || {
+                MediaThread::new(
+                    session,
+                    rtp_socket,
+                    rtcp_socket,
+                    command_receiver,
+                    playout_sender,
+                    dtmf_sender,
+                    thread_stats,
+                    codec,
+                    decoder,
+                    stun_server,
+                )
+                .run();
+            })
+            .map_err(|err| format!("Failed to create media thread: {}", err))?;
+
+        Ok(MediaSession {
+            thread,
+            command_sender,
+            playout_receiver,
+            dtmf_receiver,
+            stats,
+            sample_rate,
+            frame_samples,
+        })
+    }
+
+    /// The PCM rate audio has to be captured and played at.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// How many samples `send_audio` wants at a time, and `receive` hands out.
+    pub fn frame_samples(&self) -> usize {
+        self.frame_samples
+    }
+
+    pub fn send_audio(&self, pcm: Vec<i16>) {
+        let _ = self.command_sender.send(MediaCommand::SendAudio(pcm));
+    }
+
+    pub fn send_packet<F: FnOnce(&mut RtpSender) -> RtpPacket + Send + 'static>(&self, build: F) {
+        let _ = self
+            .command_sender
+            .send(MediaCommand::SendPacket(Box::new(build)));
+    }
+
+    pub fn send_dtmf(&self, digit: DtmfDigit) {
+        let _ = self.command_sender.send(MediaCommand::SendDtmf(digit));
+    }
+
+    pub fn update(&mut self, session: NegotiatedSession) {
+        if let Ok(codec) = codec_for(&session) {
+            self.sample_rate = codec.sample_rate();
+            self.frame_samples =
+                codec.samples_per_packet(Duration::from_millis(session.ptime as u64));
+        }
+        let _ = self.command_sender.send(MediaCommand::Update(session));
+    }
+
+    pub fn attach_audio(&self, capture: Box<dyn CaptureStream>, playback: Box<dyn PlaybackStream>) {
+        let _ = self
+            .command_sender
+            .send(MediaCommand::AttachAudio(capture, playback));
+    }
+
+    pub fn detach_audio(&self) {
+        let _ = self.command_sender.send(MediaCommand::DetachAudio);
+    }
+
+    /// The next frame of decoded PCM to play, if one is due.
+    pub fn receive(&self) -> Option<Vec<i16>> {
+        self.playout_receiver.try_recv().ok()
+    }
+
+    /// A key the far end pressed, from its telephone-events.
+    pub fn received_dtmf(&self) -> Option<DtmfDigit> {
+        self.dtmf_receiver.try_recv().ok()
+    }
+
+    pub fn stats(&self) -> MediaStats {
+        match self.stats.lock() {
+            Ok(stats) => *stats,
+            Err(_) => MediaStats::default(),
+        }
+    }
+
+    pub fn stop(self) {
+        let _ = self.command_sender.send(MediaCommand::Stop);
+        if self.thread.join().is_err() {
+            println!("Media thread panicked");
+        }
+    }
+}
+
+struct MediaThread {
+    session: NegotiatedSession,
+    rtp_socket: UdpSocket,
+    rtcp_socket: UdpSocket,
+    command_receiver: Receiver<MediaCommand>,
+    playout_sender: SyncSender<Vec<i16>>,
+    dtmf_out: Sender<DtmfDigit>,
+    stats: Arc<Mutex<MediaStats>>,
+    encoder: Box<dyn Codec>,
+    decoder: Box<dyn Codec>,
+    capture: Option<Box<dyn CaptureStream>>,
+    playback: Option<Box<dyn PlaybackStream>>,
+    captured: Vec<i16>,
+    sender: RtpSender,
+    dtmf_sender: DtmfSender,
+    dtmf_receiver: DtmfReceiver,
+    sent_since_report: bool,
+    reception: ReceptionStats,
+    jitter_buffer: JitterBuffer,
+    next_playout: Instant,
+    next_report: Instant,
+    talkspurt: bool,
+    stun_server: Option<SocketAddr>,
+    next_stun_refresh: Instant,
+}
+
+impl MediaThread {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        session: NegotiatedSession,
+        rtp_socket: UdpSocket,
+        rtcp_socket: UdpSocket,
+        command_receiver: Receiver<MediaCommand>,
+        playout_sender: SyncSender<Vec<i16>>,
+        dtmf_out: Sender<DtmfDigit>,
+        stats: Arc<Mutex<MediaStats>>,
+        encoder: Box<dyn Codec>,
+        decoder: Box<dyn Codec>,
+        stun_server: Option<SocketAddr>,
+    ) -> MediaThread {
+        let frame_duration = Duration::from_millis(session.ptime as u64);
+        let now = Instant::now();
+        MediaThread {
+            encoder,
+            decoder,
+            capture: None,
+            playback: None,
+            captured: vec![],
+            reception: ReceptionStats::new(session.clock_rate),
+            jitter_buffer: JitterBuffer::new(frame_duration),
+            session,
+            rtp_socket,
+            rtcp_socket,
+            command_receiver,
+            playout_sender,
+            dtmf_out,
+            stats,
+            sender: RtpSender::new(),
+            dtmf_sender: DtmfSender::new(),
+            dtmf_receiver: DtmfReceiver::new(),
+            sent_since_report: false,
+            next_playout: now + frame_duration,
+            next_report: now + RTCP_REPORT_INTERVAL,
+            talkspurt: false,
+            stun_server,
+            // The mapping is fresh from the call setup.
+            next_stun_refresh: now + STUN_KEEPALIVE_INTERVAL,
+        }
+    }
+
+    fn frame_duration(&self) -> Duration {
+        Duration::from_millis(self.session.ptime as u64)
+    }
+
+    /// None when the remote RTP port is the last one, leaving nowhere for RTCP.
+    fn rtcp_addr(&self) -> Option<SocketAddr> {
+        let remote = self.session.remote_addr;
+        let port = remote.port().checked_add(1)?;
+        Some(SocketAddr::new(remote.ip(), port))
+    }
+
+    fn run(mut self) {
+        let mut buffer = vec![0u8; MEDIA_RECEIVE_BUFFER_BYTES];
+        loop {
+            loop {
+                match self.command_receiver.try_recv() {
+                    Ok(MediaCommand::SendAudio(pcm)) => self.send_audio(&pcm),
+                    Ok(MediaCommand::SendPacket(build)) => {
+                        let packet = build(&mut self.sender);
+                        self.send_rtp(&packet);
+                    }
+                    Ok(MediaCommand::SendDtmf(digit)) => self.dtmf_sender.push(digit),
+                    Ok(MediaCommand::Update(session)) => self.update(session),
+                    Ok(MediaCommand::AttachAudio(capture, playback)) => {
+                        self.capture = Some(capture);
+                        self.playback = Some(playback);
+                        self.captured.clear();
+                    }
+                    Ok(MediaCommand::DetachAudio) => {
+                        self.capture = None;
+                        self.playback = None;
+                        self.captured.clear();
+                    }
+                    Ok(MediaCommand::Stop) | Err(TryRecvError::Disconnected) => {
+                        self.send_bye();
+                        return;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            self.pump_dtmf();
+            self.pump_capture();
+
+            let now = Instant::now();
+            if let Ok((len, _)) = self.rtp_socket.recv_from(&mut buffer) {
+                self.on_rtp(&buffer[..len], now);
+            }
+            while let Ok((len, _)) = self.rtcp_socket.recv_from(&mut buffer) {
+                self.on_rtcp(&buffer[..len], now);
+            }
+
+            let now = Instant::now();
+            if now >= self.next_playout {
+                self.next_playout += self.frame_duration();
+                // Don't try to catch up after a stall, just carry on from here.
+                if self.next_playout < now {
+                    self.next_playout = now + self.frame_duration();
+                }
+                if self.session.direction.receives() {
+                    let pcm = self.play_next();
+                    match &mut self.playback {
+                        Some(playback) => {
+                            let pcm = resample(
+                                &pcm,
+                                self.decoder.sample_rate(),
+                                playback.format().sample_rate,
+                            );
+                            playback.write(&pcm);
+                        }
+                        // A full queue means nobody is listening; dropping is fine.
+                        None => {
+                            let _ = self.playout_sender.try_send(pcm);
+                        }
+                    }
+                }
+            }
+            if now >= self.next_report {
+                self.next_report = now + RTCP_REPORT_INTERVAL;
+                self.send_report(now);
+            }
+            if now >= self.next_stun_refresh {
+                self.next_stun_refresh = now + STUN_KEEPALIVE_INTERVAL;
+                self.refresh_mappings();
+            }
+        }
+    }
+
+    fn pump_dtmf(&mut self) {
+        let payload_type = match self.session.telephone_event {
+            Some(payload_type) if self.session.direction.sends() => payload_type,
+            _ => return,
+        };
+        let frame_duration = self.frame_duration();
+        if let Some(packet) = self.dtmf_sender.poll(
+            Instant::now(),
+            &mut self.sender,
+            payload_type,
+            self.session.clock_rate,
+            frame_duration,
+        ) {
+            self.send_rtp(&packet);
+        }
+    }
+
+    /// Moves whatever the mic has into packets, a frame at a time.
+    fn pump_capture(&mut self) {
+        let capture = match &mut self.capture {
+            Some(capture) => capture,
+            None => return,
+        };
+        let capture_rate = capture.format().sample_rate;
+        let mut chunk = [0i16; CAPTURE_CHUNK_SAMPLES];
+        loop {
+            let read = capture.read(&mut chunk);
+            if read == 0 {
+                break;
+            }
+            self.captured.extend(resample(
+                &chunk[..read],
+                capture_rate,
+                self.encoder.sample_rate(),
+            ));
+        }
+        let frame_samples = self.encoder.samples_per_packet(self.frame_duration());
+        while frame_samples > 0 && self.captured.len() >= frame_samples {
+            let frame: Vec<i16> = self.captured.drain(..frame_samples).collect();
+            self.send_audio(&frame);
+        }
+    }
+
+    fn play_next(&mut self) -> Vec<i16> {
+        let samples = self.decoder.samples_per_packet(self.frame_duration());
+        let mut pcm = Vec::with_capacity(samples);
+        match self.jitter_buffer.pop() {
+            Playout::Packet(packet) => self.decoder.decode(&packet.payload, &mut pcm),
+            Playout::Conceal => self.decoder.conceal(samples, &mut pcm),
+            Playout::Silence => pcm.resize(samples, 0),
+        }
+        pcm
+    }
+
+    fn update(&mut self, session: NegotiatedSession) {
+        if session.encoding != self.session.encoding
+            || session.clock_rate != self.session.clock_rate
+        {
+            match (codec_for(&session), codec_for(&session)) {
+                (Ok(encoder), Ok(decoder)) => {
+                    self.encoder = encoder;
+                    self.decoder = decoder;
+                }
+                (Err(err), _) | (_, Err(err)) => println!("Keeping old codec: {}", err),
+            }
+            self.jitter_buffer = JitterBuffer::new(Duration::from_millis(session.ptime as u64));
+            self.reception = ReceptionStats::new(session.clock_rate);
+        }
+        self.session = session;
+    }
+
+    fn send_audio(&mut self, pcm: &[i16]) {
+        // Timestamps count in the RTP clock, which can differ from the PCM rate.
+        let samples = (pcm.len() as u64 * self.encoder.clock_rate() as u64
+            / self.encoder.sample_rate().max(1) as u64) as u32;
+        // A tone is playing; the far end would hear both otherwise.
+        if !self.session.direction.sends() || self.dtmf_sender.is_sending() {
+            // Keep the clock running so the timestamps make sense when we resume.
+            self.sender.skip(samples);
+            self.talkspurt = false;
+            return;
+        }
+        let marker = !self.talkspurt;
+        self.talkspurt = true;
+        let mut payload = Vec::with_capacity(pcm.len());
+        self.encoder.encode(pcm, &mut payload);
+        let packet = self
+            .sender
+            .packetize(self.session.payload_type, payload, samples, marker);
+        self.send_rtp(&packet);
+    }
+
+    fn send_rtp(&mut self, packet: &RtpPacket) {
+        if let Err(err) = self
+            .rtp_socket
+            .send_to(&packet.to_bytes(), self.session.remote_addr)
+        {
+            println!("Failed to send RTP: {}", err);
+        }
+        self.sent_since_report = true;
+    }
+
+    /// Held calls don't send RTP, so without this the NAT could forget where the far
+    /// end's packets go. Nobody cares what the answers say.
+    fn refresh_mappings(&self) {
+        let server = match self.stun_server {
+            Some(server) => server,
+            None => return,
+        };
+        for socket in [&self.rtp_socket, &self.rtcp_socket].iter() {
+            let request = binding_request(&rand::random::<TransactionId>());
+            if let Err(err) = socket.send_to(&request, server) {
+                println!("Failed to refresh media NAT mapping: {}", err);
+            }
+        }
+    }
+
+    fn on_rtp(&mut self, bytes: &[u8], now: Instant) {
+        // Answers to `refresh_mappings`.
+        if is_stun(bytes) {
+            return;
+        }
+        let packet = match RtpPacket::parse(bytes) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        let is_event = Some(packet.payload_type) == self.session.telephone_event;
+        if packet.payload_type != self.session.payload_type && !is_event {
+            return;
+        }
+        self.reception
+            .on_packet(packet.ssrc, packet.sequence_number, packet.timestamp, now);
+        // Event packets leave a gap in the audio, which concealment covers.
+        if is_event {
+            if let Some(digit) = self.dtmf_receiver.on_packet(&packet) {
+                let _ = self.dtmf_out.send(digit);
+            }
+        } else {
+            self.jitter_buffer.push(packet, now);
+        }
+    }
+
+    fn on_rtcp(&mut self, bytes: &[u8], now: Instant) {
+        let packets = match RtcpPacket::parse_compound(bytes) {
+            Ok(packets) => packets,
+            Err(_) => return,
+        };
+        for packet in packets {
+            let reports = match packet {
+                RtcpPacket::SenderReport { info, reports, .. } => {
+                    self.reception.on_sender_report(info.ntp_timestamp, now);
+                    reports
+                }
+                RtcpPacket::ReceiverReport { reports, .. } => reports,
+                RtcpPacket::Bye { .. } => {
+                    self.jitter_buffer.reset();
+                    continue;
+                }
+                RtcpPacket::Other(_) => continue,
+            };
+            for report in reports
+                .iter()
+                .filter(|report| report.ssrc == self.sender.ssrc())
+            {
+                if let Ok(mut stats) = self.stats.lock() {
+                    stats.remote_fraction_lost = report.fraction_lost;
+                    // RFC 3550 section 6.4.1: A - LSR - DLSR.
+                    if report.last_sender_report != 0 {
+                        let round_trip = ntp_middle(ntp_now())
+                            .wrapping_sub(report.last_sender_report)
+                            .wrapping_sub(report.delay_since_last_sender_report);
+                        stats.round_trip =
+                            Some(Duration::from_micros(round_trip as u64 * 1_000_000 / 65536));
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_report(&mut self, now: Instant) {
+        let reports: Vec<_> = self.reception.report_block(now).into_iter().collect();
+        let packet = if self.sent_since_report {
+            RtcpPacket::SenderReport {
+                ssrc: self.sender.ssrc(),
+                info: SenderInfo {
+                    ntp_timestamp: ntp_now(),
+                    rtp_timestamp: self.sender.last_timestamp(),
+                    packet_count: self.sender.packets_sent(),
+                    octet_count: self.sender.octets_sent(),
+                },
+                reports,
+            }
+        } else {
+            RtcpPacket::ReceiverReport {
+                ssrc: self.sender.ssrc(),
+                reports,
+            }
+        };
+        self.sent_since_report = false;
+        if let Some(rtcp_addr) = self.rtcp_addr() {
+            let _ = self.rtcp_socket.send_to(&packet.to_bytes(), rtcp_addr);
+        }
+
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.packets_sent = self.sender.packets_sent();
+            stats.packets_received = self.reception.received();
+            stats.packets_lost = self.reception.cumulative_lost();
+            stats.jitter_ms = self.reception.jitter() * 1000 / self.session.clock_rate.max(1);
+        }
+    }
+
+    fn send_bye(&mut self) {
+        let bye = RtcpPacket::Bye {
+            ssrcs: vec![self.sender.ssrc()],
+        };
+        if let Some(rtcp_addr) = self.rtcp_addr() {
+            let _ = self.rtcp_socket.send_to(&bye.to_bytes(), rtcp_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voip::sdp::MediaDirection;
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    /// An even port whose neighbour is free too, the way the user agent hands them out.
+    fn free_port_pair() -> u16 {
+        loop {
+            let probe = UdpSocket::bind("0.0.0.0:0").unwrap();
+            let port = probe.local_addr().unwrap().port();
+            drop(probe);
+            if port & 1 == 0
+                && port < u16::MAX - 1
+                && UdpSocket::bind(("0.0.0.0", port)).is_ok()
+                && UdpSocket::bind(("0.0.0.0", port + 1)).is_ok()
+            {
+                return port;
+            }
+        }
+    }
+
+    fn start(peer: &UdpSocket) -> (MediaSession, u16) {
+        let local_port = free_port_pair();
+        let session = NegotiatedSession {
+            local_port,
+            remote_addr: peer.local_addr().unwrap(),
+            payload_type: 0,
+            encoding: "PCMU".into(),
+            clock_rate: 8000,
+            ptime: 20,
+            telephone_event: Some(101),
+            direction: MediaDirection::SendRecv,
+        };
+        (MediaSession::start(session, None).unwrap(), local_port)
+    }
+
+    fn peer() -> UdpSocket {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(WAIT)).unwrap();
+        peer
+    }
+
+    #[test]
+    fn sends_audio_as_rtp() {
+        let peer = peer();
+        let (media, _) = start(&peer);
+        media.send_audio(vec![1000; media.frame_samples()]);
+        media.send_audio(vec![1000; media.frame_samples()]);
+
+        let mut buffer = [0u8; MEDIA_RECEIVE_BUFFER_BYTES];
+        let (len, _) = peer.recv_from(&mut buffer).unwrap();
+        let first = RtpPacket::parse(&buffer[..len]).unwrap();
+        let (len, _) = peer.recv_from(&mut buffer).unwrap();
+        let second = RtpPacket::parse(&buffer[..len]).unwrap();
+        assert_eq!(first.payload_type, 0);
+        assert_eq!(first.payload.len(), 160);
+        // Only the start of a talkspurt is marked.
+        assert!(first.marker && !second.marker);
+        assert_eq!(second.timestamp, first.timestamp.wrapping_add(160));
+        media.stop();
+    }
+
+    #[test]
+    fn plays_out_what_arrives() {
+        let peer = peer();
+        let (media, local_port) = start(&peer);
+        let to = SocketAddr::from(([127, 0, 0, 1], local_port));
+        let mut encoder = create_codec("PCMU", 8000).unwrap();
+        let mut sender = RtpSender::new();
+
+        let deadline = Instant::now() + WAIT;
+        let mut heard = false;
+        while !heard && Instant::now() < deadline {
+            let mut payload = vec![];
+            encoder.encode(&[1000; 160], &mut payload);
+            let packet = sender.packetize(0, payload, 160, false);
+            peer.send_to(&packet.to_bytes(), to).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            while let Some(pcm) = media.receive() {
+                heard |= pcm.iter().any(|sample| *sample != 0);
+            }
+        }
+        assert!(heard);
+        media.stop();
+    }
+}
@@ -0,0 +1,171 @@
+use std::net::SocketAddr;
+
+use rsip::prelude::*;
+
+use super::account::SipAccount;
+use super::digest::{answer_challenge, find_challenge, DigestCredentials};
+use super::sip::{
+    content_type_of, dial_target, generate_request, new_branch, new_call_id, new_tag, RequestParams,
+};
+
+pub const TEXT_PLAIN_CONTENT_TYPE: &str = "text/plain";
+// RFC 3428 section 6: past this, a MESSAGE risks fragmentation over UDP.
+pub const MAX_MESSAGE_BYTES: usize = 1300;
+const MAX_AUTH_ATTEMPTS: u8 = 2;
+
+pub type MessageId = u32;
+
+/// A text that came in, as the GUI gets to see it.
+#[derive(Clone, Debug)]
+pub struct IncomingText {
+    /// The user part of the sender's URI, which is the number for most providers.
+    pub from: String,
+    pub display_name: Option<String>,
+    pub text: String,
+}
+
+/// Reads an out-of-dialog MESSAGE, or says which status code to turn it down with.
+pub fn parse_incoming(request: &rsip::Request) -> Result<IncomingText, u16> {
+    match content_type_of(&request.headers) {
+        Some(content_type) if content_type == TEXT_PLAIN_CONTENT_TYPE => {}
+        _ => return Err(415),
+    }
+    let from = request
+        .from_header()
+        .ok()
+        .and_then(|from| from.typed().ok())
+        .ok_or(400u16)?;
+    let user = match from.uri.auth {
+        Some(auth) => auth.user,
+        None => from.uri.host_with_port.to_string(),
+    };
+    let text = String::from_utf8(request.body.clone()).map_err(|_| 400u16)?;
+    Ok(IncomingText {
+        from: user,
+        display_name: from.display_name,
+        text,
+    })
+}
+
+/// What became of a MESSAGE after a response.
+pub enum MessageProgress {
+    /// Still waiting for a final response.
+    Pending,
+    /// Send this in a new transaction; the first one got challenged.
+    Resend(rsip::Request),
+    Done(Result<(), String>),
+}
+
+/// One outgoing MESSAGE (RFC 3428), each in its own transaction. Like
+/// `RegistrationClient` it only decides what to send; the user agent runs the
+/// transaction and feeds the responses in.
+pub struct OutgoingMessage {
+    id: MessageId,
+    account: SipAccount,
+    local_addr: SocketAddr,
+    target: rsip::Uri,
+    call_id: String,
+    from_tag: String,
+    cseq: u16,
+    text: String,
+    auth_attempts: u8,
+}
+
+impl OutgoingMessage {
+    /// Builds the MESSAGE carrying `text` to `to` (a number, user@host or SIP URI).
+    pub fn new(
+        id: MessageId,
+        account: SipAccount,
+        local_addr: SocketAddr,
+        to: &str,
+        text: String,
+    ) -> Result<(OutgoingMessage, rsip::Request), String> {
+        if text.len() > MAX_MESSAGE_BYTES {
+            return Err("Message too long".into());
+        }
+        let target = dial_target(&account, to).map_err(|err| err.to_string())?;
+        let mut message = OutgoingMessage {
+            id,
+            account,
+            local_addr,
+            target,
+            call_id: new_call_id(&local_addr),
+            from_tag: new_tag(),
+            cseq: 0,
+            text,
+            auth_attempts: 0,
+        };
+        let request = message.build_request(None)?;
+        Ok((message, request))
+    }
+
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
+    fn build_request(
+        &mut self,
+        authorization: Option<rsip::Header>,
+    ) -> Result<rsip::Request, String> {
+        let aor = self.account.aor().map_err(|err| err.to_string())?;
+        self.cseq = self.cseq.wrapping_add(1);
+        Ok(generate_request(RequestParams {
+            method: rsip::Method::Message,
+            uri: self.target.clone(),
+            transport: self.account.transport.to_rsip(),
+            local_addr: self.local_addr,
+            branch: &new_branch(),
+            from: rsip::typed::From {
+                display_name: self.account.display_name.clone(),
+                uri: aor,
+                params: vec![rsip::Param::Tag(rsip::param::Tag::new(&self.from_tag))],
+            },
+            to: rsip::typed::To {
+                display_name: None,
+                uri: self.target.clone(),
+                params: Default::default(),
+            },
+            call_id: &self.call_id,
+            cseq: self.cseq,
+            // MESSAGE doesn't make a dialog, so no Contact.
+            contact: None,
+            extra_headers: authorization.into_iter().collect(),
+            content_type: Some(TEXT_PLAIN_CONTENT_TYPE),
+            body: self.text.clone().into_bytes(),
+        }))
+    }
+
+    pub fn on_response(&mut self, response: &rsip::Response) -> MessageProgress {
+        let code = response.status_code.code();
+        match code {
+            100..=199 => MessageProgress::Pending,
+            200..=299 => MessageProgress::Done(Ok(())),
+            401 | 407 => {
+                if self.auth_attempts >= MAX_AUTH_ATTEMPTS {
+                    return MessageProgress::Done(Err("Authentication failed".into()));
+                }
+                self.auth_attempts += 1;
+                let authorization = match find_challenge(response) {
+                    Some((kind, challenge)) => answer_challenge(
+                        kind,
+                        &challenge,
+                        &DigestCredentials {
+                            username: &self.account.user,
+                            password: &self.account.password,
+                        },
+                        &rsip::Method::Message,
+                        &self.target,
+                    ),
+                    None => Err("Challenge without credentials".into()),
+                };
+                match authorization
+                    .and_then(|authorization| self.build_request(Some(authorization)))
+                {
+                    Ok(request) => MessageProgress::Resend(request),
+                    Err(err) => MessageProgress::Done(Err(err)),
+                }
+            }
+            _ => MessageProgress::Done(Err(response.status_code.to_string())),
+        }
+    }
+}
@@ -1 +1,19 @@
+pub mod account;
+pub mod call;
+pub mod codec;
+pub mod dialog;
+pub mod digest;
+pub mod dns;
+pub mod dtmf;
+pub mod media;
+pub mod message;
+pub mod mwi;
+pub mod negotiation;
+pub mod registration;
+pub mod sdp;
 pub mod sip;
+pub mod stun;
+pub mod transaction;
+pub mod transfer;
+pub mod transport;
+pub mod user_agent;
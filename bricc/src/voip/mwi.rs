@@ -0,0 +1,401 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rsip::prelude::*;
+
+use super::account::SipAccount;
+use super::digest::{answer_challenge, find_challenge, DigestCredentials};
+use super::sip::{
+    generate_request, local_contact_uri, new_branch, new_call_id, new_tag, param_value, tag_of,
+    RequestParams,
+};
+
+pub const MESSAGE_SUMMARY_EVENT: &str = "message-summary";
+pub const MESSAGE_SUMMARY_CONTENT_TYPE: &str = "application/simple-message-summary";
+const SUBSCRIBE_EXPIRES_SECS: u32 = 3600;
+const SUBSCRIBE_RETRY_AFTER_FAILURE: Duration = Duration::from_secs(300);
+// Ahead of the subscription running out, so a refresh has time to get through.
+const SUBSCRIBE_REFRESH_MARGIN_SECS: u32 = 60;
+const MAX_AUTH_ATTEMPTS: u8 = 2;
+
+/// What the voicemail box says, RFC 3842 section 5.2.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VoicemailStatus {
+    pub waiting: bool,
+    pub new: u32,
+    pub old: u32,
+    /// Where the messages are, which is usually what to call to hear them.
+    pub account: Option<String>,
+}
+
+/// Reads an application/simple-message-summary body. Only voice messages count.
+pub fn parse_message_summary(body: &str) -> Result<VoicemailStatus, String> {
+    let mut waiting = None;
+    let mut status = VoicemailStatus::default();
+    for line in body.lines() {
+        let (name, value) = match line.find(':') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+            None => continue,
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "messages-waiting" => waiting = Some(value.eq_ignore_ascii_case("yes")),
+            "message-account" => status.account = Some(value.to_string()),
+            "voice-message" => {
+                // new/old, then optionally the urgent ones in parentheses.
+                let counts = value.split_whitespace().next().unwrap_or_default();
+                let mut counts = counts.split('/').map(|count| count.trim().parse::<u32>());
+                match (counts.next(), counts.next()) {
+                    (Some(Ok(new)), Some(Ok(old))) => {
+                        status.new = new;
+                        status.old = old;
+                    }
+                    _ => return Err(format!("Bad Voice-Message: {}", value)),
+                }
+            }
+            _ => {}
+        }
+    }
+    status.waiting = waiting.ok_or("No Messages-Waiting in message summary")?;
+    Ok(status)
+}
+
+/// Whether a request is about the message-summary event package.
+pub fn is_message_summary(request: &rsip::Request) -> bool {
+    request.headers.iter().any(|header| match header {
+        rsip::Header::Event(event) => {
+            event.value().split(';').next().map(|event| event.trim()) == Some(MESSAGE_SUMMARY_EVENT)
+        }
+        _ => false,
+    })
+}
+
+struct PendingSubscribe {
+    request: rsip::Request,
+    branch: String,
+    expires: u32,
+}
+
+/// Keeps a message-summary subscription (RFC 3265) to our own address of record going.
+/// Like `RegistrationClient` this only decides what to send and when; the user agent
+/// runs the transactions and passes the NOTIFYs in.
+pub struct MwiClient {
+    account: SipAccount,
+    call_id: String,
+    from_tag: String,
+    remote_tag: Option<String>,
+    remote_target: Option<rsip::Uri>,
+    cseq: u16,
+    pending: Option<PendingSubscribe>,
+    auth_attempts: u8,
+    // When to (re)subscribe. None once the server has said it won't do MWI.
+    subscribe_at: Option<Instant>,
+}
+
+impl MwiClient {
+    pub fn new(account: SipAccount, local_addr: SocketAddr, now: Instant) -> MwiClient {
+        MwiClient {
+            account,
+            call_id: new_call_id(&local_addr),
+            from_tag: new_tag(),
+            remote_tag: None,
+            remote_target: None,
+            cseq: 0,
+            pending: None,
+            auth_attempts: 0,
+            subscribe_at: Some(now),
+        }
+    }
+
+    fn is_subscribed(&self) -> bool {
+        self.remote_tag.is_some()
+    }
+
+    /// Drives the first SUBSCRIBE, refreshes and retries. Only call this while
+    /// registered; there's no point before.
+    pub fn poll(&mut self, local_addr: SocketAddr, now: Instant) -> Option<rsip::Request> {
+        if self.pending.is_some() {
+            return None;
+        }
+        match self.subscribe_at {
+            Some(subscribe_at) if now >= subscribe_at => {
+                self.subscribe_at = None;
+                self.auth_attempts = 0;
+                self.send_subscribe(local_addr, SUBSCRIBE_EXPIRES_SECS, None)
+                    .ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Ends the subscription, if there is one.
+    pub fn unsubscribe(&mut self, local_addr: SocketAddr) -> Option<rsip::Request> {
+        self.subscribe_at = None;
+        if !self.is_subscribed() {
+            return None;
+        }
+        self.auth_attempts = 0;
+        self.send_subscribe(local_addr, 0, None).ok()
+    }
+
+    // Starts over with a new dialog, for when the old one is gone.
+    fn reset_dialog(&mut self, local_addr: SocketAddr) {
+        self.call_id = new_call_id(&local_addr);
+        self.from_tag = new_tag();
+        self.remote_tag = None;
+        self.remote_target = None;
+    }
+
+    fn send_subscribe(
+        &mut self,
+        local_addr: SocketAddr,
+        expires: u32,
+        authorization: Option<rsip::Header>,
+    ) -> Result<rsip::Request, String> {
+        let aor = self.account.aor().map_err(|err| err.to_string())?;
+        self.cseq = self.cseq.wrapping_add(1);
+        let branch = new_branch();
+        let mut to_params = vec![];
+        if let Some(remote_tag) = &self.remote_tag {
+            to_params.push(rsip::Param::Tag(rsip::param::Tag::new(remote_tag)));
+        }
+        let mut extra_headers: Vec<rsip::Header> = vec![
+            rsip::headers::Event::new(MESSAGE_SUMMARY_EVENT).into(),
+            rsip::headers::Accept::new(MESSAGE_SUMMARY_CONTENT_TYPE).into(),
+            rsip::headers::Expires::new(expires.to_string()).into(),
+        ];
+        if let Some(authorization) = authorization {
+            extra_headers.push(authorization);
+        }
+        let request = generate_request(RequestParams {
+            method: rsip::Method::Subscribe,
+            uri: self.remote_target.clone().unwrap_or_else(|| aor.clone()),
+            transport: self.account.transport.to_rsip(),
+            local_addr,
+            branch: &branch,
+            from: rsip::typed::From {
+                display_name: self.account.display_name.clone(),
+                uri: aor.clone(),
+                params: vec![rsip::Param::Tag(rsip::param::Tag::new(&self.from_tag))],
+            },
+            to: rsip::typed::To {
+                display_name: None,
+                uri: aor,
+                params: to_params,
+            },
+            call_id: &self.call_id,
+            cseq: self.cseq,
+            contact: Some(local_contact_uri(&self.account, &local_addr)),
+            extra_headers,
+            content_type: None,
+            body: vec![],
+        });
+        self.pending = Some(PendingSubscribe {
+            request: request.clone(),
+            branch,
+            expires,
+        });
+        Ok(request)
+    }
+
+    /// Feeds a response in. Returns a request to send if the server has to be asked again.
+    pub fn handle_response(
+        &mut self,
+        response: &rsip::Response,
+        local_addr: SocketAddr,
+        now: Instant,
+    ) -> Option<rsip::Request> {
+        let pending = match &self.pending {
+            Some(pending) if Self::matches(response, &pending.branch) => pending,
+            _ => return None,
+        };
+        let code = response.status_code.code();
+        if code < 200 {
+            return None;
+        }
+        let (request, expires) = (pending.request.clone(), pending.expires);
+        self.pending = None;
+
+        match code {
+            200..=299 => {
+                if expires == 0 {
+                    self.reset_dialog(local_addr);
+                    return None;
+                }
+                if let Ok(to) = response.to_header() {
+                    if let Some(tag) = tag_of(to.value()) {
+                        self.remote_tag = Some(tag);
+                    }
+                }
+                if let Ok(contact) = response.contact_header().and_then(|c| c.typed()) {
+                    self.remote_target = Some(contact.uri);
+                }
+                let granted = response
+                    .headers
+                    .iter()
+                    .find_map(|header| match header {
+                        rsip::Header::Expires(expires) => expires.value().trim().parse().ok(),
+                        _ => None,
+                    })
+                    .unwrap_or(expires);
+                let refresh_after = if granted > 2 * SUBSCRIBE_REFRESH_MARGIN_SECS {
+                    granted - SUBSCRIBE_REFRESH_MARGIN_SECS
+                } else {
+                    granted / 2
+                };
+                self.subscribe_at = Some(now + Duration::from_secs(refresh_after as u64));
+                None
+            }
+            401 | 407 => {
+                if self.auth_attempts >= MAX_AUTH_ATTEMPTS {
+                    return self.fail("Authentication failed".into(), now);
+                }
+                self.auth_attempts += 1;
+                let authorization = match find_challenge(response) {
+                    Some((kind, challenge)) => answer_challenge(
+                        kind,
+                        &challenge,
+                        &DigestCredentials {
+                            username: &self.account.user,
+                            password: &self.account.password,
+                        },
+                        &rsip::Method::Subscribe,
+                        &request.uri,
+                    ),
+                    None => Err("Challenge without credentials".into()),
+                };
+                match authorization {
+                    Ok(authorization) => self
+                        .send_subscribe(local_addr, expires, Some(authorization))
+                        .ok(),
+                    Err(err) => self.fail(err, now),
+                }
+            }
+            // The server forgot us; a new subscription fixes that.
+            481 => {
+                self.reset_dialog(local_addr);
+                self.send_subscribe(local_addr, expires, None).ok()
+            }
+            // No voicemail here, so don't keep asking.
+            403 | 404 | 405 | 489 | 501 => {
+                println!("No voicemail indication: {}", response.status_code);
+                self.subscribe_at = None;
+                None
+            }
+            _ => self.fail(response.status_code.to_string(), now),
+        }
+    }
+
+    /// The SUBSCRIBE transaction gave up (timer F).
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.pending = None;
+        self.fail("Server not responding".into(), now);
+    }
+
+    fn fail(&mut self, reason: String, now: Instant) -> Option<rsip::Request> {
+        println!("Voicemail subscription failed: {}", reason);
+        self.subscribe_at = Some(now + SUBSCRIBE_RETRY_AFTER_FAILURE);
+        None
+    }
+
+    /// Whether a NOTIFY is for our subscription. It can beat the 2xx to the
+    /// SUBSCRIBE, so that's by Call-ID and our tag alone.
+    pub fn matches_notify(&self, request: &rsip::Request) -> bool {
+        let call_id = match request.call_id_header() {
+            Ok(call_id) => call_id.value().to_string(),
+            Err(_) => return false,
+        };
+        let to_tag = request.to_header().ok().and_then(|to| tag_of(to.value()));
+        call_id == self.call_id && to_tag.as_deref() == Some(self.from_tag.as_str())
+    }
+
+    /// Takes in a NOTIFY for our subscription. Returns the status code to answer it
+    /// with, and what it said about the mailbox if anything.
+    pub fn on_notify(
+        &mut self,
+        request: &rsip::Request,
+        local_addr: SocketAddr,
+        now: Instant,
+    ) -> (u16, Option<VoicemailStatus>) {
+        if !is_message_summary(request) {
+            return (489, None);
+        }
+        if self.remote_tag.is_none() {
+            self.remote_tag = request.from_header().ok().and_then(|f| tag_of(f.value()));
+        }
+        if let Ok(contact) = request.contact_header().and_then(|c| c.typed()) {
+            self.remote_target = Some(contact.uri);
+        }
+        let state = request.headers.iter().find_map(|header| match header {
+            rsip::Header::SubscriptionState(state) => Some(state.value().to_string()),
+            _ => None,
+        });
+        if let Some(state) = state {
+            if state.trim_start().starts_with("terminated") {
+                // RFC 3265 section 3.2.4: we may try again unless told not to.
+                let reason = param_value(&state, "reason");
+                self.reset_dialog(local_addr);
+                self.subscribe_at = match reason.as_deref() {
+                    Some("rejected") | Some("noresource") => None,
+                    _ => Some(now),
+                };
+            }
+        }
+        if request.body.is_empty() {
+            return (200, None);
+        }
+        match parse_message_summary(&String::from_utf8_lossy(&request.body)) {
+            Ok(status) => (200, Some(status)),
+            Err(err) => {
+                println!("Bad message summary: {}", err);
+                (400, None)
+            }
+        }
+    }
+
+    fn matches(response: &rsip::Response, branch: &str) -> bool {
+        match response.via_header() {
+            Ok(via) => param_value(via.value(), "branch").as_deref() == Some(branch),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_message_summary() {
+        let body = "Messages-Waiting: yes\r\n\
+                    Message-Account: sip:*97@example.com\r\n\
+                    Voice-Message: 2/8 (0/0)\r\n";
+        assert_eq!(
+            parse_message_summary(body),
+            Ok(VoicemailStatus {
+                waiting: true,
+                new: 2,
+                old: 8,
+                account: Some("sip:*97@example.com".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn counts_only_voice_messages() {
+        let body = "messages-waiting: no\r\nFax-Message: 1/0\r\n";
+        assert_eq!(parse_message_summary(body), Ok(VoicemailStatus::default()));
+    }
+
+    #[test]
+    fn needs_messages_waiting() {
+        assert!(parse_message_summary("Voice-Message: 2/8 (0/0)\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_a_bad_count() {
+        let body = "Messages-Waiting: yes\r\nVoice-Message: 2/many\r\n";
+        assert!(parse_message_summary(body).is_err());
+        let body = "Messages-Waiting: yes\r\nVoice-Message: 2\r\n";
+        assert!(parse_message_summary(body).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use super::codec::{codec_by_name, Codec};
+use super::sdp::{MediaDescription, MediaDirection, Origin, RtpMap, SessionDescription};
+
+pub const DEFAULT_PTIME_MS: u32 = 20;
+pub const DEFAULT_TELEPHONE_EVENT_PAYLOAD_TYPE: u8 = 101;
+const TELEPHONE_EVENT: &str = "telephone-event";
+// DTMF digits 0-9, *, #, A-D and flash.
+const TELEPHONE_EVENT_FMTP: &str = "0-16";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MediaConfig {
+    /// SDP encoding names, most preferred first. Ones we have no `Codec` for are skipped.
+    pub codecs: Vec<String>,
+    pub ptime: u32,
+    /// Payload type to offer RFC 4733 telephone-event with, or None to not offer it.
+    pub telephone_event: Option<u8>,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        MediaConfig {
+            codecs: vec!["PCMU".into(), "PCMA".into()],
+            ptime: DEFAULT_PTIME_MS,
+            telephone_event: Some(DEFAULT_TELEPHONE_EVENT_PAYLOAD_TYPE),
+        }
+    }
+}
+
+/// What both ends agreed on, which is all the media layer needs to know.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NegotiatedSession {
+    pub local_port: u16,
+    pub remote_addr: SocketAddr,
+    /// Payload type to send with, as numbered by the other side.
+    pub payload_type: u8,
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub ptime: u32,
+    pub telephone_event: Option<u8>,
+    /// From our point of view.
+    pub direction: MediaDirection,
+}
+
+/// The port RTP is bound to, and what it looks like from outside if STUN told us.
+#[derive(Clone, Copy, Debug)]
+pub struct RtpAddress {
+    pub local_port: u16,
+    pub public: Option<SocketAddr>,
+}
+
+/// Runs the RFC 3264 offer/answer model for the single audio stream of a call.
+pub struct MediaNegotiator {
+    config: MediaConfig,
+    local_ip: IpAddr,
+    rtp: RtpAddress,
+    session_id: u64,
+    session_version: u64,
+    last_local: Option<SessionDescription>,
+    direction: MediaDirection,
+    session: Option<NegotiatedSession>,
+}
+
+impl MediaNegotiator {
+    pub fn new(config: MediaConfig, local_ip: IpAddr, rtp: RtpAddress) -> MediaNegotiator {
+        MediaNegotiator {
+            config,
+            local_ip,
+            rtp,
+            session_id: rand::random::<u32>() as u64,
+            session_version: 0,
+            last_local: None,
+            direction: MediaDirection::SendRecv,
+            session: None,
+        }
+    }
+
+    // What goes in the SDP: the public side of the NAT if we know it.
+    fn advertised_ip(&self) -> IpAddr {
+        match self.rtp.public {
+            Some(public) => public.ip(),
+            None => self.local_ip,
+        }
+    }
+
+    fn advertised_port(&self) -> u16 {
+        match self.rtp.public {
+            Some(public) => public.port(),
+            None => self.rtp.local_port,
+        }
+    }
+
+    fn codecs(&self) -> Vec<Box<dyn Codec>> {
+        self.config
+            .codecs
+            .iter()
+            .filter_map(|name| codec_by_name(name))
+            .collect()
+    }
+
+    pub fn session(&self) -> Option<&NegotiatedSession> {
+        self.session.as_ref()
+    }
+
+    pub fn direction(&self) -> MediaDirection {
+        self.direction
+    }
+
+    /// What we want from the next exchange. sendonly or inactive puts the call on hold.
+    pub fn set_direction(&mut self, direction: MediaDirection) {
+        self.direction = direction;
+    }
+
+    pub fn create_offer(&mut self) -> SessionDescription {
+        let mut audio = MediaDescription::new_audio(self.advertised_port());
+        for codec in self.codecs() {
+            audio.formats.push(codec.payload_type());
+            audio.rtpmaps.push(RtpMap {
+                payload_type: codec.payload_type(),
+                encoding: codec.name().into(),
+                clock_rate: codec.clock_rate(),
+                channels: None,
+            });
+        }
+        if let Some(payload_type) = self.config.telephone_event {
+            Self::add_telephone_event(&mut audio, payload_type);
+        }
+        audio.ptime = Some(self.config.ptime);
+        audio.direction = Some(self.direction);
+        self.describe(audio)
+    }
+
+    /// Answers a remote offer and updates the session. Fails if we have nothing in common,
+    /// which the caller should turn into a 488.
+    pub fn answer(&mut self, offer: &SessionDescription) -> Result<SessionDescription, String> {
+        let remote = offer.audio().ok_or("Offer without audio")?;
+        let session = self.negotiate(offer, remote, true)?;
+
+        let mut audio = MediaDescription::new_audio(self.advertised_port());
+        audio.formats.push(session.payload_type);
+        if let Some(rtpmap) = remote.rtpmap(session.payload_type) {
+            audio.rtpmaps.push(rtpmap);
+        }
+        if let Some(payload_type) = session.telephone_event {
+            Self::add_telephone_event(&mut audio, payload_type);
+        }
+        audio.ptime = Some(self.config.ptime);
+        audio.direction = Some(session.direction);
+
+        self.session = Some(session);
+        Ok(self.describe(audio))
+    }
+
+    /// Applies the answer to our last offer.
+    pub fn accept_answer(&mut self, answer: &SessionDescription) -> Result<(), String> {
+        let remote = answer.audio().ok_or("Answer without audio")?;
+        let session = self.negotiate(answer, remote, false)?;
+        self.session = Some(session);
+        Ok(())
+    }
+
+    fn negotiate(
+        &self,
+        description: &SessionDescription,
+        remote: &MediaDescription,
+        is_offer: bool,
+    ) -> Result<NegotiatedSession, String> {
+        if remote.port == 0 {
+            return Err("Audio stream rejected".into());
+        }
+        let remote_ip = description
+            .connection_for(remote)
+            .ok_or("No connection address")?;
+        let mut remote_direction = description.direction_for(remote);
+        // Pre-RFC 3264 hold: "don't send me anything" by way of a null address.
+        if remote_ip.is_unspecified() {
+            remote_direction = match remote_direction {
+                MediaDirection::SendRecv | MediaDirection::SendOnly => MediaDirection::SendOnly,
+                _ => MediaDirection::Inactive,
+            };
+        }
+
+        let codecs = self.codecs();
+        let (payload_type, rtpmap) = if is_offer {
+            // Our preference decides among what was offered.
+            codecs
+                .iter()
+                .find_map(|codec| {
+                    remote
+                        .formats
+                        .iter()
+                        .find_map(|pt| match remote.rtpmap(*pt) {
+                            Some(rtpmap) if rtpmap.is(codec.name(), codec.clock_rate()) => {
+                                Some((*pt, rtpmap))
+                            }
+                            _ => None,
+                        })
+                })
+                .ok_or("No common codec")?
+        } else {
+            // The answerer already picked, take the first thing we understand.
+            remote
+                .formats
+                .iter()
+                .find_map(|pt| match remote.rtpmap(*pt) {
+                    Some(rtpmap)
+                        if codecs
+                            .iter()
+                            .any(|codec| rtpmap.is(codec.name(), codec.clock_rate())) =>
+                    {
+                        Some((*pt, rtpmap))
+                    }
+                    _ => None,
+                })
+                .ok_or("No common codec")?
+        };
+
+        let telephone_event = match self.config.telephone_event {
+            Some(_) => remote
+                .formats
+                .iter()
+                .copied()
+                .find(|pt| match remote.rtpmap(*pt) {
+                    Some(event) => event.is(TELEPHONE_EVENT, rtpmap.clock_rate),
+                    None => false,
+                }),
+            None => None,
+        };
+
+        let direction = if is_offer {
+            self.direction.intersect(&remote_direction.reversed())
+        } else {
+            remote_direction.reversed()
+        };
+
+        Ok(NegotiatedSession {
+            local_port: self.rtp.local_port,
+            remote_addr: SocketAddr::new(remote_ip, remote.port),
+            payload_type,
+            encoding: rtpmap.encoding,
+            clock_rate: rtpmap.clock_rate,
+            ptime: remote.ptime.unwrap_or(self.config.ptime),
+            telephone_event,
+            direction,
+        })
+    }
+
+    fn add_telephone_event(audio: &mut MediaDescription, payload_type: u8) {
+        audio.formats.push(payload_type);
+        audio.rtpmaps.push(RtpMap {
+            payload_type,
+            encoding: TELEPHONE_EVENT.into(),
+            clock_rate: 8000,
+            channels: None,
+        });
+        audio
+            .fmtps
+            .push((payload_type, TELEPHONE_EVENT_FMTP.into()));
+    }
+
+    // The origin version only moves when what we describe actually changes.
+    fn describe(&mut self, audio: MediaDescription) -> SessionDescription {
+        let mut description = SessionDescription {
+            origin: Origin {
+                username: "-".into(),
+                session_id: self.session_id,
+                session_version: self.session_version,
+                address: self.advertised_ip(),
+            },
+            session_name: "-".into(),
+            connection: Some(self.advertised_ip()),
+            direction: None,
+            attributes: vec![],
+            media: vec![audio],
+        };
+        if let Some(last_local) = &self.last_local {
+            if last_local.media != description.media {
+                self.session_version += 1;
+                description.origin.session_version = self.session_version;
+            }
+        }
+        self.last_local = Some(description.clone());
+        description
+    }
+}
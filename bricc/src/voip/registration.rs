@@ -0,0 +1,449 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rsip::prelude::*;
+
+use super::account::SipAccount;
+use super::digest::{answer_challenge, find_challenge, DigestCredentials};
+use super::sip::{
+    generate_register, new_branch, new_call_id, new_tag, param_value, RegisterParams,
+};
+use super::transaction::T1;
+
+const REGISTER_RETRY_AFTER_FAILURE: Duration = Duration::from_secs(60);
+const MAX_AUTH_ATTEMPTS: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistrationState {
+    Unregistered,
+    Registering,
+    Registered,
+    Failed(String),
+}
+
+impl fmt::Display for RegistrationState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistrationState::Unregistered => write!(f, "Offline"),
+            RegistrationState::Registering => write!(f, "Registering"),
+            RegistrationState::Registered => write!(f, "Registered"),
+            RegistrationState::Failed(reason) => write!(f, "Failed: {}", reason),
+        }
+    }
+}
+
+struct PendingRegister {
+    request: rsip::Request,
+    branch: String,
+    expires: u32,
+}
+
+/// Keeps one binding alive at the registrar. This only decides what to send and
+/// when; the user agent runs the transactions and feeds responses and the clock in.
+pub struct RegistrationClient {
+    account: SipAccount,
+    call_id: Option<String>,
+    from_tag: String,
+    cseq: u16,
+    state: RegistrationState,
+    pending: Option<PendingRegister>,
+    auth_attempts: u8,
+    requested_expires: u32,
+    refresh_at: Option<Instant>,
+}
+
+impl RegistrationClient {
+    pub fn new(account: SipAccount) -> RegistrationClient {
+        let requested_expires = account.expires;
+        RegistrationClient {
+            account,
+            call_id: None,
+            from_tag: new_tag(),
+            cseq: 0,
+            state: RegistrationState::Unregistered,
+            pending: None,
+            auth_attempts: 0,
+            requested_expires,
+            refresh_at: None,
+        }
+    }
+
+    pub fn state(&self) -> RegistrationState {
+        self.state.clone()
+    }
+
+    pub fn account(&self) -> &SipAccount {
+        &self.account
+    }
+
+    pub fn register(&mut self, local_addr: SocketAddr) -> Result<rsip::Request, String> {
+        self.auth_attempts = 0;
+        self.state = RegistrationState::Registering;
+        self.send_register(local_addr, self.requested_expires, None)
+    }
+
+    pub fn unregister(&mut self, local_addr: SocketAddr) -> Result<rsip::Request, String> {
+        self.auth_attempts = 0;
+        self.refresh_at = None;
+        self.send_register(local_addr, 0, None)
+    }
+
+    fn send_register(
+        &mut self,
+        local_addr: SocketAddr,
+        expires: u32,
+        authorization: Option<rsip::Header>,
+    ) -> Result<rsip::Request, String> {
+        // The Call-ID has to stay the same for every refresh of one binding.
+        let call_id = match &self.call_id {
+            Some(call_id) => call_id.clone(),
+            None => {
+                let call_id = new_call_id(&local_addr);
+                self.call_id = Some(call_id.clone());
+                call_id
+            }
+        };
+        self.cseq = self.cseq.wrapping_add(1);
+        let branch = new_branch();
+        let request = match generate_register(&RegisterParams {
+            account: &self.account,
+            local_addr,
+            call_id: &call_id,
+            from_tag: &self.from_tag,
+            cseq: self.cseq,
+            branch: &branch,
+            expires,
+            authorization,
+        }) {
+            Ok(request) => request,
+            Err(err) => {
+                self.state = RegistrationState::Failed(err.to_string());
+                return Err(err.to_string());
+            }
+        };
+        self.pending = Some(PendingRegister {
+            request: request.clone(),
+            branch,
+            expires,
+        });
+        Ok(request)
+    }
+
+    /// Feeds a response in. Returns a request to send if the registrar has to be asked again.
+    pub fn handle_response(
+        &mut self,
+        response: &rsip::Response,
+        local_addr: SocketAddr,
+        now: Instant,
+    ) -> Option<rsip::Request> {
+        let pending = match &self.pending {
+            Some(pending) if Self::matches(response, &pending.branch) => pending,
+            _ => return None,
+        };
+        let code = response.status_code.code();
+        let expires = pending.expires;
+
+        if code < 200 {
+            return None;
+        }
+        let request = pending.request.clone();
+        self.pending = None;
+
+        match code {
+            200..=299 => {
+                if expires == 0 {
+                    self.state = RegistrationState::Unregistered;
+                    self.refresh_at = None;
+                } else {
+                    let granted = Self::granted_expires(response, &local_addr).unwrap_or(expires);
+                    self.state = RegistrationState::Registered;
+                    self.refresh_at = Some(now + Self::refresh_after(granted));
+                }
+                None
+            }
+            401 | 407 => {
+                if self.auth_attempts >= MAX_AUTH_ATTEMPTS {
+                    return self.fail("Authentication failed".into(), now);
+                }
+                self.auth_attempts += 1;
+                let (kind, challenge) = match find_challenge(response) {
+                    Some(challenge) => challenge,
+                    None => return self.fail("Challenge without credentials".into(), now),
+                };
+                let authorization = match answer_challenge(
+                    kind,
+                    &challenge,
+                    &DigestCredentials {
+                        username: &self.account.user,
+                        password: &self.account.password,
+                    },
+                    &rsip::Method::Register,
+                    &request.uri,
+                ) {
+                    Ok(authorization) => authorization,
+                    Err(err) => return self.fail(err, now),
+                };
+                self.send_register(local_addr, expires, Some(authorization))
+                    .ok()
+            }
+            423 => {
+                // Interval Too Brief, the registrar tells us the minimum.
+                let min_expires = response.headers.iter().find_map(|header| match header {
+                    rsip::Header::MinExpires(min) => min.value().trim().parse::<u32>().ok(),
+                    _ => None,
+                });
+                match min_expires {
+                    Some(min_expires) if min_expires > self.requested_expires => {
+                        self.requested_expires = min_expires;
+                        self.send_register(local_addr, min_expires, None).ok()
+                    }
+                    _ => self.fail(response.status_code.to_string(), now),
+                }
+            }
+            _ => self.fail(response.status_code.to_string(), now),
+        }
+    }
+
+    /// The REGISTER transaction gave up (timer F).
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.pending = None;
+        self.fail("Registrar not responding".into(), now);
+    }
+
+    /// Drives refreshes and retries. Call this regularly.
+    pub fn poll(&mut self, local_addr: SocketAddr, now: Instant) -> Option<rsip::Request> {
+        if self.pending.is_some() {
+            return None;
+        }
+        match self.refresh_at {
+            Some(refresh_at) if now >= refresh_at => {
+                self.refresh_at = None;
+                self.auth_attempts = 0;
+                if self.state != RegistrationState::Registered {
+                    self.state = RegistrationState::Registering;
+                }
+                self.send_register(local_addr, self.requested_expires, None)
+                    .ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn fail(&mut self, reason: String, now: Instant) -> Option<rsip::Request> {
+        println!("SIP registration failed: {}", reason);
+        self.state = RegistrationState::Failed(reason);
+        self.refresh_at = Some(now + REGISTER_RETRY_AFTER_FAILURE);
+        None
+    }
+
+    fn matches(response: &rsip::Response, branch: &str) -> bool {
+        match response.via_header() {
+            Ok(via) => param_value(via.value(), "branch").as_deref() == Some(branch),
+            Err(_) => false,
+        }
+    }
+
+    // Leave room for a whole transaction timeout (64*T1) before the binding lapses.
+    fn refresh_after(expires: u32) -> Duration {
+        let transaction_timeout = (64 * T1).as_secs() as u32;
+        if expires > 2 * transaction_timeout {
+            Duration::from_secs((expires - transaction_timeout) as u64)
+        } else {
+            Duration::from_secs((expires / 2) as u64)
+        }
+    }
+
+    fn granted_expires(response: &rsip::Response, local_addr: &SocketAddr) -> Option<u32> {
+        let local = local_addr.to_string();
+        for header in response.headers.iter() {
+            if let rsip::Header::Contact(contact) = header {
+                if contact.value().contains(&local) {
+                    if let Some(expires) = param_value(contact.value(), "expires") {
+                        return expires.parse().ok();
+                    }
+                }
+            }
+        }
+        response.headers.iter().find_map(|header| match header {
+            rsip::Header::Expires(expires) => expires.value().trim().parse().ok(),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rsip::headers::auth::AuthQop;
+
+    use super::*;
+    use crate::voip::account::SipTransport;
+    use crate::voip::negotiation::MediaConfig;
+    use crate::voip::sip::{create_unauthorized_from, generate_response};
+
+    const PASSWORD: &str = "wonderland";
+    const GRANTED_EXPIRES: u32 = 600;
+
+    fn account(password: &str) -> SipAccount {
+        SipAccount {
+            user: "alice".into(),
+            domain: "atlanta.example.com".into(),
+            registrar: None,
+            password: password.into(),
+            transport: SipTransport::Udp,
+            display_name: None,
+            expires: 3600,
+            media: MediaConfig::default(),
+            stun_server: None,
+            voicemail: None,
+        }
+    }
+
+    fn local_addr() -> SocketAddr {
+        "192.0.2.10:5060".parse().unwrap()
+    }
+
+    /// Challenges with `create_unauthorized_from` until the digest checks out, worked
+    /// out here by hand rather than with our own `compute_response`.
+    struct StandInRegistrar;
+
+    impl StandInRegistrar {
+        fn handle(&self, request: &rsip::Request) -> rsip::Response {
+            let authorization = request.headers.iter().find_map(|header| match header {
+                rsip::Header::Authorization(authorization) => authorization.typed().ok(),
+                _ => None,
+            });
+            match authorization {
+                Some(authorization) if Self::is_valid(request, &authorization) => {
+                    let mut ok =
+                        generate_response(request, 200, Some("reg"), None, None, vec![]).unwrap();
+                    ok.headers.push(
+                        rsip::headers::Contact::new(format!(
+                            "<sip:alice@{}>;expires={}",
+                            local_addr(),
+                            GRANTED_EXPIRES
+                        ))
+                        .into(),
+                    );
+                    ok
+                }
+                _ => match create_unauthorized_from(request.clone()).unwrap() {
+                    rsip::SipMessage::Response(response) => response,
+                    rsip::SipMessage::Request(_) => unreachable!(),
+                },
+            }
+        }
+
+        fn is_valid(request: &rsip::Request, authorization: &rsip::typed::Authorization) -> bool {
+            let (cnonce, nc) = match &authorization.qop {
+                Some(AuthQop::Auth { cnonce, nc }) => (cnonce, nc),
+                _ => return false,
+            };
+            let ha1 = md5::compute(format!(
+                "{}:{}:{}",
+                authorization.username, authorization.realm, PASSWORD
+            ));
+            let ha2 = md5::compute(format!("REGISTER:{}", request.uri));
+            let expected = md5::compute(format!(
+                "{:x}:{}:{:08x}:{}:auth:{:x}",
+                ha1, authorization.nonce, nc, cnonce, ha2
+            ));
+            authorization.response == format!("{:x}", expected)
+        }
+    }
+
+    #[test]
+    fn registers_after_answering_the_challenge() {
+        let registrar = StandInRegistrar;
+        let mut client = RegistrationClient::new(account(PASSWORD));
+        let now = Instant::now();
+
+        let first = client.register(local_addr()).unwrap();
+        assert_eq!(client.state(), RegistrationState::Registering);
+        let challenge = registrar.handle(&first);
+        assert_eq!(challenge.status_code.code(), 401);
+
+        let second = client
+            .handle_response(&challenge, local_addr(), now)
+            .expect("the challenge should be answered");
+        assert_eq!(
+            second.call_id_header().unwrap().value(),
+            first.call_id_header().unwrap().value()
+        );
+        assert_eq!(second.cseq_header().unwrap().typed().unwrap().seq, 2);
+
+        let ok = registrar.handle(&second);
+        assert_eq!(ok.status_code.code(), 200);
+        assert!(client.handle_response(&ok, local_addr(), now).is_none());
+        assert_eq!(client.state(), RegistrationState::Registered);
+    }
+
+    #[test]
+    fn refreshes_before_the_granted_expiry() {
+        let registrar = StandInRegistrar;
+        let mut client = RegistrationClient::new(account(PASSWORD));
+        let now = Instant::now();
+
+        let first = client.register(local_addr()).unwrap();
+        let second = client
+            .handle_response(&registrar.handle(&first), local_addr(), now)
+            .unwrap();
+        client.handle_response(&registrar.handle(&second), local_addr(), now);
+
+        // Granted 600s, less a transaction timeout of 32s.
+        assert!(client
+            .poll(local_addr(), now + Duration::from_secs(567))
+            .is_none());
+        let refresh = client
+            .poll(local_addr(), now + Duration::from_secs(569))
+            .expect("the binding should be refreshed");
+        assert_eq!(refresh.method, rsip::Method::Register);
+        assert_eq!(client.state(), RegistrationState::Registered);
+    }
+
+    #[test]
+    fn fails_when_the_password_is_wrong() {
+        let registrar = StandInRegistrar;
+        let mut client = RegistrationClient::new(account("not the password"));
+        let now = Instant::now();
+
+        let mut request = client.register(local_addr()).unwrap();
+        for _ in 0..MAX_AUTH_ATTEMPTS {
+            request = client
+                .handle_response(&registrar.handle(&request), local_addr(), now)
+                .unwrap();
+        }
+        assert!(client
+            .handle_response(&registrar.handle(&request), local_addr(), now)
+            .is_none());
+        assert_eq!(
+            client.state(),
+            RegistrationState::Failed("Authentication failed".into())
+        );
+        // And tries again later.
+        assert!(client
+            .poll(local_addr(), now + REGISTER_RETRY_AFTER_FAILURE)
+            .is_some());
+    }
+
+    #[test]
+    fn unregisters_with_zero_expires() {
+        let registrar = StandInRegistrar;
+        let mut client = RegistrationClient::new(account(PASSWORD));
+        let now = Instant::now();
+
+        let request = client.unregister(local_addr()).unwrap();
+        assert_eq!(
+            request.headers.iter().find_map(|header| match header {
+                rsip::Header::Expires(expires) => Some(expires.value().to_string()),
+                _ => None,
+            }),
+            Some("0".into())
+        );
+        let request = client
+            .handle_response(&registrar.handle(&request), local_addr(), now)
+            .unwrap();
+        client.handle_response(&registrar.handle(&request), local_addr(), now);
+        assert_eq!(client.state(), RegistrationState::Unregistered);
+    }
+}
@@ -0,0 +1,503 @@
+use std::fmt;
+use std::net::IpAddr;
+
+pub const SDP_CONTENT_TYPE: &str = "application/sdp";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MediaDirection {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl MediaDirection {
+    pub fn from_attribute(attribute: &str) -> Option<MediaDirection> {
+        match attribute {
+            "sendrecv" => Some(MediaDirection::SendRecv),
+            "sendonly" => Some(MediaDirection::SendOnly),
+            "recvonly" => Some(MediaDirection::RecvOnly),
+            "inactive" => Some(MediaDirection::Inactive),
+            _ => None,
+        }
+    }
+
+    pub fn attribute(&self) -> &'static str {
+        match self {
+            MediaDirection::SendRecv => "sendrecv",
+            MediaDirection::SendOnly => "sendonly",
+            MediaDirection::RecvOnly => "recvonly",
+            MediaDirection::Inactive => "inactive",
+        }
+    }
+
+    /// The same stream seen from the other end.
+    pub fn reversed(&self) -> MediaDirection {
+        match self {
+            MediaDirection::SendOnly => MediaDirection::RecvOnly,
+            MediaDirection::RecvOnly => MediaDirection::SendOnly,
+            other => *other,
+        }
+    }
+
+    /// What we can do given what we want and what the other side offered, RFC 3264 section 6.1.
+    pub fn intersect(&self, other: &MediaDirection) -> MediaDirection {
+        let sends = self.sends() && other.receives();
+        let receives = self.receives() && other.sends();
+        match (sends, receives) {
+            (true, true) => MediaDirection::SendRecv,
+            (true, false) => MediaDirection::SendOnly,
+            (false, true) => MediaDirection::RecvOnly,
+            (false, false) => MediaDirection::Inactive,
+        }
+    }
+
+    pub fn sends(&self) -> bool {
+        *self == MediaDirection::SendRecv || *self == MediaDirection::SendOnly
+    }
+
+    pub fn receives(&self) -> bool {
+        *self == MediaDirection::SendRecv || *self == MediaDirection::RecvOnly
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpMap {
+    pub payload_type: u8,
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub channels: Option<u8>,
+}
+
+impl RtpMap {
+    /// The payload types RFC 3551 assigns statically, which may come without an a=rtpmap.
+    pub fn from_static(payload_type: u8) -> Option<RtpMap> {
+        let (encoding, clock_rate) = match payload_type {
+            0 => ("PCMU", 8000),
+            3 => ("GSM", 8000),
+            8 => ("PCMA", 8000),
+            9 => ("G722", 8000),
+            18 => ("G729", 8000),
+            _ => return None,
+        };
+        Some(RtpMap {
+            payload_type,
+            encoding: encoding.into(),
+            clock_rate,
+            channels: None,
+        })
+    }
+
+    fn parse(value: &str) -> Option<RtpMap> {
+        let mut parts = value.splitn(2, ' ');
+        let payload_type = parts.next()?.trim().parse().ok()?;
+        let mut encoding = parts.next()?.trim().split('/');
+        Some(RtpMap {
+            payload_type,
+            encoding: encoding.next()?.into(),
+            clock_rate: encoding.next()?.parse().ok()?,
+            channels: match encoding.next() {
+                Some(channels) => Some(channels.parse().ok()?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn is(&self, encoding: &str, clock_rate: u32) -> bool {
+        self.encoding.eq_ignore_ascii_case(encoding) && self.clock_rate == clock_rate
+    }
+}
+
+impl fmt::Display for RtpMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.channels {
+            Some(channels) => write!(
+                f,
+                "{} {}/{}/{}",
+                self.payload_type, self.encoding, self.clock_rate, channels
+            ),
+            None => write!(
+                f,
+                "{} {}/{}",
+                self.payload_type, self.encoding, self.clock_rate
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaDescription {
+    pub media: String,
+    pub port: u16,
+    pub protocol: String,
+    pub formats: Vec<u8>,
+    pub connection: Option<IpAddr>,
+    pub rtpmaps: Vec<RtpMap>,
+    pub fmtps: Vec<(u8, String)>,
+    pub direction: Option<MediaDirection>,
+    pub ptime: Option<u32>,
+    /// Anything we don't interpret, kept as the text after "a=".
+    pub attributes: Vec<String>,
+}
+
+impl MediaDescription {
+    pub fn new_audio(port: u16) -> MediaDescription {
+        MediaDescription {
+            media: "audio".into(),
+            port,
+            protocol: "RTP/AVP".into(),
+            formats: vec![],
+            connection: None,
+            rtpmaps: vec![],
+            fmtps: vec![],
+            direction: None,
+            ptime: None,
+            attributes: vec![],
+        }
+    }
+
+    /// The rtpmap for a payload type, falling back to the static assignments.
+    pub fn rtpmap(&self, payload_type: u8) -> Option<RtpMap> {
+        match self
+            .rtpmaps
+            .iter()
+            .find(|rtpmap| rtpmap.payload_type == payload_type)
+        {
+            Some(rtpmap) => Some(rtpmap.clone()),
+            None => RtpMap::from_static(payload_type),
+        }
+    }
+
+    pub fn fmtp(&self, payload_type: u8) -> Option<&str> {
+        self.fmtps
+            .iter()
+            .find(|(pt, _)| *pt == payload_type)
+            .map(|(_, fmtp)| fmtp.as_str())
+    }
+
+    fn parse_attribute(&mut self, attribute: &str) {
+        let mut parts = attribute.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next();
+        if let Some(direction) = MediaDirection::from_attribute(name) {
+            self.direction = Some(direction);
+            return;
+        }
+        match (name, value) {
+            ("rtpmap", Some(value)) => {
+                if let Some(rtpmap) = RtpMap::parse(value) {
+                    self.rtpmaps.push(rtpmap);
+                }
+            }
+            ("fmtp", Some(value)) => {
+                let mut parts = value.splitn(2, ' ');
+                if let (Some(Ok(payload_type)), Some(params)) =
+                    (parts.next().map(|pt| pt.parse()), parts.next())
+                {
+                    self.fmtps.push((payload_type, params.trim().into()));
+                }
+            }
+            ("ptime", Some(value)) => self.ptime = value.trim().parse().ok(),
+            _ => self.attributes.push(attribute.into()),
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let formats: Vec<String> = self.formats.iter().map(|pt| pt.to_string()).collect();
+        write!(
+            f,
+            "m={} {} {} {}\r\n",
+            self.media,
+            self.port,
+            self.protocol,
+            formats.join(" ")
+        )?;
+        if let Some(connection) = self.connection {
+            write_connection(f, &connection)?;
+        }
+        for rtpmap in self.rtpmaps.iter() {
+            write!(f, "a=rtpmap:{}\r\n", rtpmap)?;
+        }
+        for (payload_type, params) in self.fmtps.iter() {
+            write!(f, "a=fmtp:{} {}\r\n", payload_type, params)?;
+        }
+        if let Some(ptime) = self.ptime {
+            write!(f, "a=ptime:{}\r\n", ptime)?;
+        }
+        for attribute in self.attributes.iter() {
+            write!(f, "a={}\r\n", attribute)?;
+        }
+        if let Some(direction) = self.direction {
+            write!(f, "a={}\r\n", direction.attribute())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub username: String,
+    pub session_id: u64,
+    pub session_version: u64,
+    pub address: IpAddr,
+}
+
+/// An RFC 4566 session description, as much of it as a phone call needs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionDescription {
+    pub origin: Origin,
+    pub session_name: String,
+    pub connection: Option<IpAddr>,
+    pub direction: Option<MediaDirection>,
+    pub attributes: Vec<String>,
+    pub media: Vec<MediaDescription>,
+}
+
+fn write_connection(f: &mut fmt::Formatter, address: &IpAddr) -> fmt::Result {
+    match address {
+        IpAddr::V4(address) => write!(f, "c=IN IP4 {}\r\n", address),
+        IpAddr::V6(address) => write!(f, "c=IN IP6 {}\r\n", address),
+    }
+}
+
+fn parse_connection(value: &str) -> Result<IpAddr, String> {
+    let address = value
+        .split_whitespace()
+        .nth(2)
+        .ok_or_else(|| format!("Bad connection line: {}", value))?;
+    // Multicast addresses may carry a /ttl suffix.
+    let address = address.split('/').next().unwrap_or(address);
+    address
+        .parse()
+        .map_err(|_| format!("Bad connection address: {}", address))
+}
+
+impl SessionDescription {
+    pub fn parse(text: &str) -> Result<SessionDescription, String> {
+        let mut origin = None;
+        let mut session_name = String::new();
+        let mut connection = None;
+        let mut direction = None;
+        let mut attributes = vec![];
+        let mut media: Vec<MediaDescription> = vec![];
+
+        for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+            // Anything but a one-letter type is skipped, not sliced into: it's off the wire.
+            let (kind, value) = match line.split_once('=') {
+                Some((kind, value)) if kind.len() == 1 => (kind, value),
+                _ => continue,
+            };
+            match (kind, media.last_mut()) {
+                ("o", _) => {
+                    let parts: Vec<&str> = value.split_whitespace().collect();
+                    if parts.len() < 6 {
+                        return Err(format!("Bad origin line: {}", value));
+                    }
+                    origin = Some(Origin {
+                        username: parts[0].into(),
+                        session_id: parts[1].parse().unwrap_or(0),
+                        session_version: parts[2].parse().unwrap_or(0),
+                        address: parts[5]
+                            .parse()
+                            .map_err(|_| format!("Bad origin address: {}", parts[5]))?,
+                    });
+                }
+                ("s", _) => session_name = value.into(),
+                ("c", None) => connection = Some(parse_connection(value)?),
+                ("c", Some(current)) => current.connection = Some(parse_connection(value)?),
+                ("m", _) => {
+                    let parts: Vec<&str> = value.split_whitespace().collect();
+                    if parts.len() < 3 {
+                        return Err(format!("Bad media line: {}", value));
+                    }
+                    // "port/count" is allowed, we only ever use the first port.
+                    let port = parts[1]
+                        .split('/')
+                        .next()
+                        .unwrap_or("")
+                        .parse()
+                        .map_err(|_| format!("Bad media port: {}", parts[1]))?;
+                    let mut description = MediaDescription::new_audio(port);
+                    description.media = parts[0].into();
+                    description.protocol = parts[2].into();
+                    description.formats = parts[3..]
+                        .iter()
+                        .filter_map(|format| format.parse().ok())
+                        .collect();
+                    media.push(description);
+                }
+                ("a", None) => match MediaDirection::from_attribute(value) {
+                    Some(session_direction) => direction = Some(session_direction),
+                    None => attributes.push(value.into()),
+                },
+                ("a", Some(current)) => current.parse_attribute(value),
+                _ => {}
+            }
+        }
+
+        Ok(SessionDescription {
+            origin: origin.ok_or("SDP without origin")?,
+            session_name,
+            connection,
+            direction,
+            attributes,
+            media,
+        })
+    }
+
+    pub fn parse_bytes(body: &[u8]) -> Result<SessionDescription, String> {
+        match std::str::from_utf8(body) {
+            Ok(text) => SessionDescription::parse(text),
+            Err(_) => Err("SDP is not UTF-8".into()),
+        }
+    }
+
+    /// The first audio stream, which is the only one we ever use.
+    pub fn audio(&self) -> Option<&MediaDescription> {
+        self.media.iter().find(|media| media.media == "audio")
+    }
+
+    pub fn connection_for(&self, media: &MediaDescription) -> Option<IpAddr> {
+        media.connection.or(self.connection)
+    }
+
+    /// Media-level direction wins over session-level, and sendrecv is the default.
+    pub fn direction_for(&self, media: &MediaDescription) -> MediaDirection {
+        media
+            .direction
+            .or(self.direction)
+            .unwrap_or(MediaDirection::SendRecv)
+    }
+}
+
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v=0\r\n")?;
+        let address_type = match self.origin.address {
+            IpAddr::V4(_) => "IP4",
+            IpAddr::V6(_) => "IP6",
+        };
+        write!(
+            f,
+            "o={} {} {} IN {} {}\r\n",
+            self.origin.username,
+            self.origin.session_id,
+            self.origin.session_version,
+            address_type,
+            self.origin.address
+        )?;
+        let session_name = if self.session_name.is_empty() {
+            "-"
+        } else {
+            &self.session_name
+        };
+        write!(f, "s={}\r\n", session_name)?;
+        if let Some(connection) = self.connection {
+            write_connection(f, &connection)?;
+        }
+        write!(f, "t=0 0\r\n")?;
+        for attribute in self.attributes.iter() {
+            write!(f, "a={}\r\n", attribute)?;
+        }
+        if let Some(direction) = self.direction {
+            write!(f, "a={}\r\n", direction.attribute())?;
+        }
+        for media in self.media.iter() {
+            media.write(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The offer from RFC 3264 section 10.1, cut down to its audio stream.
+    const OFFER: &str = "v=0\r\n\
+        o=alice 2890844526 2890844526 IN IP4 host.atlanta.example.com.invalid\r\n\
+        s=-\r\n\
+        c=IN IP4 192.0.2.10\r\n\
+        t=0 0\r\n\
+        m=audio 49170 RTP/AVP 0 8 97 101\r\n\
+        a=rtpmap:0 PCMU/8000\r\n\
+        a=rtpmap:8 PCMA/8000\r\n\
+        a=rtpmap:97 iLBC/8000\r\n\
+        a=rtpmap:101 telephone-event/8000\r\n\
+        a=fmtp:101 0-15\r\n\
+        a=ptime:30\r\n\
+        a=sendonly\r\n";
+
+    fn offer() -> String {
+        OFFER.replace("host.atlanta.example.com.invalid", "192.0.2.10")
+    }
+
+    #[test]
+    fn parses_an_audio_offer() {
+        let sdp = SessionDescription::parse(&offer()).unwrap();
+        assert_eq!(sdp.origin.username, "alice");
+        assert_eq!(sdp.origin.session_id, 2890844526);
+        assert_eq!(sdp.connection, Some("192.0.2.10".parse().unwrap()));
+
+        let audio = sdp.audio().unwrap();
+        assert_eq!(audio.port, 49170);
+        assert_eq!(audio.formats, vec![0, 8, 97, 101]);
+        assert_eq!(audio.rtpmap(97).unwrap().encoding, "iLBC");
+        assert!(audio.rtpmap(101).unwrap().is("telephone-event", 8000));
+        assert_eq!(audio.fmtp(101), Some("0-15"));
+        assert_eq!(audio.ptime, Some(30));
+        assert_eq!(sdp.direction_for(audio), MediaDirection::SendOnly);
+        assert_eq!(sdp.connection_for(audio), sdp.connection);
+    }
+
+    #[test]
+    fn falls_back_to_static_payload_types() {
+        let sdp = SessionDescription::parse(
+            "v=0\r\no=- 1 1 IN IP4 192.0.2.1\r\ns=-\r\nt=0 0\r\nm=audio 5004 RTP/AVP 8\r\n",
+        )
+        .unwrap();
+        let audio = sdp.audio().unwrap();
+        assert!(audio.rtpmap(8).unwrap().is("PCMA", 8000));
+        assert_eq!(sdp.direction_for(audio), MediaDirection::SendRecv);
+    }
+
+    #[test]
+    fn round_trips() {
+        let sdp = SessionDescription::parse(&offer()).unwrap();
+        let again = SessionDescription::parse(&sdp.to_string()).unwrap();
+        assert_eq!(sdp, again);
+    }
+
+    #[test]
+    fn skips_lines_it_cant_slice() {
+        let text = format!("{}é=x\r\n€\r\n=\r\nab=c\r\n", offer());
+        let sdp = SessionDescription::parse(&text).unwrap();
+        assert_eq!(sdp.audio().unwrap().port, 49170);
+        assert!(SessionDescription::parse("é").is_err());
+    }
+
+    #[test]
+    fn rejects_what_it_cant_use() {
+        assert!(SessionDescription::parse("v=0\r\ns=-\r\n").is_err());
+        assert!(SessionDescription::parse_bytes(&[0x6f, 0x3d, 0xff]).is_err());
+        let bad_port = offer().replace("49170", "seventy");
+        assert!(SessionDescription::parse(&bad_port).is_err());
+    }
+
+    #[test]
+    fn directions_reverse_and_intersect() {
+        assert_eq!(
+            MediaDirection::SendOnly.reversed(),
+            MediaDirection::RecvOnly
+        );
+        assert_eq!(
+            MediaDirection::SendRecv.intersect(&MediaDirection::RecvOnly),
+            MediaDirection::SendOnly
+        );
+        assert_eq!(
+            MediaDirection::SendOnly.intersect(&MediaDirection::SendOnly),
+            MediaDirection::Inactive
+        );
+    }
+}
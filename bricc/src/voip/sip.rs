@@ -1,48 +1,131 @@
-pub fn generate_register() -> rsip::SipMessage {
-    let mut headers: rsip::Headers = Default::default();
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rsip::prelude::*;
+
+use super::account::SipAccount;
+
+pub const USER_AGENT_NAME: &str = "kyp-bricc";
+
+pub fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// RFC 3261 branch ids must start with the magic cookie.
+pub fn new_branch() -> String {
+    format!("z9hG4bK{}", random_token(16))
+}
+
+pub fn new_tag() -> String {
+    random_token(10)
+}
+
+pub fn new_call_id(local_addr: &SocketAddr) -> String {
+    format!("{}@{}", random_token(20), local_addr.ip())
+}
+
+/// Finds `;name=value` (or a bare `;name`) in a raw header value.
+pub fn param_value(header_value: &str, name: &str) -> Option<String> {
+    for part in header_value.split(';').skip(1) {
+        let mut kv = part.splitn(2, '=');
+        let key = kv.next().unwrap_or("").trim();
+        if key.eq_ignore_ascii_case(name) {
+            return Some(
+                kv.next()
+                    .unwrap_or("")
+                    .trim()
+                    .trim_matches('"')
+                    .trim_end_matches('>')
+                    .into(),
+            );
+        }
+    }
+    None
+}
 
-    let base_uri = rsip::Uri {
-        scheme: Some(rsip::Scheme::Sips),
-        auth: Some(("bob", Option::<String>::None).into()),
-        host_with_port: rsip::Domain::from("biloxi.example.com").into(),
+pub fn local_via(
+    transport: rsip::Transport,
+    local_addr: &SocketAddr,
+    branch: &str,
+) -> rsip::Header {
+    rsip::typed::Via {
+        version: rsip::Version::V2,
+        transport,
+        uri: rsip::Uri {
+            host_with_port: (*local_addr).into(),
+            ..Default::default()
+        },
+        params: vec![
+            rsip::Param::Branch(rsip::param::Branch::new(branch)),
+            rsip::Param::Other("rport".into(), None),
+        ],
+    }
+    .into()
+}
+
+pub fn local_contact_uri(account: &SipAccount, local_addr: &SocketAddr) -> rsip::Uri {
+    let mut uri = rsip::Uri {
+        scheme: Some(rsip::Scheme::Sip),
+        auth: Some((account.user.clone(), Option::<String>::None).into()),
+        host_with_port: (*local_addr).into(),
         ..Default::default()
     };
+    if account.transport != super::account::SipTransport::Udp {
+        uri.params
+            .push(rsip::Param::Transport(account.transport.to_rsip()));
+    }
+    uri
+}
 
-    headers.push(
-        rsip::typed::Via {
-            version: rsip::Version::V2,
-            transport: rsip::Transport::Tls,
-            uri: rsip::Uri {
-                host_with_port: (rsip::Domain::from("client.biloxi.example.com"), 5060).into(),
-                ..Default::default()
-            },
-            params: vec![rsip::Param::Branch(rsip::param::Branch::new(
-                "z9hG4bKnashds7",
-            ))],
-        }
-        .into(),
-    );
+pub struct RegisterParams<'a> {
+    pub account: &'a SipAccount,
+    pub local_addr: SocketAddr,
+    pub call_id: &'a str,
+    pub from_tag: &'a str,
+    pub cseq: u16,
+    pub branch: &'a str,
+    pub expires: u32,
+    pub authorization: Option<rsip::Header>,
+}
+
+pub fn generate_register(params: &RegisterParams) -> Result<rsip::Request, rsip::Error> {
+    let account = params.account;
+    let mut headers: rsip::Headers = Default::default();
+
+    let aor = account.aor()?;
+
+    headers.push(local_via(
+        account.transport.to_rsip(),
+        &params.local_addr,
+        params.branch,
+    ));
     headers.push(rsip::headers::MaxForwards::default().into());
     headers.push(
         rsip::typed::From {
-            display_name: Some("Bob".into()),
-            uri: base_uri.clone(),
-            params: vec![rsip::Param::Tag(rsip::param::Tag::new("a73kszlfl"))],
+            display_name: account.display_name.clone(),
+            uri: aor.clone(),
+            params: vec![rsip::Param::Tag(rsip::param::Tag::new(params.from_tag))],
         }
         .into(),
     );
     headers.push(
         rsip::typed::To {
-            display_name: Some("Bob".into()),
-            uri: base_uri.clone(),
+            display_name: account.display_name.clone(),
+            uri: aor,
             params: Default::default(),
         }
         .into(),
     );
-    headers.push(rsip::headers::CallId::default().into());
+    headers.push(rsip::headers::CallId::new(params.call_id).into());
     headers.push(
         rsip::typed::CSeq {
-            seq: 1,
+            seq: params.cseq,
             method: rsip::Method::Register,
         }
         .into(),
@@ -50,30 +133,214 @@ pub fn generate_register() -> rsip::SipMessage {
     headers.push(
         rsip::typed::Contact {
             display_name: None,
-            uri: base_uri,
+            uri: local_contact_uri(account, &params.local_addr),
             params: Default::default(),
         }
         .into(),
     );
+    headers.push(rsip::headers::Expires::new(params.expires.to_string()).into());
+    if let Some(authorization) = &params.authorization {
+        headers.push(authorization.clone());
+    }
+    headers.push(rsip::headers::UserAgent::new(USER_AGENT_NAME).into());
     headers.push(rsip::headers::ContentLength::default().into());
 
-    rsip::Request {
+    Ok(rsip::Request {
         method: rsip::Method::Register,
-        uri: rsip::Uri {
-            scheme: Some(rsip::Scheme::Sips),
-            host_with_port: rsip::Domain::from("ss2.biloxi.example.com").into(),
-            ..Default::default()
-        },
+        uri: account.registrar_uri()?,
         headers: headers,
         version: rsip::Version::V2,
         body: Default::default(),
+    })
+}
+
+pub fn parse_message(bytes: &[u8]) -> Result<rsip::SipMessage, rsip::Error> {
+    rsip::SipMessage::try_from(bytes)
+}
+
+pub fn tag_of(header_value: &str) -> Option<String> {
+    param_value(header_value, "tag")
+}
+
+/// The media type of the body, without parameters.
+pub fn content_type_of(headers: &rsip::Headers) -> Option<String> {
+    headers.iter().find_map(|header| match header {
+        rsip::Header::ContentType(content_type) => content_type
+            .value()
+            .split(';')
+            .next()
+            .map(|media_type| media_type.trim().to_ascii_lowercase()),
+        _ => None,
+    })
+}
+
+/// Turns what the user typed into a request target: a full SIP URI, user@host, or
+/// a bare number/user on the account's domain.
+pub fn dial_target(account: &SipAccount, dialed: &str) -> Result<rsip::Uri, rsip::Error> {
+    let dialed = dialed.trim();
+    if dialed.starts_with("sip:") || dialed.starts_with("sips:") {
+        rsip::Uri::try_from(dialed)
+    } else if dialed.contains('@') {
+        rsip::Uri::try_from(format!("{}:{}", account.transport.scheme(), dialed))
+    } else {
+        rsip::Uri::try_from(format!(
+            "{}:{}@{}",
+            account.transport.scheme(),
+            dialed,
+            account.domain
+        ))
     }
-    .into()
 }
 
-pub fn create_unauthorized_from(request: rsip::Request) -> Result<rsip::SipMessage, rsip::Error> {
-    use rsip::prelude::*;
+pub struct RequestParams<'a> {
+    pub method: rsip::Method,
+    pub uri: rsip::Uri,
+    pub transport: rsip::Transport,
+    pub local_addr: SocketAddr,
+    pub branch: &'a str,
+    pub from: rsip::typed::From,
+    pub to: rsip::typed::To,
+    pub call_id: &'a str,
+    pub cseq: u16,
+    pub contact: Option<rsip::Uri>,
+    pub extra_headers: Vec<rsip::Header>,
+    pub content_type: Option<&'a str>,
+    pub body: Vec<u8>,
+}
 
+pub fn generate_request(params: RequestParams) -> rsip::Request {
+    let mut headers: rsip::Headers = Default::default();
+    headers.push(local_via(
+        params.transport,
+        &params.local_addr,
+        params.branch,
+    ));
+    headers.push(rsip::headers::MaxForwards::default().into());
+    headers.push(params.from.into());
+    headers.push(params.to.into());
+    headers.push(rsip::headers::CallId::new(params.call_id).into());
+    headers.push(
+        rsip::typed::CSeq {
+            seq: params.cseq,
+            method: params.method,
+        }
+        .into(),
+    );
+    if let Some(contact) = params.contact {
+        headers.push(
+            rsip::typed::Contact {
+                display_name: None,
+                uri: contact,
+                params: Default::default(),
+            }
+            .into(),
+        );
+    }
+    for header in params.extra_headers {
+        headers.push(header);
+    }
+    headers.push(rsip::headers::UserAgent::new(USER_AGENT_NAME).into());
+    if let Some(content_type) = params.content_type {
+        headers.push(rsip::headers::ContentType::new(content_type).into());
+    }
+    headers.push(rsip::headers::ContentLength::new(params.body.len().to_string()).into());
+
+    rsip::Request {
+        method: params.method,
+        uri: params.uri,
+        headers,
+        version: rsip::Version::V2,
+        body: params.body,
+    }
+}
+
+/// Builds a response per section 8.2.6. `to_tag` is added if the To header has none yet.
+pub fn generate_response(
+    request: &rsip::Request,
+    status_code: u16,
+    to_tag: Option<&str>,
+    contact: Option<rsip::Uri>,
+    content_type: Option<&str>,
+    body: Vec<u8>,
+) -> Result<rsip::Response, rsip::Error> {
+    let mut headers: rsip::Headers = Default::default();
+    for header in request.headers.iter() {
+        match header {
+            rsip::Header::Via(_) => headers.push(header.clone()),
+            rsip::Header::RecordRoute(_) if status_code > 100 && status_code < 300 => {
+                headers.push(header.clone())
+            }
+            _ => {}
+        }
+    }
+    headers.push(request.from_header()?.clone().into());
+    let to = request.to_header()?;
+    match to_tag {
+        Some(tag) if tag_of(to.value()).is_none() && status_code > 100 => {
+            let mut to = to.typed()?;
+            to.with_tag(tag.into());
+            headers.push(to.into());
+        }
+        _ => headers.push(to.clone().into()),
+    }
+    headers.push(request.call_id_header()?.clone().into());
+    headers.push(request.cseq_header()?.clone().into());
+    if let Some(contact) = contact {
+        headers.push(
+            rsip::typed::Contact {
+                display_name: None,
+                uri: contact,
+                params: Default::default(),
+            }
+            .into(),
+        );
+    }
+    headers.push(rsip::headers::Server::new(USER_AGENT_NAME).into());
+    if let Some(content_type) = content_type {
+        headers.push(rsip::headers::ContentType::new(content_type).into());
+    }
+    headers.push(rsip::headers::ContentLength::new(body.len().to_string()).into());
+
+    Ok(rsip::Response {
+        status_code: status_code.into(),
+        headers,
+        version: rsip::Version::V2,
+        body,
+    })
+}
+
+/// CANCEL for a pending INVITE, section 9.1. It shares the INVITE's branch.
+pub fn generate_cancel(invite: &rsip::Request) -> Result<rsip::Request, rsip::Error> {
+    let mut headers: rsip::Headers = Default::default();
+    headers.push(invite.via_header()?.clone().into());
+    for header in invite.headers.iter() {
+        if let rsip::Header::Route(_) = header {
+            headers.push(header.clone());
+        }
+    }
+    headers.push(rsip::headers::MaxForwards::default().into());
+    headers.push(invite.from_header()?.clone().into());
+    headers.push(invite.to_header()?.clone().into());
+    headers.push(invite.call_id_header()?.clone().into());
+    headers.push(
+        rsip::typed::CSeq {
+            seq: invite.cseq_header()?.typed()?.seq,
+            method: rsip::Method::Cancel,
+        }
+        .into(),
+    );
+    headers.push(rsip::headers::ContentLength::default().into());
+
+    Ok(rsip::Request {
+        method: rsip::Method::Cancel,
+        uri: invite.uri.clone(),
+        headers,
+        version: rsip::Version::V2,
+        body: Default::default(),
+    })
+}
+
+pub fn create_unauthorized_from(request: rsip::Request) -> Result<rsip::SipMessage, rsip::Error> {
     let mut headers: rsip::Headers = Default::default();
     headers.push(request.via_header()?.clone().into());
     headers.push(request.from_header()?.clone().into());
@@ -0,0 +1,410 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_STUN_PORT: u16 = 3478;
+// NATs commonly forget UDP mappings after 30 seconds of silence.
+pub const STUN_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_BYTES: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+// RFC 5389 section 7.2.1, with fewer attempts: a refresh is due again soon anyway.
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MAX_ATTEMPTS: u32 = 4;
+// For the blocking lookup of an RTP port, done off the user agent thread.
+const DISCOVER_ATTEMPTS: u32 = 3;
+const DISCOVER_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const DISCOVER_BUFFER_BYTES: usize = 576;
+
+pub type TransactionId = [u8; 12];
+
+/// RFC 5389 section 6: a STUN header has the top two bits clear and the magic cookie.
+/// SIP and RTP can both share a socket with it on that basis.
+pub fn is_stun(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_BYTES
+        && bytes[0] & 0xc0 == 0
+        && u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) == MAGIC_COOKIE
+}
+
+/// A Binding request with no attributes.
+pub fn binding_request(transaction_id: &TransactionId) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_BYTES);
+    bytes.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+    bytes.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    bytes.extend_from_slice(transaction_id);
+    bytes
+}
+
+/// What a STUN server says to a Binding request from `source`: a success response
+/// with its XOR-MAPPED-ADDRESS. None if it isn't a Binding request.
+pub fn answer_binding_request(request: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+    if !is_stun(request) || u16::from_be_bytes([request[0], request[1]]) != BINDING_REQUEST {
+        return None;
+    }
+    let xor = &request[4..HEADER_BYTES];
+    let (family, address) = match source.ip() {
+        IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+    };
+    let mut value = vec![0, family];
+    value.extend(
+        source
+            .port()
+            .to_be_bytes()
+            .iter()
+            .zip(xor)
+            .map(|(a, b)| a ^ b),
+    );
+    value.extend(address.iter().zip(xor).map(|(a, b)| a ^ b));
+
+    let mut bytes = Vec::with_capacity(HEADER_BYTES + 4 + value.len());
+    bytes.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+    bytes.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&request[4..HEADER_BYTES]);
+    bytes.extend_from_slice(&ATTRIBUTE_XOR_MAPPED_ADDRESS.to_be_bytes());
+    bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    bytes.extend(value);
+    Some(bytes)
+}
+
+/// The address the server saw a Binding request come from. Prefers XOR-MAPPED-ADDRESS,
+/// since some NATs rewrite addresses they find in packets, and falls back to the
+/// RFC 3489 MAPPED-ADDRESS that older servers send.
+pub fn parse_binding_response(
+    bytes: &[u8],
+    transaction_id: &TransactionId,
+) -> Result<SocketAddr, String> {
+    if !is_stun(bytes) {
+        return Err("Not a STUN message".into());
+    }
+    let message_type = u16::from_be_bytes([bytes[0], bytes[1]]);
+    if message_type != BINDING_SUCCESS {
+        return Err(format!("STUN message type {:#06x}", message_type));
+    }
+    if bytes[8..HEADER_BYTES] != transaction_id[..] {
+        return Err("Wrong STUN transaction".into());
+    }
+    let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    let attributes = bytes
+        .get(HEADER_BYTES..HEADER_BYTES + length)
+        .ok_or("Truncated STUN message")?;
+
+    let mut mapped = None;
+    let mut at = 0;
+    while at + 4 <= attributes.len() {
+        let attribute_type = u16::from_be_bytes([attributes[at], attributes[at + 1]]);
+        let attribute_length =
+            u16::from_be_bytes([attributes[at + 2], attributes[at + 3]]) as usize;
+        let value = attributes
+            .get(at + 4..at + 4 + attribute_length)
+            .ok_or("Truncated STUN attribute")?;
+        match attribute_type {
+            ATTRIBUTE_XOR_MAPPED_ADDRESS => {
+                return parse_address(value, Some(&bytes[4..HEADER_BYTES]));
+            }
+            ATTRIBUTE_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+        // Attributes are padded to four bytes.
+        at += 4 + ((attribute_length + 3) & !3);
+    }
+    mapped.ok_or_else(|| "No mapped address in STUN response".into())
+}
+
+/// Reads a (XOR-)MAPPED-ADDRESS value. `xor` is the cookie and transaction id, which
+/// the XOR variant masks the port and address with.
+fn parse_address(value: &[u8], xor: Option<&[u8]>) -> Result<SocketAddr, String> {
+    if value.len() < 4 {
+        return Err("Short STUN address".into());
+    }
+    let mask = |bytes: &[u8]| -> Vec<u8> {
+        match xor {
+            Some(xor) => bytes.iter().zip(xor.iter()).map(|(a, b)| a ^ b).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+    let port = mask(&value[2..4]);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let ip = match value[1] {
+        FAMILY_IPV4 if value.len() >= 8 => {
+            let octets = mask(&value[4..8]);
+            let octets = <[u8; 4]>::try_from(&octets[..]).map_err(|_| "Bad IPv4")?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_IPV6 if value.len() >= 20 => {
+            let octets = mask(&value[4..20]);
+            let octets = <[u8; 16]>::try_from(&octets[..]).map_err(|_| "Bad IPv6")?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        family => return Err(format!("Unknown STUN address family {}", family)),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Keeps track of one socket's public mapping. Like `RegistrationClient` it only
+/// decides what to send and when; the socket belongs to someone else.
+pub struct StunClient {
+    server: SocketAddr,
+    mapped: Option<SocketAddr>,
+    // The outstanding request, when to retransmit it and how many tries it's had.
+    pending: Option<(TransactionId, Instant, u32)>,
+    next_refresh_at: Instant,
+    settled: bool,
+}
+
+impl StunClient {
+    pub fn new(server: SocketAddr, now: Instant) -> StunClient {
+        StunClient {
+            server,
+            mapped: None,
+            pending: None,
+            next_refresh_at: now,
+            settled: false,
+        }
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Our address as the rest of the internet sees it, once we know.
+    pub fn mapped_addr(&self) -> Option<SocketAddr> {
+        self.mapped
+    }
+
+    /// Whether the first lookup is over, one way or the other.
+    pub fn is_settled(&self) -> bool {
+        self.settled
+    }
+
+    /// A request to send to `server()`, if one is due. The refreshes double as
+    /// keep-alives for the mapping.
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.pending {
+            Some((transaction_id, retransmit_at, attempts)) if now >= retransmit_at => {
+                if attempts >= MAX_ATTEMPTS {
+                    println!("STUN server {} not answering", self.server);
+                    self.pending = None;
+                    self.settled = true;
+                    self.next_refresh_at = now + STUN_KEEPALIVE_INTERVAL;
+                    return None;
+                }
+                self.pending = Some((
+                    transaction_id,
+                    now + INITIAL_RTO * 2u32.pow(attempts),
+                    attempts + 1,
+                ));
+                Some(binding_request(&transaction_id))
+            }
+            Some(_) => None,
+            None if now >= self.next_refresh_at => {
+                let transaction_id = rand::random::<TransactionId>();
+                self.pending = Some((transaction_id, now + INITIAL_RTO, 1));
+                Some(binding_request(&transaction_id))
+            }
+            None => None,
+        }
+    }
+
+    /// Feeds in a STUN message that came from the server. Returns the new mapping if
+    /// it changed, which means Contact and friends need updating.
+    pub fn on_response(&mut self, bytes: &[u8], now: Instant) -> Option<SocketAddr> {
+        let transaction_id = match &self.pending {
+            Some((transaction_id, _, _)) => *transaction_id,
+            None => return None,
+        };
+        match parse_binding_response(bytes, &transaction_id) {
+            Ok(mapped) => {
+                self.pending = None;
+                self.settled = true;
+                self.next_refresh_at = now + STUN_KEEPALIVE_INTERVAL;
+                if self.mapped == Some(mapped) {
+                    return None;
+                }
+                self.mapped = Some(mapped);
+                Some(mapped)
+            }
+            Err(err) => {
+                println!("Ignoring STUN message: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Asks `server` what `local_port` looks like from outside, waiting for the answer.
+/// For RTP, whose socket doesn't exist until the call is set up.
+pub fn discover(local_port: u16, server: SocketAddr) -> Result<SocketAddr, String> {
+    let socket = UdpSocket::bind(("0.0.0.0", local_port))
+        .map_err(|err| format!("Can't bind port {}: {}", local_port, err))?;
+    let transaction_id = rand::random::<TransactionId>();
+    let request = binding_request(&transaction_id);
+    let mut buffer = [0u8; DISCOVER_BUFFER_BYTES];
+    let mut timeout = DISCOVER_INITIAL_TIMEOUT;
+    for _ in 0..DISCOVER_ATTEMPTS {
+        socket
+            .send_to(&request, server)
+            .map_err(|err| err.to_string())?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            socket
+                .set_read_timeout(Some(deadline - now))
+                .map_err(|err| err.to_string())?;
+            match socket.recv_from(&mut buffer) {
+                Ok((len, from)) if from == server => {
+                    if let Ok(mapped) = parse_binding_response(&buffer[..len], &transaction_id) {
+                        return Ok(mapped);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        timeout *= 2;
+    }
+    Err(format!("No answer from STUN server {}", server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const TRANSACTION_ID: TransactionId = [7; 12];
+
+    fn response_with(attributes: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![];
+        for (attribute_type, value) in attributes.iter() {
+            body.extend_from_slice(&attribute_type.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            body.resize((body.len() + 3) & !3, 0);
+        }
+        let mut bytes = BINDING_SUCCESS.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        bytes.extend_from_slice(&TRANSACTION_ID);
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn answers_and_reads_xor_mapped_addresses() {
+        let request = binding_request(&TRANSACTION_ID);
+        assert!(is_stun(&request));
+        for source in ["203.0.113.5:40000", "[2001:db8::5]:40001"].iter() {
+            let source: SocketAddr = source.parse().unwrap();
+            let response = answer_binding_request(&request, source).unwrap();
+            assert_eq!(
+                parse_binding_response(&response, &TRANSACTION_ID),
+                Ok(source)
+            );
+        }
+        assert_eq!(
+            answer_binding_request(b"INVITE sip:bob SIP/2.0", "192.0.2.1:5060".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn falls_back_to_mapped_address_past_other_attributes() {
+        // SOFTWARE, three bytes and padded, then an RFC 3489 MAPPED-ADDRESS.
+        let response = response_with(&[
+            (0x8022, b"abc".to_vec()),
+            (
+                ATTRIBUTE_MAPPED_ADDRESS,
+                vec![0, FAMILY_IPV4, 0x13, 0xc4, 198, 51, 100, 7],
+            ),
+        ]);
+        assert_eq!(
+            parse_binding_response(&response, &TRANSACTION_ID),
+            Ok("198.51.100.7:5060".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_what_it_didnt_ask_for() {
+        let response = answer_binding_request(
+            &binding_request(&TRANSACTION_ID),
+            "203.0.113.5:40000".parse().unwrap(),
+        )
+        .unwrap();
+        assert!(parse_binding_response(&response, &[8; 12]).is_err());
+        assert!(parse_binding_response(&response[..response.len() - 1], &TRANSACTION_ID).is_err());
+        assert!(parse_binding_response(&response_with(&[]), &TRANSACTION_ID).is_err());
+        let short = response_with(&[(ATTRIBUTE_XOR_MAPPED_ADDRESS, vec![0, FAMILY_IPV4])]);
+        assert!(parse_binding_response(&short, &TRANSACTION_ID).is_err());
+    }
+
+    #[test]
+    fn client_backs_off_then_gives_up() {
+        let server = "192.0.2.1:3478".parse().unwrap();
+        let start = Instant::now();
+        let mut client = StunClient::new(server, start);
+        assert!(client.poll(start).is_some());
+        assert!(client.poll(start + INITIAL_RTO / 2).is_none());
+        let mut now = start;
+        for attempt in 1..MAX_ATTEMPTS {
+            now += INITIAL_RTO * 2u32.pow(attempt - 1);
+            assert!(client.poll(now).is_some(), "attempt {}", attempt);
+        }
+        assert!(!client.is_settled());
+        now += INITIAL_RTO * 2u32.pow(MAX_ATTEMPTS - 1);
+        assert!(client.poll(now).is_none());
+        assert!(client.is_settled());
+        assert!(client.poll(now + STUN_KEEPALIVE_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn client_reports_a_mapping_when_it_changes() {
+        let server = "192.0.2.1:3478".parse().unwrap();
+        let mapped: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+        let now = Instant::now();
+        let mut client = StunClient::new(server, now);
+
+        let request = client.poll(now).unwrap();
+        let response = answer_binding_request(&request, mapped).unwrap();
+        assert_eq!(client.on_response(&response, now), Some(mapped));
+        assert!(client.is_settled());
+        // A late retransmission's answer is no news.
+        assert_eq!(client.on_response(&response, now), None);
+
+        let later = now + STUN_KEEPALIVE_INTERVAL;
+        let request = client.poll(later).unwrap();
+        let response = answer_binding_request(&request, mapped).unwrap();
+        assert_eq!(client.on_response(&response, later), None);
+        assert_eq!(client.mapped_addr(), Some(mapped));
+    }
+
+    #[test]
+    fn discovers_through_a_stand_in_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let stand_in = thread::spawn(move || {
+            let mut buffer = [0u8; DISCOVER_BUFFER_BYTES];
+            let (len, source) = server.recv_from(&mut buffer).unwrap();
+            let response = answer_binding_request(&buffer[..len], source).unwrap();
+            server.send_to(&response, source).unwrap();
+            source
+        });
+        let local_port = UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let public = discover(local_port, server_addr).unwrap();
+        assert_eq!(public, stand_in.join().unwrap());
+        assert_eq!(public.port(), local_port);
+    }
+}
@@ -0,0 +1,522 @@
+use std::cmp::min;
+use std::time::{Duration, Instant};
+
+use rsip::prelude::*;
+
+use super::sip::param_value;
+use super::transport::TransportTarget;
+
+// RFC 3261 section 17.1.1.1
+pub const T1: Duration = Duration::from_millis(500);
+pub const T2: Duration = Duration::from_secs(4);
+pub const T4: Duration = Duration::from_secs(5);
+
+const TIMER_D_UNRELIABLE: Duration = Duration::from_secs(32);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionState {
+    Calling,
+    Trying,
+    Proceeding,
+    Completed,
+    Confirmed,
+    Terminated,
+}
+
+pub enum TransactionEvent {
+    /// Put this on the wire.
+    Send(rsip::SipMessage),
+    /// Hand this response up to whoever started the transaction.
+    Response(rsip::Response),
+    /// Timer B, F or H went off.
+    Timeout,
+}
+
+pub fn branch_of(message: &rsip::SipMessage) -> Option<String> {
+    let via = match message {
+        rsip::SipMessage::Request(request) => request.via_header().ok()?.value().to_string(),
+        rsip::SipMessage::Response(response) => response.via_header().ok()?.value().to_string(),
+    };
+    param_value(&via, "branch")
+}
+
+pub fn cseq_method_of(message: &rsip::SipMessage) -> Option<rsip::Method> {
+    let cseq = match message {
+        rsip::SipMessage::Request(request) => request.cseq_header().ok()?.typed().ok()?,
+        rsip::SipMessage::Response(response) => response.cseq_header().ok()?.typed().ok()?,
+    };
+    Some(cseq.method)
+}
+
+/// A retransmission timer that doubles each time it fires, up to `cap` if there is one.
+pub struct BackoffTimer {
+    fires_at: Instant,
+    interval: Duration,
+    cap: Option<Duration>,
+}
+
+impl BackoffTimer {
+    pub fn new(now: Instant, interval: Duration, cap: Option<Duration>) -> BackoffTimer {
+        BackoffTimer {
+            fires_at: now + interval,
+            interval,
+            cap,
+        }
+    }
+
+    pub fn fire_if_due(&mut self, now: Instant) -> bool {
+        if now < self.fires_at {
+            return false;
+        }
+        self.interval = match self.cap {
+            Some(cap) => min(self.interval * 2, cap),
+            None => self.interval * 2,
+        };
+        self.fires_at = now + self.interval;
+        true
+    }
+}
+
+/// INVITE (timers A, B, D) and non-INVITE (timers E, F, K) client transactions.
+pub struct ClientTransaction {
+    branch: String,
+    method: rsip::Method,
+    request: rsip::Request,
+    state: TransactionState,
+    reliable: bool,
+    retransmit: Option<BackoffTimer>,
+    timeout_at: Option<Instant>,
+    terminate_at: Option<Instant>,
+    ack: Option<rsip::Request>,
+}
+
+impl ClientTransaction {
+    /// Starts the transaction. The request still has to be sent once by the caller.
+    pub fn new(request: rsip::Request, reliable: bool, now: Instant) -> ClientTransaction {
+        let branch = branch_of(&request.clone().into()).unwrap_or_default();
+        let method = request.method;
+        let is_invite = method == rsip::Method::Invite;
+        let retransmit = if reliable {
+            None
+        } else if is_invite {
+            Some(BackoffTimer::new(now, T1, None))
+        } else {
+            Some(BackoffTimer::new(now, T1, Some(T2)))
+        };
+        ClientTransaction {
+            branch,
+            method,
+            request,
+            state: if is_invite {
+                TransactionState::Calling
+            } else {
+                TransactionState::Trying
+            },
+            reliable,
+            retransmit,
+            timeout_at: Some(now + 64 * T1),
+            terminate_at: None,
+            ack: None,
+        }
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    pub fn method(&self) -> &rsip::Method {
+        &self.method
+    }
+
+    pub fn request(&self) -> &rsip::Request {
+        &self.request
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.state == TransactionState::Terminated
+    }
+
+    pub fn matches(&self, response: &rsip::Response) -> bool {
+        let message: rsip::SipMessage = response.clone().into();
+        branch_of(&message).as_deref() == Some(self.branch.as_str())
+            && cseq_method_of(&message).as_ref() == Some(&self.method)
+    }
+
+    pub fn on_response(&mut self, response: rsip::Response, now: Instant) -> Vec<TransactionEvent> {
+        let code = response.status_code.code();
+        let is_invite = self.method == rsip::Method::Invite;
+        match self.state {
+            TransactionState::Calling | TransactionState::Trying | TransactionState::Proceeding => {
+                if code < 200 {
+                    self.state = TransactionState::Proceeding;
+                    if is_invite {
+                        // Timer B only runs while calling, it may ring for as long as it likes.
+                        self.retransmit = None;
+                        self.timeout_at = None;
+                    } else if !self.reliable {
+                        // Timer E keeps running at T2 while proceeding.
+                        self.retransmit = Some(BackoffTimer::new(now, T2, Some(T2)));
+                    }
+                    return vec![TransactionEvent::Response(response)];
+                }
+                self.retransmit = None;
+                self.timeout_at = None;
+                if is_invite && code < 300 {
+                    // The TU acknowledges 2xx itself, since the ACK is a new transaction.
+                    self.state = TransactionState::Terminated;
+                    return vec![TransactionEvent::Response(response)];
+                }
+                self.state = TransactionState::Completed;
+                let mut events = vec![];
+                if is_invite {
+                    let ack = Self::ack_for(&self.request, &response);
+                    events.push(TransactionEvent::Send(ack.clone().into()));
+                    self.ack = Some(ack);
+                    self.terminate_at = Some(if self.reliable {
+                        now
+                    } else {
+                        now + TIMER_D_UNRELIABLE
+                    });
+                } else {
+                    self.terminate_at = Some(if self.reliable { now } else { now + T4 });
+                }
+                events.push(TransactionEvent::Response(response));
+                events
+            }
+            TransactionState::Completed => {
+                // A retransmitted final response means our ACK got lost.
+                match &self.ack {
+                    Some(ack) if code >= 300 => vec![TransactionEvent::Send(ack.clone().into())],
+                    _ => vec![],
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn on_tick(&mut self, now: Instant) -> Vec<TransactionEvent> {
+        if let Some(terminate_at) = self.terminate_at {
+            if now >= terminate_at {
+                self.state = TransactionState::Terminated;
+                self.terminate_at = None;
+            }
+            return vec![];
+        }
+        if let Some(timeout_at) = self.timeout_at {
+            if now >= timeout_at {
+                self.state = TransactionState::Terminated;
+                self.timeout_at = None;
+                self.retransmit = None;
+                return vec![TransactionEvent::Timeout];
+            }
+        }
+        if let Some(timer) = &mut self.retransmit {
+            if timer.fire_if_due(now) {
+                return vec![TransactionEvent::Send(self.request.clone().into())];
+            }
+        }
+        vec![]
+    }
+
+    /// The ACK for a non-2xx final response, section 17.1.1.3.
+    fn ack_for(request: &rsip::Request, response: &rsip::Response) -> rsip::Request {
+        let mut headers: rsip::Headers = Default::default();
+        if let Ok(via) = request.via_header() {
+            headers.push(via.clone().into());
+        }
+        if let Ok(from) = request.from_header() {
+            headers.push(from.clone().into());
+        }
+        if let Ok(to) = response.to_header() {
+            headers.push(to.clone().into());
+        }
+        if let Ok(call_id) = request.call_id_header() {
+            headers.push(call_id.clone().into());
+        }
+        if let Ok(Ok(cseq)) = request.cseq_header().map(|cseq| cseq.typed()) {
+            headers.push(
+                rsip::typed::CSeq {
+                    seq: cseq.seq,
+                    method: rsip::Method::Ack,
+                }
+                .into(),
+            );
+        }
+        for header in request.headers.iter() {
+            if let rsip::Header::Route(_) = header {
+                headers.push(header.clone());
+            }
+        }
+        headers.push(rsip::headers::MaxForwards::default().into());
+        headers.push(rsip::headers::ContentLength::default().into());
+        rsip::Request {
+            method: rsip::Method::Ack,
+            uri: request.uri.clone(),
+            headers,
+            version: rsip::Version::V2,
+            body: Default::default(),
+        }
+    }
+}
+
+/// INVITE (timers G, H, I) and non-INVITE (timer J) server transactions.
+pub struct ServerTransaction {
+    branch: String,
+    method: rsip::Method,
+    request: rsip::Request,
+    source: TransportTarget,
+    state: TransactionState,
+    reliable: bool,
+    last_response: Option<rsip::Response>,
+    retransmit: Option<BackoffTimer>,
+    timeout_at: Option<Instant>,
+    terminate_at: Option<Instant>,
+}
+
+impl ServerTransaction {
+    pub fn new(request: rsip::Request, source: TransportTarget) -> ServerTransaction {
+        let branch = branch_of(&request.clone().into()).unwrap_or_default();
+        let method = request.method;
+        ServerTransaction {
+            branch,
+            state: if method == rsip::Method::Invite {
+                TransactionState::Proceeding
+            } else {
+                TransactionState::Trying
+            },
+            method,
+            request,
+            source,
+            reliable: source.transport.is_reliable(),
+            last_response: None,
+            retransmit: None,
+            timeout_at: None,
+            terminate_at: None,
+        }
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    pub fn method(&self) -> &rsip::Method {
+        &self.method
+    }
+
+    pub fn request(&self) -> &rsip::Request {
+        &self.request
+    }
+
+    pub fn source(&self) -> TransportTarget {
+        self.source
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.state == TransactionState::Terminated
+    }
+
+    /// An ACK to a non-2xx response belongs to the INVITE transaction it acknowledges.
+    pub fn matches(&self, request: &rsip::Request) -> bool {
+        let message: rsip::SipMessage = request.clone().into();
+        if branch_of(&message).as_deref() != Some(self.branch.as_str()) {
+            return false;
+        }
+        request.method == self.method
+            || (request.method == rsip::Method::Ack && self.method == rsip::Method::Invite)
+    }
+
+    /// Absorbs retransmitted requests and ACKs.
+    pub fn on_request(&mut self, request: &rsip::Request, now: Instant) -> Vec<TransactionEvent> {
+        if request.method == rsip::Method::Ack {
+            if self.state == TransactionState::Completed {
+                self.state = TransactionState::Confirmed;
+                self.retransmit = None;
+                self.timeout_at = None;
+                self.terminate_at = Some(if self.reliable { now } else { now + T4 });
+            }
+            return vec![];
+        }
+        match (&self.state, &self.last_response) {
+            (TransactionState::Proceeding, Some(response))
+            | (TransactionState::Completed, Some(response)) => {
+                vec![TransactionEvent::Send(response.clone().into())]
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn respond(&mut self, response: rsip::Response, now: Instant) -> Vec<TransactionEvent> {
+        let code = response.status_code.code();
+        let is_invite = self.method == rsip::Method::Invite;
+        match self.state {
+            TransactionState::Trying | TransactionState::Proceeding => {}
+            _ => return vec![],
+        }
+        self.last_response = Some(response.clone());
+        if code < 200 {
+            self.state = TransactionState::Proceeding;
+        } else if is_invite && code < 300 {
+            // 2xx retransmissions are the dialog's job from here on.
+            self.state = TransactionState::Terminated;
+        } else if is_invite {
+            self.state = TransactionState::Completed;
+            if !self.reliable {
+                self.retransmit = Some(BackoffTimer::new(now, T1, Some(T2)));
+            }
+            self.timeout_at = Some(now + 64 * T1);
+        } else {
+            self.state = TransactionState::Completed;
+            self.terminate_at = Some(if self.reliable { now } else { now + 64 * T1 });
+        }
+        vec![TransactionEvent::Send(response.into())]
+    }
+
+    pub fn on_tick(&mut self, now: Instant) -> Vec<TransactionEvent> {
+        if let Some(terminate_at) = self.terminate_at {
+            if now >= terminate_at {
+                self.state = TransactionState::Terminated;
+                self.terminate_at = None;
+            }
+            return vec![];
+        }
+        if let Some(timeout_at) = self.timeout_at {
+            if now >= timeout_at {
+                // Timer H: the ACK never came.
+                self.state = TransactionState::Terminated;
+                self.timeout_at = None;
+                self.retransmit = None;
+                return vec![TransactionEvent::Timeout];
+            }
+        }
+        if let (Some(timer), Some(response)) = (&mut self.retransmit, &self.last_response) {
+            if timer.fire_if_due(now) {
+                return vec![TransactionEvent::Send(response.clone().into())];
+            }
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    fn invite() -> rsip::Request {
+        rsip::Request::try_from(
+            "INVITE sip:bob@biloxi.example.com SIP/2.0\r\n\
+             Via: SIP/2.0/UDP 192.0.2.10:5060;branch=z9hG4bK776asdhds\r\n\
+             Max-Forwards: 70\r\n\
+             To: <sip:bob@biloxi.example.com>\r\n\
+             From: <sip:alice@atlanta.example.com>;tag=1928301774\r\n\
+             Call-ID: a84b4c76e66710@192.0.2.10\r\n\
+             CSeq: 4711 INVITE\r\n\
+             Content-Length: 0\r\n\r\n"
+                .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn response(code: u16) -> rsip::Response {
+        let reason = match code {
+            180 => "Ringing",
+            200 => "OK",
+            _ => "Busy Here",
+        };
+        rsip::Response::try_from(
+            format!(
+                "SIP/2.0 {} {}\r\n\
+                 Via: SIP/2.0/UDP 192.0.2.10:5060;branch=z9hG4bK776asdhds\r\n\
+                 To: <sip:bob@biloxi.example.com>;tag=a6c85cf\r\n\
+                 From: <sip:alice@atlanta.example.com>;tag=1928301774\r\n\
+                 Call-ID: a84b4c76e66710@192.0.2.10\r\n\
+                 CSeq: 4711 INVITE\r\n\
+                 Content-Length: 0\r\n\r\n",
+                code, reason
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn sends(events: &[TransactionEvent]) -> usize {
+        events
+            .iter()
+            .filter(|event| matches!(event, TransactionEvent::Send(_)))
+            .count()
+    }
+
+    #[test]
+    fn invite_retransmits_until_provisional() {
+        let now = Instant::now();
+        let mut transaction = ClientTransaction::new(invite(), false, now);
+        assert_eq!(transaction.state(), TransactionState::Calling);
+        assert_eq!(sends(&transaction.on_tick(now + T1)), 1);
+        // Timer A doubles.
+        assert_eq!(sends(&transaction.on_tick(now + T1 + T1)), 0);
+        assert_eq!(sends(&transaction.on_tick(now + 3 * T1)), 1);
+
+        assert!(transaction.matches(&response(180)));
+        transaction.on_response(response(180), now + 3 * T1);
+        assert_eq!(transaction.state(), TransactionState::Proceeding);
+        assert_eq!(sends(&transaction.on_tick(now + 10 * T1)), 0);
+    }
+
+    #[test]
+    fn ringing_outlasts_timer_b() {
+        let now = Instant::now();
+        let mut transaction = ClientTransaction::new(invite(), false, now);
+        transaction.on_response(response(180), now);
+        let events = transaction.on_tick(now + Duration::from_secs(120));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, TransactionEvent::Timeout)));
+        assert_eq!(transaction.state(), TransactionState::Proceeding);
+    }
+
+    #[test]
+    fn timer_b_fires_without_an_answer() {
+        let now = Instant::now();
+        let mut transaction = ClientTransaction::new(invite(), false, now);
+        let events = transaction.on_tick(now + 64 * T1);
+        assert!(matches!(events.as_slice(), [TransactionEvent::Timeout]));
+        assert!(transaction.is_terminated());
+    }
+
+    #[test]
+    fn acks_non_2xx_and_again_for_retransmissions() {
+        let now = Instant::now();
+        let mut transaction = ClientTransaction::new(invite(), false, now);
+        let events = transaction.on_response(response(486), now);
+        assert_eq!(sends(&events), 1);
+        assert_eq!(transaction.state(), TransactionState::Completed);
+        match &events[0] {
+            TransactionEvent::Send(rsip::SipMessage::Request(ack)) => {
+                assert_eq!(ack.method, rsip::Method::Ack);
+                assert_eq!(ack.cseq_header().unwrap().typed().unwrap().seq, 4711);
+            }
+            _ => panic!("expected the ACK first"),
+        }
+        assert_eq!(sends(&transaction.on_response(response(486), now)), 1);
+        transaction.on_tick(now + TIMER_D_UNRELIABLE);
+        assert!(transaction.is_terminated());
+    }
+
+    #[test]
+    fn invite_ends_on_2xx_for_the_call_to_ack() {
+        let now = Instant::now();
+        let mut transaction = ClientTransaction::new(invite(), true, now);
+        let events = transaction.on_response(response(200), now);
+        assert_eq!(sends(&events), 0);
+        assert!(transaction.is_terminated());
+    }
+}
@@ -0,0 +1,283 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use rsip::prelude::*;
+
+use super::sip::param_value;
+
+/// The event package a REFER's implicit subscription reports progress in, RFC 3515.
+pub const REFER_EVENT: &str = "refer";
+pub const SIPFRAG_CONTENT_TYPE: &str = "message/sipfrag";
+
+/// The dialog an INVITE with a Replaces header (RFC 3891) takes over, named the way
+/// whoever receives that INVITE sees it: `to_tag` is their tag, `from_tag` the far end's.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replaces {
+    pub call_id: String,
+    pub to_tag: String,
+    pub from_tag: String,
+}
+
+impl fmt::Display for Replaces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{};to-tag={};from-tag={}",
+            self.call_id, self.to_tag, self.from_tag
+        )
+    }
+}
+
+impl Replaces {
+    pub fn parse(value: &str) -> Option<Replaces> {
+        let call_id = value.split(';').next()?.trim();
+        if call_id.is_empty() {
+            return None;
+        }
+        Some(Replaces {
+            call_id: call_id.into(),
+            to_tag: param_value(value, "to-tag")?,
+            from_tag: param_value(value, "from-tag")?,
+        })
+    }
+
+    /// The one in an incoming INVITE, if it has one.
+    pub fn from_headers(headers: &rsip::Headers) -> Option<Result<Replaces, String>> {
+        other_header(headers, &["replaces"])
+            .map(|value| Replaces::parse(&value).ok_or_else(|| "Bad Replaces".into()))
+    }
+
+    pub fn header(&self) -> rsip::Header {
+        rsip::Header::Other("Replaces".into(), self.to_string())
+    }
+}
+
+// rsip has no typed Refer-To, Referred-By or Replaces, so they come through as `Other`.
+// The names may be in either case or compact form.
+fn other_header(headers: &rsip::Headers, names: &[&str]) -> Option<String> {
+    headers.iter().find_map(|header| match header {
+        rsip::Header::Other(name, value)
+            if names
+                .iter()
+                .any(|wanted| name.trim().eq_ignore_ascii_case(wanted)) =>
+        {
+            Some(value.clone())
+        }
+        _ => None,
+    })
+}
+
+/// Refer-To for sending the far end to `target`, taking over the `replaces` dialog
+/// there for an attended transfer.
+pub fn refer_to_header(target: &rsip::Uri, replaces: Option<&Replaces>) -> rsip::Header {
+    let value = match replaces {
+        Some(replaces) => format!("<{}?Replaces={}>", target, escape(&replaces.to_string())),
+        None => format!("<{}>", target),
+    };
+    rsip::Header::Other("Refer-To".into(), value)
+}
+
+pub fn referred_by_header(aor: &rsip::Uri) -> rsip::Header {
+    rsip::Header::Other("Referred-By".into(), format!("<{}>", aor))
+}
+
+/// Where a REFER asks us to go, and which dialog there to replace if any.
+pub fn parse_refer_to(headers: &rsip::Headers) -> Result<(rsip::Uri, Option<Replaces>), String> {
+    let value = other_header(headers, &["refer-to", "r"]).ok_or("REFER without Refer-To")?;
+    let value = match (value.find('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => value[start + 1..end].to_string(),
+        _ => value.trim().to_string(),
+    };
+    let mut parts = value.splitn(2, '?');
+    let uri =
+        rsip::Uri::try_from(parts.next().unwrap_or_default()).map_err(|err| err.to_string())?;
+    let replaces = parts.next().and_then(|embedded| {
+        embedded.split('&').find_map(|header| {
+            let mut kv = header.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("replaces") => {
+                    Replaces::parse(&unescape(value))
+                }
+                _ => None,
+            }
+        })
+    });
+    Ok((uri, replaces))
+}
+
+/// The body of a NOTIFY telling the referrer how the referred INVITE went.
+pub fn sipfrag(status_code: u16) -> String {
+    let status: rsip::StatusCode = status_code.into();
+    format!("SIP/2.0 {}\r\n", status)
+}
+
+/// The status code in a NOTIFY's message/sipfrag.
+pub fn parse_sipfrag(body: &[u8]) -> Option<u16> {
+    let body = String::from_utf8_lossy(body);
+    let status_line = body.lines().next()?;
+    if !status_line.starts_with("SIP/") {
+        return None;
+    }
+    status_line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Whether a NOTIFY is about a REFER.
+pub fn is_refer_notify(request: &rsip::Request) -> bool {
+    request.headers.iter().any(|header| match header {
+        rsip::Header::Event(event) => {
+            event.value().split(';').next().map(|event| event.trim()) == Some(REFER_EVENT)
+        }
+        _ => false,
+    })
+}
+
+// Headers embedded in a URI are escaped like the rest of it, RFC 3261 section 19.1.1.
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'!'
+            | b'~'
+            | b'*'
+            | b'\''
+            | b'('
+            | b')' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut unescaped = vec![];
+    let mut index = 0;
+    while index < bytes.len() {
+        let decoded = if bytes[index] == b'%' && index + 2 < bytes.len() {
+            std::str::from_utf8(&bytes[index + 1..index + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match decoded {
+            Some(byte) => {
+                unescaped.push(byte);
+                index += 3;
+            }
+            None => {
+                unescaped.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replaces() -> Replaces {
+        Replaces {
+            call_id: "98asjd8@bob.example.com".into(),
+            to_tag: "12345".into(),
+            from_tag: "abc~def".into(),
+        }
+    }
+
+    fn headers(header: rsip::Header) -> rsip::Headers {
+        let mut headers = rsip::Headers::default();
+        headers.push(header);
+        headers
+    }
+
+    #[test]
+    fn escapes_what_a_uri_header_cannot_carry() {
+        assert_eq!(
+            escape("a@b.c;to-tag=1 2"),
+            "a%40b.c%3Bto-tag%3D1%202".to_string()
+        );
+        assert_eq!(unescape("a%40b.c%3Bto-tag%3D1%202"), "a@b.c;to-tag=1 2");
+        assert_eq!(unescape(&escape("100% ~done")), "100% ~done");
+    }
+
+    #[test]
+    fn leaves_stray_percent_signs_alone() {
+        assert_eq!(unescape("50%"), "50%");
+        assert_eq!(unescape("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn parses_replaces() {
+        assert_eq!(
+            Replaces::parse("98asjd8@bob.example.com;from-tag=abc~def;to-tag=12345"),
+            Some(replaces())
+        );
+        assert_eq!(Replaces::parse(&replaces().to_string()), Some(replaces()));
+        assert_eq!(
+            Replaces::parse("98asjd8@bob.example.com;to-tag=12345"),
+            None
+        );
+        assert_eq!(Replaces::parse(";to-tag=1;from-tag=2"), None);
+    }
+
+    #[test]
+    fn round_trips_a_blind_refer_to() {
+        let target = rsip::Uri::try_from("sip:carol@example.com").unwrap();
+        let (uri, replaces) = parse_refer_to(&headers(refer_to_header(&target, None))).unwrap();
+        assert_eq!(uri, target);
+        assert_eq!(replaces, None);
+    }
+
+    #[test]
+    fn round_trips_an_attended_refer_to() {
+        let target = rsip::Uri::try_from("sip:carol@example.com").unwrap();
+        let header = refer_to_header(&target, Some(&replaces()));
+        match &header {
+            rsip::Header::Other(_, value) => assert_eq!(
+                value,
+                "<sip:carol@example.com?Replaces=98asjd8%40bob.example.com%3Bto-tag%3D12345%3Bfrom-tag%3Dabc~def>"
+            ),
+            _ => panic!("Refer-To isn't a plain header"),
+        }
+        let (uri, parsed) = parse_refer_to(&headers(header)).unwrap();
+        assert_eq!(uri, target);
+        assert_eq!(parsed, Some(replaces()));
+    }
+
+    #[test]
+    fn reads_a_compact_refer_to() {
+        let header = rsip::Header::Other("r".into(), "sip:carol@example.com".into());
+        let (uri, replaces) = parse_refer_to(&headers(header)).unwrap();
+        assert_eq!(uri, rsip::Uri::try_from("sip:carol@example.com").unwrap());
+        assert_eq!(replaces, None);
+    }
+
+    #[test]
+    fn needs_a_refer_to() {
+        let header = rsip::Header::Other("Referred-By".into(), "<sip:bob@example.com>".into());
+        assert!(parse_refer_to(&headers(header)).is_err());
+    }
+
+    #[test]
+    fn parses_sipfrag() {
+        assert_eq!(parse_sipfrag(sipfrag(180).as_bytes()), Some(180));
+        assert_eq!(parse_sipfrag(b"SIP/2.0 200 OK\r\n"), Some(200));
+        assert_eq!(parse_sipfrag(b"SIP/2.0 503\r\n"), Some(503));
+    }
+
+    #[test]
+    fn rejects_malformed_sipfrag() {
+        assert_eq!(parse_sipfrag(b""), None);
+        assert_eq!(parse_sipfrag(b"HTTP/1.1 200 OK\r\n"), None);
+        assert_eq!(parse_sipfrag(b"SIP/2.0 OK\r\n"), None);
+        assert_eq!(parse_sipfrag(b"SIP/2.0\r\n"), None);
+    }
+}
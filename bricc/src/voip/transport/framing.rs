@@ -0,0 +1,145 @@
+// RFC 3261 section 18.3: nothing else is allowed to be this big anyway.
+const MAX_MESSAGE_BYTES: usize = 65535;
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+/// Cuts a byte stream into SIP messages using Content-Length, which stream
+/// transports must send. CRLF keep-alives between messages are skipped.
+pub struct StreamFramer {
+    buffer: Vec<u8>,
+}
+
+impl StreamFramer {
+    pub fn new() -> StreamFramer {
+        StreamFramer { buffer: vec![] }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next whole message, if it's all arrived. An error means the stream is
+    /// garbage from here on and the connection should go.
+    pub fn next_message(&mut self) -> Result<Option<Vec<u8>>, String> {
+        // RFC 5626 keep-alives, and stray line ends before a start line.
+        let leading = self
+            .buffer
+            .iter()
+            .take_while(|byte| **byte == b'\r' || **byte == b'\n')
+            .count();
+        self.buffer.drain(..leading);
+
+        let header_len = match self
+            .buffer
+            .windows(HEADER_END.len())
+            .position(|window| window == HEADER_END)
+        {
+            Some(position) => position + HEADER_END.len(),
+            None if self.buffer.len() > MAX_MESSAGE_BYTES => {
+                return Err("SIP headers too long".into())
+            }
+            None => return Ok(None),
+        };
+        let headers = std::str::from_utf8(&self.buffer[..header_len])
+            .map_err(|_| "SIP headers aren't UTF-8".to_string())?;
+        let body_len = content_length(headers).ok_or("SIP message without Content-Length")?;
+        let total = header_len + body_len;
+        if total > MAX_MESSAGE_BYTES {
+            return Err(format!("SIP message of {} bytes is too long", total));
+        }
+        if self.buffer.len() < total {
+            return Ok(None);
+        }
+        Ok(Some(self.buffer.drain(..total).collect()))
+    }
+}
+
+impl Default for StreamFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn content_length(headers: &str) -> Option<usize> {
+    headers.split("\r\n").skip(1).find_map(|line| {
+        let mut name_value = line.splitn(2, ':');
+        let name = name_value.next()?.trim();
+        // "l" is the compact form.
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("l") {
+            name_value.next()?.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: &[u8] = b"OPTIONS sip:bob@biloxi.example.com SIP/2.0\r\n\
+                             Call-ID: a84b4c76e66710\r\n\
+                             Content-Length: 0\r\n\r\n";
+    const MESSAGE: &[u8] = b"MESSAGE sip:bob@biloxi.example.com SIP/2.0\r\n\
+                             Call-ID: 3848276298220188511\r\n\
+                             l: 5\r\n\r\n\
+                             Hello";
+
+    #[test]
+    fn waits_for_a_message_split_across_reads() {
+        let mut framer = StreamFramer::new();
+        let (start, rest) = MESSAGE.split_at(MESSAGE.len() - 3);
+        framer.push(start);
+        assert_eq!(framer.next_message(), Ok(None));
+        framer.push(rest);
+        assert_eq!(framer.next_message(), Ok(Some(MESSAGE.to_vec())));
+        assert_eq!(framer.next_message(), Ok(None));
+    }
+
+    #[test]
+    fn splits_two_messages_in_one_read() {
+        let mut framer = StreamFramer::new();
+        framer.push(&[MESSAGE, OPTIONS].concat());
+        assert_eq!(framer.next_message(), Ok(Some(MESSAGE.to_vec())));
+        assert_eq!(framer.next_message(), Ok(Some(OPTIONS.to_vec())));
+        assert_eq!(framer.next_message(), Ok(None));
+    }
+
+    #[test]
+    fn skips_keep_alives_between_messages() {
+        let mut framer = StreamFramer::new();
+        framer.push(b"\r\n\r\n");
+        assert_eq!(framer.next_message(), Ok(None));
+        framer.push(&[OPTIONS, b"\r\n\r\n\r\n", OPTIONS].concat());
+        assert_eq!(framer.next_message(), Ok(Some(OPTIONS.to_vec())));
+        assert_eq!(framer.next_message(), Ok(Some(OPTIONS.to_vec())));
+        assert_eq!(framer.next_message(), Ok(None));
+    }
+
+    #[test]
+    fn needs_a_content_length() {
+        let mut framer = StreamFramer::new();
+        framer.push(b"OPTIONS sip:bob@biloxi.example.com SIP/2.0\r\nCall-ID: 1\r\n\r\n");
+        assert!(framer.next_message().is_err());
+    }
+
+    #[test]
+    fn takes_only_the_body_content_length_covers() {
+        let mut framer = StreamFramer::new();
+        framer.push(b"MESSAGE sip:bob@biloxi.example.com SIP/2.0\r\nl: 4\r\n\r\nHello");
+        assert_eq!(
+            framer.next_message(),
+            Ok(Some(
+                b"MESSAGE sip:bob@biloxi.example.com SIP/2.0\r\nl: 4\r\n\r\nHell".to_vec()
+            ))
+        );
+        // The rest is the start of whatever comes next.
+        assert_eq!(framer.buffer, b"o".to_vec());
+    }
+
+    #[test]
+    fn gives_up_on_endless_headers() {
+        let mut framer = StreamFramer::new();
+        framer.push(&vec![b'a'; MAX_MESSAGE_BYTES + 1]);
+        assert!(framer.next_message().is_err());
+    }
+}
@@ -0,0 +1,295 @@
+pub mod framing;
+mod runtime;
+mod tls;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rsip::prelude::*;
+
+use super::account::SipTransport;
+use super::sip::parse_message;
+use super::stun::is_stun;
+
+const TRANSPORT_THREAD_STACK_SIZE_BYTES: usize = 16384usize;
+
+/// A peer and the transport to reach it on. Stream transports keep one connection
+/// per target, used both ways.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TransportTarget {
+    pub transport: SipTransport,
+    pub addr: SocketAddr,
+}
+
+impl TransportTarget {
+    /// RFC 3263 section 4.1: `sips:` means TLS, then the `transport` parameter, then
+    /// whatever we'd use anyway.
+    pub fn transport_for_uri(
+        uri: &rsip::Uri,
+        fallback: SipTransport,
+    ) -> Result<SipTransport, String> {
+        if let Some(rsip::Scheme::Sips) = uri.scheme {
+            return Ok(SipTransport::Tls);
+        }
+        for param in uri.params.iter() {
+            if let rsip::Param::Transport(transport) = param {
+                return SipTransport::from_rsip(transport)
+                    .ok_or_else(|| format!("Unsupported transport {}", transport));
+            }
+        }
+        Ok(fallback)
+    }
+}
+
+pub enum ReceivedMessage {
+    Sip {
+        message: rsip::SipMessage,
+        source: TransportTarget,
+    },
+    /// STUN shares the UDP socket, so its responses turn up here too.
+    Stun { bytes: Vec<u8>, source: SocketAddr },
+}
+
+pub enum TransportCommand {
+    /// Bytes for a target, and the name to check its certificate against if a new TLS
+    /// connection is needed.
+    Send(TransportTarget, Option<String>, Vec<u8>),
+    Terminate,
+}
+
+/// UDP, TCP and TLS behind one interface, run by smol on a thread of its own.
+/// Listens for UDP and TCP on the same port; TLS is outgoing only.
+pub struct SipTransportLayer {
+    thread: Option<JoinHandle<()>>,
+    command_sender: smol::channel::Sender<TransportCommand>,
+    received: Receiver<ReceivedMessage>,
+    local_port: u16,
+}
+
+impl SipTransportLayer {
+    pub fn start() -> Result<SipTransportLayer, String> {
+        let udp = UdpSocket::bind("0.0.0.0:0").map_err(|err| err.to_string())?;
+        let local_port = udp.local_addr().map_err(|err| err.to_string())?.port();
+        // Not fatal: we can still make outgoing connections without it.
+        let tcp = match std::net::TcpListener::bind(("0.0.0.0", local_port)) {
+            Ok(listener) => Some(listener),
+            Err(err) => {
+                println!("Not listening for SIP over TCP: {}", err);
+                None
+            }
+        };
+        let (command_sender, command_receiver) = smol::channel::unbounded();
+        let (received_sender, received) = channel::<ReceivedMessage>();
+        let thread_builder = thread::Builder::new().stack_size(TRANSPORT_THREAD_STACK_SIZE_BYTES);
+        let thread = thread_builder
+            .spawn(move || runtime::run(udp, tcp, command_receiver, received_sender))
+            .map_err(|err| format!("Failed to create SIP transport thread: {}", err))?;
+        Ok(SipTransportLayer {
+            thread: Some(thread),
+            command_sender,
+            received,
+            local_port,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    pub fn send(
+        &self,
+        target: TransportTarget,
+        server_name: Option<String>,
+        message: rsip::SipMessage,
+    ) {
+        self.send_bytes(target, server_name, message.to_string().into_bytes());
+    }
+
+    /// Sends something that isn't SIP, like STUN or a keep-alive, the same way.
+    pub fn send_bytes(&self, target: TransportTarget, server_name: Option<String>, bytes: Vec<u8>) {
+        if self
+            .command_sender
+            .try_send(TransportCommand::Send(target, server_name, bytes))
+            .is_err()
+        {
+            println!("SIP transport thread is gone");
+        }
+    }
+
+    /// The next message from anywhere, waiting up to `timeout` for one.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ReceivedMessage> {
+        match self.received.recv_timeout(timeout) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(timeout);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for SipTransportLayer {
+    fn drop(&mut self) {
+        let _ = self.command_sender.try_send(TransportCommand::Terminate);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                println!("SIP transport thread panicked");
+            }
+        }
+    }
+}
+
+/// Parses what came in and, for requests, records on the top Via where it really
+/// came from (RFC 3261 section 18.2.1, RFC 3581). Responses then go back there.
+fn accept_message(bytes: &[u8], source: TransportTarget) -> Option<ReceivedMessage> {
+    if bytes.iter().all(|byte| byte.is_ascii_whitespace()) {
+        // Keep-alive.
+        return None;
+    }
+    if source.transport == SipTransport::Udp && is_stun(bytes) {
+        return Some(ReceivedMessage::Stun {
+            bytes: bytes.to_vec(),
+            source: source.addr,
+        });
+    }
+    let message = match parse_message(bytes) {
+        Ok(rsip::SipMessage::Request(mut request)) => {
+            stamp_via(&mut request, source.addr);
+            rsip::SipMessage::Request(request)
+        }
+        Ok(response) => response,
+        Err(err) => {
+            println!(
+                "Dropping unparseable SIP message from {}: {}",
+                source.addr, err
+            );
+            return None;
+        }
+    };
+    Some(ReceivedMessage::Sip { message, source })
+}
+
+/// Adds `received` and `rport` to the top Via, as anything receiving a request
+/// over UDP should.
+pub fn stamp_via(request: &mut rsip::Request, source: SocketAddr) {
+    let mut headers: rsip::Headers = Default::default();
+    let mut stamped = false;
+    for header in request.headers.iter() {
+        match header {
+            rsip::Header::Via(via) if !stamped => {
+                stamped = true;
+                headers.push(rsip::Header::Via(rsip::headers::Via::new(stamped_via(
+                    via.value(),
+                    source,
+                ))));
+            }
+            header => headers.push(header.clone()),
+        }
+    }
+    request.headers = headers;
+}
+
+fn stamped_via(via: &str, source: SocketAddr) -> String {
+    let mut parts: Vec<String> = via.split(';').map(|part| part.trim().to_string()).collect();
+    let sent_by = parts[0].split_whitespace().nth(1).unwrap_or("");
+    let sent_by_host = match sent_by.rfind(']') {
+        Some(bracket) => sent_by[..=bracket].trim_matches(|c| c == '[' || c == ']'),
+        None => sent_by.split(':').next().unwrap_or(""),
+    };
+    let mut wants_received = sent_by_host != source.ip().to_string();
+    for part in parts.iter_mut().skip(1) {
+        if part.eq_ignore_ascii_case("rport") {
+            *part = format!("rport={}", source.port());
+            // RFC 3581 section 4: even if the host matches.
+            wants_received = true;
+        }
+    }
+    let has_received = parts
+        .iter()
+        .skip(1)
+        .any(|part| part.to_ascii_lowercase().starts_with("received="));
+    if wants_received && !has_received {
+        parts.push(format!("received={}", source.ip()));
+    }
+    parts.join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> SocketAddr {
+        "203.0.113.7:40123".parse().unwrap()
+    }
+
+    #[test]
+    fn stamps_rport_and_received() {
+        assert_eq!(
+            stamped_via(
+                "SIP/2.0/UDP 192.168.1.20:5060;rport;branch=z9hG4bK776",
+                source()
+            ),
+            "SIP/2.0/UDP 192.168.1.20:5060;rport=40123;branch=z9hG4bK776;received=203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn stamps_received_only_when_the_host_differs() {
+        assert_eq!(
+            stamped_via("SIP/2.0/UDP 192.168.1.20;branch=z9hG4bK776", source()),
+            "SIP/2.0/UDP 192.168.1.20;branch=z9hG4bK776;received=203.0.113.7"
+        );
+        let same_host = "SIP/2.0/UDP 203.0.113.7:5060;branch=z9hG4bK776";
+        assert_eq!(stamped_via(same_host, source()), same_host);
+        // With rport, received goes in whatever the host.
+        assert_eq!(
+            stamped_via("SIP/2.0/UDP 203.0.113.7:5060;rport", source()),
+            "SIP/2.0/UDP 203.0.113.7:5060;rport=40123;received=203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn keeps_an_existing_received() {
+        assert_eq!(
+            stamped_via(
+                "SIP/2.0/UDP [2001:db8::9]:5060;received=198.51.100.1",
+                source()
+            ),
+            "SIP/2.0/UDP [2001:db8::9]:5060;received=198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn stamps_only_the_top_via() {
+        let mut request = match parse_message(
+            b"OPTIONS sip:bob@biloxi.example.com SIP/2.0\r\n\
+              Via: SIP/2.0/UDP 192.168.1.20:5060;rport;branch=z9hG4bK1\r\n\
+              Via: SIP/2.0/UDP 10.0.0.1:5060;rport;branch=z9hG4bK2\r\n\
+              Call-ID: a84b4c76e66710\r\n\
+              CSeq: 1 OPTIONS\r\n\
+              Content-Length: 0\r\n\r\n",
+        ) {
+            Ok(rsip::SipMessage::Request(request)) => request,
+            _ => panic!("expected a request"),
+        };
+        stamp_via(&mut request, source());
+        let vias: Vec<String> = request
+            .headers
+            .iter()
+            .filter_map(|header| match header {
+                rsip::Header::Via(via) => Some(via.value().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vias,
+            vec![
+                "SIP/2.0/UDP 192.168.1.20:5060;rport=40123;branch=z9hG4bK1;received=203.0.113.7",
+                "SIP/2.0/UDP 10.0.0.1:5060;rport;branch=z9hG4bK2",
+            ]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::rc::Rc;
+use std::sync::mpsc::Sender;
+
+use smol::channel::{Receiver, TrySendError};
+use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use smol::{Async, LocalExecutor};
+
+use super::framing::StreamFramer;
+use super::tls;
+use super::{accept_message, ReceivedMessage, TransportCommand, TransportTarget};
+use crate::voip::account::SipTransport;
+
+const SIP_DATAGRAM_BUFFER_BYTES: usize = 65535;
+const STREAM_READ_BUFFER_BYTES: usize = 4096;
+
+struct TransportState {
+    udp: Async<UdpSocket>,
+    // Bytes waiting for each stream connection's task to write them.
+    connections: RefCell<HashMap<TransportTarget, smol::channel::Sender<Vec<u8>>>>,
+    received: Sender<ReceivedMessage>,
+}
+
+impl TransportState {
+    fn deliver(&self, bytes: &[u8], source: TransportTarget) {
+        if let Some(message) = accept_message(bytes, source) {
+            // Nobody listening just means the user agent is on its way out.
+            let _ = self.received.send(message);
+        }
+    }
+
+    fn forget_closed_connections(&self) {
+        self.connections
+            .borrow_mut()
+            .retain(|_, outgoing| !outgoing.is_closed());
+    }
+}
+
+/// The transport thread, until told to stop.
+pub fn run(
+    udp: UdpSocket,
+    tcp: Option<TcpListener>,
+    commands: Receiver<TransportCommand>,
+    received: Sender<ReceivedMessage>,
+) {
+    let udp = match Async::new(udp) {
+        Ok(udp) => udp,
+        Err(err) => {
+            println!("Can't poll the SIP UDP socket: {}", err);
+            return;
+        }
+    };
+    let tcp = tcp.and_then(|listener| Async::new(listener).ok());
+    let executor = LocalExecutor::new();
+    let state = Rc::new(TransportState {
+        udp,
+        connections: RefCell::new(HashMap::new()),
+        received,
+    });
+    smol::block_on(executor.run(smol::future::or(
+        run_commands(&executor, &state, commands),
+        smol::future::or(
+            receive_datagrams(&state),
+            accept_connections(&executor, &state, tcp),
+        ),
+    )));
+}
+
+async fn run_commands(
+    executor: &LocalExecutor<'_>,
+    state: &Rc<TransportState>,
+    commands: Receiver<TransportCommand>,
+) {
+    while let Ok(command) = commands.recv().await {
+        match command {
+            TransportCommand::Send(target, server_name, bytes) => {
+                if target.transport == SipTransport::Udp {
+                    if let Err(err) = state.udp.send_to(&bytes, target.addr).await {
+                        println!("Failed to send SIP message to {}: {}", target.addr, err);
+                    }
+                } else {
+                    send_on_stream(executor, state, target, server_name, bytes);
+                }
+            }
+            TransportCommand::Terminate => return,
+        }
+    }
+}
+
+async fn receive_datagrams(state: &Rc<TransportState>) {
+    let mut buffer = vec![0u8; SIP_DATAGRAM_BUFFER_BYTES];
+    loop {
+        match state.udp.recv_from(&mut buffer).await {
+            Ok((len, from)) => state.deliver(
+                &buffer[..len],
+                TransportTarget {
+                    transport: SipTransport::Udp,
+                    addr: from,
+                },
+            ),
+            // Typically ICMP unreachable for something we sent; nothing to do.
+            Err(err) => println!("SIP UDP receive failed: {}", err),
+        }
+    }
+}
+
+async fn accept_connections(
+    executor: &LocalExecutor<'_>,
+    state: &Rc<TransportState>,
+    listener: Option<Async<TcpListener>>,
+) {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => return smol::future::pending().await,
+    };
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let peer = TransportTarget {
+                    transport: SipTransport::Tcp,
+                    addr,
+                };
+                let (outgoing_sender, outgoing) = smol::channel::unbounded();
+                state.connections.borrow_mut().insert(peer, outgoing_sender);
+                let state = state.clone();
+                executor
+                    .spawn(async move {
+                        run_connection(&state, peer, stream, outgoing).await;
+                        state.forget_closed_connections();
+                    })
+                    .detach();
+            }
+            Err(err) => println!("SIP TCP accept failed: {}", err),
+        }
+    }
+}
+
+/// Queues on the connection to `target`, connecting first if there isn't one.
+fn send_on_stream(
+    executor: &LocalExecutor<'_>,
+    state: &Rc<TransportState>,
+    target: TransportTarget,
+    server_name: Option<String>,
+    bytes: Vec<u8>,
+) {
+    let bytes = match state.connections.borrow().get(&target) {
+        Some(outgoing) => match outgoing.try_send(bytes) {
+            Ok(()) => return,
+            Err(TrySendError::Closed(bytes)) | Err(TrySendError::Full(bytes)) => bytes,
+        },
+        None => bytes,
+    };
+    let (outgoing_sender, outgoing) = smol::channel::unbounded();
+    let _ = outgoing_sender.try_send(bytes);
+    state
+        .connections
+        .borrow_mut()
+        .insert(target, outgoing_sender);
+    let state = state.clone();
+    executor
+        .spawn(async move {
+            connect(&state, target, server_name, outgoing).await;
+            state.forget_closed_connections();
+        })
+        .detach();
+}
+
+async fn connect(
+    state: &Rc<TransportState>,
+    target: TransportTarget,
+    server_name: Option<String>,
+    outgoing: Receiver<Vec<u8>>,
+) {
+    let tcp = match Async::<TcpStream>::connect(target.addr).await {
+        Ok(tcp) => tcp,
+        Err(err) => {
+            println!("Can't connect to {}: {}", target.addr, err);
+            return;
+        }
+    };
+    match target.transport {
+        SipTransport::Tls => {
+            let server_name = server_name.unwrap_or_else(|| target.addr.ip().to_string());
+            match tls::connect(tcp, &server_name).await {
+                Ok(stream) => run_connection(state, target, stream, outgoing).await,
+                Err(err) => println!("TLS to {} failed: {}", server_name, err),
+            }
+        }
+        _ => run_connection(state, target, tcp, outgoing).await,
+    }
+}
+
+enum ConnectionEvent {
+    Read(std::io::Result<usize>),
+    Write(Option<Vec<u8>>),
+}
+
+/// Moves bytes both ways on one connection until either side is done with it.
+async fn run_connection<Stream: AsyncRead + AsyncWrite + Unpin>(
+    state: &TransportState,
+    peer: TransportTarget,
+    mut stream: Stream,
+    outgoing: Receiver<Vec<u8>>,
+) {
+    let mut framer = StreamFramer::new();
+    let mut buffer = vec![0u8; STREAM_READ_BUFFER_BYTES];
+    loop {
+        let event = smol::future::or(
+            async { ConnectionEvent::Write(outgoing.recv().await.ok()) },
+            async { ConnectionEvent::Read(stream.read(&mut buffer).await) },
+        )
+        .await;
+        match event {
+            ConnectionEvent::Write(Some(bytes)) => {
+                if let Err(err) = stream.write_all(&bytes).await {
+                    println!("Connection to {} failed: {}", peer.addr, err);
+                    return;
+                }
+            }
+            // The transport layer is shutting down.
+            ConnectionEvent::Write(None) => return,
+            ConnectionEvent::Read(Ok(0)) => {
+                println!("{} closed the connection", peer.addr);
+                return;
+            }
+            ConnectionEvent::Read(Ok(len)) => {
+                framer.push(&buffer[..len]);
+                loop {
+                    match framer.next_message() {
+                        Ok(Some(bytes)) => state.deliver(&bytes, peer),
+                        Ok(None) => break,
+                        Err(err) => {
+                            println!("Dropping connection to {}: {}", peer.addr, err);
+                            return;
+                        }
+                    }
+                }
+            }
+            ConnectionEvent::Read(Err(err)) => {
+                println!("Connection to {} failed: {}", peer.addr, err);
+                return;
+            }
+        }
+    }
+}
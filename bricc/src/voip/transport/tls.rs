@@ -0,0 +1,44 @@
+use std::net::TcpStream;
+
+use smol::Async;
+
+#[cfg(feature = "tls")]
+pub async fn connect(
+    tcp: Async<TcpStream>,
+    server_name: &str,
+) -> Result<futures_rustls::client::TlsStream<Async<TcpStream>>, String> {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    use futures_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+    use futures_rustls::TlsConnector;
+
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(server_name)
+        .map_err(|_| format!("{} is not a valid TLS server name", server_name))?;
+    TlsConnector::from(Arc::new(config))
+        .connect(name, tcp)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Builds without the `tls` feature (the ESP32 has no room for rustls) can only
+/// fail here. The stream type is just to keep the caller the same.
+#[cfg(not(feature = "tls"))]
+pub async fn connect(
+    _tcp: Async<TcpStream>,
+    _server_name: &str,
+) -> Result<Async<TcpStream>, String> {
+    Err("Built without TLS support".into())
+}
@@ -0,0 +1,1768 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rsip::prelude::*;
+
+use super::account::{SipAccount, SipTransport};
+use super::call::{
+    Call, CallAction, CallDirection, CallEndReason, CallHandle, CallInfo, CallState,
+};
+use super::dns::locate::ServerLocator;
+use super::dns::SystemResolver;
+use super::dtmf::DtmfDigit;
+use super::media::session::MediaSession;
+use super::message::{parse_incoming, IncomingText, MessageId, MessageProgress, OutgoingMessage};
+use super::mwi::{is_message_summary, parse_message_summary, MwiClient, VoicemailStatus};
+use super::negotiation::{NegotiatedSession, RtpAddress};
+use super::registration::{RegistrationClient, RegistrationState};
+use super::sip::{dial_target, generate_response, new_tag, tag_of};
+use super::stun::{discover, StunClient, DEFAULT_STUN_PORT, STUN_KEEPALIVE_INTERVAL};
+use super::transaction::{
+    branch_of, cseq_method_of, ClientTransaction, ServerTransaction, TransactionEvent,
+};
+use super::transfer::Replaces;
+use super::transport::{ReceivedMessage, SipTransportLayer, TransportTarget};
+use crate::audio::traits::{CaptureStream, PlaybackStream};
+use crate::events::{SystemEvent, SystemEventSender};
+
+const USER_AGENT_THREAD_STACK_SIZE_BYTES: usize = 16384usize;
+const LOOKUP_THREAD_STACK_SIZE_BYTES: usize = 12288usize;
+const SIP_SOCKET_POLL_PERIOD: Duration = Duration::from_millis(20);
+// Ended calls stay visible for a bit so the GUI can say why they ended.
+const ENDED_CALL_LINGER: Duration = Duration::from_secs(3);
+// One on the line and one on hold or waiting; busy past that.
+const MAX_CALLS: usize = 2;
+// RTP wants even ports, RTCP gets the odd one above.
+const RTP_PORT_RANGE_START: u16 = 16384;
+const RTP_PORT_RANGE_END: u16 = 32766;
+// RFC 5626 section 3.5.1: a double CRLF keeps the registrar's flow, and our NAT
+// mapping to it, alive.
+const SIP_KEEPALIVE: &[u8] = b"\r\n\r\n";
+const SIP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+// Devices handed over for a call before its media session is up.
+type PendingAudio = (CallHandle, Box<dyn CaptureStream>, Box<dyn PlaybackStream>);
+
+pub enum UserAgentCommand {
+    SetAccount(Box<Option<SipAccount>>),
+    Register,
+    Unregister,
+    PlaceCall(CallHandle, String),
+    Answer(CallHandle),
+    Reject(CallHandle),
+    Hangup(CallHandle),
+    SetHold(CallHandle, bool),
+    Transfer(CallHandle, String),
+    AttendedTransfer(CallHandle, CallHandle),
+    SendDtmf(CallHandle, DtmfDigit),
+    AttachAudio(CallHandle, Box<dyn CaptureStream>, Box<dyn PlaybackStream>),
+    SendMessage(MessageId, String, String),
+    Terminate,
+}
+
+/// Things that happened, sent out as `SystemEvent::UserAgent`.
+#[derive(Clone, Debug)]
+pub enum UserAgentEvent {
+    /// A new call is ringing. It's in `calls()` by the time this arrives.
+    IncomingCall(CallInfo),
+    /// A call we placed never got going. It's in `calls()`, ended with the reason, by
+    /// the time this arrives.
+    CallFailed(CallInfo),
+    Dtmf(CallHandle, DtmfDigit),
+    MessageReceived(IncomingText),
+    /// How a `send_message` went: delivered to the far end's server, or why not.
+    MessageSent(MessageId, Result<(), String>),
+}
+
+#[derive(Clone)]
+pub struct UserAgentStatus {
+    pub registration: RegistrationState,
+    pub calls: Vec<CallInfo>,
+    /// The last message summary we got, if the provider does MWI.
+    pub voicemail: Option<VoicemailStatus>,
+    pub voicemail_number: Option<String>,
+}
+
+/// Cheap handle for talking to the user agent thread from anywhere, much like
+/// `WifiModuleInterface`.
+#[derive(Clone)]
+pub struct UserAgentInterface {
+    command_sender: Sender<UserAgentCommand>,
+    status: Arc<Mutex<UserAgentStatus>>,
+    next_call_handle: Arc<AtomicU32>,
+    next_message_id: Arc<AtomicU32>,
+}
+
+impl UserAgentInterface {
+    pub fn registration_state(&self) -> RegistrationState {
+        match self.status.lock() {
+            Ok(status) => status.registration.clone(),
+            Err(_) => RegistrationState::Failed("User agent crashed".into()),
+        }
+    }
+
+    pub fn calls(&self) -> Vec<CallInfo> {
+        match self.status.lock() {
+            Ok(status) => status.calls.clone(),
+            Err(_) => vec![],
+        }
+    }
+
+    pub fn call(&self, handle: CallHandle) -> Option<CallInfo> {
+        self.calls().into_iter().find(|call| call.handle == handle)
+    }
+
+    pub fn voicemail(&self) -> Option<VoicemailStatus> {
+        match self.status.lock() {
+            Ok(status) => status.voicemail.clone(),
+            Err(_) => None,
+        }
+    }
+
+    /// What to dial to hear voicemail: the account's setting, or failing that the
+    /// mailbox the provider told us about.
+    pub fn voicemail_number(&self) -> Option<String> {
+        match self.status.lock() {
+            Ok(status) => status.voicemail_number.clone(),
+            Err(_) => None,
+        }
+    }
+
+    pub fn set_account(
+        &mut self,
+        account: Option<SipAccount>,
+    ) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender
+            .send(UserAgentCommand::SetAccount(Box::new(account)))
+    }
+
+    pub fn register(&mut self) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender.send(UserAgentCommand::Register)
+    }
+
+    pub fn unregister(&mut self) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender.send(UserAgentCommand::Unregister)
+    }
+
+    /// Starts calling `dialed` (a number, user@host or SIP URI).
+    pub fn place_call(
+        &mut self,
+        dialed: String,
+    ) -> Result<CallHandle, SendError<UserAgentCommand>> {
+        let handle = self.next_call_handle.fetch_add(1, Ordering::Relaxed);
+        self.command_sender
+            .send(UserAgentCommand::PlaceCall(handle, dialed))?;
+        Ok(handle)
+    }
+
+    pub fn answer(&mut self, handle: CallHandle) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender.send(UserAgentCommand::Answer(handle))
+    }
+
+    pub fn reject(&mut self, handle: CallHandle) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender.send(UserAgentCommand::Reject(handle))
+    }
+
+    pub fn hangup(&mut self, handle: CallHandle) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender.send(UserAgentCommand::Hangup(handle))
+    }
+
+    pub fn hold(&mut self, handle: CallHandle) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender
+            .send(UserAgentCommand::SetHold(handle, true))
+    }
+
+    pub fn resume(&mut self, handle: CallHandle) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender
+            .send(UserAgentCommand::SetHold(handle, false))
+    }
+
+    /// Hands the call over to `dialed` without talking to them first.
+    pub fn transfer(
+        &mut self,
+        handle: CallHandle,
+        dialed: String,
+    ) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender
+            .send(UserAgentCommand::Transfer(handle, dialed))
+    }
+
+    /// Hands the call over to whoever is on the other end of call `to`, which then
+    /// gets replaced by theirs.
+    pub fn attended_transfer(
+        &mut self,
+        handle: CallHandle,
+        to: CallHandle,
+    ) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender
+            .send(UserAgentCommand::AttendedTransfer(handle, to))
+    }
+
+    /// Sends a key press down the call, as a telephone-event if the far end takes
+    /// them and as SIP INFO if not.
+    pub fn send_dtmf(
+        &mut self,
+        handle: CallHandle,
+        digit: DtmfDigit,
+    ) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender
+            .send(UserAgentCommand::SendDtmf(handle, digit))
+    }
+
+    /// Connects the mic and speaker to a call. If its media isn't up yet, they're
+    /// kept until it is.
+    pub fn attach_audio(
+        &mut self,
+        handle: CallHandle,
+        capture: Box<dyn CaptureStream>,
+        playback: Box<dyn PlaybackStream>,
+    ) -> Result<(), SendError<UserAgentCommand>> {
+        self.command_sender
+            .send(UserAgentCommand::AttachAudio(handle, capture, playback))
+    }
+
+    /// Texts `to` (a number, user@host or SIP URI). The outcome comes back as
+    /// `UserAgentEvent::MessageSent` with the returned id.
+    pub fn send_message(
+        &mut self,
+        to: String,
+        text: String,
+    ) -> Result<MessageId, SendError<UserAgentCommand>> {
+        let id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        self.command_sender
+            .send(UserAgentCommand::SendMessage(id, to, text))?;
+        Ok(id)
+    }
+}
+
+pub struct SipUserAgent {
+    thread: JoinHandle<()>,
+    interface_seed: UserAgentInterface,
+}
+
+impl SipUserAgent {
+    pub fn start(account: Option<SipAccount>, system_events: SystemEventSender) -> SipUserAgent {
+        let (command_sender, command_receiver) = channel::<UserAgentCommand>();
+        let status = Arc::new(Mutex::new(UserAgentStatus {
+            registration: RegistrationState::Unregistered,
+            calls: vec![],
+            voicemail: None,
+            voicemail_number: None,
+        }));
+        let next_call_handle = Arc::new(AtomicU32::new(1));
+        let thread_status = status.clone();
+        let thread_next_call_handle = next_call_handle.clone();
+        let thread_builder = thread::Builder::new().stack_size(USER_AGENT_THREAD_STACK_SIZE_BYTES);
+
+        let thread = match thread_builder.spawn(move || {
+            UserAgentThread::new(
+                account,
+                command_receiver,
+                system_events,
+                thread_status,
+                thread_next_call_handle,
+            )
+            .run();
+        }) {
+            Ok(handle) => handle,
+            Err(err) => {
+                println!("Failed to create user agent thread: {}", err);
+                panic!()
+            }
+        };
+
+        SipUserAgent {
+            thread,
+            interface_seed: UserAgentInterface {
+                command_sender,
+                status,
+                next_call_handle,
+                next_message_id: Arc::new(AtomicU32::new(1)),
+            },
+        }
+    }
+
+    pub fn get_interface(&self) -> UserAgentInterface {
+        self.interface_seed.clone()
+    }
+
+    pub fn signal_terminate(&mut self) {
+        if self
+            .interface_seed
+            .command_sender
+            .send(UserAgentCommand::Terminate)
+            .is_err()
+        {
+            println!("User agent thread already gone");
+        }
+    }
+
+    pub fn join(self) {
+        if self.thread.join().is_err() {
+            println!("User agent thread panicked");
+        }
+    }
+}
+
+/// What the lookup for a newly set account found: the registrar's servers, best
+/// first, and the STUN server if the account has one.
+struct AccountLookup {
+    registrar_uri: rsip::Uri,
+    targets: Result<Vec<TransportTarget>, String>,
+    stun_server: Option<SocketAddr>,
+    /// The server that stopped answering, if this is a lookup again after every one
+    /// we knew of had been tried. The account is already set up then.
+    failed_over_from: Option<TransportTarget>,
+}
+
+/// The RTP port the next call gets, its public address asked for ahead of time so
+/// STUN doesn't hold the call up. Asked again before the NAT forgets the mapping.
+enum SpareRtp {
+    Discovering(Receiver<RtpAddress>),
+    Ready(RtpAddress, Instant),
+}
+
+/// Who gets the responses of a client transaction.
+#[derive(Clone, Copy, PartialEq)]
+enum TransactionUser {
+    Registration,
+    Call(CallHandle),
+    Message(MessageId),
+    Subscription,
+}
+
+struct UserAgentThread {
+    command_receiver: Receiver<UserAgentCommand>,
+    system_events: SystemEventSender,
+    status: Arc<Mutex<UserAgentStatus>>,
+    next_call_handle: Arc<AtomicU32>,
+    account: Option<SipAccount>,
+    transport: Option<SipTransportLayer>,
+    // What goes in Contact and Via: the public side of the NAT once STUN has told us.
+    local_addr: Option<SocketAddr>,
+    // The registrar, which doubles as our outbound proxy, and its name for TLS.
+    outbound: Option<TransportTarget>,
+    outbound_host: Option<String>,
+    // Where to go if the registrar stops answering, best first.
+    fallback_targets: Vec<TransportTarget>,
+    // Shared with the lookup thread, which fills its cache.
+    locator: Arc<Mutex<ServerLocator>>,
+    account_lookup: Option<Receiver<AccountLookup>>,
+    stun_server: Option<SocketAddr>,
+    // Only for UDP; stream connections go through the NAT however they like.
+    stun: Option<StunClient>,
+    register_when_mapped: bool,
+    next_keepalive: Instant,
+    registration: Option<RegistrationClient>,
+    mwi: Option<MwiClient>,
+    voicemail: Option<VoicemailStatus>,
+    client_transactions: Vec<(TransactionUser, ClientTransaction)>,
+    server_transactions: Vec<ServerTransaction>,
+    calls: Vec<Call>,
+    // Calls that failed before there was a `Call` to end, shown like ended ones.
+    failed_calls: Vec<CallInfo>,
+    messages: Vec<OutgoingMessage>,
+    media_sessions: Vec<(CallHandle, NegotiatedSession, MediaSession)>,
+    pending_audio: Vec<PendingAudio>,
+    // Calls we were referred from, and the call we placed because of it.
+    referrals: Vec<(CallHandle, CallHandle)>,
+    next_rtp_port: u16,
+    spare_rtp: Option<SpareRtp>,
+}
+
+impl UserAgentThread {
+    fn new(
+        account: Option<SipAccount>,
+        command_receiver: Receiver<UserAgentCommand>,
+        system_events: SystemEventSender,
+        status: Arc<Mutex<UserAgentStatus>>,
+        next_call_handle: Arc<AtomicU32>,
+    ) -> UserAgentThread {
+        let mut ua = UserAgentThread {
+            command_receiver,
+            system_events,
+            status,
+            next_call_handle,
+            account: None,
+            transport: None,
+            local_addr: None,
+            outbound: None,
+            outbound_host: None,
+            fallback_targets: vec![],
+            locator: Arc::new(Mutex::new(ServerLocator::new(Box::new(
+                SystemResolver::from_system(),
+            )))),
+            account_lookup: None,
+            stun_server: None,
+            stun: None,
+            register_when_mapped: false,
+            next_keepalive: Instant::now() + SIP_KEEPALIVE_INTERVAL,
+            registration: None,
+            mwi: None,
+            voicemail: None,
+            client_transactions: vec![],
+            server_transactions: vec![],
+            calls: vec![],
+            failed_calls: vec![],
+            messages: vec![],
+            media_sessions: vec![],
+            pending_audio: vec![],
+            referrals: vec![],
+            next_rtp_port: RTP_PORT_RANGE_START,
+            spare_rtp: None,
+        };
+        ua.set_account(account);
+        ua
+    }
+
+    fn run(mut self) {
+        loop {
+            loop {
+                match self.command_receiver.try_recv() {
+                    Ok(UserAgentCommand::SetAccount(account)) => self.set_account(*account),
+                    Ok(UserAgentCommand::Register) => self.register(),
+                    Ok(UserAgentCommand::Unregister) => self.unregister(),
+                    Ok(UserAgentCommand::PlaceCall(handle, dialed)) => {
+                        self.place_call(handle, dialed)
+                    }
+                    Ok(UserAgentCommand::Answer(handle)) => {
+                        self.hold_others(handle);
+                        self.with_call(handle, |call, now| call.answer(now))
+                    }
+                    Ok(UserAgentCommand::Reject(handle)) => {
+                        self.with_call(handle, |call, _| call.reject(603))
+                    }
+                    Ok(UserAgentCommand::Hangup(handle)) => {
+                        self.with_call(handle, |call, _| call.hangup())
+                    }
+                    Ok(UserAgentCommand::SetHold(handle, hold)) => {
+                        if hold {
+                            self.hold(handle);
+                        } else {
+                            self.hold_others(handle);
+                            self.with_call(handle, |call, _| call.set_hold(false))
+                        }
+                    }
+                    Ok(UserAgentCommand::Transfer(handle, dialed)) => self.transfer(handle, dialed),
+                    Ok(UserAgentCommand::AttendedTransfer(handle, to)) => {
+                        self.attended_transfer(handle, to)
+                    }
+                    Ok(UserAgentCommand::SendDtmf(handle, digit)) => self.send_dtmf(handle, digit),
+                    Ok(UserAgentCommand::AttachAudio(handle, capture, playback)) => {
+                        self.pending_audio.push((handle, capture, playback))
+                    }
+                    Ok(UserAgentCommand::SendMessage(id, to, text)) => {
+                        self.send_message(id, to, text)
+                    }
+                    Ok(UserAgentCommand::Terminate) => {
+                        self.unregister();
+                        return;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            let received = match &self.transport {
+                Some(transport) => transport.recv_timeout(SIP_SOCKET_POLL_PERIOD),
+                None => {
+                    thread::sleep(SIP_SOCKET_POLL_PERIOD);
+                    None
+                }
+            };
+            match received {
+                Some(ReceivedMessage::Sip { message, source }) => {
+                    self.handle_message(message, source)
+                }
+                Some(ReceivedMessage::Stun { bytes, source }) => self.handle_stun(&bytes, source),
+                None => {}
+            }
+
+            self.poll_account_lookup();
+            self.poll();
+            self.publish_status();
+        }
+    }
+
+    fn set_account(&mut self, account: Option<SipAccount>) {
+        for call in self.calls.iter_mut() {
+            call.hangup();
+        }
+        self.calls.clear();
+        let unsent: Vec<MessageId> = self
+            .messages
+            .drain(..)
+            .map(|message| message.id())
+            .collect();
+        for id in unsent {
+            self.notify(UserAgentEvent::MessageSent(
+                id,
+                Err("Account changed".into()),
+            ));
+        }
+        self.sync_media();
+        self.client_transactions.clear();
+        self.server_transactions.clear();
+        self.registration = None;
+        self.mwi = None;
+        self.voicemail = None;
+        self.transport = None;
+        self.local_addr = None;
+        self.outbound = None;
+        self.outbound_host = None;
+        self.fallback_targets.clear();
+        // A lookup for the previous account finds nobody to tell.
+        self.account_lookup = None;
+        self.stun_server = None;
+        self.spare_rtp = None;
+        self.stun = None;
+        self.register_when_mapped = false;
+        self.account = account.clone();
+        if let Ok(mut status) = self.status.lock() {
+            status.registration = RegistrationState::Unregistered;
+        }
+
+        let account = match account {
+            Some(account) => account,
+            None => return,
+        };
+        self.registration = Some(RegistrationClient::new(account.clone()));
+
+        let registrar_uri = match account.registrar_uri() {
+            Ok(uri) => uri,
+            Err(err) => {
+                self.set_failed(err.to_string());
+                return;
+            }
+        };
+        self.start_account_lookup(registrar_uri, &account, None);
+    }
+
+    /// DNS can take seconds, so the servers are looked up off this thread and the
+    /// account set up once `poll_account_lookup` has the answer.
+    fn start_account_lookup(
+        &mut self,
+        registrar_uri: rsip::Uri,
+        account: &SipAccount,
+        failed_over_from: Option<TransportTarget>,
+    ) {
+        let (sender, receiver) = channel::<AccountLookup>();
+        let locator = self.locator.clone();
+        let transport = account.transport;
+        // Failing over only needs the registrar again.
+        let stun_server = match failed_over_from {
+            Some(_) => None,
+            None => account.stun_server.clone(),
+        };
+        let spawned = thread::Builder::new()
+            .stack_size(LOOKUP_THREAD_STACK_SIZE_BYTES)
+            .spawn(move || {
+                let mut locator = match locator.lock() {
+                    Ok(locator) => locator,
+                    Err(_) => return,
+                };
+                let targets = locator.locate(&registrar_uri, transport);
+                let stun_server =
+                    stun_server.and_then(|server| Self::find_stun_server(&mut locator, &server));
+                let _ = sender.send(AccountLookup {
+                    registrar_uri,
+                    targets,
+                    stun_server,
+                    failed_over_from,
+                });
+            });
+        match spawned {
+            Ok(_) => self.account_lookup = Some(receiver),
+            Err(err) => self.set_failed(format!("Failed to create lookup thread: {}", err)),
+        }
+    }
+
+    fn poll_account_lookup(&mut self) {
+        let lookup = match &self.account_lookup {
+            Some(receiver) => match receiver.try_recv() {
+                Ok(lookup) => lookup,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.account_lookup = None;
+                    self.set_failed("Server lookup failed".into());
+                    return;
+                }
+            },
+            None => return,
+        };
+        self.account_lookup = None;
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => return,
+        };
+        let registrar_uri = lookup.registrar_uri;
+        if let Some(current) = lookup.failed_over_from {
+            self.finish_fail_over(current, &registrar_uri, lookup.targets);
+            return;
+        }
+        let mut targets = match lookup.targets {
+            Ok(targets) if !targets.is_empty() => targets,
+            Ok(_) => {
+                self.set_failed(format!("No servers for {}", registrar_uri));
+                return;
+            }
+            Err(err) => {
+                self.set_failed(err);
+                return;
+            }
+        };
+        let outbound = targets.remove(0);
+        self.fallback_targets = targets;
+        self.stun_server = lookup.stun_server;
+        self.prepare_spare_rtp();
+        match Self::open_transport(&outbound.addr) {
+            Ok((transport, local_addr)) => {
+                println!("SIP transport on {}, registrar {:?}", local_addr, outbound);
+                self.transport = Some(transport);
+                self.local_addr = Some(local_addr);
+                self.mwi = Some(MwiClient::new(account.clone(), local_addr, Instant::now()));
+                self.outbound = Some(outbound);
+                self.outbound_host = Some(registrar_uri.host_with_port.host.to_string());
+                match self.stun_server {
+                    Some(server) if account.transport == SipTransport::Udp => {
+                        // The first REGISTER should already have the public Contact.
+                        self.stun = Some(StunClient::new(server, Instant::now()));
+                        self.register_when_mapped = true;
+                    }
+                    _ => self.register(),
+                }
+            }
+            Err(err) => self.set_failed(err),
+        }
+    }
+
+    fn find_stun_server(locator: &mut ServerLocator, server: &str) -> Option<SocketAddr> {
+        match locator.locate_service("_stun._udp", server, DEFAULT_STUN_PORT) {
+            Ok(addrs) => addrs.into_iter().next(),
+            Err(err) => {
+                println!("Not using STUN server {}: {}", server, err);
+                None
+            }
+        }
+    }
+
+    // The transport listens on every interface, but Contact/Via need the address the
+    // registrar will actually see us on, so ask the routing table via a connected socket.
+    fn open_transport(remote_addr: &SocketAddr) -> Result<(SipTransportLayer, SocketAddr), String> {
+        let probe = UdpSocket::bind("0.0.0.0:0").map_err(|err| err.to_string())?;
+        probe.connect(remote_addr).map_err(|err| err.to_string())?;
+        let local_ip = probe.local_addr().map_err(|err| err.to_string())?.ip();
+
+        let transport = SipTransportLayer::start()?;
+        let port = transport.local_port();
+        Ok((transport, SocketAddr::new(local_ip, port)))
+    }
+
+    /// Moves on to the next server for the registrar. Returns whether there's a
+    /// different one to try now; once they've all been tried they're looked up again,
+    /// off this thread, and registering is retried when that's done.
+    fn fail_over(&mut self) -> bool {
+        let current = match self.outbound {
+            Some(current) => current,
+            None => return false,
+        };
+        if self.fallback_targets.is_empty() {
+            let account = match &self.account {
+                Some(account) => account.clone(),
+                None => return false,
+            };
+            let registrar_uri = match account.registrar_uri() {
+                Ok(uri) => uri,
+                Err(_) => return false,
+            };
+            if self.account_lookup.is_none() {
+                // Cached, so this only hits the network once the TTLs are up.
+                self.start_account_lookup(registrar_uri, &account, Some(current));
+            }
+            return false;
+        }
+        self.use_next_target(current);
+        true
+    }
+
+    fn finish_fail_over(
+        &mut self,
+        current: TransportTarget,
+        registrar_uri: &rsip::Uri,
+        targets: Result<Vec<TransportTarget>, String>,
+    ) {
+        match targets {
+            Ok(targets) => self.fallback_targets = targets,
+            Err(err) => println!("Can't look up {} again: {}", registrar_uri, err),
+        }
+        self.fallback_targets.retain(|target| *target != current);
+        if self.fallback_targets.is_empty() || self.outbound != Some(current) {
+            return;
+        }
+        self.use_next_target(current);
+        self.register();
+    }
+
+    fn use_next_target(&mut self, current: TransportTarget) {
+        let next = self.fallback_targets.remove(0);
+        println!(
+            "Registrar {} not answering, trying {}",
+            current.addr, next.addr
+        );
+        self.outbound = Some(next);
+    }
+
+    fn is_reliable(&self) -> bool {
+        match &self.account {
+            Some(account) => account.transport.is_reliable(),
+            None => false,
+        }
+    }
+
+    fn set_failed(&mut self, reason: String) {
+        println!("SIP user agent: {}", reason);
+        if let Ok(mut status) = self.status.lock() {
+            status.registration = RegistrationState::Failed(reason);
+        }
+    }
+
+    fn register(&mut self) {
+        let local_addr = match self.local_addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        let request = match &mut self.registration {
+            Some(registration) => registration.register(local_addr),
+            None => return,
+        };
+        match request {
+            Ok(request) => self.start_transaction(TransactionUser::Registration, request),
+            Err(err) => println!("Failed to build REGISTER: {}", err),
+        }
+    }
+
+    fn unregister(&mut self) {
+        let local_addr = match self.local_addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        let unsubscribe = match &mut self.mwi {
+            Some(mwi) => mwi.unsubscribe(local_addr),
+            None => None,
+        };
+        if let Some(request) = unsubscribe {
+            self.start_transaction(TransactionUser::Subscription, request);
+        }
+        let request = match &mut self.registration {
+            Some(registration) if registration.state() == RegistrationState::Registered => {
+                registration.unregister(local_addr)
+            }
+            _ => return,
+        };
+        if let Ok(request) = request {
+            self.start_transaction(TransactionUser::Registration, request);
+        }
+    }
+
+    fn place_call(&mut self, handle: CallHandle, dialed: String) {
+        let (account, local_addr) = match (&self.account, self.local_addr) {
+            (Some(account), Some(local_addr)) => (account.clone(), local_addr),
+            (None, _) => return self.fail_call(handle, dialed, "No VoIP account".into()),
+            (Some(_), None) => return self.fail_call(handle, dialed, "No network".into()),
+        };
+        let target = match dial_target(&account, &dialed) {
+            Ok(target) => target,
+            Err(err) => return self.fail_call(handle, dialed, err.to_string()),
+        };
+        self.hold_others(handle);
+        if let Err(err) = self.start_call(handle, account, local_addr, target, None) {
+            self.fail_call(handle, dialed, err);
+        }
+    }
+
+    // So the GUI can say why, rather than the call just never showing up.
+    fn fail_call(&mut self, handle: CallHandle, dialed: String, reason: String) {
+        println!("Can't call {}: {}", dialed, reason);
+        let now = Instant::now();
+        let info = CallInfo {
+            handle,
+            direction: CallDirection::Outgoing,
+            remote_uri: dialed,
+            remote_display_name: None,
+            state: CallState::Ended(CallEndReason::Failed(reason)),
+            on_hold: false,
+            transfer: None,
+            started_at: now,
+            connected_at: None,
+            ended_at: Some(now),
+        };
+        self.failed_calls.push(info.clone());
+        self.publish_status();
+        self.notify(UserAgentEvent::CallFailed(info));
+    }
+
+    fn start_call(
+        &mut self,
+        handle: CallHandle,
+        account: SipAccount,
+        local_addr: SocketAddr,
+        target: rsip::Uri,
+        replaces: Option<Replaces>,
+    ) -> Result<(), String> {
+        let rtp = self.allocate_rtp_address();
+        let (call, actions) = Call::outgoing(
+            handle,
+            account,
+            local_addr,
+            rtp,
+            target,
+            replaces,
+            Instant::now(),
+        )?;
+        self.calls.push(call);
+        self.run_call_actions(handle, actions);
+        Ok(())
+    }
+
+    /// Puts every other call that's up on hold, so `handle` has the line to itself.
+    fn hold_others(&mut self, handle: CallHandle) {
+        let others: Vec<CallHandle> = self
+            .calls
+            .iter()
+            .filter(|call| call.handle() != handle && *call.state() == CallState::Connected)
+            .map(|call| call.handle())
+            .collect();
+        for other in others {
+            self.hold(other);
+        }
+    }
+
+    fn hold(&mut self, handle: CallHandle) {
+        self.with_call(handle, |call, _| call.set_hold(true));
+        // They shouldn't hear us while they wait. The GUI attaches it again on resume.
+        let media = self
+            .media_sessions
+            .iter()
+            .find(|(media_handle, _, _)| *media_handle == handle);
+        if let Some((_, _, media)) = media {
+            media.detach_audio();
+        }
+    }
+
+    fn transfer(&mut self, handle: CallHandle, dialed: String) {
+        let target = match &self.account {
+            Some(account) => dial_target(account, &dialed),
+            None => return,
+        };
+        match target {
+            Ok(target) => {
+                println!("Transferring call {} to {}", handle, dialed);
+                self.with_call(handle, |call, _| call.transfer(target, None))
+            }
+            Err(err) => println!("Can't transfer to {}: {}", dialed, err),
+        }
+    }
+
+    fn attended_transfer(&mut self, handle: CallHandle, to: CallHandle) {
+        let target = self
+            .calls
+            .iter()
+            .find(|call| call.handle() == to && *call.state() == CallState::Connected)
+            .and_then(|call| {
+                call.as_replaces()
+                    .map(|replaces| (call.remote_uri().clone(), replaces))
+            });
+        match target {
+            Some((uri, replaces)) => {
+                println!("Transferring call {} to call {}", handle, to);
+                self.with_call(handle, |call, _| call.transfer(uri, Some(replaces)))
+            }
+            None => println!("Call {} isn't up, can't transfer to it", to),
+        }
+    }
+
+    /// Calls where a REFER on `referred` sent us, reporting back as it goes.
+    fn follow_referral(
+        &mut self,
+        referred: CallHandle,
+        target: rsip::Uri,
+        replaces: Option<Replaces>,
+    ) {
+        let (account, local_addr) = match (&self.account, self.local_addr) {
+            (Some(account), Some(local_addr)) => (account.clone(), local_addr),
+            _ => return,
+        };
+        let handle = self.next_call_handle.fetch_add(1, Ordering::Relaxed);
+        match self.start_call(handle, account, local_addr, target, replaces) {
+            Ok(()) => self.referrals.push((referred, handle)),
+            Err(err) => {
+                println!("Can't follow the transfer: {}", err);
+                self.with_call(referred, |call, _| call.referral_progress(503));
+            }
+        }
+    }
+
+    // Tells whoever referred us once the call they sent us to is up or has failed.
+    fn update_referrals(&mut self) {
+        let mut progress = vec![];
+        let calls = &self.calls;
+        self.referrals.retain(|(referred, handle)| {
+            let state = calls
+                .iter()
+                .find(|call| call.handle() == *handle)
+                .map(|call| call.state());
+            let status_code = match state {
+                Some(CallState::Dialing) | Some(CallState::Ringing) => return true,
+                Some(CallState::Connected) => 200,
+                Some(CallState::Ended(reason)) => reason.status_code(),
+                Some(CallState::Terminating) | None => 487,
+            };
+            progress.push((*referred, status_code));
+            false
+        });
+        for (referred, status_code) in progress {
+            self.with_call(referred, |call, _| call.referral_progress(status_code));
+        }
+    }
+
+    fn send_message(&mut self, id: MessageId, to: String, text: String) {
+        let (account, local_addr) = match (&self.account, self.local_addr) {
+            (Some(account), Some(local_addr)) => (account.clone(), local_addr),
+            _ => {
+                self.notify(UserAgentEvent::MessageSent(
+                    id,
+                    Err("No VoIP account".into()),
+                ));
+                return;
+            }
+        };
+        match OutgoingMessage::new(id, account, local_addr, &to, text) {
+            Ok((message, request)) => {
+                self.messages.push(message);
+                self.start_transaction(TransactionUser::Message(id), request);
+            }
+            Err(err) => {
+                println!("Can't text {}: {}", to, err);
+                self.notify(UserAgentEvent::MessageSent(id, Err(err)));
+            }
+        }
+    }
+
+    fn message_done(&mut self, id: MessageId, result: Result<(), String>) {
+        self.messages.retain(|message| message.id() != id);
+        self.notify(UserAgentEvent::MessageSent(id, result));
+    }
+
+    /// The next RTP port, and what it looks like from outside if STUN has told us
+    /// already. Never waits for STUN; the next call's port gets asked about instead.
+    fn allocate_rtp_address(&mut self) -> RtpAddress {
+        let address = match self.spare_rtp.take() {
+            Some(SpareRtp::Ready(address, _)) => address,
+            _ => RtpAddress {
+                local_port: self.allocate_rtp_port(),
+                public: None,
+            },
+        };
+        self.prepare_spare_rtp();
+        address
+    }
+
+    fn prepare_spare_rtp(&mut self) {
+        if self.stun_server.is_some() {
+            let local_port = self.allocate_rtp_port();
+            self.discover_spare_rtp(local_port);
+        }
+    }
+
+    fn discover_spare_rtp(&mut self, local_port: u16) {
+        let server = match self.stun_server {
+            Some(server) => server,
+            None => return,
+        };
+        let (sender, receiver) = channel::<RtpAddress>();
+        let spawned = thread::Builder::new()
+            .stack_size(LOOKUP_THREAD_STACK_SIZE_BYTES)
+            .spawn(move || {
+                let public = match discover(local_port, server) {
+                    Ok(public) => Some(public),
+                    Err(err) => {
+                        println!("No public address for RTP port {}: {}", local_port, err);
+                        None
+                    }
+                };
+                let _ = sender.send(RtpAddress { local_port, public });
+            });
+        self.spare_rtp = match spawned {
+            Ok(_) => Some(SpareRtp::Discovering(receiver)),
+            Err(err) => {
+                println!("Failed to create STUN thread: {}", err);
+                None
+            }
+        };
+    }
+
+    fn poll_spare_rtp(&mut self, now: Instant) {
+        let refresh = match &self.spare_rtp {
+            Some(SpareRtp::Discovering(receiver)) => match receiver.try_recv() {
+                Ok(address) => {
+                    self.spare_rtp = Some(SpareRtp::Ready(address, now));
+                    None
+                }
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => {
+                    self.spare_rtp = None;
+                    None
+                }
+            },
+            Some(SpareRtp::Ready(address, discovered_at))
+                if now - *discovered_at >= STUN_KEEPALIVE_INTERVAL =>
+            {
+                Some(address.local_port)
+            }
+            _ => None,
+        };
+        if let Some(local_port) = refresh {
+            self.discover_spare_rtp(local_port);
+        }
+    }
+
+    /// The next even port in the range whose RTCP port above it is free too.
+    /// Another program may hold some, so busy pairs are skipped.
+    fn allocate_rtp_port(&mut self) -> u16 {
+        let pairs = (RTP_PORT_RANGE_END - RTP_PORT_RANGE_START) / 2 + 1;
+        let first = self.next_rtp_port;
+        for _ in 0..pairs {
+            let port = self.next_rtp_port;
+            self.next_rtp_port = if port + 2 > RTP_PORT_RANGE_END {
+                RTP_PORT_RANGE_START
+            } else {
+                port + 2
+            };
+            if rtp_ports_free(port) {
+                return port;
+            }
+        }
+        // Let the media session report it when it can't bind.
+        println!(
+            "No free RTP port between {} and {}",
+            RTP_PORT_RANGE_START, RTP_PORT_RANGE_END
+        );
+        first
+    }
+
+    fn with_call<F: FnOnce(&mut Call, Instant) -> Vec<CallAction>>(
+        &mut self,
+        handle: CallHandle,
+        f: F,
+    ) {
+        let actions = match self.calls.iter_mut().find(|call| call.handle() == handle) {
+            Some(call) => f(call, Instant::now()),
+            None => return,
+        };
+        self.run_call_actions(handle, actions);
+    }
+
+    fn run_call_actions(&mut self, handle: CallHandle, actions: Vec<CallAction>) {
+        for action in actions {
+            match action {
+                CallAction::StartTransaction(request) => {
+                    self.start_transaction(TransactionUser::Call(handle), request)
+                }
+                CallAction::Respond(branch, response) => self.respond(&branch, response),
+                CallAction::Send(message) => {
+                    let destination = match &message {
+                        rsip::SipMessage::Response(_) => self
+                            .calls
+                            .iter()
+                            .find(|call| call.handle() == handle)
+                            .and_then(|call| call.peer()),
+                        rsip::SipMessage::Request(_) => None,
+                    };
+                    self.send(message, destination);
+                }
+                CallAction::ReceivedDtmf(digit) => self.notify(UserAgentEvent::Dtmf(handle, digit)),
+                CallAction::Refer(target, replaces) => {
+                    self.follow_referral(handle, target, replaces)
+                }
+            }
+        }
+    }
+
+    fn send_dtmf(&mut self, handle: CallHandle, digit: DtmfDigit) {
+        let media = self
+            .media_sessions
+            .iter()
+            .find(|(media_handle, _, _)| *media_handle == handle);
+        match media {
+            Some((_, session, media)) if session.telephone_event.is_some() => {
+                media.send_dtmf(digit)
+            }
+            _ => self.with_call(handle, |call, _| call.send_dtmf_info(digit)),
+        }
+    }
+
+    fn start_transaction(&mut self, user: TransactionUser, request: rsip::Request) {
+        let transaction =
+            ClientTransaction::new(request.clone(), self.is_reliable(), Instant::now());
+        self.client_transactions.push((user, transaction));
+        self.send(request.into(), None);
+    }
+
+    // CANCEL shares its branch with the INVITE, so the CSeq method picks the transaction.
+    fn respond(&mut self, branch: &str, response: rsip::Response) {
+        let now = Instant::now();
+        let method = cseq_method_of(&response.clone().into());
+        let mut outgoing = vec![];
+        for transaction in self.server_transactions.iter_mut() {
+            if transaction.branch() == branch
+                && Some(transaction.method()) == method.as_ref()
+                && !transaction.is_terminated()
+            {
+                let source = transaction.source();
+                for event in transaction.respond(response.clone(), now) {
+                    if let TransactionEvent::Send(message) = event {
+                        outgoing.push((message, source));
+                    }
+                }
+                break;
+            }
+        }
+        for (message, source) in outgoing {
+            self.send(message, Some(source));
+        }
+    }
+
+    /// Answers a request nobody else will, keeping a transaction for its retransmissions.
+    fn respond_statelessly(&mut self, request: &rsip::Request, source: TransportTarget, code: u16) {
+        let to_tag = new_tag();
+        let response = match generate_response(request, code, Some(&to_tag), None, None, vec![]) {
+            Ok(response) => response,
+            Err(err) => {
+                println!("Failed to answer {}: {}", request.method, err);
+                return;
+            }
+        };
+        let transaction = ServerTransaction::new(request.clone(), source);
+        let branch = transaction.branch().to_string();
+        self.server_transactions.push(transaction);
+        self.respond(&branch, response);
+    }
+
+    fn handle_message(&mut self, message: rsip::SipMessage, from: TransportTarget) {
+        match message {
+            rsip::SipMessage::Response(response) => self.handle_response(response),
+            rsip::SipMessage::Request(request) => self.handle_request(request, from),
+        }
+    }
+
+    fn handle_response(&mut self, response: rsip::Response) {
+        let now = Instant::now();
+        let mut events = vec![];
+        let transaction = self
+            .client_transactions
+            .iter_mut()
+            .find(|(_, transaction)| transaction.matches(&response));
+        match transaction {
+            Some((user, transaction)) => {
+                let method = *transaction.method();
+                for event in transaction.on_response(response, now) {
+                    events.push((*user, method, event));
+                }
+            }
+            // The INVITE transaction ends with the first 2xx, the rest are for the call.
+            None if response.status_code.code() / 100 == 2 => {
+                if cseq_method_of(&response.clone().into()) == Some(rsip::Method::Invite) {
+                    self.handle_retransmitted_ok(&response);
+                }
+                return;
+            }
+            None => {}
+        }
+        self.handle_transaction_events(events);
+    }
+
+    fn handle_retransmitted_ok(&mut self, response: &rsip::Response) {
+        let found = self.calls.iter().find_map(|call| {
+            let actions = call.on_retransmitted_ok(response);
+            if actions.is_empty() {
+                None
+            } else {
+                Some((call.handle(), actions))
+            }
+        });
+        if let Some((handle, actions)) = found {
+            self.run_call_actions(handle, actions);
+        }
+    }
+
+    fn handle_transaction_events(
+        &mut self,
+        events: Vec<(TransactionUser, rsip::Method, TransactionEvent)>,
+    ) {
+        let now = Instant::now();
+        let local_addr = match self.local_addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        for (user, method, event) in events {
+            match (user, event) {
+                (_, TransactionEvent::Send(message)) => self.send(message, None),
+                (TransactionUser::Registration, TransactionEvent::Response(response)) => {
+                    let next = match &mut self.registration {
+                        Some(registration) => {
+                            registration.handle_response(&response, local_addr, now)
+                        }
+                        None => None,
+                    };
+                    if let Some(request) = next {
+                        self.start_transaction(TransactionUser::Registration, request);
+                    }
+                }
+                (TransactionUser::Registration, TransactionEvent::Timeout) => {
+                    if let Some(registration) = &mut self.registration {
+                        registration.handle_timeout(now);
+                    }
+                    if self.fail_over() {
+                        self.register();
+                    }
+                }
+                (TransactionUser::Call(handle), TransactionEvent::Response(response)) => {
+                    self.with_call(handle, |call, now| call.on_response(response, now))
+                }
+                (TransactionUser::Call(handle), TransactionEvent::Timeout) => {
+                    self.with_call(handle, |call, _| call.on_timeout(&method))
+                }
+                (TransactionUser::Message(id), TransactionEvent::Response(response)) => {
+                    let progress = match self.messages.iter_mut().find(|message| message.id() == id)
+                    {
+                        Some(message) => message.on_response(&response),
+                        None => MessageProgress::Pending,
+                    };
+                    match progress {
+                        MessageProgress::Pending => {}
+                        MessageProgress::Resend(request) => {
+                            self.start_transaction(TransactionUser::Message(id), request)
+                        }
+                        MessageProgress::Done(result) => self.message_done(id, result),
+                    }
+                }
+                (TransactionUser::Message(id), TransactionEvent::Timeout) => {
+                    self.message_done(id, Err("No response".into()))
+                }
+                (TransactionUser::Subscription, TransactionEvent::Response(response)) => {
+                    let next = match &mut self.mwi {
+                        Some(mwi) => mwi.handle_response(&response, local_addr, now),
+                        None => None,
+                    };
+                    if let Some(request) = next {
+                        self.start_transaction(TransactionUser::Subscription, request);
+                    }
+                }
+                (TransactionUser::Subscription, TransactionEvent::Timeout) => {
+                    if let Some(mwi) = &mut self.mwi {
+                        mwi.handle_timeout(now);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: rsip::Request, source: TransportTarget) {
+        let now = Instant::now();
+
+        // Retransmissions, and ACKs to our non-2xx final responses.
+        let mut retransmit = None;
+        for transaction in self.server_transactions.iter_mut() {
+            if transaction.matches(&request) {
+                retransmit = Some(transaction.on_request(&request, now));
+                break;
+            }
+        }
+        if let Some(events) = retransmit {
+            for event in events {
+                if let TransactionEvent::Send(message) = event {
+                    self.send(message, Some(source));
+                }
+            }
+            return;
+        }
+
+        if request.method == rsip::Method::Ack {
+            let handle = self
+                .calls
+                .iter()
+                .find(|call| call.matches_dialog(&request))
+                .map(|call| call.handle());
+            if let Some(handle) = handle {
+                self.with_call(handle, |call, now| call.on_request(request, now));
+            }
+            return;
+        }
+
+        if request.method == rsip::Method::Cancel {
+            let branch = branch_of(&request.clone().into()).unwrap_or_default();
+            let handle = self
+                .calls
+                .iter()
+                .find(|call| call.invite_branch() == branch)
+                .map(|call| call.handle());
+            match handle {
+                Some(handle) => {
+                    self.respond_statelessly(&request, source, 200);
+                    self.with_call(handle, |call, _| call.on_cancel());
+                }
+                None => self.respond_statelessly(&request, source, 481),
+            }
+            return;
+        }
+
+        let is_our_notify = match &self.mwi {
+            Some(mwi) => request.method == rsip::Method::Notify && mwi.matches_notify(&request),
+            None => false,
+        };
+        if is_our_notify {
+            self.handle_mwi_notify(request, source);
+            return;
+        }
+
+        let has_to_tag = match request.to_header() {
+            Ok(to) => tag_of(to.value()).is_some(),
+            Err(_) => false,
+        };
+        if has_to_tag {
+            let handle = self
+                .calls
+                .iter()
+                .find(|call| call.matches_dialog(&request))
+                .map(|call| call.handle());
+            match handle {
+                Some(handle) => {
+                    self.server_transactions
+                        .push(ServerTransaction::new(request.clone(), source));
+                    self.with_call(handle, |call, now| call.on_request(request, now));
+                }
+                None => self.respond_statelessly(&request, source, 481),
+            }
+            return;
+        }
+
+        match request.method {
+            rsip::Method::Invite => self.handle_new_invite(request, source),
+            rsip::Method::Options => self.respond_statelessly(&request, source, 200),
+            rsip::Method::Message => self.handle_message_request(request, source),
+            // Some providers send MWI without being asked.
+            rsip::Method::Notify if is_message_summary(&request) => {
+                match parse_message_summary(&String::from_utf8_lossy(&request.body)) {
+                    Ok(status) => {
+                        self.respond_statelessly(&request, source, 200);
+                        self.set_voicemail(status);
+                    }
+                    Err(err) => {
+                        println!("Bad message summary: {}", err);
+                        self.respond_statelessly(&request, source, 400);
+                    }
+                }
+            }
+            _ => self.respond_statelessly(&request, source, 405),
+        }
+    }
+
+    fn handle_message_request(&mut self, request: rsip::Request, source: TransportTarget) {
+        match parse_incoming(&request) {
+            Ok(text) => {
+                println!("Text from {}", text.from);
+                self.respond_statelessly(&request, source, 200);
+                self.notify(UserAgentEvent::MessageReceived(text));
+            }
+            Err(code) => self.respond_statelessly(&request, source, code),
+        }
+    }
+
+    fn handle_mwi_notify(&mut self, request: rsip::Request, source: TransportTarget) {
+        let local_addr = match self.local_addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        let (code, status) = match &mut self.mwi {
+            Some(mwi) => mwi.on_notify(&request, local_addr, Instant::now()),
+            None => return,
+        };
+        self.respond_statelessly(&request, source, code);
+        if let Some(status) = status {
+            self.set_voicemail(status);
+        }
+    }
+
+    fn set_voicemail(&mut self, status: VoicemailStatus) {
+        println!(
+            "Voicemail: {} new, {} old{}",
+            status.new,
+            status.old,
+            if status.waiting { ", waiting" } else { "" }
+        );
+        self.voicemail = Some(status);
+    }
+
+    fn handle_new_invite(&mut self, request: rsip::Request, source: TransportTarget) {
+        let (account, local_addr) = match (&self.account, self.local_addr) {
+            (Some(account), Some(local_addr)) => (account.clone(), local_addr),
+            _ => return,
+        };
+        // An attended transfer to us, RFC 3891: this call takes over one we're in.
+        let replaced = match Replaces::from_headers(&request.headers) {
+            Some(Ok(replaces)) => {
+                let replaced = self
+                    .calls
+                    .iter()
+                    .find(|call| {
+                        *call.state() == CallState::Connected && call.is_replaced_by(&replaces)
+                    })
+                    .map(|call| call.handle());
+                match replaced {
+                    Some(handle) => Some(handle),
+                    None => {
+                        self.respond_statelessly(&request, source, 481);
+                        return;
+                    }
+                }
+            }
+            Some(Err(_)) => {
+                self.respond_statelessly(&request, source, 400);
+                return;
+            }
+            None => None,
+        };
+        // Call waiting: a second call may ring while we're in one, but not a third.
+        let active = self.calls.iter().filter(|call| !call.is_ended()).count();
+        let ringing = self.calls.iter().any(|call| {
+            call.direction() == CallDirection::Incoming && *call.state() == CallState::Ringing
+        });
+        if replaced.is_none() && (active >= MAX_CALLS || ringing) {
+            self.respond_statelessly(&request, source, 486);
+            return;
+        }
+        self.server_transactions
+            .push(ServerTransaction::new(request.clone(), source));
+        let handle = self.next_call_handle.fetch_add(1, Ordering::Relaxed);
+        let rtp = self.allocate_rtp_address();
+        match Call::incoming(
+            handle,
+            account,
+            local_addr,
+            rtp,
+            request,
+            source,
+            Instant::now(),
+        ) {
+            Ok((call, actions)) => {
+                let info = call.info();
+                println!("Incoming call from {}", info.remote_uri);
+                self.calls.push(call);
+                self.run_call_actions(handle, actions);
+                if info.state != CallState::Ringing {
+                    // Already turned down, for an offer we can't answer say.
+                    return;
+                }
+                if let Some(replaced) = replaced {
+                    // Nothing to ask the user, they're already talking to the call it replaces.
+                    println!("Call {} replaces call {}", handle, replaced);
+                    self.with_call(replaced, |call, _| call.replaced());
+                    self.hold_others(handle);
+                    self.with_call(handle, |call, now| call.answer(now));
+                    return;
+                }
+                // So the call is already there for whoever reacts to the event.
+                self.publish_status();
+                self.notify(UserAgentEvent::IncomingCall(info));
+            }
+            Err(err) => println!("Dropping INVITE: {}", err),
+        }
+    }
+
+    fn poll(&mut self) {
+        let local_addr = match self.local_addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        let now = Instant::now();
+        self.poll_nat(now);
+
+        let next = match &mut self.registration {
+            Some(registration) => registration.poll(local_addr, now),
+            None => None,
+        };
+        if let Some(request) = next {
+            self.start_transaction(TransactionUser::Registration, request);
+        }
+
+        let registered = match &self.registration {
+            Some(registration) => registration.state() == RegistrationState::Registered,
+            None => false,
+        };
+        let subscribe = match &mut self.mwi {
+            Some(mwi) if registered => mwi.poll(local_addr, now),
+            _ => None,
+        };
+        if let Some(request) = subscribe {
+            self.start_transaction(TransactionUser::Subscription, request);
+        }
+
+        let mut events = vec![];
+        for (user, transaction) in self.client_transactions.iter_mut() {
+            let method = *transaction.method();
+            for event in transaction.on_tick(now) {
+                events.push((*user, method, event));
+            }
+        }
+        self.handle_transaction_events(events);
+
+        let mut outgoing = vec![];
+        for transaction in self.server_transactions.iter_mut() {
+            let source = transaction.source();
+            for event in transaction.on_tick(now) {
+                if let TransactionEvent::Send(message) = event {
+                    outgoing.push((message, source));
+                }
+            }
+        }
+        for (message, source) in outgoing {
+            self.send(message, Some(source));
+        }
+
+        let mut call_actions = vec![];
+        for call in self.calls.iter_mut() {
+            call_actions.push((call.handle(), call.on_tick(now)));
+        }
+        for (handle, actions) in call_actions {
+            self.run_call_actions(handle, actions);
+        }
+        self.update_referrals();
+        self.sync_media();
+        for (handle, _, media) in self.media_sessions.iter() {
+            while let Some(digit) = media.received_dtmf() {
+                self.notify(UserAgentEvent::Dtmf(*handle, digit));
+            }
+        }
+
+        self.client_transactions
+            .retain(|(_, transaction)| !transaction.is_terminated());
+        self.server_transactions
+            .retain(|transaction| !transaction.is_terminated());
+        self.calls.retain(|call| match call.ended_at() {
+            Some(ended_at) => now - ended_at < ENDED_CALL_LINGER,
+            None => true,
+        });
+        self.failed_calls.retain(|call| match call.ended_at {
+            Some(ended_at) => now - ended_at < ENDED_CALL_LINGER,
+            None => false,
+        });
+    }
+
+    /// Keeps the STUN mappings fresh and the registrar's flow open.
+    fn poll_nat(&mut self, now: Instant) {
+        self.poll_spare_rtp(now);
+        let request = match &mut self.stun {
+            Some(stun) => stun.poll(now).map(|request| (stun.server(), request)),
+            None => None,
+        };
+        if let Some((server, request)) = request {
+            let target = TransportTarget {
+                transport: SipTransport::Udp,
+                addr: server,
+            };
+            self.send_bytes(request, target);
+        }
+        let settled = match &self.stun {
+            Some(stun) => stun.is_settled(),
+            None => true,
+        };
+        if self.register_when_mapped && settled {
+            self.register_when_mapped = false;
+            self.register();
+        }
+
+        if now >= self.next_keepalive {
+            self.next_keepalive = now + SIP_KEEPALIVE_INTERVAL;
+            let registered = match &self.registration {
+                Some(registration) => registration.state() == RegistrationState::Registered,
+                None => false,
+            };
+            if let (true, Some(outbound)) = (registered, self.outbound) {
+                self.send_bytes(SIP_KEEPALIVE.to_vec(), outbound);
+            }
+        }
+    }
+
+    fn handle_stun(&mut self, bytes: &[u8], source: SocketAddr) {
+        let mapped = match &mut self.stun {
+            Some(stun) if stun.server() == source => stun.on_response(bytes, Instant::now()),
+            _ => None,
+        };
+        let mapped = match mapped {
+            Some(mapped) => mapped,
+            None => return,
+        };
+        println!("SIP is reachable from outside on {}", mapped);
+        self.local_addr = Some(mapped);
+        // A changed mapping needs a new Contact at the registrar; the first one
+        // gets registered once `poll_nat` sees STUN is done.
+        if !self.register_when_mapped {
+            self.register();
+        }
+    }
+
+    /// Starts, updates and stops media streams to follow the calls' offer/answer state.
+    fn sync_media(&mut self) {
+        let mut wanted = vec![];
+        for call in self.calls.iter() {
+            if call.is_ended() {
+                continue;
+            }
+            if let Some(session) = call.media_session() {
+                wanted.push((call.handle(), session.clone()));
+            }
+        }
+
+        let mut index = 0;
+        while index < self.media_sessions.len() {
+            let handle = self.media_sessions[index].0;
+            if wanted
+                .iter()
+                .any(|(wanted_handle, _)| *wanted_handle == handle)
+            {
+                index += 1;
+            } else {
+                let (_, _, media) = self.media_sessions.remove(index);
+                media.stop();
+            }
+        }
+
+        for (handle, session) in wanted {
+            match self
+                .media_sessions
+                .iter_mut()
+                .find(|(media_handle, _, _)| *media_handle == handle)
+            {
+                Some((_, current, media)) => {
+                    if *current != session {
+                        media.update(session.clone());
+                        *current = session;
+                    }
+                }
+                None => match MediaSession::start(session.clone(), self.stun_server) {
+                    Ok(media) => self.media_sessions.push((handle, session, media)),
+                    Err(err) => {
+                        println!("Call {} has no media: {}", handle, err);
+                        self.with_call(handle, |call, _| call.hangup());
+                    }
+                },
+            }
+        }
+
+        let calls = &self.calls;
+        self.pending_audio.retain(|(handle, _, _)| {
+            calls
+                .iter()
+                .any(|call| call.handle() == *handle && !call.is_ended())
+        });
+        let mut index = 0;
+        while index < self.pending_audio.len() {
+            let handle = self.pending_audio[index].0;
+            match self
+                .media_sessions
+                .iter()
+                .find(|(media_handle, _, _)| *media_handle == handle)
+            {
+                Some((_, _, media)) => {
+                    let (_, capture, playback) = self.pending_audio.remove(index);
+                    // One call at a time gets the mic and speaker.
+                    for (other, _, other_media) in self.media_sessions.iter() {
+                        if *other != handle {
+                            other_media.detach_audio();
+                        }
+                    }
+                    media.attach_audio(capture, playback);
+                }
+                None => index += 1,
+            }
+        }
+    }
+
+    /// Requests go to the registrar, which doubles as our outbound proxy. Responses go
+    /// back the way their request came, over the same connection if it was a stream.
+    fn send(&self, message: rsip::SipMessage, destination: Option<TransportTarget>) {
+        let destination = match destination.or(self.outbound) {
+            Some(destination) => destination,
+            None => return,
+        };
+        let transport = match &self.transport {
+            Some(transport) => transport,
+            None => return,
+        };
+        transport.send(destination, self.server_name_for(destination), message);
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>, destination: TransportTarget) {
+        if let Some(transport) = &self.transport {
+            transport.send_bytes(destination, self.server_name_for(destination), bytes);
+        }
+    }
+
+    // The name to check a TLS certificate against, which we only know for the registrar.
+    fn server_name_for(&self, destination: TransportTarget) -> Option<String> {
+        if Some(destination) == self.outbound {
+            self.outbound_host.clone()
+        } else {
+            None
+        }
+    }
+
+    fn notify(&self, event: UserAgentEvent) {
+        if self
+            .system_events
+            .send(SystemEvent::UserAgent(event))
+            .is_err()
+        {
+            println!("Nobody is listening for user agent events");
+        }
+    }
+
+    fn publish_status(&self) {
+        let registration = match &self.registration {
+            Some(registration) => registration.state(),
+            None => RegistrationState::Unregistered,
+        };
+        let voicemail_number = self
+            .account
+            .as_ref()
+            .and_then(|account| account.voicemail.clone())
+            .or_else(|| self.voicemail.as_ref().and_then(|vm| vm.account.clone()));
+        if let Ok(mut status) = self.status.lock() {
+            status.calls = self
+                .calls
+                .iter()
+                .map(|call| call.info())
+                .chain(self.failed_calls.iter().cloned())
+                .collect();
+            status.voicemail = self.voicemail.clone();
+            status.voicemail_number = voicemail_number;
+            // Socket setup failures are reported before a registration ever starts.
+            if let RegistrationState::Failed(_) = status.registration {
+                if self.transport.is_none() {
+                    return;
+                }
+            }
+            status.registration = registration;
+        }
+    }
+}
+
+/// Binds the pair for a moment to see nobody else has it.
+fn rtp_ports_free(port: u16) -> bool {
+    UdpSocket::bind(("0.0.0.0", port))
+        .and_then(|rtp| UdpSocket::bind(("0.0.0.0", port + 1)).map(|rtcp| (rtp, rtcp)))
+        .is_ok()
+}
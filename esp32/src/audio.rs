@@ -0,0 +1,74 @@
+use bricc::audio::traits::{AudioError, AudioFormat, AudioModule, CaptureStream, PlaybackStream};
+use bricc::audio::AudioControls;
+
+// What the codec will most likely want; nothing is wired up yet so anything goes.
+const ESP_AUDIO_SAMPLE_RATE: u32 = 8000;
+
+/// Placeholder until the board's codec chip has a driver. The mic is silent and
+/// the speaker goes nowhere, but calls still get a working audio path.
+pub struct EspAudioModule {
+    controls: AudioControls,
+}
+
+impl EspAudioModule {
+    pub fn new() -> EspAudioModule {
+        EspAudioModule {
+            controls: AudioControls::new(),
+        }
+    }
+}
+
+impl AudioModule for EspAudioModule {
+    type Capture = EspCapture;
+    type Playback = EspPlayback;
+
+    fn negotiate_format(&self, _wanted: AudioFormat) -> AudioFormat {
+        AudioFormat::mono(ESP_AUDIO_SAMPLE_RATE)
+    }
+
+    fn open_capture(&mut self, format: AudioFormat) -> Result<EspCapture, AudioError> {
+        if format != AudioFormat::mono(ESP_AUDIO_SAMPLE_RATE) {
+            return Err(AudioError::Unsupported(format));
+        }
+        Ok(EspCapture { format })
+    }
+
+    fn open_playback(&mut self, format: AudioFormat) -> Result<EspPlayback, AudioError> {
+        if format != AudioFormat::mono(ESP_AUDIO_SAMPLE_RATE) {
+            return Err(AudioError::Unsupported(format));
+        }
+        Ok(EspPlayback { format })
+    }
+
+    fn controls(&self) -> AudioControls {
+        self.controls.clone()
+    }
+}
+
+pub struct EspCapture {
+    format: AudioFormat,
+}
+
+impl CaptureStream for EspCapture {
+    fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn read(&mut self, _pcm: &mut [i16]) -> usize {
+        0
+    }
+}
+
+pub struct EspPlayback {
+    format: AudioFormat,
+}
+
+impl PlaybackStream for EspPlayback {
+    fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn write(&mut self, pcm: &[i16]) -> usize {
+        pcm.len()
+    }
+}
@@ -1,3 +1,4 @@
+mod audio;
 mod kv_store;
 mod rt_system;
 mod wifi;
@@ -14,7 +15,7 @@ use wifi::{EspWifiModule, EspWifiModuleInterface};
 use esp_idf_sys::{self as _}; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use std::{sync::Arc, time::Duration};
 
-use crate::{kv_store::EspKvStore, rt_system::EspRtSystemControl};
+use crate::{audio::EspAudioModule, kv_store::EspKvStore, rt_system::EspRtSystemControl};
 
 fn main() {
     esp_idf_sys::link_patches();
@@ -61,11 +62,14 @@ fn main() {
     };
 
     #[cfg(feature = "telnet")]
-    let mut bricc_system = Bricc::new::<TelnetModule>(
+    let mut bricc_system = Bricc::new(
         EspKvStore::new(default_nvs.clone()),
         wifi_module,
         input_module_interface,
+        EspAudioModule::new(),
     );
+    #[cfg(feature = "telnet")]
+    display_module.attach_contacts(bricc_system.contacts());
 
     loop {
         #[cfg(feature = "telnet")]
@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bricc::audio::traits::{AudioError, AudioFormat, AudioModule, CaptureStream, PlaybackStream};
+use bricc::audio::{resample, AudioControls};
+
+const WAV_HEADER_BYTES: u32 = 44;
+// A second of audio at phone rates; the loopback drops the oldest beyond that.
+const LOOPBACK_CAPACITY_SAMPLES: usize = 8000;
+
+/// Audio for the simulator, with no sound card involved. Either the mic plays a WAV
+/// file and the speaker records to one, or whatever is played comes straight back
+/// into the mic.
+///
+/// Set `KYP_MIC_WAV` and/or `KYP_SPEAKER_WAV` to use files, otherwise it loops back.
+pub struct SimAudioModule {
+    backend: Backend,
+    controls: AudioControls,
+}
+
+enum Backend {
+    Wav {
+        mic: Option<PathBuf>,
+        speaker: Option<PathBuf>,
+    },
+    Loopback(Arc<Mutex<VecDeque<i16>>>),
+}
+
+impl SimAudioModule {
+    pub fn wav(mic: Option<PathBuf>, speaker: Option<PathBuf>) -> SimAudioModule {
+        SimAudioModule {
+            backend: Backend::Wav { mic, speaker },
+            controls: AudioControls::new(),
+        }
+    }
+
+    pub fn loopback() -> SimAudioModule {
+        SimAudioModule {
+            backend: Backend::Loopback(Arc::new(Mutex::new(VecDeque::new()))),
+            controls: AudioControls::new(),
+        }
+    }
+
+    pub fn from_env() -> SimAudioModule {
+        let mic = std::env::var_os("KYP_MIC_WAV").map(PathBuf::from);
+        let speaker = std::env::var_os("KYP_SPEAKER_WAV").map(PathBuf::from);
+        if mic.is_none() && speaker.is_none() {
+            println!("Simulator audio: loopback");
+            SimAudioModule::loopback()
+        } else {
+            println!("Simulator audio: mic {:?}, speaker {:?}", mic, speaker);
+            SimAudioModule::wav(mic, speaker)
+        }
+    }
+}
+
+impl AudioModule for SimAudioModule {
+    type Capture = SimCapture;
+    type Playback = SimPlayback;
+
+    fn negotiate_format(&self, wanted: AudioFormat) -> AudioFormat {
+        // Everything gets resampled on the way in and out, so any mono rate works.
+        AudioFormat::mono(wanted.sample_rate)
+    }
+
+    fn open_capture(&mut self, format: AudioFormat) -> Result<SimCapture, AudioError> {
+        if format.channels != 1 {
+            return Err(AudioError::Unsupported(format));
+        }
+        let source = match &self.backend {
+            Backend::Wav { mic: Some(mic), .. } => {
+                let (rate, samples) = read_wav(mic).map_err(AudioError::Device)?;
+                CaptureSource::File {
+                    samples: resample(&samples, rate, format.sample_rate),
+                    position: 0,
+                }
+            }
+            Backend::Wav { mic: None, .. } => CaptureSource::Silence,
+            Backend::Loopback(queue) => CaptureSource::Loopback(queue.clone()),
+        };
+        Ok(SimCapture {
+            format,
+            source,
+            controls: self.controls.clone(),
+            started_at: Instant::now(),
+            delivered: 0,
+        })
+    }
+
+    fn open_playback(&mut self, format: AudioFormat) -> Result<SimPlayback, AudioError> {
+        if format.channels != 1 {
+            return Err(AudioError::Unsupported(format));
+        }
+        let sink = match &self.backend {
+            Backend::Wav {
+                speaker: Some(speaker),
+                ..
+            } => PlaybackSink::File(WavWriter::create(speaker, format.sample_rate)?),
+            Backend::Wav { speaker: None, .. } => PlaybackSink::Discard,
+            Backend::Loopback(queue) => PlaybackSink::Loopback(queue.clone()),
+        };
+        Ok(SimPlayback {
+            format,
+            sink,
+            controls: self.controls.clone(),
+        })
+    }
+
+    fn controls(&self) -> AudioControls {
+        self.controls.clone()
+    }
+}
+
+enum CaptureSource {
+    /// Loops the file forever.
+    File {
+        samples: Vec<i16>,
+        position: usize,
+    },
+    Silence,
+    Loopback(Arc<Mutex<VecDeque<i16>>>),
+}
+
+pub struct SimCapture {
+    format: AudioFormat,
+    source: CaptureSource,
+    controls: AudioControls,
+    started_at: Instant,
+    delivered: u64,
+}
+
+impl CaptureStream for SimCapture {
+    fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn read(&mut self, pcm: &mut [i16]) -> usize {
+        let read = match &mut self.source {
+            CaptureSource::Loopback(queue) => match queue.lock() {
+                Ok(mut queue) => {
+                    let read = pcm.len().min(queue.len());
+                    for (sample, queued) in pcm.iter_mut().zip(queue.drain(..read)) {
+                        *sample = queued;
+                    }
+                    read
+                }
+                Err(_) => 0,
+            },
+            source => {
+                // A real mic only has as much as time has let it record.
+                let due = self.started_at.elapsed().as_micros() as u64
+                    * self.format.sample_rate as u64
+                    / 1_000_000;
+                let read = (due.saturating_sub(self.delivered) as usize).min(pcm.len());
+                match source {
+                    CaptureSource::File { samples, position } if !samples.is_empty() => {
+                        for sample in pcm[..read].iter_mut() {
+                            *sample = samples[*position];
+                            *position = (*position + 1) % samples.len();
+                        }
+                    }
+                    _ => pcm[..read].iter_mut().for_each(|sample| *sample = 0),
+                }
+                self.delivered += read as u64;
+                read
+            }
+        };
+        self.controls.apply_mute(&mut pcm[..read]);
+        read
+    }
+}
+
+enum PlaybackSink {
+    File(WavWriter),
+    Discard,
+    Loopback(Arc<Mutex<VecDeque<i16>>>),
+}
+
+pub struct SimPlayback {
+    format: AudioFormat,
+    sink: PlaybackSink,
+    controls: AudioControls,
+}
+
+impl PlaybackStream for SimPlayback {
+    fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn write(&mut self, pcm: &[i16]) -> usize {
+        let mut pcm = pcm.to_vec();
+        self.controls.apply_volume(&mut pcm);
+        match &mut self.sink {
+            PlaybackSink::File(writer) => {
+                if let Err(err) = writer.write_samples(&pcm) {
+                    println!("Failed to write speaker WAV: {}", err);
+                }
+            }
+            PlaybackSink::Discard => {}
+            PlaybackSink::Loopback(queue) => {
+                if let Ok(mut queue) = queue.lock() {
+                    queue.extend(pcm.iter());
+                    let excess = queue.len().saturating_sub(LOOPBACK_CAPACITY_SAMPLES);
+                    queue.drain(..excess);
+                }
+            }
+        }
+        pcm.len()
+    }
+}
+
+/// Reads a 16 bit PCM WAV file, mixed down to mono. Returns the sample rate and samples.
+fn read_wav(path: &PathBuf) -> Result<(u32, Vec<i16>), String> {
+    let bytes = std::fs::read(path).map_err(|err| format!("Can't read {:?}: {}", path, err))?;
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(format!("{:?} is not a WAV file", path));
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    let u32_at =
+        |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+
+    let mut format = None;
+    let mut at = 12;
+    while at + 8 <= bytes.len() {
+        let chunk_len = u32_at(at + 4) as usize;
+        let body = at + 8;
+        if body + chunk_len > bytes.len() {
+            return Err(format!("{:?} is truncated", path));
+        }
+        match &bytes[at..at + 4] {
+            b"fmt " if chunk_len >= 16 => {
+                let encoding = u16_at(body);
+                let channels = u16_at(body + 2);
+                let sample_rate = u32_at(body + 4);
+                let bits = u16_at(body + 14);
+                if encoding != 1 || bits != 16 || channels == 0 {
+                    return Err(format!("{:?} is not 16 bit PCM", path));
+                }
+                format = Some((sample_rate, channels as usize));
+            }
+            b"data" => {
+                let (sample_rate, channels) =
+                    format.ok_or_else(|| format!("{:?} has data before fmt", path))?;
+                let samples = bytes[body..body + chunk_len]
+                    .chunks_exact(2 * channels)
+                    .map(|frame| {
+                        let sum: i32 = frame
+                            .chunks_exact(2)
+                            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as i32)
+                            .sum();
+                        (sum / channels as i32) as i16
+                    })
+                    .collect();
+                return Ok((sample_rate, samples));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        at = body + chunk_len + (chunk_len & 1);
+    }
+    Err(format!("{:?} has no audio", path))
+}
+
+/// Writes mono 16 bit PCM, filling in the sizes in the header when dropped.
+struct WavWriter {
+    file: BufWriter<File>,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    fn create(path: &PathBuf, sample_rate: u32) -> Result<WavWriter, AudioError> {
+        let file = File::create(path)
+            .map_err(|err| AudioError::Device(format!("Can't create {:?}: {}", path, err)))?;
+        let mut writer = WavWriter {
+            file: BufWriter::new(file),
+            data_bytes: 0,
+        };
+        writer
+            .write_header(sample_rate)
+            .map_err(|err| AudioError::Device(err.to_string()))?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> std::io::Result<()> {
+        let file = &mut self.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(WAV_HEADER_BYTES - 8).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * 2).to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())
+    }
+
+    fn write_samples(&mut self, pcm: &[i16]) -> std::io::Result<()> {
+        for sample in pcm.iter() {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_bytes += 2 * pcm.len() as u32;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(WAV_HEADER_BYTES - 8 + self.data_bytes).to_le_bytes())?;
+        self.file
+            .seek(SeekFrom::Start(WAV_HEADER_BYTES as u64 - 4))?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            println!("Failed to finish speaker WAV: {}", err);
+        }
+    }
+}
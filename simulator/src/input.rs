@@ -1,11 +1,33 @@
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
 
 use bricc::input::traits::{InputModule, UserInput};
 use embedded_graphics_simulator::sdl2::Keycode;
 use embedded_graphics_simulator::SimulatorEvent;
 
+// Held at least this long, a digit key counts as a long press.
+const LONG_PRESS: Duration = Duration::from_millis(800);
+
 pub struct SimulatorInput {
     receiver: Receiver<SimulatorEvent>,
+    // When each digit key went down, to tell long presses apart.
+    pressed_at: [Option<Instant>; 10],
+}
+
+fn digit_of(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
 }
 
 impl InputModule for SimulatorInput {
@@ -17,32 +39,35 @@ impl InputModule for SimulatorInput {
                         keycode,
                         keymod: _,
                         repeat: _,
-                    } => match keycode {
-                        Keycode::Up => return Some(UserInput::Up),
-                        Keycode::Down => return Some(UserInput::Down),
-                        Keycode::Num0 => return Some(UserInput::Number(0)),
-                        Keycode::Num1 => return Some(UserInput::Number(1)),
-                        Keycode::Num2 => return Some(UserInput::Number(2)),
-                        Keycode::Num3 => return Some(UserInput::Number(3)),
-                        Keycode::Num4 => return Some(UserInput::Number(4)),
-                        Keycode::Num5 => return Some(UserInput::Number(5)),
-                        Keycode::Num6 => return Some(UserInput::Number(6)),
-                        Keycode::Num7 => return Some(UserInput::Number(7)),
-                        Keycode::Num8 => return Some(UserInput::Number(8)),
-                        Keycode::Num9 => return Some(UserInput::Number(9)),
-                        Keycode::Hash => return Some(UserInput::Hash),
-                        Keycode::Asterisk => return Some(UserInput::Star),
-                        Keycode::C => return Some(UserInput::Call),
-                        Keycode::Space => return Some(UserInput::SoftKey),
+                    } => match (keycode, digit_of(keycode)) {
+                        (_, Some(digit)) => {
+                            let held = match self.pressed_at[digit as usize].take() {
+                                Some(pressed_at) => pressed_at.elapsed() >= LONG_PRESS,
+                                None => false,
+                            };
+                            if held {
+                                return Some(UserInput::NumberHeld(digit));
+                            }
+                            return Some(UserInput::Number(digit));
+                        }
+                        (Keycode::Up, _) => return Some(UserInput::Up),
+                        (Keycode::Down, _) => return Some(UserInput::Down),
+                        (Keycode::Hash, _) => return Some(UserInput::Hash),
+                        (Keycode::Asterisk, _) => return Some(UserInput::Star),
+                        (Keycode::C, _) => return Some(UserInput::Call),
+                        (Keycode::Space, _) => return Some(UserInput::SoftKey),
                         _ => {
                             continue;
                         }
                     },
                     SimulatorEvent::KeyDown {
-                        keycode: _,
+                        keycode,
                         keymod: _,
-                        repeat: _,
+                        repeat,
                     } => {
+                        if let (Some(digit), false) = (digit_of(keycode), repeat) {
+                            self.pressed_at[digit as usize] = Some(Instant::now());
+                        }
                         continue;
                     }
                     SimulatorEvent::MouseButtonUp {
@@ -78,6 +103,12 @@ impl InputModule for SimulatorInput {
 impl SimulatorInput {
     pub fn new() -> (SimulatorInput, Sender<SimulatorEvent>) {
         let (sender, receiver) = channel();
-        return (SimulatorInput { receiver }, sender);
+        return (
+            SimulatorInput {
+                receiver,
+                pressed_at: [None; 10],
+            },
+            sender,
+        );
     }
 }
@@ -30,7 +30,9 @@ impl<'a> KvStore for SimKvStore<'a> {
 
 impl<'a> SimKvStore<'a> {
     pub fn new() -> SimKvStore<'a> {
-        let cfg = Config::new(".bricc_prefs/");
+        // Each simulator running at once needs its own.
+        let path = std::env::var("KYP_PREFS_DIR").unwrap_or_else(|_| ".bricc_prefs/".into());
+        let cfg = Config::new(path);
 
         // Open the key/value store
         SimKvStore {
@@ -6,17 +6,53 @@ use embedded_graphics_simulator::{
     BinaryColorTheme, OutputSettingsBuilder, SimulatorDisplay, Window,
 };
 
+use audio::SimAudioModule;
 use bricc::{debug::telnet::TelnetModule, network::wifi::WifiModule};
 use kv_store::SimKvStore;
 
+mod audio;
 mod dummy_rt_system;
 mod dummy_wifi;
 
 mod input;
 mod kv_store;
+mod sip_server;
 
 use crate::input::SimulatorInput;
 use dummy_wifi::{DummyWifiInterface, DummyWifiModule};
+use sip_server::SipTestServer;
+
+/// Starts the SIP test server on `KYP_SIP_SERVER` (an ip:port) if it's set, and signs
+/// up as `KYP_SIP_USER` on it. A second simulator finds the port taken and just
+/// uses the first one's server.
+fn start_test_server(kv_store: &mut SimKvStore) -> Option<SipTestServer> {
+    let addr = std::env::var("KYP_SIP_SERVER").ok()?;
+    let addr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(err) => {
+            println!("KYP_SIP_SERVER {} isn't an address: {}", addr, err);
+            return None;
+        }
+    };
+    let server = match SipTestServer::start(addr) {
+        Ok(server) => Some(server),
+        Err(err) => {
+            println!("Not starting the SIP test server: {}", err);
+            None
+        }
+    };
+    // Where ours ended up, should the port have been left to the system.
+    let addr = match &server {
+        Some(server) => server.local_addr(),
+        None => addr,
+    };
+    if let Ok(user) = std::env::var("KYP_SIP_USER") {
+        if let Err(err) = sip_server::test_account(&user, addr).save(kv_store) {
+            println!("Can't save the test account: {}", err);
+        }
+    }
+    server
+}
 
 #[allow(unused)]
 fn main_simulator() -> Result<(), core::convert::Infallible> {
@@ -28,10 +64,13 @@ fn main_simulator() -> Result<(), core::convert::Infallible> {
     let mut win = Window::new("Hello World", &output_settings);
 
     let (input_impl, sender) = SimulatorInput::new();
-    let mut bricc_system = bricc::Bricc::new::<SimulatorDisplay<BinaryColor>>(
-        SimKvStore::new(),
+    let mut kv_store = SimKvStore::new();
+    let _test_server = start_test_server(&mut kv_store);
+    let mut bricc_system = bricc::Bricc::new(
+        kv_store,
         DummyWifiModule::new(),
         input_impl,
+        SimAudioModule::from_env(),
     );
 
     loop {
@@ -57,11 +96,15 @@ fn main_telnet() -> Result<(), core::convert::Infallible> {
 
     thread::sleep(Duration::from_millis(1000));
 
-    let mut bricc_system = bricc::Bricc::new::<SimulatorDisplay<BinaryColor>>(
-        SimKvStore::new(),
+    let mut kv_store = SimKvStore::new();
+    let _test_server = start_test_server(&mut kv_store);
+    let mut bricc_system = bricc::Bricc::new(
+        kv_store,
         DummyWifiModule::new(),
         input_interface,
+        SimAudioModule::from_env(),
     );
+    display.attach_contacts(bricc_system.contacts());
 
     loop {
         bricc_system.bricc_loop(&mut display);
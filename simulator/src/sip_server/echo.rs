@@ -0,0 +1,202 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bricc::voip::media::rtp::RtpPacket;
+use bricc::voip::negotiation::{MediaConfig, MediaNegotiator, RtpAddress};
+use bricc::voip::sdp::SessionDescription;
+use bricc::voip::sip::{generate_response, new_tag};
+use rsip::prelude::*;
+
+const ECHO_THREAD_STACK_SIZE_BYTES: usize = 16384usize;
+const ECHO_SOCKET_POLL_PERIOD: Duration = Duration::from_millis(20);
+const ECHO_BUFFER_BYTES: usize = 1500;
+const SDP_CONTENT_TYPE: &str = "application/sdp";
+
+/// Sends every RTP packet back where it came from, under an SSRC of its own.
+struct EchoStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EchoStream {
+    fn start(socket: UdpSocket) -> Result<EchoStream, String> {
+        socket
+            .set_read_timeout(Some(ECHO_SOCKET_POLL_PERIOD))
+            .map_err(|err| err.to_string())?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .stack_size(ECHO_THREAD_STACK_SIZE_BYTES)
+            .spawn(move || {
+                let ssrc = echo_ssrc(&socket);
+                let mut buffer = [0u8; ECHO_BUFFER_BYTES];
+                while !thread_stop.load(Ordering::Relaxed) {
+                    let (len, from) = match socket.recv_from(&mut buffer) {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    if let Ok(mut packet) = RtpPacket::parse(&buffer[..len]) {
+                        packet.ssrc = ssrc;
+                        let _ = socket.send_to(&packet.to_bytes(), from);
+                    }
+                }
+            })
+            .map_err(|err| format!("Failed to create echo thread: {}", err))?;
+        Ok(EchoStream {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for EchoStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Good enough to tell us apart from the caller, which is all it's for.
+fn echo_ssrc(socket: &UdpSocket) -> u32 {
+    match socket.local_addr() {
+        Ok(addr) => 0x6563_0000 | addr.port() as u32,
+        Err(_) => 0x6563_686f,
+    }
+}
+
+struct EchoCall {
+    call_id: String,
+    to_tag: String,
+    answer: SessionDescription,
+    _stream: EchoStream,
+}
+
+/// The echo test extension: answers straight away and plays you back to yourself
+/// until you hang up.
+pub struct EchoService {
+    local_addr: SocketAddr,
+    calls: Vec<EchoCall>,
+}
+
+impl EchoService {
+    pub fn new(local_addr: SocketAddr) -> EchoService {
+        EchoService {
+            local_addr,
+            calls: vec![],
+        }
+    }
+
+    /// The responses to `request`, if it needs any.
+    pub fn handle_request(
+        &mut self,
+        request: &rsip::Request,
+    ) -> Result<Option<rsip::Response>, rsip::Error> {
+        let call_id = request.call_id_header()?.value().to_string();
+        let existing = self.calls.iter().position(|call| call.call_id == call_id);
+        let response = match (&request.method, existing) {
+            (rsip::Method::Ack, _) => return Ok(None),
+            // A retransmission, or a re-INVITE for hold; either way the answer stands.
+            (rsip::Method::Invite, Some(index)) => {
+                let call = &self.calls[index];
+                self.ok_with_answer(request, &call.to_tag, &call.answer)?
+            }
+            (rsip::Method::Invite, None) => self.answer(request, call_id)?,
+            (rsip::Method::Bye, Some(index)) => {
+                println!("Test server: echo call {} hung up", call_id);
+                self.calls.remove(index);
+                generate_response(request, 200, None, None, None, vec![])?
+            }
+            (rsip::Method::Bye, None) => generate_response(request, 481, None, None, None, vec![])?,
+            // We answer before anything could be cancelled.
+            (rsip::Method::Cancel, _) | (rsip::Method::Options, _) => {
+                generate_response(request, 200, Some(&new_tag()), None, None, vec![])?
+            }
+            _ => generate_response(request, 405, Some(&new_tag()), None, None, vec![])?,
+        };
+        Ok(Some(response))
+    }
+
+    fn answer(
+        &mut self,
+        request: &rsip::Request,
+        call_id: String,
+    ) -> Result<rsip::Response, rsip::Error> {
+        let to_tag = new_tag();
+        let offer = match SessionDescription::parse_bytes(&request.body) {
+            Ok(offer) => offer,
+            Err(err) => {
+                println!("Test server: echo needs an offer in the INVITE: {}", err);
+                return generate_response(request, 488, Some(&to_tag), None, None, vec![]);
+            }
+        };
+        let socket = match UdpSocket::bind(SocketAddr::new(self.local_addr.ip(), 0)) {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("Test server: no RTP port for echo: {}", err);
+                return generate_response(request, 500, Some(&to_tag), None, None, vec![]);
+            }
+        };
+        let local_port = socket.local_addr().map(|addr| addr.port()).unwrap_or(0);
+        let mut media = MediaNegotiator::new(
+            MediaConfig::default(),
+            self.local_addr.ip(),
+            RtpAddress {
+                local_port,
+                public: None,
+            },
+        );
+        let answer = match media.answer(&offer) {
+            Ok(answer) => answer,
+            Err(err) => {
+                println!("Test server: can't echo that: {}", err);
+                return generate_response(request, 488, Some(&to_tag), None, None, vec![]);
+            }
+        };
+        let stream = match EchoStream::start(socket) {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("Test server: {}", err);
+                return generate_response(request, 500, Some(&to_tag), None, None, vec![]);
+            }
+        };
+        println!(
+            "Test server: echoing call {} on port {}",
+            call_id, local_port
+        );
+        let response = self.ok_with_answer(request, &to_tag, &answer)?;
+        self.calls.push(EchoCall {
+            call_id,
+            to_tag,
+            answer,
+            _stream: stream,
+        });
+        Ok(response)
+    }
+
+    fn ok_with_answer(
+        &self,
+        request: &rsip::Request,
+        to_tag: &str,
+        answer: &SessionDescription,
+    ) -> Result<rsip::Response, rsip::Error> {
+        let contact = rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            auth: Some((super::ECHO_EXTENSION.to_string(), Option::<String>::None).into()),
+            host_with_port: self.local_addr.into(),
+            ..Default::default()
+        };
+        generate_response(
+            request,
+            200,
+            Some(to_tag),
+            Some(contact),
+            Some(SDP_CONTENT_TYPE),
+            answer.to_string().into_bytes(),
+        )
+    }
+}
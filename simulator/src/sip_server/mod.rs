@@ -0,0 +1,449 @@
+//! A registrar, stateless proxy, echo service and STUN server for trying phones
+//! against each other without a provider. UDP only, and every user has the same
+//! password.
+
+mod echo;
+mod registrar;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bricc::voip::account::{SipAccount, SipTransport, DEFAULT_REGISTRATION_EXPIRES_SECS};
+use bricc::voip::sip::{generate_response, new_tag, param_value, parse_message};
+use bricc::voip::stun::{answer_binding_request, is_stun};
+use bricc::voip::transport::stamp_via;
+use rsip::prelude::*;
+
+use echo::EchoService;
+use registrar::{Registrar, TEST_PASSWORD};
+
+/// Call this to hear yourself.
+pub const ECHO_EXTENSION: &str = "echo";
+const SERVER_THREAD_STACK_SIZE_BYTES: usize = 32768usize;
+const SERVER_SOCKET_POLL_PERIOD: Duration = Duration::from_millis(20);
+const SERVER_BUFFER_BYTES: usize = 65535;
+
+pub struct SipTestServer {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SipTestServer {
+    /// Binds `addr`, which should be one the phones can reach: it goes in our Vias.
+    pub fn start(addr: SocketAddr) -> Result<SipTestServer, String> {
+        let socket =
+            UdpSocket::bind(addr).map_err(|err| format!("Can't bind {}: {}", addr, err))?;
+        socket
+            .set_read_timeout(Some(SERVER_SOCKET_POLL_PERIOD))
+            .map_err(|err| err.to_string())?;
+        let local_addr = socket.local_addr().map_err(|err| err.to_string())?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .stack_size(SERVER_THREAD_STACK_SIZE_BYTES)
+            .spawn(move || {
+                let mut server = ServerThread {
+                    socket,
+                    local_addr,
+                    registrar: Registrar::new(),
+                    echo: EchoService::new(local_addr),
+                };
+                while !thread_stop.load(Ordering::Relaxed) {
+                    server.poll();
+                }
+            })
+            .map_err(|err| format!("Failed to create SIP test server thread: {}", err))?;
+        println!("SIP test server on {}", local_addr);
+        Ok(SipTestServer {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for SipTestServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// An account for `user` on the test server at `server`, which also does STUN.
+pub fn test_account(user: &str, server: SocketAddr) -> SipAccount {
+    SipAccount {
+        user: user.into(),
+        domain: server.to_string(),
+        registrar: None,
+        password: TEST_PASSWORD.into(),
+        transport: SipTransport::Udp,
+        display_name: Some(user.into()),
+        expires: DEFAULT_REGISTRATION_EXPIRES_SECS,
+        media: Default::default(),
+        stun_server: Some(server.to_string()),
+        voicemail: None,
+    }
+}
+
+struct ServerThread {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    registrar: Registrar,
+    echo: EchoService,
+}
+
+impl ServerThread {
+    fn poll(&mut self) {
+        let mut buffer = vec![0u8; SERVER_BUFFER_BYTES];
+        let (len, source) = match self.socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(_) => return,
+        };
+        let bytes = &buffer[..len];
+        if bytes.iter().all(|byte| byte.is_ascii_whitespace()) {
+            // Keep-alive.
+            return;
+        }
+        if is_stun(bytes) {
+            if let Some(response) = answer_binding_request(bytes, source) {
+                self.send_bytes(&response, source);
+            }
+            return;
+        }
+        let result = match parse_message(bytes) {
+            Ok(rsip::SipMessage::Request(mut request)) => {
+                stamp_via(&mut request, source);
+                self.handle_request(request, source)
+            }
+            Ok(rsip::SipMessage::Response(response)) => self.forward_response(response),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            println!("Test server: dropping message from {}: {}", source, err);
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        request: rsip::Request,
+        source: SocketAddr,
+    ) -> Result<(), rsip::Error> {
+        let now = Instant::now();
+        if request.method == rsip::Method::Register {
+            let response = self.registrar.handle_register(&request, source, now)?;
+            self.send(response.into(), source);
+            return Ok(());
+        }
+        let user = request.uri.auth.as_ref().map(|auth| auth.user.clone());
+        let response = match user {
+            Some(user) if user == ECHO_EXTENSION => self.echo.handle_request(&request)?,
+            Some(user) => match self.registrar.binding(&user, now) {
+                Some(binding) => {
+                    let (contact, target) = (binding.contact.clone(), binding.source);
+                    return self.proxy(request, source, contact, target);
+                }
+                None if request.method == rsip::Method::Ack => None,
+                None => Some(generate_response(
+                    &request,
+                    404,
+                    Some(&new_tag()),
+                    None,
+                    None,
+                    vec![],
+                )?),
+            },
+            // Addressed to us rather than anyone registered.
+            None if request.method == rsip::Method::Ack => None,
+            None => Some(generate_response(
+                &request,
+                200,
+                Some(&new_tag()),
+                None,
+                None,
+                vec![],
+            )?),
+        };
+        if let Some(response) = response {
+            self.send(response.into(), source);
+        }
+        Ok(())
+    }
+
+    /// Stateless forwarding, RFC 3261 section 16.11. Our branch is derived from the
+    /// incoming one so retransmissions and CANCELs come out the same.
+    fn proxy(
+        &mut self,
+        request: rsip::Request,
+        source: SocketAddr,
+        contact: rsip::Uri,
+        target: SocketAddr,
+    ) -> Result<(), rsip::Error> {
+        let branch = request
+            .via_header()
+            .ok()
+            .and_then(|via| param_value(via.value(), "branch"))
+            .unwrap_or_else(new_tag);
+        let mut headers: rsip::Headers = Default::default();
+        headers.push(rsip::Header::Via(rsip::headers::Via::new(format!(
+            "SIP/2.0/UDP {};branch={}.proxy",
+            self.local_addr, branch
+        ))));
+        for header in request.headers.iter() {
+            match header {
+                rsip::Header::MaxForwards(max_forwards) => {
+                    let hops: u32 = max_forwards.value().trim().parse().unwrap_or(70);
+                    if hops == 0 {
+                        let response =
+                            generate_response(&request, 483, Some(&new_tag()), None, None, vec![])?;
+                        self.send(response.into(), source);
+                        return Ok(());
+                    }
+                    headers.push(rsip::headers::MaxForwards::new((hops - 1).to_string()).into());
+                }
+                header => headers.push(header.clone()),
+            }
+        }
+        // New calls are for the address of record; requests inside a dialog already
+        // carry the contact.
+        let in_dialog = request
+            .to_header()
+            .map(|to| to.value().contains(";tag="))
+            .unwrap_or(false);
+        let uri = if in_dialog { request.uri } else { contact };
+        let forwarded = rsip::Request {
+            method: request.method,
+            uri,
+            headers,
+            version: request.version,
+            body: request.body,
+        };
+        self.send(forwarded.into(), target);
+        Ok(())
+    }
+
+    /// Pops our Via and sends the response on to the next one.
+    fn forward_response(&mut self, response: rsip::Response) -> Result<(), rsip::Error> {
+        let ours = response.via_header()?.value().to_string();
+        if !ours.contains(&self.local_addr.to_string()) {
+            println!("Test server: dropping response that isn't for us");
+            return Ok(());
+        }
+        let mut headers: rsip::Headers = Default::default();
+        let mut popped = false;
+        let mut next = None;
+        for header in response.headers.iter() {
+            match header {
+                rsip::Header::Via(_) if !popped => popped = true,
+                rsip::Header::Via(via) => {
+                    if next.is_none() {
+                        next = via_destination(via.value());
+                    }
+                    headers.push(header.clone());
+                }
+                header => headers.push(header.clone()),
+            }
+        }
+        let next = match next {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        let forwarded = rsip::Response {
+            status_code: response.status_code,
+            headers,
+            version: response.version,
+            body: response.body,
+        };
+        self.send(forwarded.into(), next);
+        Ok(())
+    }
+
+    fn send(&self, message: rsip::SipMessage, destination: SocketAddr) {
+        self.send_bytes(message.to_string().as_bytes(), destination);
+    }
+
+    fn send_bytes(&self, bytes: &[u8], destination: SocketAddr) {
+        if let Err(err) = self.socket.send_to(bytes, destination) {
+            println!("Test server: failed to send to {}: {}", destination, err);
+        }
+    }
+}
+
+/// Where responses for a Via go: `received` and `rport` if we stamped them, otherwise
+/// the sent-by.
+fn via_destination(via: &str) -> Option<SocketAddr> {
+    let sent_by = via.split(';').next()?.split_whitespace().nth(1)?;
+    let sent_by: SocketAddr = match sent_by.parse() {
+        Ok(addr) => addr,
+        Err(_) => format!("{}:5060", sent_by).parse().ok()?,
+    };
+    let ip = param_value(via, "received")
+        .and_then(|received| received.parse().ok())
+        .unwrap_or_else(|| sent_by.ip());
+    let port = param_value(via, "rport")
+        .and_then(|rport| rport.parse().ok())
+        .unwrap_or_else(|| sent_by.port());
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bricc::voip::message::OutgoingMessage;
+    use bricc::voip::registration::{RegistrationClient, RegistrationState};
+    use bricc::voip::stun::discover;
+
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// A phone's SIP socket, pointed at the server.
+    struct Phone {
+        socket: UdpSocket,
+        server: SocketAddr,
+    }
+
+    impl Phone {
+        fn new(server: &SipTestServer) -> Phone {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_read_timeout(Some(RECEIVE_TIMEOUT)).unwrap();
+            Phone {
+                socket,
+                server: server.local_addr(),
+            }
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.socket.local_addr().unwrap()
+        }
+
+        fn account(&self, user: &str) -> SipAccount {
+            test_account(user, self.server)
+        }
+
+        fn send(&self, message: rsip::SipMessage) {
+            self.socket
+                .send_to(message.to_string().as_bytes(), self.server)
+                .unwrap();
+        }
+
+        fn receive(&self) -> rsip::SipMessage {
+            let mut buffer = vec![0u8; SERVER_BUFFER_BYTES];
+            let (len, _) = self.socket.recv_from(&mut buffer).unwrap();
+            parse_message(&buffer[..len]).unwrap()
+        }
+
+        fn receive_request(&self) -> rsip::Request {
+            match self.receive() {
+                rsip::SipMessage::Request(request) => request,
+                rsip::SipMessage::Response(response) => panic!("Got a response: {}", response),
+            }
+        }
+
+        fn receive_response(&self) -> rsip::Response {
+            match self.receive() {
+                rsip::SipMessage::Response(response) => response,
+                rsip::SipMessage::Request(request) => panic!("Got a request: {}", request),
+            }
+        }
+
+        /// Runs a registration through to the end, challenge and all.
+        fn register(&self, account: SipAccount) -> RegistrationState {
+            let mut client = RegistrationClient::new(account);
+            let mut request = client.register(self.addr()).unwrap();
+            loop {
+                self.send(request.into());
+                let response = self.receive_response();
+                match client.handle_response(&response, self.addr(), Instant::now()) {
+                    Some(next) => request = next,
+                    None => return client.state(),
+                }
+            }
+        }
+    }
+
+    fn start_server() -> SipTestServer {
+        SipTestServer::start("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn answers_stun() {
+        let server = start_server();
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mapped = discover(port, server.local_addr()).unwrap();
+        assert_eq!(mapped.port(), port);
+    }
+
+    #[test]
+    fn registers_with_the_test_password() {
+        let server = start_server();
+        let phone = Phone::new(&server);
+        assert_eq!(
+            phone.register(phone.account("alice")),
+            RegistrationState::Registered
+        );
+    }
+
+    #[test]
+    fn turns_away_a_wrong_password() {
+        let server = start_server();
+        let phone = Phone::new(&server);
+        let mut account = phone.account("alice");
+        account.password = "guess".into();
+        assert!(matches!(
+            phone.register(account),
+            RegistrationState::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn relays_between_registered_phones() {
+        let server = start_server();
+        let (alice, bob) = (Phone::new(&server), Phone::new(&server));
+        alice.register(alice.account("alice"));
+        bob.register(bob.account("bob"));
+
+        let (_, request) =
+            OutgoingMessage::new(1, bob.account("bob"), bob.addr(), "alice", "Hi".into()).unwrap();
+        bob.send(request.into());
+        let relayed = alice.receive_request();
+        assert_eq!(relayed.method, rsip::Method::Message);
+        assert_eq!(relayed.body, b"Hi".to_vec());
+        let top_via = relayed.via_header().unwrap().value().to_string();
+        assert!(top_via.contains(&server.local_addr().to_string()));
+
+        let ok = generate_response(&relayed, 200, Some(&new_tag()), None, None, vec![]).unwrap();
+        alice.send(ok.into());
+        let response = bob.receive_response();
+        assert_eq!(response.status_code.code(), 200);
+        // Our Via came off on the way back.
+        let vias = response
+            .headers
+            .iter()
+            .filter(|header| matches!(header, rsip::Header::Via(_)))
+            .count();
+        assert_eq!(vias, 1);
+    }
+
+    #[test]
+    fn doesnt_know_unregistered_users() {
+        let server = start_server();
+        let phone = Phone::new(&server);
+        let (_, request) =
+            OutgoingMessage::new(1, phone.account("bob"), phone.addr(), "carol", "Hi".into())
+                .unwrap();
+        phone.send(request.into());
+        assert_eq!(phone.receive_response().status_code.code(), 404);
+    }
+}
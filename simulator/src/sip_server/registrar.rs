@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bricc::voip::digest::{compute_response, DigestCredentials};
+use bricc::voip::sip::{create_unauthorized_from, generate_response, new_tag, param_value};
+use rsip::headers::auth::Algorithm;
+use rsip::prelude::*;
+
+/// Everyone on the test server has this password.
+pub const TEST_PASSWORD: &str = "kyp";
+const MAX_EXPIRES_SECS: u32 = 3600;
+
+pub struct Binding {
+    pub contact: rsip::Uri,
+    /// Where the REGISTER came from, which is where requests for the user go. Unlike
+    /// the Contact, that works from behind NAT.
+    pub source: SocketAddr,
+    expires_at: Instant,
+}
+
+/// One binding per user, authenticated with digest against `TEST_PASSWORD`.
+pub struct Registrar {
+    bindings: HashMap<String, Binding>,
+}
+
+impl Registrar {
+    pub fn new() -> Registrar {
+        Registrar {
+            bindings: HashMap::new(),
+        }
+    }
+
+    pub fn binding(&self, user: &str, now: Instant) -> Option<&Binding> {
+        self.bindings
+            .get(user)
+            .filter(|binding| binding.expires_at > now)
+    }
+
+    pub fn handle_register(
+        &mut self,
+        request: &rsip::Request,
+        source: SocketAddr,
+        now: Instant,
+    ) -> Result<rsip::Response, rsip::Error> {
+        let authorization = request.headers.iter().find_map(|header| match header {
+            rsip::Header::Authorization(authorization) => authorization.typed().ok(),
+            _ => None,
+        });
+        let authorization = match authorization {
+            Some(authorization) => authorization,
+            None => match create_unauthorized_from(request.clone())? {
+                rsip::SipMessage::Response(challenge) => return Ok(challenge),
+                rsip::SipMessage::Request(_) => unreachable!(),
+            },
+        };
+        let user = match request.to_header()?.typed()?.uri.auth {
+            Some(auth) => auth.user,
+            None => return generate_response(request, 400, None, None, None, vec![]),
+        };
+        let credentials = DigestCredentials {
+            username: &authorization.username,
+            password: TEST_PASSWORD,
+        };
+        let expected = compute_response(
+            &authorization.algorithm.unwrap_or(Algorithm::Md5),
+            &credentials,
+            &authorization.realm,
+            &authorization.nonce,
+            &request.method,
+            &authorization.uri,
+            authorization.qop.as_ref(),
+        );
+        if authorization.username != user || expected != Ok(authorization.response.clone()) {
+            println!("Test server: bad credentials for {}", user);
+            return generate_response(request, 403, Some(&new_tag()), None, None, vec![]);
+        }
+
+        let contact = request.headers.iter().find_map(|header| match header {
+            rsip::Header::Contact(contact) => Some(contact.clone()),
+            _ => None,
+        });
+        let contact = match contact {
+            Some(contact) => contact,
+            // Just asking what's registered; we don't say.
+            None => return generate_response(request, 200, Some(&new_tag()), None, None, vec![]),
+        };
+        let expires = param_value(contact.value(), "expires")
+            .or_else(|| {
+                request.headers.iter().find_map(|header| match header {
+                    rsip::Header::Expires(expires) => Some(expires.value().trim().to_string()),
+                    _ => None,
+                })
+            })
+            .and_then(|expires| expires.parse::<u32>().ok())
+            .unwrap_or(MAX_EXPIRES_SECS)
+            .min(MAX_EXPIRES_SECS);
+        let contact_uri = contact.typed()?.uri;
+
+        let mut response = generate_response(request, 200, Some(&new_tag()), None, None, vec![])?;
+        if expires == 0 {
+            println!("Test server: {} unregistered", user);
+            self.bindings.remove(&user);
+        } else {
+            println!(
+                "Test server: {} registered at {} via {}",
+                user, contact_uri, source
+            );
+            response
+                .headers
+                .push(rsip::Header::Contact(rsip::headers::Contact::new(format!(
+                    "<{}>;expires={}",
+                    contact_uri, expires
+                ))));
+            self.bindings.insert(
+                user,
+                Binding {
+                    contact: contact_uri,
+                    source,
+                    expires_at: now + Duration::from_secs(expires as u64),
+                },
+            );
+        }
+        Ok(response)
+    }
+}